@@ -1,11 +1,21 @@
-use functor::Functor;
+use functor::{self, Functor, Marks, MarkFunctors};
 use intern::InternedString;
-use std::fmt::{Debug, Error, Formatter};
+use std::fmt::{Debug, Display, Error, Formatter};
+
+use self::ops::Operators;
+use self::write::Writer;
+
+pub mod ops;
+pub mod read;
+pub mod write;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub enum Term {
     Variable(InternedString),
     Structure(Structure),
+    Integer(i64),
+    /// A string, as opposed to an atom or a list of codes.
+    String(String),
 }
 
 #[derive(Clone, PartialEq, Eq, Hash)]
@@ -14,21 +24,199 @@ pub struct Structure {
     pub terms: Vec<Term>
 }
 
+/// A program clause `head :- body`; a fact has an empty body.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Clause {
+    pub head: Structure,
+    pub body: Vec<Term>,
+}
+
+impl Term {
+    pub fn atom(name: &str) -> Term {
+        Term::Structure(Structure { functor: Functor::transient(name, 0),
+                                    terms: vec![] })
+    }
+
+    pub fn string(text: &str) -> Term {
+        Term::String(text.to_string())
+    }
+
+    /// The list of `items`, ending in `tail` (`[]` for a proper list).
+    pub fn list(items: Vec<Term>, tail: Term) -> Term {
+        items.into_iter().rev().fold(tail, |rest, item| {
+            Term::Structure(Structure { functor: dot(), terms: vec![item, rest] })
+        })
+    }
+
+    pub fn nil() -> Term {
+        Term::atom("[]")
+    }
+
+    /// The items of this term, if it is a proper list.
+    pub fn to_list(&self) -> Option<Vec<Term>> {
+        let mut items = vec![];
+        let mut term = self;
+        loop {
+            match *term {
+                Term::Structure(ref s) if s.functor == dot() => {
+                    items.push(s.terms[0].clone());
+                    term = &s.terms[1];
+                }
+                Term::Structure(ref s) if s.functor == nil() => return Some(items),
+                _ => return None,
+            }
+        }
+    }
+
+    /// The goals of a conjunction, leaving out any `true`.
+    pub fn conjuncts(&self) -> Vec<Term> {
+        let mut goals = vec![];
+        conjuncts(self, &mut goals);
+        goals
+    }
+
+    /// Adds the variables of this term to `out` in order of first
+    /// occurrence, skipping any that are already there.
+    pub fn variables(&self, out: &mut Vec<InternedString>) {
+        match *self {
+            Term::Variable(v) => {
+                if !out.contains(&v) {
+                    out.push(v);
+                }
+            }
+            Term::Structure(ref s) => {
+                for term in &s.terms {
+                    term.variables(out);
+                }
+            }
+            Term::Integer(_) | Term::String(_) => { }
+        }
+    }
+
+    /// The predicate indicator `name/arity` for `f`.
+    pub fn indicator(f: Functor) -> Term {
+        let name = f.with_text(|text| Term::atom(text));
+        Term::Structure(Structure { functor: slash(),
+                                    terms: vec![name, Term::Integer(f.arity() as i64)] })
+    }
+
+    /// The functor named by the indicator `name/arity`, if this is one.
+    pub fn to_indicator(&self) -> Option<Functor> {
+        match *self {
+            Term::Structure(ref s) if s.functor == slash() => {
+                match (&s.terms[0], &s.terms[1]) {
+                    (&Term::Structure(ref name), &Term::Integer(arity))
+                        if name.terms.is_empty() && arity >= 0 =>
+                    {
+                        let f = name.functor.with_text(|text| {
+                            Functor::transient(text, arity as usize)
+                        });
+                        Some(f)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Clause {
+    /// Reads `Head :- Body` (or just `Head`) as a clause, flattening
+    /// conjunctions in the body. Returns `None` if the head is not
+    /// callable.
+    pub fn from_term(term: &Term) -> Option<Clause> {
+        let (head, body) = match *term {
+            Term::Structure(ref s) if s.functor == neck() => (&s.terms[0], Some(&s.terms[1])),
+            _ => (term, None),
+        };
+        let head = match *head {
+            Term::Structure(ref s) => s.clone(),
+            Term::Variable(_) | Term::Integer(_) | Term::String(_) => return None,
+        };
+        let goals = body.map(|body| body.conjuncts()).unwrap_or(vec![]);
+        Some(Clause { head: head, body: goals })
+    }
+
+    /// The body as a single term: a conjunction, or `true` for a fact.
+    pub fn body_term(&self) -> Term {
+        let mut goals = self.body.iter().rev();
+        let last = match goals.next() {
+            Some(goal) => goal.clone(),
+            None => return Term::atom("true"),
+        };
+        goals.fold(last, |rest, goal| {
+            Term::Structure(Structure { functor: comma(), terms: vec![goal.clone(), rest] })
+        })
+    }
+
+    /// The clause as `Head :- Body`, even if it is a fact.
+    pub fn to_rule(&self) -> Term {
+        let head = Term::Structure(self.head.clone());
+        Term::Structure(Structure { functor: neck(), terms: vec![head, self.body_term()] })
+    }
+
+    /// The clause as `Head :- Body`, or just `Head` for a fact.
+    pub fn to_term(&self) -> Term {
+        if self.body.is_empty() {
+            Term::Structure(self.head.clone())
+        } else {
+            self.to_rule()
+        }
+    }
+}
+
+fn conjuncts(term: &Term, goals: &mut Vec<Term>) {
+    match *term {
+        Term::Structure(ref s) if s.functor == comma() => {
+            conjuncts(&s.terms[0], goals);
+            conjuncts(&s.terms[1], goals);
+        }
+        Term::Structure(ref s) if s.terms.is_empty() && s.functor.with_text(|t| t == "true") => {
+        }
+        _ => goals.push(term.clone()),
+    }
+}
+
+fn neck() -> Functor {
+    functor::functors().functor(":-", 2, true)
+}
+
+fn comma() -> Functor {
+    functor::functors().functor(",", 2, true)
+}
+
+fn slash() -> Functor {
+    functor::functors().functor("/", 2, true)
+}
+
+fn dot() -> Functor {
+    functor::functors().functor(".", 2, true)
+}
+
+fn nil() -> Functor {
+    functor::functors().functor("[]", 0, true)
+}
+
 impl Debug for Term {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         match *self {
             Term::Variable(t) => write!(fmt, "?{}", t),
             Term::Structure(ref s) => write!(fmt, "{:?}", s),
+            Term::Integer(i) => write!(fmt, "{}", i),
+            Term::String(ref s) => write!(fmt, "{:?}", s),
         }
     }
 }
 
 impl Debug for Structure {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        if self.terms.is_empty() {
-            write!(fmt, "{}", self.functor.text())
+        if self.functor == dot() {
+            write_list(fmt, self, |fmt, term| write!(fmt, "{:?}", term))
+        } else if self.terms.is_empty() {
+            self.functor.with_text(|text| write!(fmt, "{}", text))
         } else {
-            try!(write!(fmt, "{}", self.functor.text()));
+            try!(self.functor.with_text(|text| write!(fmt, "{}", text)));
             let mut sep = '(';
             for term in &self.terms {
                 try!(write!(fmt, "{}{:?}", sep, term));
@@ -39,6 +227,98 @@ impl Debug for Structure {
     }
 }
 
+/// Writes a list in `[a,b|T]` notation, using `write` for each item
+/// and for the tail.
+fn write_list<F>(fmt: &mut Formatter, list: &Structure, write: F) -> Result<(), Error>
+    where F: Fn(&mut Formatter, &Term) -> Result<(), Error>
+{
+    try!(write!(fmt, "["));
+    try!(write(fmt, &list.terms[0]));
+    let mut tail = &list.terms[1];
+    loop {
+        match *tail {
+            Term::Structure(ref s) if s.functor == dot() => {
+                try!(write!(fmt, ","));
+                try!(write(fmt, &s.terms[0]));
+                tail = &s.terms[1];
+            }
+            Term::Structure(ref s) if s.functor == nil() => break,
+            _ => {
+                try!(write!(fmt, "|"));
+                try!(write(fmt, tail));
+                break;
+            }
+        }
+    }
+    write!(fmt, "]")
+}
+
+impl Debug for Clause {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        try!(write!(fmt, "{:?}", self.head));
+        let mut sep = " :- ";
+        for goal in &self.body {
+            try!(write!(fmt, "{}{:?}", sep, goal));
+            sep = ", ";
+        }
+        write!(fmt, ".")
+    }
+}
+
+// Display writes terms in Prolog syntax with the ISO operators,
+// quoting atoms where needed, so that the output can be read back in.
+
+impl Display for Term {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}", Writer::new(Operators::iso()).to_string(self))
+    }
+}
+
+impl Display for Structure {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}", Term::Structure(self.clone()))
+    }
+}
+
+/// Lays the clause out as `listing/1` does, one goal per line.
+impl Display for Clause {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let mut writer = Writer::new(Operators::iso());
+        let mut out = String::new();
+        writer.write(&mut out, &Term::Structure(self.head.clone()), 1199);
+        let mut sep = " :-\n    ";
+        for goal in &self.body {
+            out.push_str(sep);
+            writer.write(&mut out, goal, 999);
+            sep = ",\n    ";
+        }
+        write!(fmt, "{}.", out)
+    }
+}
+
+impl MarkFunctors for Term {
+    fn mark_functors(&self, marks: &mut Marks) {
+        match *self {
+            Term::Variable(_) | Term::Integer(_) | Term::String(_) => { }
+            Term::Structure(ref s) => s.mark_functors(marks),
+        }
+    }
+}
+
+impl MarkFunctors for Structure {
+    fn mark_functors(&self, marks: &mut Marks) {
+        marks.mark(self.functor);
+        self.terms.mark_functors(marks);
+    }
+}
+
+impl MarkFunctors for Clause {
+    fn mark_functors(&self, marks: &mut Marks) {
+        self.head.mark_functors(marks);
+        self.body.mark_functors(marks);
+    }
+}
+
 macro_rules! term {
     ($($args:tt)*) => {
         {
@@ -52,7 +332,43 @@ macro_rules! structure {
     ($($args:tt)*) => {
         match terms_tt!($($args,)*,,) {
             ($crate::ast::Term::Structure(s), ()) => s,
-            (r @ $crate::ast::Term::Variable(_), ()) => panic!("{:?} is not a structure", r),
+            (r, ()) => panic!("{:?} is not a structure", r),
+        }
+    }
+}
+
+macro_rules! clause {
+    ($name:ident ($($args:tt)*) :- $($body:tt)*) => {
+        $crate::ast::Clause {
+            head: structure!($name($($args)*)),
+            body: goals!($($body)*),
+        }
+    };
+
+    ($name:ident :- $($body:tt)*) => {
+        $crate::ast::Clause {
+            head: structure!($name),
+            body: goals!($($body)*),
+        }
+    };
+
+    ($($head:tt)*) => {
+        $crate::ast::Clause {
+            head: structure!($($head)*),
+            body: vec![],
+        }
+    };
+}
+
+/// A comma-separated list of terms, e.g. the body of a clause or a
+/// query.
+macro_rules! goals {
+    ($($args:tt)*) => {
+        {
+            let mut vec = vec![];
+            let goals = terms_tt!($($args,)*,,);
+            $crate::ast::ToTermVec::push_to_term_vec(goals, &mut vec);
+            vec
         }
     }
 }
@@ -62,6 +378,19 @@ macro_rules! terms_tt {
         ()
     };
 
+    (!, ,, $($remainder:tt,)*) => {
+        (
+            {
+                let name = $crate::intern::intern("!");
+                $crate::ast::Term::Structure($crate::ast::Structure {
+                    functor: $crate::functor::Functor::new(name, 0),
+                    terms: vec![]
+                })
+            },
+            terms_tt!($($remainder,)*)
+        )
+    };
+
     (?, $x:ident, ,, $($remainder:tt,)*) => {
         ($crate::ast::Term::Variable($crate::intern::intern(stringify!($x))),
          terms_tt!($($remainder,)*))
@@ -97,6 +426,10 @@ macro_rules! terms_tt {
             terms_tt!($($remainder,)*)
         )
     };
+
+    ($n:literal, ,, $($remainder:tt,)*) => {
+        ($crate::ast::Term::Integer($n), terms_tt!($($remainder,)*))
+    };
 }
 
 pub trait ToTermVec {
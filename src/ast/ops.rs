@@ -0,0 +1,212 @@
+//! Operator definitions, as used by `read` and `write`. Each engine
+//! has a table of its own, which starts out with the ISO operators
+//! (plus a few common declarations such as `dynamic`) and can be
+//! changed with `op/3`.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Error, Formatter};
+use std::sync::LazyLock;
+
+/// The type of an operator: where its arguments go (`x` and `y`) and
+/// whether an argument may have the operator's own priority (`y`) or
+/// must have a lower one (`x`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OpType {
+    XFX,
+    XFY,
+    YFX,
+    FY,
+    FX,
+    XF,
+    YF,
+}
+
+/// Where an operator goes relative to its arguments. A name may be an
+/// operator of each kind at once, except that it cannot be both infix
+/// and postfix.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Fixity {
+    Prefix,
+    Infix,
+    Postfix,
+}
+
+/// A single operator definition.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Op {
+    pub priority: usize,
+    pub op_type: OpType,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Operators {
+    ops: BTreeMap<(String, Fixity), Op>,
+}
+
+/// Why `Operators::add` refused a definition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OpError {
+    /// The priority was above 1200.
+    Priority,
+    /// `,` cannot be redefined.
+    Modify,
+    /// The definition would make the name both infix and postfix.
+    Create,
+}
+
+const ISO: &'static [(usize, OpType, &'static [&'static str])] = &[
+    (1200, OpType::XFX, &[":-", "-->"]),
+    (1200, OpType::FX, &[":-", "?-"]),
+    (1150, OpType::FX, &["dynamic", "discontiguous", "initialization", "meta_predicate",
+                         "multifile"]),
+    (1100, OpType::XFY, &[";", "|"]),
+    (1050, OpType::XFY, &["->", "*->"]),
+    (1000, OpType::XFY, &[","]),
+    (900, OpType::FY, &["\\+"]),
+    (700, OpType::XFX, &["=", "\\=", "==", "\\==", "@<", "@>", "@=<", "@>=", "=..", "is",
+                         "=:=", "=\\=", "<", ">", "=<", ">="]),
+    (600, OpType::XFY, &[":"]),
+    (500, OpType::YFX, &["+", "-", "/\\", "\\/", "xor"]),
+    (400, OpType::YFX, &["*", "/", "//", "rem", "mod", "div", "<<", ">>"]),
+    (200, OpType::XFX, &["**"]),
+    (200, OpType::XFY, &["^"]),
+    (200, OpType::FY, &["-", "+", "\\"]),
+];
+
+static DEFAULT: LazyLock<Operators> = LazyLock::new(Operators::new);
+
+impl Operators {
+    /// The ISO operator table.
+    pub fn new() -> Operators {
+        let mut ops = Operators { ops: BTreeMap::new() };
+        for &(priority, op_type, names) in ISO {
+            for name in names {
+                let op = Op { priority: priority, op_type: op_type };
+                ops.ops.insert((name.to_string(), op_type.fixity()), op);
+            }
+        }
+        ops
+    }
+
+    /// A shared copy of the ISO table, for printing terms without an
+    /// engine at hand.
+    pub fn iso() -> &'static Operators {
+        &DEFAULT
+    }
+
+    /// Defines `name` as an operator of type `op_type`, replacing any
+    /// operator of the same fixity; a priority of 0 removes it instead.
+    pub fn add(&mut self, priority: usize, op_type: OpType, name: &str) -> Result<(), OpError> {
+        if priority > 1200 {
+            return Err(OpError::Priority);
+        }
+        if name == "," {
+            return Err(OpError::Modify);
+        }
+        let fixity = op_type.fixity();
+        let clash = match fixity {
+            Fixity::Infix => Some(Fixity::Postfix),
+            Fixity::Postfix => Some(Fixity::Infix),
+            Fixity::Prefix => None,
+        };
+        if let Some(clash) = clash {
+            if priority > 0 && self.ops.contains_key(&(name.to_string(), clash)) {
+                return Err(OpError::Create);
+            }
+        }
+        if priority == 0 {
+            self.ops.remove(&(name.to_string(), fixity));
+        } else {
+            let op = Op { priority: priority, op_type: op_type };
+            self.ops.insert((name.to_string(), fixity), op);
+        }
+        Ok(())
+    }
+
+    pub fn prefix(&self, name: &str) -> Option<Op> {
+        self.get(name, Fixity::Prefix)
+    }
+
+    pub fn infix(&self, name: &str) -> Option<Op> {
+        self.get(name, Fixity::Infix)
+    }
+
+    pub fn postfix(&self, name: &str) -> Option<Op> {
+        self.get(name, Fixity::Postfix)
+    }
+
+    /// Whether `name` is an operator of any kind.
+    pub fn is_op(&self, name: &str) -> bool {
+        self.prefix(name).is_some() || self.infix(name).is_some() || self.postfix(name).is_some()
+    }
+
+    fn get(&self, name: &str, fixity: Fixity) -> Option<Op> {
+        self.ops.get(&(name.to_string(), fixity)).cloned()
+    }
+
+    /// Every operator, ordered by name.
+    pub fn iter<'ops>(&'ops self) -> impl Iterator<Item=(&'ops str, Op)> + 'ops {
+        self.ops.iter().map(|(&(ref name, _), &op)| (&name[..], op))
+    }
+}
+
+impl Default for Operators {
+    fn default() -> Operators {
+        Operators::new()
+    }
+}
+
+impl Op {
+    /// The highest priorities the left and right arguments may have
+    /// (0 where there is no argument).
+    pub fn argument_priorities(&self) -> (usize, usize) {
+        let p = self.priority;
+        match self.op_type {
+            OpType::XFX => (p - 1, p - 1),
+            OpType::XFY => (p - 1, p),
+            OpType::YFX => (p, p - 1),
+            OpType::FY => (0, p),
+            OpType::FX => (0, p - 1),
+            OpType::XF => (p - 1, 0),
+            OpType::YF => (p, 0),
+        }
+    }
+}
+
+impl OpType {
+    pub fn from_name(name: &str) -> Option<OpType> {
+        Some(match name {
+            "xfx" => OpType::XFX,
+            "xfy" => OpType::XFY,
+            "yfx" => OpType::YFX,
+            "fy" => OpType::FY,
+            "fx" => OpType::FX,
+            "xf" => OpType::XF,
+            "yf" => OpType::YF,
+            _ => return None,
+        })
+    }
+
+    pub fn fixity(self) -> Fixity {
+        match self {
+            OpType::XFX | OpType::XFY | OpType::YFX => Fixity::Infix,
+            OpType::FY | OpType::FX => Fixity::Prefix,
+            OpType::XF | OpType::YF => Fixity::Postfix,
+        }
+    }
+}
+
+impl Display for OpType {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let name = match *self {
+            OpType::XFX => "xfx",
+            OpType::XFY => "xfy",
+            OpType::YFX => "yfx",
+            OpType::FY => "fy",
+            OpType::FX => "fx",
+            OpType::XF => "xf",
+            OpType::YF => "yf",
+        };
+        write!(fmt, "{}", name)
+    }
+}
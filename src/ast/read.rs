@@ -0,0 +1,548 @@
+//! Reads Prolog text into terms. Operators are parsed according to an
+//! operator table, by precedence climbing: an operand is read first,
+//! then as many infix and postfix operators as its priority allows.
+//!
+//! Double-quoted text is read as a list of character codes unless
+//! the reader is told otherwise, and `_` is a fresh variable each time
+//! it appears.
+
+use functor::Functor;
+use intern::{self, InternedString};
+use std::char;
+use std::fmt::{Display, Error, Formatter};
+
+use super::{Structure, Term};
+use super::ops::Operators;
+
+/// A mistake in the text, at the given byte offset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub offset: usize,
+}
+
+/// Reads the terms of a text one after another, each ending with a
+/// `.` followed by layout.
+pub struct Reader<'text> {
+    lexer: Lexer<'text>,
+    peeked: Option<Lexeme>,
+    ops: &'text Operators,
+    /// The variables of the term being read, in order of appearance.
+    vars: Vec<(String, InternedString)>,
+    anonymous: usize,
+    /// Whether the last token read was the end of a term.
+    ended: bool,
+    double_quotes: DoubleQuotes,
+}
+
+/// What double-quoted text stands for, as set by the `double_quotes`
+/// flag.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DoubleQuotes {
+    /// A list of character codes.
+    Codes,
+    /// A list of one-character atoms.
+    Chars,
+    Atom,
+    /// A string, as `Term::String`.
+    String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Var(String),
+    Int(i64),
+    Str(String),
+    /// One of `(`, `)`, `[`, `]`, `{`, `}`, `,` and `|`.
+    Punct(&'static str),
+    /// The `.` that ends a term.
+    End,
+    Eof,
+}
+
+struct Lexeme {
+    token: Token,
+    offset: usize,
+    /// Whether layout (whitespace or comments) came before the token.
+    layout: bool,
+}
+
+/// Reads the single term in `text`, whose final `.` may be omitted.
+pub fn read_term(text: &str, ops: &Operators) -> Result<Term, SyntaxError> {
+    Reader::new(text, ops).read_single()
+}
+
+impl<'text> Reader<'text> {
+    pub fn new(text: &'text str, ops: &'text Operators) -> Reader<'text> {
+        Reader { lexer: Lexer { text: text, pos: 0 },
+                 peeked: None,
+                 ops: ops,
+                 vars: vec![],
+                 anonymous: 0,
+                 ended: false,
+                 double_quotes: DoubleQuotes::Codes }
+    }
+
+    pub fn double_quotes(mut self, double_quotes: DoubleQuotes) -> Reader<'text> {
+        self.double_quotes = double_quotes;
+        self
+    }
+
+    /// Reads the single term that makes up the text, whose final `.`
+    /// may be omitted.
+    pub fn read_single(&mut self) -> Result<Term, SyntaxError> {
+        let (term, _) = try!(self.parse(1200));
+        let lexeme = try!(self.next());
+        match lexeme.token {
+            Token::End | Token::Eof => Ok(term),
+            _ => Err(SyntaxError::new("operator expected", lexeme.offset)),
+        }
+    }
+
+    /// Reads the next term, or returns `None` at the end of the text.
+    /// After an error, reading resumes after the end of the bad term.
+    pub fn read_term(&mut self) -> Result<Option<Term>, SyntaxError> {
+        self.vars.clear();
+        self.ended = false;
+        let result = self.term();
+        if result.is_err() {
+            self.skip_term();
+        }
+        result
+    }
+
+    fn term(&mut self) -> Result<Option<Term>, SyntaxError> {
+        if try!(self.peek()).token == Token::Eof {
+            return Ok(None);
+        }
+        let (term, _) = try!(self.parse(1200));
+        let lexeme = try!(self.next());
+        match lexeme.token {
+            Token::End => Ok(Some(term)),
+            _ => Err(SyntaxError::new("operator expected", lexeme.offset)),
+        }
+    }
+
+    /// The named variables of the last term read, in order of first
+    /// appearance.
+    pub fn variable_names(&self) -> &[(String, InternedString)] {
+        &self.vars
+    }
+
+    fn skip_term(&mut self) {
+        while !self.ended {
+            match self.next() {
+                Ok(Lexeme { token: Token::Eof, .. }) => return,
+                Ok(_) => { }
+                Err(_) => self.lexer.skip_char(),
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Result<&Lexeme, SyntaxError> {
+        if self.peeked.is_none() {
+            self.peeked = Some(try!(self.lexer.lexeme()));
+        }
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    fn next(&mut self) -> Result<Lexeme, SyntaxError> {
+        let lexeme = match self.peeked.take() {
+            Some(lexeme) => lexeme,
+            None => try!(self.lexer.lexeme()),
+        };
+        self.ended = lexeme.token == Token::End;
+        Ok(lexeme)
+    }
+
+    /// Consumes the punctuation `p` if it comes next.
+    fn accept(&mut self, p: &str) -> Result<bool, SyntaxError> {
+        let found = match try!(self.peek()).token {
+            Token::Punct(q) => p == q,
+            _ => false,
+        };
+        if found {
+            try!(self.next());
+        }
+        Ok(found)
+    }
+
+    fn expect(&mut self, p: &str) -> Result<(), SyntaxError> {
+        let lexeme = try!(self.next());
+        match lexeme.token {
+            Token::Punct(q) if p == q => Ok(()),
+            _ => Err(SyntaxError::new(&format!("expected `{}`", p), lexeme.offset)),
+        }
+    }
+
+    /// Reads a term of priority at most `max`, returning it along with
+    /// its priority.
+    fn parse(&mut self, max: usize) -> Result<(Term, usize), SyntaxError> {
+        let (mut left, mut priority) = try!(self.primary(max));
+        loop {
+            let name = match try!(self.peek()).token {
+                Token::Name(ref name) => name.clone(),
+                Token::Punct(p) if p == "," || p == "|" => p.to_string(),
+                _ => break,
+            };
+            if let Some(op) = self.ops.infix(&name) {
+                let (left_max, right_max) = op.argument_priorities();
+                if op.priority <= max && priority <= left_max {
+                    try!(self.next());
+                    let (right, _) = try!(self.parse(right_max));
+                    // `(a | b)` is another way to write `(a ; b)`
+                    let name = if name == "|" { ";" } else { &name[..] };
+                    left = compound(name, vec![left, right]);
+                    priority = op.priority;
+                    continue;
+                }
+            }
+            if let Some(op) = self.ops.postfix(&name) {
+                let (left_max, _) = op.argument_priorities();
+                if op.priority <= max && priority <= left_max {
+                    try!(self.next());
+                    left = compound(&name, vec![left]);
+                    priority = op.priority;
+                    continue;
+                }
+            }
+            break;
+        }
+        Ok((left, priority))
+    }
+
+    /// Reads an argument of a compound term or a list.
+    fn argument(&mut self) -> Result<Term, SyntaxError> {
+        self.parse(999).map(|(term, _)| term)
+    }
+
+    /// Reads a term that is not an infix or postfix operator term.
+    fn primary(&mut self, max: usize) -> Result<(Term, usize), SyntaxError> {
+        let lexeme = try!(self.next());
+        match lexeme.token {
+            Token::Int(i) => Ok((Term::Integer(i), 0)),
+            Token::Var(name) => Ok((self.variable(name), 0)),
+            Token::Str(text) => {
+                let term = match self.double_quotes {
+                    DoubleQuotes::Codes => {
+                        let codes = text.chars().map(|c| Term::Integer(c as i64)).collect();
+                        Term::list(codes, Term::nil())
+                    }
+                    DoubleQuotes::Chars => {
+                        let chars = text.chars().map(|c| Term::atom(&c.to_string())).collect();
+                        Term::list(chars, Term::nil())
+                    }
+                    DoubleQuotes::Atom => Term::atom(&text),
+                    DoubleQuotes::String => Term::String(text),
+                };
+                Ok((term, 0))
+            }
+            Token::Name(name) => self.name(name, max),
+            Token::Punct("(") => {
+                let (term, _) = try!(self.parse(1200));
+                try!(self.expect(")"));
+                Ok((term, 0))
+            }
+            Token::Punct("[") => {
+                if try!(self.accept("]")) {
+                    return self.name("[]".to_string(), max);
+                }
+                let mut items = vec![try!(self.argument())];
+                while try!(self.accept(",")) {
+                    items.push(try!(self.argument()));
+                }
+                let tail = if try!(self.accept("|")) { try!(self.argument()) } else { Term::nil() };
+                try!(self.expect("]"));
+                Ok((Term::list(items, tail), 0))
+            }
+            Token::Punct("{") => {
+                if try!(self.accept("}")) {
+                    return self.name("{}".to_string(), max);
+                }
+                let (term, _) = try!(self.parse(1200));
+                try!(self.expect("}"));
+                Ok((compound("{}", vec![term]), 0))
+            }
+            Token::Punct(p) => Err(SyntaxError::new(&format!("unexpected `{}`", p), lexeme.offset)),
+            Token::End => Err(SyntaxError::new("unexpected end of clause", lexeme.offset)),
+            Token::Eof => Err(SyntaxError::new("unexpected end of file", lexeme.offset)),
+        }
+    }
+
+    /// Reads what follows the name `name`: the arguments of a compound
+    /// term, the operand of a prefix operator, or nothing at all.
+    fn name(&mut self, name: String, max: usize) -> Result<(Term, usize), SyntaxError> {
+        let (functional, negative) = {
+            let next = try!(self.peek());
+            let negative = match next.token {
+                Token::Int(i) if name == "-" && !next.layout => Some(-i),
+                _ => None,
+            };
+            (next.token == Token::Punct("(") && !next.layout, negative)
+        };
+        if functional {
+            try!(self.next());
+            let mut args = vec![try!(self.argument())];
+            while try!(self.accept(",")) {
+                args.push(try!(self.argument()));
+            }
+            try!(self.expect(")"));
+            return Ok((compound(&name, args), 0));
+        }
+        if let Some(i) = negative {
+            try!(self.next());
+            return Ok((Term::Integer(i), 0));
+        }
+        if let Some(op) = self.ops.prefix(&name) {
+            if try!(self.starts_operand()) {
+                // an operator that is too strong for where it appears is
+                // read as an ordinary argument, as in `f(dynamic p)`
+                let (priority, right_max) = if op.priority > max {
+                    (max, max)
+                } else {
+                    (op.priority, op.argument_priorities().1)
+                };
+                let (arg, _) = try!(self.parse(right_max));
+                return Ok((compound(&name, vec![arg]), priority));
+            }
+        }
+        Ok((Term::atom(&name), 0))
+    }
+
+    /// Whether the next token can start the operand of a prefix
+    /// operator, rather than ending the operator as an atom, as in
+    /// `- = X` or `f(-)`.
+    fn starts_operand(&mut self) -> Result<bool, SyntaxError> {
+        let ops = self.ops;
+        Ok(match try!(self.peek()).token {
+            Token::Int(_) | Token::Var(_) | Token::Str(_) => true,
+            Token::Punct(p) => p == "(" || p == "[" || p == "{",
+            Token::Name(ref name) => ops.infix(name).is_none() || ops.prefix(name).is_some(),
+            Token::End | Token::Eof => false,
+        })
+    }
+
+    fn variable(&mut self, name: String) -> Term {
+        if name == "_" {
+            self.anonymous += 1;
+            return Term::Variable(intern::intern(&format!("_G{}", self.anonymous)));
+        }
+        if let Some(&(_, v)) = self.vars.iter().find(|&&(ref n, _)| *n == name) {
+            return Term::Variable(v);
+        }
+        let v = intern::intern(&name);
+        self.vars.push((name, v));
+        Term::Variable(v)
+    }
+}
+
+fn compound(name: &str, args: Vec<Term>) -> Term {
+    let functor = Functor::transient(name, args.len());
+    Term::Structure(Structure { functor: functor, terms: args })
+}
+
+impl SyntaxError {
+    fn new(message: &str, offset: usize) -> SyntaxError {
+        SyntaxError { message: message.to_string(), offset: offset }
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "syntax error at offset {}: {}", self.offset, self.message)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Tokens
+
+/// The characters that make up symbolic atoms such as `=..`.
+pub const SYMBOLS: &'static str = "+-*/\\^<>=~:.?@#&$";
+
+struct Lexer<'text> {
+    text: &'text str,
+    pos: usize,
+}
+
+impl<'text> Lexer<'text> {
+    fn rest(&self) -> &'text str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_char(&mut self) {
+        if let Some(c) = self.rest().chars().next() {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, pred: F) -> String {
+        let len = self.rest().find(|c| !pred(c)).unwrap_or(self.rest().len());
+        let text = self.rest()[..len].to_string();
+        self.pos += len;
+        text
+    }
+
+    /// Skips whitespace and comments, returning whether there were any.
+    fn layout(&mut self) -> Result<bool, SyntaxError> {
+        let start = self.pos;
+        loop {
+            let rest = self.rest();
+            if rest.starts_with(char::is_whitespace) {
+                self.skip_char();
+            } else if rest.starts_with('%') {
+                self.pos += rest.find('\n').unwrap_or(rest.len());
+            } else if rest.starts_with("/*") {
+                match rest[2..].find("*/") {
+                    Some(end) => self.pos += end + 4,
+                    None => return Err(SyntaxError::new("unterminated comment", self.pos)),
+                }
+            } else {
+                return Ok(self.pos > start);
+            }
+        }
+    }
+
+    fn lexeme(&mut self) -> Result<Lexeme, SyntaxError> {
+        let layout = try!(self.layout());
+        let offset = self.pos;
+        let c = match self.rest().chars().next() {
+            Some(c) => c,
+            None => return Ok(Lexeme { token: Token::Eof, offset: offset, layout: layout }),
+        };
+        let alphanumeric = |c: char| c.is_alphanumeric() || c == '_';
+        let token = if c.is_digit(10) {
+            try!(self.number())
+        } else if c == '_' || c.is_uppercase() {
+            Token::Var(self.take_while(alphanumeric))
+        } else if c.is_alphabetic() {
+            Token::Name(self.take_while(alphanumeric))
+        } else if c == '\'' {
+            Token::Name(try!(self.quoted()))
+        } else if c == '"' {
+            Token::Str(try!(self.quoted()))
+        } else if c == '!' || c == ';' {
+            self.skip_char();
+            Token::Name(c.to_string())
+        } else if SYMBOLS.contains(c) {
+            let name = self.take_while(|c| SYMBOLS.contains(c));
+            let rest = self.rest();
+            if name == "." && (rest.is_empty() || rest.starts_with(char::is_whitespace) ||
+                               rest.starts_with('%')) {
+                Token::End
+            } else {
+                Token::Name(name)
+            }
+        } else {
+            self.skip_char();
+            Token::Punct(match c {
+                '(' => "(",
+                ')' => ")",
+                '[' => "[",
+                ']' => "]",
+                '{' => "{",
+                '}' => "}",
+                ',' => ",",
+                '|' => "|",
+                _ => return Err(SyntaxError::new(&format!("unexpected `{}`", c), offset)),
+            })
+        };
+        Ok(Lexeme { token: token, offset: offset, layout: layout })
+    }
+
+    fn number(&mut self) -> Result<Token, SyntaxError> {
+        let offset = self.pos;
+        if self.rest().starts_with("0'") {
+            self.pos += 2;
+            let c = match self.rest().chars().next() {
+                Some('\\') => try!(self.escape()),
+                Some('\'') if self.rest().starts_with("''") => {
+                    self.pos += 2;
+                    '\''
+                }
+                Some(c) => {
+                    self.skip_char();
+                    c
+                }
+                None => return Err(SyntaxError::new("missing character code", offset)),
+            };
+            return Ok(Token::Int(c as i64));
+        }
+        let (radix, digits) = if self.rest().starts_with("0x") {
+            self.pos += 2;
+            (16, self.take_while(|c| c.is_digit(16)))
+        } else {
+            (10, self.take_while(|c| c.is_digit(10)))
+        };
+        match i64::from_str_radix(&digits, radix) {
+            Ok(i) => Ok(Token::Int(i)),
+            Err(_) => Err(SyntaxError::new("integer out of range", offset)),
+        }
+    }
+
+    /// Reads the digits of a character code, and the `\` after them.
+    fn code(&mut self, radix: u32, offset: usize) -> Result<char, SyntaxError> {
+        let digits = self.take_while(|c| c.is_digit(radix));
+        if self.rest().starts_with('\\') {
+            self.skip_char();
+        }
+        match u32::from_str_radix(&digits, radix).ok().and_then(char::from_u32) {
+            Some(c) => Ok(c),
+            None => Err(SyntaxError::new("bad character code", offset)),
+        }
+    }
+
+    /// Reads text between quotes, which are written twice (or escaped)
+    /// to include them.
+    fn quoted(&mut self) -> Result<String, SyntaxError> {
+        let offset = self.pos;
+        let quote = self.rest().chars().next().unwrap();
+        self.skip_char();
+        let mut text = String::new();
+        loop {
+            match self.rest().chars().next() {
+                Some('\\') => text.push(try!(self.escape())),
+                Some(c) if c == quote => {
+                    self.skip_char();
+                    if !self.rest().starts_with(quote) {
+                        return Ok(text);
+                    }
+                    self.skip_char();
+                    text.push(quote);
+                }
+                Some(c) => {
+                    self.skip_char();
+                    text.push(c);
+                }
+                None => return Err(SyntaxError::new("unterminated quoted text", offset)),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, SyntaxError> {
+        let offset = self.pos;
+        self.skip_char();
+        let c = match self.rest().chars().next() {
+            Some(c) => c,
+            None => return Err(SyntaxError::new("unterminated escape", offset)),
+        };
+        self.skip_char();
+        Ok(match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'a' => '\x07',
+            'b' => '\x08',
+            'f' => '\x0c',
+            'v' => '\x0b',
+            '\\' | '\'' | '"' | '`' => c,
+            // `\x41\` and `\101\` are the character with that code
+            'x' => try!(self.code(16, offset)),
+            '0'..='7' => {
+                self.pos -= 1;
+                try!(self.code(8, offset))
+            }
+            _ => return Err(SyntaxError::new(&format!("unknown escape `\\{}`", c), offset)),
+        })
+    }
+}
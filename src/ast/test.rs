@@ -1,3 +1,10 @@
+use functor::Functor;
+use intern;
+use super::{Structure, Term};
+use super::ops::Operators;
+use super::read::{read_term, Reader};
+use super::write::Writer;
+
 #[test]
 fn var_terms() {
     let t1 = term!(?X);
@@ -9,3 +16,81 @@ fn complex_terms() {
     let t = term!(a(?X, b(?Y), c(?X, ?Y), d));
     assert_eq!(&format!("{:?}", t), "a(?X,b(?Y),c(?X,?Y),d)");
 }
+
+#[test]
+fn clauses() {
+    let c = clause!(app(cons(?H, ?T), ?L, cons(?H, ?R)) :- app(?T, ?L, ?R), !);
+    assert_eq!(&format!("{:?}", c), "app(cons(?H,?T),?L,cons(?H,?R)) :- app(?T,?L,?R), !.");
+    let c = clause!(app(nil, ?L, ?L));
+    assert_eq!(&format!("{:?}", c), "app(nil,?L,?L).");
+}
+
+#[test]
+fn prolog_syntax() {
+    let c = clause!(app(cons(?H, ?T), ?L, cons(?H, ?R)) :- app(?T, ?L, ?R), !);
+    assert_eq!(&format!("{}", c), "app(cons(H,T),L,cons(H,R)) :-\n    app(T,L,R),\n    !.");
+    let t = Term::Structure(Structure { functor: Functor::new(intern::intern("f"), 2),
+                                        terms: vec![Term::atom("Hello world"),
+                                                    Term::atom("it's")] });
+    assert_eq!(&format!("{}", t), "f('Hello world','it\\'s')");
+    assert_eq!(&format!("{}", Term::atom("=..")), "=..");
+    let list = Term::list(vec![Term::atom("a"), term!(b(?X))], term!(?T));
+    assert_eq!(&format!("{}", list), "[a,b(X)|T]");
+    assert_eq!(list.to_list(), None);
+    assert_eq!(Term::list(vec![], Term::nil()).to_list(), Some(vec![]));
+}
+
+/// `text` read and written back with the ISO operators.
+fn round_trip(text: &str) -> String {
+    let ops = Operators::new();
+    Writer::new(&ops).to_string(&read_term(text, &ops).unwrap())
+}
+
+#[test]
+fn operators() {
+    let ops = Operators::new();
+    assert_eq!(&format!("{:?}", read_term("X is 1+2*3", &ops).unwrap()), "is(?X,+(1,*(2,3)))");
+    assert_eq!(round_trip("X is 1 + 2 * 3"), "X is 1+2*3");
+    assert_eq!(round_trip("(1+2)*3"), "(1+2)*3");
+    assert_eq!(round_trip("1-(2-3)"), "1-(2-3)");
+    assert_eq!(round_trip("(1-2)-3"), "1-2-3");
+    assert_eq!(round_trip("a:-b,c;d->e"), "a:-b,c;d->e");
+    assert_eq!(round_trip("(a:-b),c"), "(a:-b),c");
+
+    // negative numbers, and the prefix operator applied to numbers
+    assert_eq!(&format!("{:?}", read_term("- 1", &ops).unwrap()), "-(1)");
+    assert_eq!(round_trip("- 1"), "- 1");
+    assert_eq!(round_trip("-1"), "-1");
+    assert_eq!(round_trip("a- -1"), "a- -1");
+    assert_eq!(round_trip("-(1)"), "- 1");
+    assert_eq!(round_trip("-(2^2)"), "- 2^2");
+    assert_eq!(round_trip("-(2**2)"), "- 2**2");
+    assert_eq!(round_trip("- 2^2"), "- 2^2");
+    assert_eq!(round_trip("- (1)"), "- 1");
+    assert_eq!(round_trip("\\+ (a,b)"), "\\+ (a,b)");
+
+    // operators as atoms
+    assert_eq!(round_trip("f(-, (a:-b), [x|Y])"), "f(-,(a:-b),[x|Y])");
+    assert_eq!(round_trip("- = X"), "(-)=X");
+    assert_eq!(round_trip("[-]"), "[-]");
+
+    assert_eq!(round_trip("'hello world'(\"ab\", 0'c, {a,b})"),
+               "'hello world'([97,98],99,{a,b})");
+    assert_eq!(Writer::new(&ops).ignore_ops(true).to_string(&read_term("1+2", &ops).unwrap()),
+               "+(1,2)");
+}
+
+#[test]
+fn reading_clauses() {
+    let ops = Operators::new();
+    let mut reader = Reader::new("a. % comment\n b :- 1 + . c(X, _, X, _).\n/* end */", &ops);
+    assert_eq!(reader.read_term(), Ok(Some(Term::atom("a"))));
+    assert!(reader.read_term().is_err());
+    let c = reader.read_term().unwrap().unwrap();
+    assert_eq!(&format!("{:?}", c), "c(?X,?_G1,?X,?_G2)");
+    assert_eq!(reader.variable_names().len(), 1);
+    assert_eq!(reader.read_term(), Ok(None));
+
+    assert!(read_term("f(a", &ops).is_err());
+    assert!(read_term("a b", &ops).is_err());
+}
@@ -0,0 +1,304 @@
+//! Writes terms as Prolog text, using an operator table so that
+//! `+(X,*(Y,Z))` comes out as `X+Y*Z`. Parentheses are only added
+//! where the operator priorities require them.
+//!
+//! The options are those of `write_term/2`; see the builder methods.
+
+use intern::InternedString;
+use std::collections::HashMap;
+
+use super::{dot, nil, Structure, Term};
+use super::ops::Operators;
+use super::read::SYMBOLS;
+
+pub struct Writer<'w> {
+    ops: &'w Operators,
+    quoted: bool,
+    ignore_ops: bool,
+    numbervars: bool,
+    max_depth: usize,
+    variable_names: HashMap<InternedString, String>,
+    portray: Option<&'w mut dyn FnMut(&Term) -> Option<String>>,
+}
+
+impl<'w> Writer<'w> {
+    /// A writer that quotes atoms where needed for the text to be read
+    /// back in.
+    pub fn new(ops: &'w Operators) -> Writer<'w> {
+        Writer { ops: ops,
+                 quoted: true,
+                 ignore_ops: false,
+                 numbervars: false,
+                 max_depth: 0,
+                 variable_names: HashMap::new(),
+                 portray: None }
+    }
+
+    pub fn quoted(mut self, quoted: bool) -> Writer<'w> {
+        self.quoted = quoted;
+        self
+    }
+
+    /// Writes every compound term in canonical form, as `+(1,2)`.
+    pub fn ignore_ops(mut self, ignore_ops: bool) -> Writer<'w> {
+        self.ignore_ops = ignore_ops;
+        self
+    }
+
+    /// Writes `'$VAR'(N)` as a variable name: `A` for 0, `B` for 1, and
+    /// on to `Z1` for 51.
+    pub fn numbervars(mut self, numbervars: bool) -> Writer<'w> {
+        self.numbervars = numbervars;
+        self
+    }
+
+    /// Writes `...` for terms nested more deeply than `depth`, and for
+    /// list items beyond the first `depth`; 0 means no limit.
+    pub fn max_depth(mut self, depth: usize) -> Writer<'w> {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Writes each variable in `names` with the name given there.
+    pub fn variable_names(mut self, names: HashMap<InternedString, String>) -> Writer<'w> {
+        self.variable_names = names;
+        self
+    }
+
+    /// Offers each subterm (other than a variable) to `portray` first,
+    /// writing the text it returns instead, if any.
+    pub fn portray(mut self, portray: &'w mut dyn FnMut(&Term) -> Option<String>) -> Writer<'w> {
+        self.portray = Some(portray);
+        self
+    }
+
+    pub fn to_string(&mut self, term: &Term) -> String {
+        let mut out = String::new();
+        self.write(&mut out, term, 1200);
+        out
+    }
+
+    /// Writes `term` where a term of priority at most `max` may go.
+    pub fn write(&mut self, out: &mut String, term: &Term, max: usize) {
+        self.write_at(out, term, max, 1)
+    }
+
+    fn write_at(&mut self, out: &mut String, term: &Term, max: usize, depth: usize) {
+        if self.max_depth > 0 && depth > self.max_depth {
+            return out.push_str("...");
+        }
+        if let Term::Variable(v) = *term {
+            return match self.variable_names.get(&v) {
+                Some(name) => out.push_str(name),
+                None => out.push_str(&v.to_string()),
+            };
+        }
+        if let Some(ref mut portray) = self.portray {
+            if let Some(text) = portray(term) {
+                return out.push_str(&text);
+            }
+        }
+        match *term {
+            Term::Variable(_) => unreachable!(),
+            Term::Integer(i) => out.push_str(&i.to_string()),
+            Term::String(ref s) if self.quoted => out.push_str(&quote_string(s)),
+            Term::String(ref s) => out.push_str(s),
+            Term::Structure(ref s) => self.write_structure(out, s, max, depth),
+        }
+    }
+
+    fn write_structure(&mut self, out: &mut String, s: &Structure, max: usize, depth: usize) {
+        let name = s.functor.with_text(|text| text.to_string());
+        if s.functor == dot() {
+            return self.write_list(out, s, depth);
+        }
+        if s.terms.is_empty() {
+            // an operator as an operand of another is bracketed, as in `(-)-a`
+            let bracket = max < 999 && self.ops.is_op(&name) && !self.ignore_ops;
+            let text = self.atom(&name);
+            return push_bracketed(out, bracket, &text);
+        }
+        if self.numbervars && name == "$VAR" && s.terms.len() == 1 {
+            if let Term::Integer(n) = s.terms[0] {
+                if n >= 0 {
+                    let letter = (b'A' + (n % 26) as u8) as char;
+                    let number = if n >= 26 { (n / 26).to_string() } else { String::new() };
+                    return out.push_str(&format!("{}{}", letter, number));
+                }
+            }
+        }
+        if !self.ignore_ops {
+            if name == "{}" && s.terms.len() == 1 {
+                out.push('{');
+                self.write_at(out, &s.terms[0], 1200, depth + 1);
+                out.push('}');
+                return;
+            }
+            if let Some((text, priority)) = self.operator(&name, s, depth) {
+                return push_bracketed(out, priority > max, &text);
+            }
+        }
+        out.push_str(&self.atom(&name));
+        let mut sep = '(';
+        for term in &s.terms {
+            out.push(sep);
+            self.write_at(out, term, 999, depth + 1);
+            sep = ',';
+        }
+        out.push(')');
+    }
+
+    /// The text of `s` written with `name` as an operator, along with
+    /// its priority, if `name` is an operator of the right kind.
+    fn operator(&mut self, name: &str, s: &Structure, depth: usize) -> Option<(String, usize)> {
+        let name_text = self.atom(name);
+        match s.terms.len() {
+            2 => {
+                let op = match self.ops.infix(name) {
+                    Some(op) => op,
+                    None => return None,
+                };
+                let (left_max, right_max) = op.argument_priorities();
+                let left = self.operand(&s.terms[0], left_max, depth);
+                let right = self.operand(&s.terms[1], right_max, depth);
+                let text = if name == "," {
+                    format!("{},{}", left, right)
+                } else if is_alphabetic(&name_text) {
+                    format!("{} {} {}", left, name_text, right)
+                } else {
+                    let left_space = if ends_symbolic(&left) { " " } else { "" };
+                    let right_space = if starts_symbolic(&right) { " " } else { "" };
+                    format!("{}{}{}{}{}", left, left_space, name_text, right_space, right)
+                };
+                Some((text, op.priority))
+            }
+            1 => {
+                if let Some(op) = self.ops.prefix(name) {
+                    let (_, right_max) = op.argument_priorities();
+                    let arg = self.operand(&s.terms[0], right_max, depth);
+                    // `- 1` is not the same as `-1`, nor `- (a,b)` as `-(a,b)`;
+                    // the same goes for any operand that starts with a digit,
+                    // such as `2^2`
+                    let number = (name == "-" || name == "+") &&
+                        arg.starts_with(|c: char| c.is_ascii_digit());
+                    let space = is_alphabetic(&name_text) || starts_symbolic(&arg) || number ||
+                        arg.starts_with('(');
+                    let sep = if space { " " } else { "" };
+                    return Some((format!("{}{}{}", name_text, sep, arg), op.priority));
+                }
+                if let Some(op) = self.ops.postfix(name) {
+                    let (left_max, _) = op.argument_priorities();
+                    let arg = self.operand(&s.terms[0], left_max, depth);
+                    let sep = if ends_symbolic(&arg) || is_alphabetic(&name_text) {
+                        " "
+                    } else {
+                        ""
+                    };
+                    return Some((format!("{}{}{}", arg, sep, name_text), op.priority));
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn operand(&mut self, term: &Term, max: usize, depth: usize) -> String {
+        let mut out = String::new();
+        self.write_at(&mut out, term, max, depth + 1);
+        out
+    }
+
+    fn write_list(&mut self, out: &mut String, list: &Structure, depth: usize) {
+        out.push('[');
+        self.write_at(out, &list.terms[0], 999, depth + 1);
+        let mut tail = &list.terms[1];
+        let mut length = 1;
+        loop {
+            match *tail {
+                Term::Structure(ref s) if s.functor == dot() => {
+                    if self.max_depth > 0 && length >= self.max_depth {
+                        out.push_str("|...");
+                        break;
+                    }
+                    out.push(',');
+                    self.write_at(out, &s.terms[0], 999, depth + 1);
+                    tail = &s.terms[1];
+                    length += 1;
+                }
+                Term::Structure(ref s) if s.functor == nil() => break,
+                _ => {
+                    out.push('|');
+                    self.write_at(out, tail, 999, depth + 1);
+                    break;
+                }
+            }
+        }
+        out.push(']');
+    }
+
+    fn atom(&self, text: &str) -> String {
+        if self.quoted { quote_atom(text) } else { text.to_string() }
+    }
+}
+
+fn push_bracketed(out: &mut String, bracket: bool, text: &str) {
+    if bracket {
+        out.push('(');
+    }
+    out.push_str(text);
+    if bracket {
+        out.push(')');
+    }
+}
+
+/// `text` as an atom that reads back in, quoted if need be.
+pub fn quote_atom(text: &str) -> String {
+    let mut chars = text.chars();
+    let plain = match chars.next() {
+        Some(c) if c.is_lowercase() => chars.all(|c| c.is_alphanumeric() || c == '_'),
+        Some(_) if text == "!" || text == ";" || text == "[]" || text == "{}" => true,
+        Some(_) => text.chars().all(|c| SYMBOLS.contains(c)),
+        None => false,
+    };
+    if plain {
+        return text.to_string();
+    }
+    quote(text, '\'')
+}
+
+/// `text` as a double-quoted string.
+pub fn quote_string(text: &str) -> String {
+    quote(text, '"')
+}
+
+/// `text` between `quote` characters, escaped so that it reads back in.
+fn quote(text: &str, quote: char) -> String {
+    let mut out = quote.to_string();
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push_str(&format!("\\x{:x}\\", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push(quote);
+    out
+}
+
+fn is_alphabetic(name: &str) -> bool {
+    name.starts_with(char::is_alphabetic)
+}
+
+fn starts_symbolic(text: &str) -> bool {
+    text.starts_with(|c| SYMBOLS.contains(c))
+}
+
+fn ends_symbolic(text: &str) -> bool {
+    text.ends_with(|c| SYMBOLS.contains(c))
+}
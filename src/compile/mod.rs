@@ -0,0 +1,629 @@
+//! Compile clauses and queries into WAM code, following chapters 3
+//! to 5 of the tutorial.
+//!
+//! Unlike `interpret`, which drives a machine directly from a single
+//! term, this produces the code for a whole clause: argument
+//! registers A1..An are used to pass arguments, variables that must
+//! survive a call live in an environment as Y registers, and the last
+//! call of a body is compiled as `execute`. Every variable is created
+//! on the heap, so there are no unsafe variables to worry about.
+//!
+//! The control constructs (`,`, `;`, `->`, `*->`, `\+` and friends)
+//! are compiled inline, as branches within the clause's own code. A
+//! goal that is only known at runtime becomes a call of `call/1`.
+
+use ast::{Clause, Structure, Term};
+use intern::{self, InternedString};
+use machine::{Code, Instruction};
+use machine::mem::{Permanent, Register, Var};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+#[cfg(test)]
+mod test;
+
+/// The code for a query, along with the permanent variable holding
+/// each of its named variables once it succeeds.
+#[derive(Debug)]
+pub struct QueryCode {
+    pub code: Code,
+    pub variables: Vec<(InternedString, Permanent)>,
+}
+
+enum Goal {
+    Cut,
+    Call(Structure),
+    Fail,
+    /// `(Either ; Or)`.
+    Or(Vec<Goal>, Vec<Goal>),
+    /// `(If -> Then ; Else)`, or `(If *-> Then ; Else)` if `soft`.
+    IfThenElse { soft: bool, cond: Vec<Goal>, then: Vec<Goal>, otherwise: Vec<Goal> },
+}
+
+pub fn clause(clause: &Clause) -> Code {
+    let goals = goals(&clause.body);
+    if goals.iter().any(Goal::is_control) {
+        return control_clause(clause, &goals);
+    }
+    let calls = goals.iter().filter(|g| match **g { Goal::Call(_) => true, _ => false }).count();
+    let ends_with_call = match goals.last() {
+        Some(&Goal::Call(_)) => true,
+        _ => false,
+    };
+    let permanents = permanent_variables(&clause.head, &goals);
+
+    // an environment is needed to remember where to continue after a
+    // call, unless that call is the last thing in the body
+    let environment = !permanents.is_empty() || calls > 1 || (calls == 1 && !ends_with_call);
+
+    let mut compiler = Compiler::new(max_arity(Some(&clause.head), &goals), &permanents);
+    if environment {
+        compiler.emit(Instruction::Allocate(permanents.len()));
+    }
+    for (i, term) in clause.head.terms.iter().enumerate() {
+        compiler.get_argument(term, Register(i + 1));
+    }
+
+    let mut called = false;
+    for (i, goal) in goals.iter().enumerate() {
+        match *goal {
+            Goal::Cut => {
+                // until the first call, B0 still holds the choice point
+                // to cut back to; after that, it is saved in the
+                // environment
+                compiler.emit(if called { Instruction::Cut } else { Instruction::NeckCut });
+            }
+            Goal::Call(ref goal) => {
+                compiler.put_arguments(goal);
+                if i + 1 == goals.len() {
+                    if environment {
+                        compiler.emit(Instruction::Deallocate);
+                    }
+                    compiler.emit(Instruction::Execute(goal.functor));
+                } else {
+                    compiler.emit(Instruction::Call(goal.functor));
+                }
+                called = true;
+            }
+            _ => unreachable!(),
+        }
+    }
+    if !ends_with_call {
+        if environment {
+            compiler.emit(Instruction::Deallocate);
+        }
+        compiler.emit(Instruction::Proceed);
+    }
+
+    Code { predicate: Some(clause.head.functor),
+           instructions: compiler.code,
+           source: Some(clause.clone()) }
+}
+
+/// Compiles a clause whose body has control constructs. A variable
+/// may be reached along more than one branch, so every variable lives
+/// in the environment, and any that first occur within a construct
+/// are created before entering it.
+fn control_clause(clause: &Clause, goals: &[Goal]) -> Code {
+    let mut names = vec![];
+    for term in &clause.head.terms {
+        variables(term, &mut names);
+    }
+    goal_variables(goals, &mut names);
+    let mut seen = HashSet::new();
+    names.retain(|&v| seen.insert(v));
+
+    let mut compiler = Compiler::new(max_arity(Some(&clause.head), goals), &names);
+    compiler.emit(Instruction::Allocate(names.len()));
+    for (i, term) in clause.head.terms.iter().enumerate() {
+        compiler.get_argument(term, Register(i + 1));
+    }
+    compiler.body(goals, true);
+    compiler.allocated();
+
+    Code { predicate: Some(clause.head.functor),
+           instructions: compiler.code,
+           source: Some(clause.clone()) }
+}
+
+/// Compiles the conjunction `goals`. Every variable is made permanent
+/// so that its value can be read from the query's environment when
+/// the code reaches its final `succeed`.
+pub fn query(goals: &[Term]) -> QueryCode {
+    goal(goals, &[])
+}
+
+/// Compiles `goals` as `query` does, except that the variables `args`
+/// are already bound to the values in argument registers A1..An.
+pub fn goal(goals: &[Term], args: &[InternedString]) -> QueryCode {
+    let goals = self::goals(goals);
+    let mut names = args.to_vec();
+    goal_variables(&goals, &mut names);
+    let mut seen = HashSet::new();
+    names.retain(|&v| seen.insert(v));
+
+    let mut compiler = Compiler::new(max_arity(None, &goals), &names);
+    compiler.emit(Instruction::Allocate(names.len()));
+    for (i, &v) in args.iter().enumerate() {
+        let (var, _) = compiler.var(v);
+        compiler.emit(Instruction::GetVariable(var, Register(i + 1)));
+    }
+    compiler.body(&goals, false);
+    compiler.emit(Instruction::Succeed);
+    compiler.allocated();
+
+    let variables = names.iter()
+                         .enumerate()
+                         .map(|(i, &v)| (v, Permanent(i + 1)))
+                         .collect();
+    QueryCode { code: Code { predicate: None, instructions: compiler.code, source: None },
+                variables: variables }
+}
+
+fn goals(body: &[Term]) -> Vec<Goal> {
+    let mut goals = vec![];
+    for term in body {
+        push_goal(term, &mut goals);
+    }
+    goals
+}
+
+/// Appends the goals of the body term `term` to `goals`, expanding
+/// the control constructs.
+fn push_goal(term: &Term, goals: &mut Vec<Goal>) {
+    let s = match *term {
+        Term::Structure(ref s) => s,
+        // a variable goal `X` means `call(X)`, which also reports
+        // any other goal that is not callable
+        Term::Variable(_) | Term::Integer(_) | Term::String(_) => {
+            let call = Structure { functor: functor!(call/1), terms: vec![term.clone()] };
+            goals.push(Goal::Call(call));
+            return;
+        }
+    };
+    let name = s.functor.with_text(|text| text.to_string());
+    let goal = match (&name[..], s.terms.len()) {
+        (",", 2) => {
+            push_goal(&s.terms[0], goals);
+            push_goal(&s.terms[1], goals);
+            return;
+        }
+        ("true", 0) => return,
+        ("!", 0) => Goal::Cut,
+        ("fail", 0) | ("false", 0) => Goal::Fail,
+        (";", 2) => {
+            let otherwise = self::goals(&s.terms[1..]);
+            match if_then(&s.terms[0]) {
+                Some((soft, c)) => {
+                    Goal::IfThenElse { soft: soft,
+                                       cond: self::goals(&c.terms[..1]),
+                                       then: self::goals(&c.terms[1..]),
+                                       otherwise: otherwise }
+                }
+                None => Goal::Or(self::goals(&s.terms[..1]), otherwise),
+            }
+        }
+        ("->", 2) | ("*->", 2) => {
+            Goal::IfThenElse { soft: name == "*->",
+                               cond: self::goals(&s.terms[..1]),
+                               then: self::goals(&s.terms[1..]),
+                               otherwise: vec![Goal::Fail] }
+        }
+        ("\\+", 1) | ("not", 1) => {
+            Goal::IfThenElse { soft: false,
+                               cond: self::goals(&s.terms),
+                               then: vec![Goal::Fail],
+                               otherwise: vec![] }
+        }
+        ("once", 1) => {
+            Goal::IfThenElse { soft: false,
+                               cond: self::goals(&s.terms),
+                               then: vec![],
+                               otherwise: vec![Goal::Fail] }
+        }
+        ("ignore", 1) => {
+            Goal::IfThenElse { soft: false,
+                               cond: self::goals(&s.terms),
+                               then: vec![],
+                               otherwise: vec![] }
+        }
+        ("forall", 2) => {
+            // `\+ (Cond, \+ Action)`
+            let mut cond = self::goals(&s.terms[..1]);
+            cond.push(Goal::IfThenElse { soft: false,
+                                         cond: self::goals(&s.terms[1..]),
+                                         then: vec![Goal::Fail],
+                                         otherwise: vec![] });
+            Goal::IfThenElse { soft: false, cond: cond, then: vec![Goal::Fail], otherwise: vec![] }
+        }
+        _ => Goal::Call(s.clone()),
+    };
+    goals.push(goal);
+}
+
+/// If `term` is `(If -> Then)` or `(If *-> Then)`, whether it is the
+/// latter, and the term itself.
+fn if_then(term: &Term) -> Option<(bool, &Structure)> {
+    match *term {
+        Term::Structure(ref s) if s.terms.len() == 2 => {
+            s.functor.with_text(|text| match text {
+                "->" => Some((false, s)),
+                "*->" => Some((true, s)),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+impl Goal {
+    fn is_control(&self) -> bool {
+        match *self {
+            Goal::Cut | Goal::Call(_) => false,
+            Goal::Fail | Goal::Or(..) | Goal::IfThenElse { .. } => true,
+        }
+    }
+
+    /// The sequences of goals nested within this one.
+    fn branches(&self) -> Vec<&[Goal]> {
+        match *self {
+            Goal::Cut | Goal::Call(_) | Goal::Fail => vec![],
+            Goal::Or(ref either, ref or) => vec![either, or],
+            Goal::IfThenElse { ref cond, ref then, ref otherwise, .. } => {
+                vec![cond, then, otherwise]
+            }
+        }
+    }
+}
+
+/// Calls `f` for each of `goals` and every goal nested within them.
+fn walk<'g, F: FnMut(&'g Goal)>(goals: &'g [Goal], f: &mut F) {
+    for goal in goals {
+        f(goal);
+        for branch in goal.branches() {
+            walk(branch, f);
+        }
+    }
+}
+
+fn goal_variables(goals: &[Goal], out: &mut Vec<InternedString>) {
+    walk(goals, &mut |goal| if let Goal::Call(ref goal) = *goal {
+        for term in &goal.terms {
+            variables(term, out);
+        }
+    });
+}
+
+fn has_cut(goals: &[Goal]) -> bool {
+    let mut cut = false;
+    walk(goals, &mut |goal| if let Goal::Cut = *goal { cut = true });
+    cut
+}
+
+fn max_arity(head: Option<&Structure>, goals: &[Goal]) -> usize {
+    let mut arity = head.map(|h| h.terms.len()).unwrap_or(0);
+    walk(goals, &mut |goal| if let Goal::Call(ref s) = *goal {
+        arity = cmp::max(arity, s.terms.len());
+    });
+    arity
+}
+
+/// The variables of a clause that occur in more than one chunk, in
+/// order of first occurrence. The head and the first goal form the
+/// first chunk, and each later call starts a new one.
+fn permanent_variables(head: &Structure, goals: &[Goal]) -> Vec<InternedString> {
+    let mut chunks: Vec<Vec<InternedString>> = vec![vec![]];
+    for term in &head.terms {
+        variables(term, &mut chunks[0]);
+    }
+    for goal in goals {
+        if let Goal::Call(ref goal) = *goal {
+            for term in &goal.terms {
+                variables(term, chunks.last_mut().unwrap());
+            }
+            chunks.push(vec![]);
+        }
+    }
+
+    let mut first_chunk = HashMap::new();
+    let mut permanent = HashSet::new();
+    let mut order = vec![];
+    for (chunk, vars) in chunks.iter().enumerate() {
+        for &v in vars {
+            let first = *first_chunk.entry(v).or_insert_with(|| {
+                order.push(v);
+                chunk
+            });
+            if first != chunk {
+                permanent.insert(v);
+            }
+        }
+    }
+    order.retain(|v| permanent.contains(v));
+    order
+}
+
+fn variables(term: &Term, out: &mut Vec<InternedString>) {
+    match *term {
+        Term::Variable(v) => out.push(v),
+        Term::Structure(ref s) => {
+            for term in &s.terms {
+                variables(term, out);
+            }
+        }
+        Term::Integer(_) | Term::String(_) => { }
+    }
+}
+
+struct Compiler {
+    code: Vec<Instruction>,
+    vars: HashMap<InternedString, Var>,
+    seen: HashSet<InternedString>,
+    /// The next free temporary register; those below it are argument
+    /// registers or already in use.
+    next_register: usize,
+    /// The number of permanent variables so far.
+    permanents: usize,
+    /// Where a cut goes, if not to the clause's own barrier: within
+    /// the condition of an if-then-else, a cut is local to it.
+    cut: Option<Var>,
+}
+
+impl Compiler {
+    fn new(arity: usize, permanents: &[InternedString]) -> Compiler {
+        let vars = permanents.iter()
+                             .enumerate()
+                             .map(|(i, &v)| (v, Var::Permanent(Permanent(i + 1))))
+                             .collect();
+        Compiler { code: vec![], vars: vars, seen: HashSet::new(), next_register: arity + 1,
+                   permanents: permanents.len(), cut: None }
+    }
+
+    fn emit(&mut self, instr: Instruction) {
+        self.code.push(instr);
+    }
+
+    fn temporary(&mut self) -> Register {
+        let r = Register(self.next_register);
+        self.next_register += 1;
+        r
+    }
+
+    /// A new permanent variable, to hold a level for `get_level`.
+    fn level(&mut self) -> Var {
+        self.permanents += 1;
+        Var::Permanent(Permanent(self.permanents))
+    }
+
+    /// Sizes the environment allocated by the first instruction to fit
+    /// every permanent variable, including levels.
+    fn allocated(&mut self) {
+        self.code[0] = Instruction::Allocate(self.permanents);
+    }
+
+    /// The register for `v`, and whether this is its first occurrence.
+    fn var(&mut self, v: InternedString) -> (Var, bool) {
+        let first = self.seen.insert(v);
+        if let Some(&var) = self.vars.get(&v) {
+            return (var, first);
+        }
+        let var = Var::Temporary(self.temporary());
+        self.vars.insert(v, var);
+        (var, first)
+    }
+
+    // Bodies with control constructs, where every variable is
+    // permanent. In the tail of a clause (`tail`), each branch ends the
+    // clause itself; otherwise branches join again after the construct.
+
+    fn body(&mut self, goals: &[Goal], tail: bool) {
+        for (i, goal) in goals.iter().enumerate() {
+            let last = tail && i + 1 == goals.len();
+            match *goal {
+                Goal::Cut => {
+                    let cut = match self.cut {
+                        Some(level) => Instruction::CutTo(level),
+                        None => Instruction::Cut,
+                    };
+                    self.emit(cut);
+                }
+                Goal::Call(ref goal) => {
+                    self.put_arguments(goal);
+                    if last {
+                        self.emit(Instruction::Deallocate);
+                        self.emit(Instruction::Execute(goal.functor));
+                        return;
+                    }
+                    self.emit(Instruction::Call(goal.functor));
+                }
+                Goal::Fail => {
+                    // nothing after this is reachable
+                    self.emit(Instruction::Fail);
+                    return;
+                }
+                Goal::Or(ref either, ref or) => {
+                    self.create_variables(goal);
+                    let branch = self.placeholder();
+                    self.body(either, last);
+                    let join = if last { None } else { Some(self.placeholder()) };
+                    self.code[branch] = Instruction::TryMeElse(self.code.len());
+                    self.body(or, last);
+                    self.join(join);
+                    if last {
+                        return;
+                    }
+                }
+                Goal::IfThenElse { soft, ref cond, ref then, ref otherwise } => {
+                    self.create_variables(goal);
+                    let before = if soft { None } else { Some(self.level()) };
+                    if let Some(before) = before {
+                        self.emit(Instruction::GetLevel(before));
+                    }
+                    let branch = self.placeholder();
+                    let after = if soft || has_cut(cond) {
+                        let after = self.level();
+                        self.emit(Instruction::GetLevel(after));
+                        Some(after)
+                    } else {
+                        None
+                    };
+                    let cut = self.cut;
+                    self.cut = after;
+                    self.body(cond, false);
+                    self.cut = cut;
+                    self.emit(match (before, after) {
+                        (Some(before), _) => Instruction::CutTo(before),
+                        (None, Some(after)) => Instruction::SoftCut(after),
+                        (None, None) => unreachable!(),
+                    });
+                    self.body(then, last);
+                    let join = if last { None } else { Some(self.placeholder()) };
+                    self.code[branch] = Instruction::TryMeElse(self.code.len());
+                    self.body(otherwise, last);
+                    self.join(join);
+                    if last {
+                        return;
+                    }
+                }
+            }
+        }
+        if tail {
+            self.emit(Instruction::Deallocate);
+            self.emit(Instruction::Proceed);
+        }
+    }
+
+    /// Creates each variable of the control construct `goal` not seen
+    /// before, so that every branch finds it in the environment.
+    fn create_variables(&mut self, goal: &Goal) {
+        let mut names = vec![];
+        for branch in goal.branches() {
+            goal_variables(branch, &mut names);
+        }
+        for v in names {
+            if !self.seen.contains(&v) {
+                let (var, _) = self.var(v);
+                let x = self.temporary();
+                self.emit(Instruction::PutVariable(var, x));
+            }
+        }
+    }
+
+    /// Emits an instruction to be filled in once its target is known.
+    fn placeholder(&mut self) -> usize {
+        self.emit(Instruction::Fail);
+        self.code.len() - 1
+    }
+
+    /// Points the `jump` at `join` (if any) just past the construct.
+    fn join(&mut self, join: Option<usize>) {
+        if let Some(join) = join {
+            self.code[join] = Instruction::Jump(self.code.len());
+        }
+    }
+
+    // Head arguments are matched top-down, as in `interpret::program`.
+
+    fn get_argument(&mut self, term: &Term, a: Register) {
+        match *term {
+            Term::Variable(v) => {
+                let (var, first) = self.var(v);
+                self.emit(if first {
+                    Instruction::GetVariable(var, a)
+                } else {
+                    Instruction::GetValue(var, a)
+                });
+            }
+            Term::Structure(ref s) => self.get_structure(s, a),
+            Term::Integer(i) => self.emit(Instruction::GetConstant(i, a)),
+            Term::String(ref s) => self.emit(Instruction::GetString(intern::intern(s), a)),
+        }
+    }
+
+    fn get_structure(&mut self, structure: &Structure, r: Register) {
+        self.emit(Instruction::GetStructure(structure.functor, r));
+        let mut nested = vec![];
+        for term in &structure.terms {
+            match *term {
+                Term::Variable(v) => {
+                    let (var, first) = self.var(v);
+                    self.emit(if first {
+                        Instruction::UnifyVariable(var)
+                    } else {
+                        Instruction::UnifyValue(var)
+                    });
+                }
+                Term::Structure(_) | Term::String(_) => {
+                    let x = self.temporary();
+                    self.emit(Instruction::UnifyVariable(Var::Temporary(x)));
+                    nested.push((term, x));
+                }
+                Term::Integer(i) => self.emit(Instruction::UnifyConstant(i)),
+            }
+        }
+        for (term, x) in nested {
+            self.get_argument(term, x);
+        }
+    }
+
+    // Goal arguments are built bottom-up, as in `interpret::query`.
+
+    fn put_arguments(&mut self, goal: &Structure) {
+        for (i, term) in goal.terms.iter().enumerate() {
+            let a = Register(i + 1);
+            match *term {
+                Term::Variable(v) => {
+                    let (var, first) = self.var(v);
+                    self.emit(if first {
+                        Instruction::PutVariable(var, a)
+                    } else {
+                        Instruction::PutValue(var, a)
+                    });
+                }
+                Term::Structure(ref s) => self.put_structure(s, a),
+                Term::Integer(i) => self.emit(Instruction::PutConstant(i, a)),
+                Term::String(ref s) => self.emit(Instruction::PutString(intern::intern(s), a)),
+            }
+        }
+    }
+
+    fn put_structure(&mut self, structure: &Structure, r: Register) {
+        let nested: Vec<_> =
+            structure.terms.iter()
+                           .map(|term| match *term {
+                               Term::Structure(ref s) => {
+                                   let x = self.temporary();
+                                   self.put_structure(s, x);
+                                   Some(x)
+                               }
+                               Term::String(ref s) => {
+                                   let x = self.temporary();
+                                   self.emit(Instruction::PutString(intern::intern(s), x));
+                                   Some(x)
+                               }
+                               Term::Variable(_) | Term::Integer(_) => None,
+                           })
+                           .collect();
+
+        self.emit(Instruction::PutStructure(structure.functor, r));
+        for (term, x) in structure.terms.iter().zip(nested) {
+            match (term, x) {
+                (&Term::Variable(v), _) => {
+                    let (var, first) = self.var(v);
+                    self.emit(if first {
+                        Instruction::SetVariable(var)
+                    } else {
+                        Instruction::SetValue(var)
+                    });
+                }
+                (&Term::Structure(_), Some(x)) | (&Term::String(_), Some(x)) => {
+                    self.emit(Instruction::SetValue(Var::Temporary(x)));
+                }
+                (&Term::Integer(i), _) => {
+                    self.emit(Instruction::SetConstant(i));
+                }
+                (&Term::Structure(_), None) | (&Term::String(_), None) => unreachable!(),
+            }
+        }
+    }
+}
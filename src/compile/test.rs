@@ -0,0 +1,149 @@
+use ast::{Clause, Structure, Term};
+use ast::ops::Operators;
+use ast::read::{DoubleQuotes, Reader};
+use functor;
+use machine::Instruction;
+use machine::bytecode::{self, Program};
+use machine::listing::Listing;
+
+use super::*;
+
+#[test]
+fn last_call() {
+    let code = clause(&clause!(app(cons(?H, ?T), ?L, cons(?H, ?R)) :- app(?T, ?L, ?R)));
+    assert_eq!(
+        &format!("{}", Listing::labeled(functor!(app/3), &code.instructions)),
+        "app/3 : get_structure cons/2, A1\n\
+         \x20       unify_variable X4\n\
+         \x20       unify_variable X5\n\
+         \x20       get_variable X6, A2\n\
+         \x20       get_structure cons/2, A3\n\
+         \x20       unify_value X4\n\
+         \x20       unify_variable X7\n\
+         \x20       put_value X5, A1\n\
+         \x20       put_value X6, A2\n\
+         \x20       put_value X7, A3\n\
+         \x20       execute app/3\n");
+}
+
+#[test]
+fn permanent_variables() {
+    // Y and Z are still needed after the call to q
+    let code = clause(&clause!(p(?X, ?Y) :- q(?X, ?Z), r(?Z, ?Y)));
+    assert_eq!(
+        &format!("{}", Listing::labeled(functor!(p/2), &code.instructions)),
+        "p/2 : allocate 2\n\
+         \x20     get_variable X3, A1\n\
+         \x20     get_variable Y1, A2\n\
+         \x20     put_value X3, A1\n\
+         \x20     put_variable Y2, A2\n\
+         \x20     call q/2\n\
+         \x20     put_value Y2, A1\n\
+         \x20     put_value Y1, A2\n\
+         \x20     deallocate\n\
+         \x20     execute r/2\n");
+}
+
+#[test]
+fn cuts() {
+    let code = clause(&clause!(p :- !, q));
+    assert_eq!(code.instructions,
+               vec![Instruction::NeckCut, Instruction::Execute(functor!(q/0))]);
+
+    let code = clause(&clause!(p :- q, !, r));
+    assert_eq!(code.instructions,
+               vec![Instruction::Allocate(0),
+                    Instruction::Call(functor!(q/0)),
+                    Instruction::Cut,
+                    Instruction::Deallocate,
+                    Instruction::Execute(functor!(r/0))]);
+}
+
+#[test]
+fn if_then_else() {
+    let cond = Term::Structure(Structure { functor: functor::functors().functor("->", 2, true),
+                                           terms: vec![term!(q(?X)), term!(r(?Y))] });
+    let body = Term::Structure(Structure { functor: functor::functors().functor(";", 2, true),
+                                           terms: vec![cond, term!(s(?Y))] });
+    let code = clause(&Clause { head: structure!(p(?X)), body: vec![body] });
+    assert_eq!(
+        &format!("{}", Listing::labeled(functor!(p/1), &code.instructions)),
+        "p/1 : allocate 3\n\
+         \x20     get_variable Y1, A1\n\
+         \x20     put_variable Y2, X2\n\
+         \x20     get_level Y3\n\
+         \x20     try_me_else L1\n\
+         \x20     put_value Y1, A1\n\
+         \x20     call q/1\n\
+         \x20     cut_to Y3\n\
+         \x20     put_value Y2, A1\n\
+         \x20     deallocate\n\
+         \x20     execute r/1\n\
+         L1:   put_value Y2, A1\n\
+         \x20     deallocate\n\
+         \x20     execute s/1\n");
+
+    // the then branch jumps past the else branch to the goal after it
+    let cond = Term::Structure(Structure { functor: functor::functors().functor("->", 2, true),
+                                           terms: vec![term!(q(?X)), term!(r(?X))] });
+    let body = Term::Structure(Structure { functor: functor::functors().functor(";", 2, true),
+                                           terms: vec![cond, term!(s(?X))] });
+    let code = clause(&Clause { head: structure!(p(?X)), body: vec![body, term!(t(?X))] });
+    assert_eq!(
+        &format!("{}", Listing::labeled(functor!(p/1), &code.instructions)),
+        "p/1 : allocate 2\n\
+         \x20     get_variable Y1, A1\n\
+         \x20     get_level Y2\n\
+         \x20     try_me_else L1\n\
+         \x20     put_value Y1, A1\n\
+         \x20     call q/1\n\
+         \x20     cut_to Y2\n\
+         \x20     put_value Y1, A1\n\
+         \x20     call r/1\n\
+         \x20     jump L2\n\
+         L1:   put_value Y1, A1\n\
+         \x20     call s/1\n\
+         L2:   put_value Y1, A1\n\
+         \x20     deallocate\n\
+         \x20     execute t/1\n");
+}
+
+#[test]
+fn bytecode_round_trip() {
+    let mut program = Program::default();
+    for c in &[clause!(app(nil, ?L, ?L)),
+               clause!(app(cons(?H, ?T), ?L, cons(?H, ?R)) :- app(?T, ?L, ?R)),
+               clause!(p(?X, ?Y) :- q(?X, ?Z), !, r(?Z, ?Y)),
+               clause!(p(?X) :- not(q(?X)), once(r(?X)))] {
+        let code = clause(c);
+        program.predicates.push((code.predicate.unwrap(), code.instructions));
+    }
+    let mut bytes = vec![];
+    bytecode::save_program(&program, &mut bytes).unwrap();
+    assert_eq!(bytecode::load_program(&bytes[..]).unwrap(), program);
+}
+
+#[test]
+fn strings() {
+    let ops = Operators::iso();
+    let term = Reader::new("p(\"a\", f(\"b\")) :- q(g(\"c\")).", &ops)
+                   .double_quotes(DoubleQuotes::String)
+                   .read_single()
+                   .unwrap();
+    let code = clause(&Clause::from_term(&term).unwrap());
+    assert_eq!(
+        &format!("{}", Listing::labeled(functor!(p/2), &code.instructions)),
+        "p/2 : get_string \"a\", A1\n\
+         \x20     get_structure f/1, A2\n\
+         \x20     unify_variable X3\n\
+         \x20     get_string \"b\", X3\n\
+         \x20     put_string \"c\", X4\n\
+         \x20     put_structure g/1, A1\n\
+         \x20     set_value X4\n\
+         \x20     execute q/1\n");
+
+    let program = Program { predicates: vec![(functor!(p/2), code.instructions)] };
+    let mut bytes = vec![];
+    bytecode::save_program(&program, &mut bytes).unwrap();
+    assert_eq!(bytecode::load_program(&bytes[..]).unwrap(), program);
+}
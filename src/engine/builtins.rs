@@ -0,0 +1,2875 @@
+//! Builtin predicates, implemented in Rust.
+
+use ast::{Clause, Structure, Term};
+use ast::ops::{OpError, OpType};
+use ast::read::DoubleQuotes;
+use ast::write::Writer;
+use functor::{self, Functor};
+use intern::{self, InternedString};
+use machine::{Code, Fallible};
+use machine::mem::{Cell, Register, Slot};
+use machine::observer::MachineObserver;
+use machine::profile::Profiler;
+use machine::stack::Redo;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::sync::Arc;
+
+use super::{by_indicator, consult, dcg, module, Builtin, Engine, Error, Unknown};
+use super::stream::{stream_term, Mode, Stream};
+
+pub fn standard<O: MachineObserver>() -> HashMap<Functor, Builtin<O>> {
+    let mut table: HashMap<Functor, Builtin<O>> = HashMap::new();
+
+    // the dynamic database
+    table.insert(functor!(assert/1), assertz);
+    table.insert(functor!(asserta/1), asserta);
+    table.insert(functor!(assertz/1), assertz);
+    table.insert(functor!(retract/1), retract);
+    table.insert(functor!(retractall/1), retractall);
+    table.insert(functor!(abolish/1), abolish);
+    table.insert(functor!(dynamic/1), dynamic);
+    table.insert(functor!(discontiguous/1), discontiguous);
+
+    // examining the program
+    table.insert(functor!(clause/2), clause);
+    table.insert(functor!(current_predicate/1), current_predicate);
+    table.insert(functor!(predicate_property/2), predicate_property);
+    table.insert(functor!(listing/1), listing);
+    table.insert(functor!(wam_listing/1), wam_listing);
+
+    // all solutions
+    table.insert(functor!(findall/3), findall);
+    table.insert(functor!(findall/4), findall_tail);
+    table.insert(functor!(bagof/3), bagof);
+    table.insert(functor!(setof/3), setof);
+    table.insert(functor!(aggregate_all/3), aggregate_all);
+
+    // unification
+    table.insert(functors("=", 2), unify);
+    table.insert(functors("\\=", 2), not_unifiable);
+
+    // comparing and sorting
+    table.insert(functor!(compare/3), compare);
+    table.insert(functors("==", 2), identical);
+    table.insert(functors("\\==", 2), not_identical);
+    table.insert(functors("@<", 2), before);
+    table.insert(functors("@>", 2), after);
+    table.insert(functors("@=<", 2), not_after);
+    table.insert(functors("@>=", 2), not_before);
+    table.insert(functor!(msort/2), msort);
+    table.insert(functor!(sort/2), sort);
+    table.insert(functor!(sort/4), sort_on);
+    table.insert(functor!(predsort/3), predsort);
+    table.insert(functor!(keysort/2), keysort);
+
+    // taking terms apart and building them
+    table.insert(functor!(functor/3), functor);
+    table.insert(functor!(arg/3), arg);
+    table.insert(functors("=..", 2), univ);
+    table.insert(functor!(copy_term/2), copy_term);
+    table.insert(functor!(term_variables/2), term_variables);
+    table.insert(functor!(setarg/3), setarg);
+    table.insert(functor!(nb_setarg/3), nb_setarg);
+
+    // exceptions
+    table.insert(functor!(throw/1), throw);
+    table.insert(functors("$exit_catch", 1), exit_catch);
+
+    // attributed variables
+    table.insert(functor!(put_attr/3), put_attr);
+    table.insert(functor!(get_attr/3), get_attr);
+    table.insert(functor!(del_attr/2), del_attr);
+
+    // type checks
+    table.insert(functor!(var/1), var);
+    table.insert(functor!(nonvar/1), nonvar);
+    table.insert(functor!(atom/1), atom);
+    table.insert(functor!(number/1), integer);
+    table.insert(functor!(integer/1), integer);
+    table.insert(functor!(float/1), float);
+    table.insert(functor!(atomic/1), atomic);
+    table.insert(functor!(string/1), is_string);
+    table.insert(functor!(compound/1), compound);
+    table.insert(functor!(callable/1), is_callable);
+    table.insert(functor!(is_list/1), is_list);
+    table.insert(functor!(ground/1), ground);
+
+    // atoms and text
+    table.insert(functor!(atom_codes/2), atom_codes);
+    table.insert(functor!(atom_chars/2), atom_chars);
+    table.insert(functor!(char_code/2), char_code);
+    table.insert(functor!(atom_length/2), atom_length);
+    table.insert(functor!(atom_concat/3), atom_concat);
+    table.insert(functor!(sub_atom/5), sub_atom);
+    table.insert(functor!(atom_number/2), atom_number);
+    table.insert(functor!(number_codes/2), number_codes);
+    table.insert(functor!(upcase_atom/2), upcase_atom);
+    table.insert(functor!(term_to_atom/2), term_to_atom);
+
+    // strings
+    table.insert(functor!(string_chars/2), string_chars);
+    table.insert(functor!(string_codes/2), string_codes);
+    table.insert(functor!(string_to_atom/2), string_to_atom);
+    table.insert(functor!(string_length/2), string_length);
+    table.insert(functor!(string_concat/3), string_concat);
+    table.insert(functor!(sub_string/5), sub_string);
+    table.insert(functor!(string_code/3), string_code);
+    table.insert(functor!(split_string/4), split_string);
+
+    // flags
+    table.insert(functor!(set_prolog_flag/2), set_prolog_flag);
+    table.insert(functor!(current_prolog_flag/2), current_prolog_flag);
+
+    // streams
+    table.insert(functor!(open/3), open);
+    table.insert(functor!(open/4), open);
+    table.insert(functor!(close/1), close);
+    table.insert(functor!(close/2), close);
+    table.insert(functor!(current_input/1), current_input);
+    table.insert(functor!(current_output/1), current_output);
+    table.insert(functor!(set_input/1), set_input);
+    table.insert(functor!(set_output/1), set_output);
+    table.insert(functor!(get_char/1), get_char);
+    table.insert(functor!(get_char/2), get_char);
+    table.insert(functor!(peek_char/1), peek_char);
+    table.insert(functor!(peek_char/2), peek_char);
+    table.insert(functor!(put_char/1), put_char);
+    table.insert(functor!(put_char/2), put_char);
+    table.insert(functor!(nl/0), nl);
+    table.insert(functor!(nl/1), nl);
+    table.insert(functor!(flush_output/0), flush_output);
+    table.insert(functor!(flush_output/1), flush_output);
+    table.insert(functor!(read_term/2), read_term);
+    table.insert(functor!(read_term/3), read_term);
+    table.insert(functor!(read/1), read);
+    table.insert(functor!(read/2), read);
+    table.insert(functor!(stream_property/2), stream_property);
+
+    // writing terms
+    table.insert(functor!(write/1), write);
+    table.insert(functor!(write/2), write);
+    table.insert(functor!(print/1), print);
+    table.insert(functor!(print/2), print);
+    table.insert(functor!(writeq/1), writeq);
+    table.insert(functor!(writeq/2), writeq);
+    table.insert(functor!(write_canonical/1), write_canonical);
+    table.insert(functor!(write_canonical/2), write_canonical);
+    table.insert(functor!(write_term/2), write_term);
+    table.insert(functor!(write_term/3), write_term);
+
+    // loading source files
+    table.insert(functor!(consult/1), consult);
+    table.insert(functor!(ensure_loaded/1), ensure_loaded);
+    table.insert(functors(".", 2), consult_list);
+    table.insert(functor!(dcg_translate_rule/2), dcg_translate_rule);
+
+    // modules
+    table.insert(functor!(use_module/1), use_module);
+    table.insert(functor!(use_module/2), use_module);
+    table.insert(functor!(meta_predicate/1), meta_predicate);
+
+    // operators
+    table.insert(functor!(op/3), op);
+    table.insert(functor!(current_op/3), current_op);
+
+    // statistics
+    table.insert(functor!(statistics/0), statistics);
+    table.insert(functor!(statistics/2), statistics);
+
+    table
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Argument checking
+
+/// The callable term `term`, or the appropriate error.
+fn callable(term: Term) -> Result<Structure, Error> {
+    match term {
+        Term::Structure(s) => Ok(s),
+        Term::Variable(_) => Err(Error::Instantiation),
+        term @ Term::Integer(_) | term @ Term::String(_) => Err(Error::Type("callable", term)),
+    }
+}
+
+/// The functor named by the predicate indicator `term`.
+fn indicator(term: Term) -> Result<Functor, Error> {
+    match term {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Structure(ref s) if s.functor == functors("/", 2) => {
+            if s.terms.iter().any(|t| match *t { Term::Variable(_) => true, _ => false }) {
+                return Err(Error::Instantiation);
+            }
+        }
+        _ => { }
+    }
+    match term.to_indicator() {
+        Some(f) => Ok(f),
+        None => Err(Error::Type("predicate_indicator", term)),
+    }
+}
+
+/// The largest arity `functor/3` will build a term with, so that a
+/// bad argument cannot ask for an arbitrarily large structure.
+const MAX_ARITY: usize = 1024;
+
+fn functors(name: &str, arity: usize) -> Functor {
+    functor::functors().functor(name, arity, true)
+}
+
+/// Fails with a permission error if `f` is a builtin, which cannot be
+/// modified.
+fn modifiable<O: MachineObserver>(engine: &Engine<O>, f: Functor) -> Result<(), Error> {
+    if engine.builtins.contains_key(&f) {
+        Err(Error::Permission("modify", "static_procedure", Term::indicator(f)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Argument `i` without its `M:` prefix, if any, and the module that
+/// prefix names (`user` if none). The cells of its variables are
+/// recorded in `vars`.
+fn module_argument<O: MachineObserver>(engine: &Engine<O>, i: usize,
+                                       vars: &mut HashMap<InternedString, Cell>)
+                                       -> Result<(InternedString, Term), Error> {
+    let (module, term) = try!(module::strip(engine.machine.read_argument(i, vars)));
+    Ok((module.unwrap_or_else(module::user), term))
+}
+
+/// Checks that `term` is a list or a partial list.
+pub fn list(term: &Term) -> Result<(), Error> {
+    let mut tail = term;
+    loop {
+        match *tail {
+            Term::Structure(ref s) if s.functor == functors(".", 2) => tail = &s.terms[1],
+            Term::Structure(ref s) if s.functor == functors("[]", 0) => return Ok(()),
+            Term::Variable(_) => return Ok(()),
+            _ => return Err(Error::Type("list", term.clone())),
+        }
+    }
+}
+
+/// Unifies argument `i` with `term`, whose variables are looked up in
+/// (or added to) `vars`.
+fn unify_argument<O: MachineObserver>(engine: &mut Engine<O>, i: usize, term: &Term,
+                                      vars: &mut HashMap<InternedString, Cell>) -> Fallible {
+    let slot = engine.machine.put_term(term, vars);
+    engine.machine.unify(slot, Register(i))
+}
+
+/// Unifies argument `i + 1` with `terms[i]`, for each `i`. Variables
+/// with the same name in different terms are the same variable.
+fn unify_arguments<O: MachineObserver>(engine: &mut Engine<O>, terms: &[Term]) -> Fallible {
+    let mut vars = HashMap::new();
+    for (i, term) in terms.iter().enumerate() {
+        try!(unify_argument(engine, i + 1, term, &mut vars));
+    }
+    Ok(())
+}
+
+/// Unifies the arguments with each solution in turn. The solutions
+/// are computed by `solve` on the first call.
+fn each_solution<O, F>(engine: &mut Engine<O>, redo: Option<Redo>, solve: F)
+                       -> Result<Fallible, Error>
+    where O: MachineObserver, F: FnOnce(&mut Engine<O>) -> Result<Vec<Vec<Term>>, Error>
+{
+    let (solutions, index) = match redo {
+        Some(Redo::Solutions(solutions, index)) => (solutions, index),
+        Some(redo) => panic!("unexpected {:?}", redo),
+        None => (Arc::new(try!(solve(engine))), 0),
+    };
+    if index >= solutions.len() {
+        return Ok(Err(()));
+    }
+    if index + 1 < solutions.len() {
+        engine.retry(Redo::Solutions(solutions.clone(), index + 1));
+    }
+    Ok(unify_arguments(engine, &solutions[index]))
+}
+
+fn source(code: &Code) -> &Clause {
+    code.source.as_ref().expect("clause compiled without its source")
+}
+
+///////////////////////////////////////////////////////////////////////////
+// The dynamic database
+
+/// The clause in argument 1, which belongs to the module it is
+/// qualified with.
+fn clause_argument<O: MachineObserver>(engine: &Engine<O>) -> Result<Clause, Error> {
+    let (module, term) = try!(module_argument(engine, 1, &mut HashMap::new()));
+    let mut clause = match Clause::from_term(&term) {
+        Some(clause) => clause,
+        None => {
+            // the head is not callable
+            let head = match term {
+                Term::Structure(ref s) => s.terms[0].clone(),
+                ref term => term.clone(),
+            };
+            return Err(callable(head).unwrap_err());
+        }
+    };
+    for goal in &clause.body {
+        if let Term::Integer(_) = *goal {
+            return Err(Error::Type("callable", clause.body_term()));
+        }
+    }
+    try!(modifiable(engine, clause.head.functor));
+    clause.head.functor = module::qualify(module, clause.head.functor);
+    clause.body = clause.body.iter().map(|goal| module::qualify_goal(engine, module, goal))
+                                    .collect();
+    Ok(clause)
+}
+
+fn asserta<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let clause = try!(clause_argument(engine));
+    try!(engine.database.assert(&clause, true));
+    Ok(Ok(()))
+}
+
+fn assertz<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let clause = try!(clause_argument(engine));
+    try!(engine.database.assert(&clause, false));
+    Ok(Ok(()))
+}
+
+/// Removes the first clause that unifies with `Head :- Body` (or with
+/// `Head`, for a fact), and on backtracking the next one. The clauses
+/// considered are those present when `retract/1` was first called.
+fn retract<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let mut vars = HashMap::new();
+    let (module, mut term) = try!(module_argument(engine, 1, &mut vars));
+    let rule = match term {
+        Term::Structure(ref s) => s.functor == functors(":-", 2),
+        _ => false,
+    };
+    let f = {
+        let head = match term {
+            Term::Structure(ref mut s) if rule => &mut s.terms[0],
+            ref mut term => term,
+        };
+        let f = try!(callable(head.clone())).functor;
+        if let Term::Structure(ref mut head) = *head {
+            head.functor = module::qualify(module, f);
+        }
+        f
+    };
+
+    let (clauses, index) = match redo {
+        Some(Redo::Clauses(clauses, index)) => (clauses, index),
+        Some(redo) => panic!("unexpected {:?}", redo),
+        None => {
+            try!(modifiable(engine, f));
+            match engine.database.predicate(module::qualify(module, f)) {
+                Some(predicate) if !predicate.is_dynamic() => {
+                    return Err(Error::Permission("modify", "static_procedure",
+                                                 Term::indicator(f)));
+                }
+                Some(predicate) if !predicate.clauses().is_empty() => {
+                    (predicate.clauses().clone(), 0)
+                }
+                _ => return Ok(Err(())),
+            }
+        }
+    };
+    if index + 1 < clauses.len() {
+        engine.retry(Redo::Clauses(clauses.clone(), index + 1));
+    }
+
+    let code = &clauses[index];
+    let source = source(code);
+    if !rule && !source.body.is_empty() {
+        return Ok(Err(()));
+    }
+    let stored = if rule { source.to_rule() } else { source.to_term() };
+    let slot = engine.machine.put_term(&stored, &mut HashMap::new());
+    let target = engine.machine.put_term(&term, &mut vars);
+    if engine.machine.unify(slot, target).is_err() {
+        return Ok(Err(()));
+    }
+
+    // someone else may have removed it since the snapshot was taken
+    if engine.database.retract(module::qualify(module, f), code) {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(()))
+    }
+}
+
+/// Removes every clause whose head unifies with the argument, which
+/// becomes a dynamic predicate if it did not exist.
+fn retractall<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    let mut vars = HashMap::new();
+    let (module, head) = try!(module_argument(engine, 1, &mut vars));
+    let mut head = try!(callable(head));
+    try!(modifiable(engine, head.functor));
+    let f = module::qualify(module, head.functor);
+    try!(engine.database.declare_dynamic(f));
+
+    head.functor = f;
+    let target = engine.machine.put_term(&Term::Structure(head), &mut vars);
+    let clauses = engine.database.predicate(f).unwrap().clauses().clone();
+    for code in clauses.iter() {
+        let head = Term::Structure(source(code).head.clone());
+        let slot = engine.machine.put_term(&head, &mut HashMap::new());
+        if engine.machine.unifiable(slot, target) {
+            engine.database.retract(f, code);
+        }
+    }
+    Ok(Ok(()))
+}
+
+fn abolish<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let (module, spec) = try!(module_argument(engine, 1, &mut HashMap::new()));
+    let f = try!(indicator(spec));
+    try!(modifiable(engine, f));
+    try!(engine.database.abolish(module::qualify(module, f)));
+    Ok(Ok(()))
+}
+
+/// The functors named by a predicate indicator or a conjunction of
+/// them in argument 1, as in `dynamic/1`, qualified with its module.
+/// Builtins are not allowed.
+fn indicators<O: MachineObserver>(engine: &Engine<O>) -> Result<Vec<Functor>, Error> {
+    let (module, spec) = try!(module_argument(engine, 1, &mut HashMap::new()));
+    let mut specs = vec![spec];
+    let mut found = vec![];
+    while let Some(spec) = specs.pop() {
+        match spec {
+            Term::Structure(ref s) if s.functor == functors(",", 2) => {
+                specs.push(s.terms[1].clone());
+                specs.push(s.terms[0].clone());
+                continue;
+            }
+            _ => { }
+        }
+        let f = try!(indicator(spec));
+        try!(modifiable(engine, f));
+        found.push(module::qualify(module, f));
+    }
+    Ok(found)
+}
+
+fn dynamic<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    for f in try!(indicators(engine)) {
+        try!(engine.database.declare_dynamic(f));
+    }
+    Ok(Ok(()))
+}
+
+fn discontiguous<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                     -> Result<Fallible, Error> {
+    for f in try!(indicators(engine)) {
+        engine.database.declare_discontiguous(f);
+    }
+    Ok(Ok(()))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Examining the program
+
+/// Finds the clauses whose head and body unify with the arguments.
+/// As with `retract/1`, the clauses considered are those present when
+/// it was first called.
+fn clause<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                              -> Result<Fallible, Error> {
+    let (clauses, index) = match redo {
+        Some(Redo::Clauses(clauses, index)) => (clauses, index),
+        Some(redo) => panic!("unexpected {:?}", redo),
+        None => {
+            let f = try!(callable(engine.machine.argument(1))).functor;
+            if let body @ Term::Integer(_) = engine.machine.argument(2) {
+                return Err(Error::Type("callable", body));
+            }
+            if engine.builtins.contains_key(&f) {
+                return Err(Error::Permission("access", "private_procedure",
+                                             Term::indicator(f)));
+            }
+            match engine.database.predicate(f) {
+                Some(predicate) if !predicate.clauses().is_empty() => {
+                    (predicate.clauses().clone(), 0)
+                }
+                _ => return Ok(Err(())),
+            }
+        }
+    };
+    if index + 1 < clauses.len() {
+        engine.retry(Redo::Clauses(clauses.clone(), index + 1));
+    }
+
+    let source = source(&clauses[index]);
+    let head = Term::Structure(source.head.clone());
+    Ok(unify_arguments(engine, &[head, source.body_term()]))
+}
+
+/// Enumerates the user-defined predicates matching `Name/Arity`.
+fn current_predicate<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                         -> Result<Fallible, Error> {
+    each_solution(engine, redo, |engine| {
+        let spec = engine.machine.argument(1);
+        let valid = match spec {
+            Term::Variable(_) => true,
+            Term::Structure(ref s) if s.functor == functors("/", 2) => {
+                let name = match s.terms[0] {
+                    Term::Variable(_) => true,
+                    Term::Structure(ref name) => name.terms.is_empty(),
+                    Term::Integer(_) | Term::String(_) => false,
+                };
+                let arity = match s.terms[1] {
+                    Term::Variable(_) | Term::Integer(_) => true,
+                    Term::Structure(_) | Term::String(_) => false,
+                };
+                name && arity
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(Error::Type("predicate_indicator", spec));
+        }
+        Ok(engine.database.functors().into_iter().map(|f| vec![Term::indicator(f)]).collect())
+    })
+}
+
+/// Enumerates the properties of predicates: `built_in`, `dynamic`,
+/// `static`, `defined` and `number_of_clauses(N)`.
+fn predicate_property<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                          -> Result<Fallible, Error> {
+    each_solution(engine, redo, |engine| {
+        let functors = match engine.machine.argument(1) {
+            Term::Variable(_) => {
+                let mut builtins: Vec<_> = engine.builtins.keys().cloned().collect();
+                builtins.sort_by_key(by_indicator);
+                let mut functors = engine.database.functors();
+                functors.extend(builtins);
+                functors
+            }
+            head => vec![try!(callable(head)).functor],
+        };
+
+        let mut solutions = vec![];
+        for f in functors {
+            let args = (0..f.arity()).map(|i| {
+                Term::Variable(intern::intern(&format!("_{}", i)))
+            });
+            let head = Term::Structure(Structure { functor: f, terms: args.collect() });
+            for property in properties(engine, f) {
+                solutions.push(vec![head.clone(), property]);
+            }
+        }
+        Ok(solutions)
+    })
+}
+
+fn properties<O: MachineObserver>(engine: &Engine<O>, f: Functor) -> Vec<Term> {
+    if engine.builtins.contains_key(&f) {
+        return vec![Term::atom("built_in"), Term::atom("defined"), Term::atom("static")];
+    }
+    match engine.database.predicate(f) {
+        Some(predicate) => {
+            let clauses = Term::Integer(predicate.clauses().len() as i64);
+            let number_of_clauses = Structure { functor: functors("number_of_clauses", 1),
+                                                terms: vec![clauses] };
+            let mut properties =
+                vec![Term::atom(if predicate.is_dynamic() { "dynamic" } else { "static" }),
+                     Term::atom("defined"),
+                     Term::Structure(number_of_clauses)];
+            if predicate.is_discontiguous() {
+                properties.push(Term::atom("discontiguous"));
+            }
+            properties
+        }
+        None => vec![],
+    }
+}
+
+/// Writes the clauses of the predicate `Name/Arity`, or of every
+/// predicate called `Name`, to the current output.
+fn listing<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    for f in try!(listed(engine)) {
+        let text = engine.database.listing(f);
+        let id = engine.streams.output();
+        try!(output(engine, id, &text));
+    }
+    Ok(Ok(()))
+}
+
+/// `wam_listing(Name)` or `wam_listing(Name/Arity)`: writes the
+/// compiled code of the predicates, in the layout of the tutorial's
+/// figures.
+fn wam_listing<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                   -> Result<Fallible, Error> {
+    for f in try!(listed(engine)) {
+        let text = engine.database.wam_listing(f);
+        let id = engine.streams.output();
+        try!(output(engine, id, &text));
+    }
+    Ok(Ok(()))
+}
+
+/// The predicates named by argument 1 of `listing/1` or
+/// `wam_listing/1`: every arity of a name, or a single indicator.
+fn listed<O: MachineObserver>(engine: &Engine<O>) -> Result<Vec<Functor>, Error> {
+    match engine.machine.argument(1) {
+        Term::Structure(ref name) if name.terms.is_empty() => {
+            let name = name.functor.with_text(|text| text.to_string());
+            Ok(engine.database.functors()
+                              .into_iter()
+                              .filter(|f| f.with_text(|text| text == name))
+                              .collect())
+        }
+        spec => Ok(vec![try!(indicator(spec))]),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// All solutions
+
+/// A copy of `template` for each solution of `goal`, in order. The
+/// variables of each copy are renamed apart from those of the others,
+/// and from those of `template` itself.
+fn find_all<O: MachineObserver>(engine: &mut Engine<O>, template: &Term, goal: &Term,
+                                vars: &HashMap<InternedString, Cell>)
+                                -> Result<Vec<Term>, Error> {
+    find(engine, template, goal, vars, true)
+}
+
+/// A copy of `template` for the first solution of `goal`, if any.
+fn find_first<O: MachineObserver>(engine: &mut Engine<O>, template: &Term, goal: &Term,
+                                  vars: &HashMap<InternedString, Cell>)
+                                  -> Result<Option<Term>, Error> {
+    Ok(try!(find(engine, template, goal, vars, false)).pop())
+}
+
+fn find<O: MachineObserver>(engine: &mut Engine<O>, template: &Term, goal: &Term,
+                            vars: &HashMap<InternedString, Cell>, all: bool)
+                            -> Result<Vec<Term>, Error> {
+    let goals = match *goal {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Integer(_) | Term::String(_) => return Err(Error::Type("callable", goal.clone())),
+        Term::Structure(_) => goal.conjuncts(),
+    };
+    let mut vars = vars.clone();
+    let template = engine.machine.put_term(template, &mut vars);
+    let mut copies = vec![];
+    let mut names = 0;
+    try!(engine.solve(&goals, &vars, |engine| {
+        // the variables of each copy are its own; they are named `_1`,
+        // `_2` and so on, so that every call reuses the same names
+        let copy = engine.machine.term(template);
+        let mut renaming = HashMap::new();
+        copies.push(rename(&copy, &mut |v| {
+            *renaming.entry(v).or_insert_with(|| {
+                names += 1;
+                intern::intern(&format!("_{}", names))
+            })
+        }));
+        Ok(all)
+    }));
+    Ok(copies)
+}
+
+/// `term`, with each variable `v` renamed to `f(v)`.
+pub fn rename<F>(term: &Term, f: &mut F) -> Term
+    where F: FnMut(InternedString) -> InternedString
+{
+    match *term {
+        Term::Variable(v) => Term::Variable(f(v)),
+        Term::Structure(ref s) => {
+            let terms = s.terms.iter().map(|t| rename(t, f)).collect();
+            Term::Structure(Structure { functor: s.functor, terms: terms })
+        }
+        Term::Integer(_) | Term::String(_) => term.clone(),
+    }
+}
+
+/// Whether `a` and `b` are equal up to a consistent renaming of
+/// variables, which is recorded in `renaming`.
+fn variant(a: &Term, b: &Term, renaming: &mut HashMap<InternedString, InternedString>) -> bool {
+    match (a, b) {
+        (&Term::Variable(x), &Term::Variable(y)) => {
+            match renaming.get(&x) {
+                Some(&z) => z == y,
+                None if renaming.values().any(|&z| z == y) => false,
+                None => {
+                    renaming.insert(x, y);
+                    true
+                }
+            }
+        }
+        (&Term::Structure(ref s), &Term::Structure(ref t)) => {
+            s.functor == t.functor &&
+                s.terms.iter().zip(&t.terms).all(|(a, b)| variant(a, b, renaming))
+        }
+        (&Term::Integer(i), &Term::Integer(j)) => i == j,
+        _ => false,
+    }
+}
+
+fn findall<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let mut vars = HashMap::new();
+    let template = engine.machine.read_argument(1, &mut vars);
+    let goal = engine.machine.read_argument(2, &mut vars);
+    try!(list(&engine.machine.argument(3)));
+    let results = try!(find_all(engine, &template, &goal, &vars));
+    Ok(unify_argument(engine, 3, &Term::list(results, Term::nil()), &mut HashMap::new()))
+}
+
+/// `findall/4`, whose list of results ends in the fourth argument
+/// rather than `[]`.
+fn findall_tail<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                    -> Result<Fallible, Error> {
+    let mut vars = HashMap::new();
+    let template = engine.machine.read_argument(1, &mut vars);
+    let goal = engine.machine.read_argument(2, &mut vars);
+    try!(list(&engine.machine.argument(3)));
+    let results = try!(find_all(engine, &template, &goal, &vars));
+
+    let mut vars = HashMap::new();
+    let tail = engine.machine.read_argument(4, &mut vars);
+    Ok(unify_argument(engine, 3, &Term::list(results, tail), &mut vars))
+}
+
+/// The template and goal of `bagof/3` or `setof/3`, with any `V^`
+/// prefixes removed from the goal, and the witness: a term holding
+/// the free variables of the goal, which are those that occur in
+/// neither the template nor a `V^` prefix.
+fn bag_arguments<O: MachineObserver>(engine: &Engine<O>, vars: &mut HashMap<InternedString, Cell>)
+                                     -> (Term, Term, Term) {
+    let template = engine.machine.read_argument(1, vars);
+    let mut goal = engine.machine.read_argument(2, vars);
+    let mut bound = vec![];
+    template.variables(&mut bound);
+    loop {
+        let inner = match goal {
+            Term::Structure(ref s) if s.functor == functors("^", 2) => {
+                s.terms[0].variables(&mut bound);
+                s.terms[1].clone()
+            }
+            _ => break,
+        };
+        goal = inner;
+    }
+
+    let mut free = vec![];
+    goal.variables(&mut free);
+    free.retain(|v| !bound.contains(v));
+    let witness = Structure { functor: functors("v", free.len()),
+                              terms: free.into_iter().map(Term::Variable).collect() };
+    (template, goal, Term::Structure(witness))
+}
+
+/// The solutions of `bagof/3` (or of `setof/3`, if `set`): a binding
+/// of the witness, followed by the templates found with it.
+fn bags<O: MachineObserver>(engine: &mut Engine<O>, set: bool) -> Result<Vec<Vec<Term>>, Error> {
+    let mut vars = HashMap::new();
+    let (template, goal, witness) = bag_arguments(engine, &mut vars);
+    let pair = Structure { functor: functors("-", 2), terms: vec![witness, template] };
+    let results = try!(find_all(engine, &Term::Structure(pair), &goal, &vars));
+
+    let mut pairs: Vec<(Term, Term)> =
+        results.into_iter()
+               .map(|pair| match pair {
+                   Term::Structure(mut s) => {
+                       let template = s.terms.pop().unwrap();
+                       (s.terms.pop().unwrap(), template)
+                   }
+                   _ => unreachable!(),
+               })
+               .collect();
+    if set {
+        // in the standard order of the witnesses, compared on the heap
+        let mut vars = HashMap::new();
+        let mut keyed: Vec<_> = pairs.into_iter()
+                                     .map(|pair| {
+                                         let slot = engine.machine.put_term(&pair.0, &mut vars);
+                                         (Cell::Ref(slot), pair)
+                                     })
+                                     .collect();
+        keyed.sort_by(|a, b| engine.machine.compare(a.0, b.0));
+        pairs = keyed.into_iter().map(|(_, pair)| pair).collect();
+    }
+
+    // solutions whose witnesses are variants go in the same bag, with
+    // their variables identified
+    let mut bags = vec![];
+    while !pairs.is_empty() {
+        let witness = pairs[0].0.clone();
+        let mut bag = vec![witness.clone()];
+        let mut rest = vec![];
+        for (w, template) in pairs {
+            let mut renaming = HashMap::new();
+            if variant(&w, &witness, &mut renaming) {
+                bag.push(rename(&template, &mut |v| *renaming.get(&v).unwrap_or(&v)));
+            } else {
+                rest.push((w, template));
+            }
+        }
+        bags.push(bag);
+        pairs = rest;
+    }
+    Ok(bags)
+}
+
+fn bagof<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                             -> Result<Fallible, Error> {
+    each_bag(engine, redo, false)
+}
+
+fn setof<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                             -> Result<Fallible, Error> {
+    each_bag(engine, redo, true)
+}
+
+/// Binds the free variables of the goal and the third argument to
+/// each solution of `bags` in turn.
+fn each_bag<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>, set: bool)
+                                -> Result<Fallible, Error> {
+    let (bags, index) = match redo {
+        Some(Redo::Solutions(bags, index)) => (bags, index),
+        Some(redo) => panic!("unexpected {:?}", redo),
+        None => {
+            try!(list(&engine.machine.argument(3)));
+            (Arc::new(try!(bags(engine, set))), 0)
+        }
+    };
+    if index >= bags.len() {
+        return Ok(Err(()));
+    }
+    if index + 1 < bags.len() {
+        engine.retry(Redo::Solutions(bags.clone(), index + 1));
+    }
+
+    let mut vars = HashMap::new();
+    let (_, _, witness) = bag_arguments(engine, &mut vars);
+    let witness = engine.machine.put_term(&witness, &mut vars);
+    let mut vars = HashMap::new();
+    let bag = engine.machine.put_term(&bags[index][0], &mut vars);
+    if engine.machine.unify(witness, bag).is_err() {
+        return Ok(Err(()));
+    }
+    let mut items: Vec<_> = bags[index][1..].iter()
+                                            .map(|item| {
+                                                Cell::Ref(engine.machine.put_term(item, &mut vars))
+                                            })
+                                            .collect();
+    if set {
+        sort_unique(engine, &mut items);
+    }
+    Ok(unify_list(engine, 3, &items))
+}
+
+/// `aggregate_all(Spec, Goal, Result)`, where `Spec` is one of
+/// `count`, `count(T)`, `sum(E)`, `max(E)`, `min(E)`, `bag(T)` or
+/// `set(T)`.
+fn aggregate_all<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                     -> Result<Fallible, Error> {
+    let mut vars = HashMap::new();
+    let spec = engine.machine.read_argument(1, &mut vars);
+    let goal = engine.machine.read_argument(2, &mut vars);
+    let (name, template) = match spec {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Structure(ref s) if s.terms.len() <= 1 => {
+            let name = s.functor.with_text(|text| text.to_string());
+            match (&*name, s.terms.first()) {
+                ("count", None) => (name, Term::nil()),
+                ("count", Some(t)) | ("sum", Some(t)) | ("max", Some(t)) |
+                ("min", Some(t)) | ("bag", Some(t)) | ("set", Some(t)) => (name, t.clone()),
+                _ => return Err(Error::Domain("aggregate_spec", spec.clone())),
+            }
+        }
+        _ => return Err(Error::Domain("aggregate_spec", spec)),
+    };
+
+    let results = try!(find_all(engine, &template, &goal, &vars));
+    let result = match &*name {
+        "count" => Term::Integer(results.len() as i64),
+        "bag" => Term::list(results, Term::nil()),
+        "set" => {
+            let mut vars = HashMap::new();
+            let mut items: Vec<_> = results.iter()
+                                           .map(|result| {
+                                               Cell::Ref(engine.machine.put_term(result, &mut vars))
+                                           })
+                                           .collect();
+            sort_unique(engine, &mut items);
+            return Ok(unify_list(engine, 3, &items));
+        }
+        _ => {
+            let mut values = vec![];
+            for result in &results {
+                values.push(try!(evaluate(result)));
+            }
+            let value = match &*name {
+                "sum" => Some(values.iter().sum()),
+                "max" => values.into_iter().max(),
+                _ => values.into_iter().min(),
+            };
+            match value {
+                Some(value) => Term::Integer(value),
+                None => return Ok(Err(())),
+            }
+        }
+    };
+    Ok(unify_argument(engine, 3, &result, &mut HashMap::new()))
+}
+
+/// The value of an arithmetic expression; for now, only integers.
+fn evaluate(term: &Term) -> Result<i64, Error> {
+    match *term {
+        Term::Integer(i) => Ok(i),
+        Term::Variable(_) => Err(Error::Instantiation),
+        Term::Structure(ref s) => Err(Error::Type("evaluable", Term::indicator(s.functor))),
+        Term::String(_) => Err(Error::Type("evaluable", term.clone())),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Unification
+
+fn unify<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    let (a, b) = (engine.machine.argument_cell(1), engine.machine.argument_cell(2));
+    Ok(engine.machine.unify_cells(a, b))
+}
+
+fn not_unifiable<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                     -> Result<Fallible, Error> {
+    let (a, b) = (engine.machine.argument_cell(1), engine.machine.argument_cell(2));
+    Ok(test(!engine.machine.unifiable_cells(a, b)))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Comparing and sorting
+
+fn test(condition: bool) -> Fallible {
+    if condition { Ok(()) } else { Err(()) }
+}
+
+/// The standard order of arguments `a` and `b`.
+fn order<O: MachineObserver>(engine: &Engine<O>, a: usize, b: usize) -> Ordering {
+    engine.machine.compare(engine.machine.argument_cell(a), engine.machine.argument_cell(b))
+}
+
+/// The items of the list in argument `i`.
+fn list_argument<O: MachineObserver>(engine: &Engine<O>, i: usize) -> Result<Vec<Cell>, Error> {
+    let (dot, nil) = (functors(".", 2), functors("[]", 0));
+    let mut items = vec![];
+    let mut tail = engine.machine.argument_cell(i);
+    loop {
+        match engine.machine.value(tail) {
+            Cell::Ref(_) => return Err(Error::Instantiation),
+            Cell::Structure(slot) => {
+                let (f, args) = engine.machine.structure(slot);
+                if f == dot {
+                    items.push(args[0]);
+                    tail = args[1];
+                    continue;
+                } else if f == nil {
+                    return Ok(items);
+                }
+            }
+            _ => { }
+        }
+        return Err(Error::Type("list", engine.machine.argument(i)));
+    }
+}
+
+/// Builds the list of `items` on the heap.
+fn new_list<O: MachineObserver>(engine: &mut Engine<O>, items: &[Cell]) -> Cell {
+    let dot = functors(".", 2);
+    let nil = engine.machine.new_structure(functors("[]", 0), &[]);
+    items.iter().rev().fold(nil, |rest, &item| engine.machine.new_structure(dot, &[item, rest]))
+}
+
+/// Unifies argument `i` with the list of `items`.
+fn unify_list<O: MachineObserver>(engine: &mut Engine<O>, i: usize, items: &[Cell]) -> Fallible {
+    let list = new_list(engine, items);
+    let argument = engine.machine.argument_cell(i);
+    engine.machine.unify_cells(list, argument)
+}
+
+/// The name of an atom, or the appropriate error.
+fn atom_name(term: &Term) -> Result<String, Error> {
+    match *term {
+        Term::Variable(_) => Err(Error::Instantiation),
+        Term::Structure(ref s) if s.terms.is_empty() => {
+            Ok(s.functor.with_text(|text| text.to_string()))
+        }
+        _ => Err(Error::Type("atom", term.clone())),
+    }
+}
+
+fn compare<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let order = engine.machine.argument(1);
+    if let Term::Structure(_) = order {
+        let name = try!(atom_name(&order));
+        if name != "<" && name != "=" && name != ">" {
+            return Err(Error::Domain("order", order));
+        }
+    } else if let Term::Integer(_) = order {
+        return Err(Error::Type("atom", order));
+    }
+    let name = match self::order(engine, 2, 3) {
+        Ordering::Less => "<",
+        Ordering::Equal => "=",
+        Ordering::Greater => ">",
+    };
+    Ok(unify_argument(engine, 1, &Term::atom(name), &mut HashMap::new()))
+}
+
+fn identical<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    Ok(test(order(engine, 1, 2) == Ordering::Equal))
+}
+
+fn not_identical<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                     -> Result<Fallible, Error> {
+    Ok(test(order(engine, 1, 2) != Ordering::Equal))
+}
+
+fn before<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                              -> Result<Fallible, Error> {
+    Ok(test(order(engine, 1, 2) == Ordering::Less))
+}
+
+fn after<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                             -> Result<Fallible, Error> {
+    Ok(test(order(engine, 1, 2) == Ordering::Greater))
+}
+
+fn not_after<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    Ok(test(order(engine, 1, 2) != Ordering::Greater))
+}
+
+fn not_before<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    Ok(test(order(engine, 1, 2) != Ordering::Less))
+}
+
+/// Sorts the list in the first argument, keeping duplicates.
+fn msort<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                             -> Result<Fallible, Error> {
+    let mut items = try!(list_argument(engine, 1));
+    try!(list(&engine.machine.argument(2)));
+    items.sort_by(|&a, &b| engine.machine.compare(a, b));
+    Ok(unify_list(engine, 2, &items))
+}
+
+/// Sorts the list in the first argument, removing duplicates.
+fn sort<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                            -> Result<Fallible, Error> {
+    let mut items = try!(list_argument(engine, 1));
+    try!(list(&engine.machine.argument(2)));
+    sort_unique(engine, &mut items);
+    Ok(unify_list(engine, 2, &items))
+}
+
+/// Sorts `items` in the standard order, removing duplicates.
+fn sort_unique<O: MachineObserver>(engine: &Engine<O>, items: &mut Vec<Cell>) {
+    items.sort_by(|&a, &b| engine.machine.compare(a, b));
+    items.dedup_by(|&mut a, &mut b| engine.machine.compare(a, b) == Ordering::Equal);
+}
+
+/// `sort(Key, Order, List, Sorted)`: sorts on argument `Key` of each
+/// item (or the whole item, if `Key` is 0). `Order` is one of `@<`
+/// and `@>`, which remove items with equal keys, or `@=<` and `@>=`,
+/// which keep them.
+fn sort_on<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let key = match engine.machine.argument(1) {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Integer(key) if key >= 0 => key as usize,
+        key @ Term::Integer(_) => return Err(Error::Domain("not_less_than_zero", key)),
+        key => return Err(Error::Type("integer", key)),
+    };
+    let order = engine.machine.argument(2);
+    let (descending, unique) = match &*try!(atom_name(&order)) {
+        "@<" => (false, true),
+        "@=<" => (false, false),
+        "@>" => (true, true),
+        "@>=" => (true, false),
+        _ => return Err(Error::Domain("order", order)),
+    };
+    let items = try!(list_argument(engine, 3));
+    try!(list(&engine.machine.argument(4)));
+
+    let mut keyed = vec![];
+    for item in items {
+        if key == 0 {
+            keyed.push((item, item));
+            continue;
+        }
+        match engine.machine.value(item) {
+            Cell::Structure(slot) => {
+                let (f, args) = engine.machine.structure(slot);
+                if key <= f.arity() {
+                    keyed.push((args[key - 1], item));
+                    continue;
+                }
+            }
+            Cell::Ref(_) => return Err(Error::Instantiation),
+            _ => { }
+        }
+        return Err(Error::Type("compound", engine.machine.cell_term(item)));
+    }
+
+    keyed.sort_by(|&(a, _), &(b, _)| {
+        let order = engine.machine.compare(a, b);
+        if descending { order.reverse() } else { order }
+    });
+    if unique {
+        keyed.dedup_by(|&mut (a, _), &mut (b, _)| engine.machine.compare(a, b) == Ordering::Equal);
+    }
+    let items: Vec<_> = keyed.into_iter().map(|(_, item)| item).collect();
+    Ok(unify_list(engine, 4, &items))
+}
+
+/// Sorts a list of `Key-Value` pairs on their keys, keeping pairs
+/// with equal keys in their original order.
+fn keysort<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let items = try!(list_argument(engine, 1));
+    try!(list(&engine.machine.argument(2)));
+    let pair = functors("-", 2);
+    let mut keyed = vec![];
+    for item in items {
+        match engine.machine.value(item) {
+            Cell::Structure(slot) => {
+                let (f, args) = engine.machine.structure(slot);
+                if f == pair {
+                    keyed.push((args[0], item));
+                    continue;
+                }
+            }
+            Cell::Ref(_) => return Err(Error::Instantiation),
+            _ => { }
+        }
+        return Err(Error::Type("pair", engine.machine.cell_term(item)));
+    }
+    keyed.sort_by(|&(a, _), &(b, _)| engine.machine.compare(a, b));
+    let items: Vec<_> = keyed.into_iter().map(|(_, item)| item).collect();
+    Ok(unify_list(engine, 2, &items))
+}
+
+/// `predsort(Pred, List, Sorted)`: sorts by calling `Pred(Order, A,
+/// B)`, removing items for which `Order` is `=`.
+fn predsort<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                -> Result<Fallible, Error> {
+    let mut vars = HashMap::new();
+    let pred = try!(callable(engine.machine.read_argument(1, &mut vars)));
+    let items = try!(list_argument(engine, 2));
+    try!(list(&engine.machine.argument(3)));
+
+    let (result, a, b) = (intern::intern("_Order"), intern::intern("_A"), intern::intern("_B"));
+    let mut terms = pred.terms.clone();
+    terms.extend(vec![Term::Variable(result), Term::Variable(a), Term::Variable(b)]);
+    let name = pred.functor.with_text(|text| text.to_string());
+    let goal = Term::Structure(Structure { functor: Functor::transient(&name, terms.len()),
+                                           terms: terms });
+
+    let sorted = try!(merge_sort(items, &mut |x, y| {
+        let mut vars = vars.clone();
+        vars.insert(a, x);
+        vars.insert(b, y);
+        let order = match try!(find_first(engine, &Term::Variable(result), &goal, &vars)) {
+            Some(order) => order,
+            None => return Ok(None),
+        };
+        match &*try!(atom_name(&order)) {
+            "<" => Ok(Some(Ordering::Less)),
+            "=" => Ok(Some(Ordering::Equal)),
+            ">" => Ok(Some(Ordering::Greater)),
+            _ => Err(Error::Domain("order", order.clone())),
+        }
+    }));
+    match sorted {
+        Some(items) => Ok(unify_list(engine, 3, &items)),
+        None => Ok(Err(())),
+    }
+}
+
+/// Sorts `items` with `compare`, which may fail (giving `None`) or
+/// raise an error. Of two items that compare equal, only the first is
+/// kept.
+fn merge_sort<F>(mut items: Vec<Cell>, compare: &mut F) -> Result<Option<Vec<Cell>>, Error>
+    where F: FnMut(Cell, Cell) -> Result<Option<Ordering>, Error>
+{
+    if items.len() <= 1 {
+        return Ok(Some(items));
+    }
+    let right = items.split_off(items.len() / 2);
+    let (left, right) = match (try!(merge_sort(items, compare)), try!(merge_sort(right, compare))) {
+        (Some(left), Some(right)) => (left, right),
+        _ => return Ok(None),
+    };
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    loop {
+        let (x, y) = match (left.peek(), right.peek()) {
+            (Some(&x), Some(&y)) => (x, y),
+            _ => break,
+        };
+        match try!(compare(x, y)) {
+            Some(Ordering::Less) => merged.push(left.next().unwrap()),
+            Some(Ordering::Greater) => merged.push(right.next().unwrap()),
+            Some(Ordering::Equal) => {
+                right.next();
+            }
+            None => return Ok(None),
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(Some(merged))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Taking terms apart and building them
+
+/// The atom called `name`, built on the heap.
+fn new_atom<O: MachineObserver>(engine: &mut Engine<O>, name: Functor) -> Cell {
+    let atom = name.with_text(|text| Functor::transient(text, 0));
+    engine.machine.new_structure(atom, &[])
+}
+
+/// The compound term in argument `i`, as the slot of its functor.
+fn compound_argument<O: MachineObserver>(engine: &Engine<O>, i: usize) -> Result<Slot, Error> {
+    match engine.machine.value(engine.machine.argument_cell(i)) {
+        Cell::Ref(_) => Err(Error::Instantiation),
+        Cell::Structure(slot) if engine.machine.structure(slot).0.arity() > 0 => Ok(slot),
+        _ => Err(Error::Type("compound", engine.machine.argument(i))),
+    }
+}
+
+/// The integer in argument `i`.
+fn integer_argument<O: MachineObserver>(engine: &Engine<O>, i: usize) -> Result<i64, Error> {
+    match engine.machine.argument(i) {
+        Term::Integer(n) => Ok(n),
+        Term::Variable(_) => Err(Error::Instantiation),
+        term => Err(Error::Type("integer", term)),
+    }
+}
+
+/// `functor(Term, Name, Arity)`: takes `Term` apart if it is bound,
+/// and otherwise builds it with fresh variables as arguments.
+fn functor<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let term = engine.machine.argument_cell(1);
+    let (name, arity) = match engine.machine.value(term) {
+        Cell::Structure(slot) => {
+            let f = engine.machine.structure(slot).0;
+            (new_atom(engine, f), f.arity())
+        }
+        Cell::Ref(_) => {
+            let arity = match try!(integer_argument(engine, 3)) {
+                arity if arity < 0 => {
+                    return Err(Error::Domain("not_less_than_zero", Term::Integer(arity)));
+                }
+                arity if arity as u64 > MAX_ARITY as u64 => {
+                    return Err(Error::Representation("max_arity"));
+                }
+                arity => arity as usize,
+            };
+            let name = engine.machine.argument_cell(2);
+            let term = match (engine.machine.value(name), arity) {
+                (Cell::Ref(_), _) => return Err(Error::Instantiation),
+                (Cell::Integer(_), 0) | (Cell::String(_), 0) => name,
+                (Cell::Structure(slot), _) if engine.machine.structure(slot).0.arity() == 0 => {
+                    let f = engine.machine.structure(slot).0;
+                    let f = f.with_text(|text| Functor::transient(text, arity));
+                    let args: Vec<_> = (0..arity).map(|_| engine.machine.new_variable()).collect();
+                    engine.machine.new_structure(f, &args)
+                }
+                (Cell::Integer(_), _) | (Cell::String(_), _) => {
+                    return Err(Error::Type("atom", engine.machine.argument(2)));
+                }
+                _ => return Err(Error::Type("atomic", engine.machine.argument(2))),
+            };
+            return Ok(engine.machine.unify_cells(term, engine.machine.argument_cell(1)));
+        }
+        integer => (integer, 0),
+    };
+    let argument = engine.machine.argument_cell(2);
+    if engine.machine.unify_cells(name, argument).is_err() {
+        return Ok(Err(()));
+    }
+    Ok(unify_argument(engine, 3, &Term::Integer(arity as i64), &mut HashMap::new()))
+}
+
+/// `arg(N, Term, Arg)`: `Arg` is argument `N` of `Term`. If `N` is
+/// unbound, each argument is tried in turn.
+fn arg<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                           -> Result<Fallible, Error> {
+    let slot = try!(compound_argument(engine, 2));
+    let args = engine.machine.structure(slot).1;
+    let n = match engine.machine.argument(1) {
+        Term::Integer(n) if n >= 1 && n as usize <= args.len() => n as usize,
+        Term::Integer(_) => return Ok(Err(())),
+        Term::Variable(_) => {
+            let n = match redo {
+                Some(Redo::Next(n)) => n,
+                Some(redo) => panic!("unexpected {:?}", redo),
+                None => 1,
+            };
+            if n < args.len() {
+                engine.retry(Redo::Next(n + 1));
+            }
+            let index = Term::Integer(n as i64);
+            if unify_argument(engine, 1, &index, &mut HashMap::new()).is_err() {
+                return Ok(Err(()));
+            }
+            n
+        }
+        term => return Err(Error::Type("integer", term)),
+    };
+    Ok(engine.machine.unify_cells(args[n - 1], engine.machine.argument_cell(3)))
+}
+
+/// `Term =.. [Name|Args]`.
+fn univ<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                            -> Result<Fallible, Error> {
+    let term = engine.machine.argument_cell(1);
+    match engine.machine.value(term) {
+        Cell::Structure(slot) => {
+            let (f, args) = engine.machine.structure(slot);
+            let mut items = vec![new_atom(engine, f)];
+            items.extend(args);
+            return Ok(unify_list(engine, 2, &items));
+        }
+        Cell::Ref(_) => { }
+        integer => return Ok(unify_list(engine, 2, &[integer])),
+    }
+
+    let items = try!(list_argument(engine, 2));
+    let name = match items.first() {
+        Some(&name) => name,
+        None => return Err(Error::Domain("non_empty_list", Term::nil())),
+    };
+    let term = match engine.machine.value(name) {
+        Cell::Ref(_) => return Err(Error::Instantiation),
+        Cell::Integer(_) | Cell::String(_) if items.len() == 1 => name,
+        Cell::Structure(slot) if engine.machine.structure(slot).0.arity() == 0 => {
+            let f = engine.machine.structure(slot).0;
+            let f = f.with_text(|text| Functor::transient(text, items.len() - 1));
+            engine.machine.new_structure(f, &items[1..])
+        }
+        Cell::Integer(_) | Cell::String(_) => {
+            return Err(Error::Type("atom", engine.machine.cell_term(name)));
+        }
+        _ => return Err(Error::Type("atomic", engine.machine.cell_term(name))),
+    };
+    Ok(engine.machine.unify_cells(term, engine.machine.argument_cell(1)))
+}
+
+/// `copy_term(Term, Copy)`: `Copy` is `Term` with fresh variables. As
+/// in SWI-Prolog, the copies of attributed variables get copies of
+/// their attributes, including any variables within them.
+fn copy_term<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    // reading the term out and putting it back creates fresh variables
+    let mut vars = HashMap::new();
+    let term = engine.machine.read_argument(1, &mut vars);
+    let mut names = vec![];
+    term.variables(&mut names);
+    let mut attributed = vec![];
+    let mut i = 0;
+    while i < names.len() {
+        if let Some(attrs) = engine.machine.attributes(vars[&names[i]]) {
+            let attrs = engine.machine.read_cell(attrs, &mut vars);
+            attrs.variables(&mut names);
+            attributed.push((names[i], attrs));
+        }
+        i += 1;
+    }
+
+    let mut copies = HashMap::new();
+    let copy = engine.machine.put_term(&term, &mut copies);
+    for (name, attrs) in attributed {
+        let attrs = engine.machine.put_term(&attrs, &mut copies);
+        let var = copies[&name];
+        engine.machine.set_attributes(var, Cell::Ref(attrs));
+    }
+    Ok(engine.machine.unify(copy, Register(2)))
+}
+
+/// The variables of the first argument, in depth-first order.
+fn term_variables<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                      -> Result<Fallible, Error> {
+    let mut vars = HashMap::new();
+    let term = engine.machine.read_argument(1, &mut vars);
+    try!(list(&engine.machine.argument(2)));
+    let mut names = vec![];
+    term.variables(&mut names);
+    let cells: Vec<_> = names.iter().map(|v| vars[v]).collect();
+    Ok(unify_list(engine, 2, &cells))
+}
+
+/// `setarg(N, Term, Value)`: replaces argument `N` of `Term` with
+/// `Value`, until backtracking undoes it.
+fn setarg<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                              -> Result<Fallible, Error> {
+    let (slot, n) = match try!(argument_position(engine)) {
+        Some(position) => position,
+        None => return Ok(Err(())),
+    };
+    let value = engine.machine.argument_cell(3);
+    engine.machine.set_arg(slot, n, value);
+    Ok(Ok(()))
+}
+
+/// Like `setarg/3`, but with a copy of `Value`, and not undone by
+/// backtracking.
+fn nb_setarg<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    let (slot, n) = match try!(argument_position(engine)) {
+        Some(position) => position,
+        None => return Ok(Err(())),
+    };
+    let value = engine.machine.argument(3);
+    engine.machine.nb_set_arg(slot, n, &value);
+    Ok(Ok(()))
+}
+
+/// The structure and argument number given to `setarg/3`, or `None`
+/// if there is no such argument.
+fn argument_position<O: MachineObserver>(engine: &Engine<O>)
+                                         -> Result<Option<(Slot, usize)>, Error> {
+    let n = try!(integer_argument(engine, 1));
+    let slot = try!(compound_argument(engine, 2));
+    let arity = engine.machine.structure(slot).0.arity();
+    if n >= 1 && n as usize <= arity {
+        Ok(Some((slot, n as usize)))
+    } else {
+        Ok(None)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Exceptions
+//
+// `catch/3` itself is part of the engine, since it runs its goal as a
+// call of its own, as `call/1` does.
+
+/// `throw(Ball)`: raises a copy of `Ball`.
+fn throw<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    match engine.machine.argument(1) {
+        Term::Variable(_) => Err(Error::Instantiation),
+        ball => Err(Error::Exception(ball)),
+    }
+}
+
+/// Called once the goal of a `catch/3` succeeds, with the flag from
+/// `Machine::push_catch`.
+fn exit_catch<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    match engine.machine.argument_cell(1) {
+        Cell::Ref(flag) => engine.machine.exit_catch(flag),
+        cell => panic!("unexpected {:?}", cell),
+    }
+    Ok(Ok(()))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Attributed variables
+//
+// The attributes of a variable are kept as the chain
+// `att(Module, Value, More)`, ending in `[]`, with at most one value
+// for each module. Once the variable is bound, the engine calls
+// `Module:attr_unify_hook(Value, Other)` for each of them, where
+// `Other` is what it was bound to.
+
+/// The module and value of each attribute in the chain `attrs`.
+pub fn attribute_list<O: MachineObserver>(engine: &Engine<O>, attrs: Cell) -> Vec<(Cell, Cell)> {
+    let mut list = vec![];
+    let mut attrs = attrs;
+    while let Cell::Structure(slot) = engine.machine.value(attrs) {
+        let (f, args) = engine.machine.structure(slot);
+        if f != functor!(att/3) {
+            break;
+        }
+        list.push((args[0], args[1]));
+        attrs = args[2];
+    }
+    list
+}
+
+/// The variable in argument 1 and the module, which must be an atom,
+/// in argument 2.
+fn attribute_arguments<O: MachineObserver>(engine: &Engine<O>) -> Result<(Cell, Cell), Error> {
+    let module = engine.machine.argument_cell(2);
+    match engine.machine.value(module) {
+        Cell::Ref(_) => return Err(Error::Instantiation),
+        Cell::Structure(slot) if engine.machine.structure(slot).0.arity() == 0 => { }
+        _ => return Err(Error::Type("atom", engine.machine.argument(2))),
+    }
+    Ok((engine.machine.argument_cell(1), module))
+}
+
+/// Sets the attribute of the unbound variable `var` for `module` to
+/// `value`, or removes it if `value` is `None`.
+fn update_attribute<O: MachineObserver>(engine: &mut Engine<O>, var: Cell, module: Cell,
+                                        value: Option<Cell>) {
+    let attrs = match engine.machine.attributes(var) {
+        Some(attrs) => attribute_list(engine, attrs),
+        None => vec![],
+    };
+    let mut found = false;
+    let mut list = vec![];
+    for (m, v) in attrs {
+        if engine.machine.compare(m, module) != Ordering::Equal {
+            list.push((m, v));
+        } else if let Some(value) = value {
+            list.push((m, value));
+            found = true;
+        } else {
+            found = true;
+        }
+    }
+    match value {
+        Some(value) if !found => list.push((module, value)),
+        None if !found => return,
+        _ => { }
+    }
+    let mut chain = new_atom(engine, functors("[]", 0));
+    for (m, v) in list.into_iter().rev() {
+        chain = engine.machine.new_structure(functor!(att/3), &[m, v, chain]);
+    }
+    engine.machine.set_attributes(var, chain);
+}
+
+/// `put_attr(Var, Module, Value)`: sets the attribute of `Var` for
+/// `Module` to `Value`, until backtracking undoes it.
+fn put_attr<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                -> Result<Fallible, Error> {
+    let (var, module) = try!(attribute_arguments(engine));
+    match engine.machine.value(var) {
+        Cell::Ref(_) => { }
+        _ => return Err(Error::Uninstantiation(engine.machine.argument(1))),
+    }
+    let value = engine.machine.argument_cell(3);
+    update_attribute(engine, var, module, Some(value));
+    Ok(Ok(()))
+}
+
+/// `get_attr(Var, Module, Value)`: unifies `Value` with the attribute
+/// of `Var` for `Module`, failing if it has none.
+fn get_attr<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                -> Result<Fallible, Error> {
+    let (var, module) = try!(attribute_arguments(engine));
+    let attrs = match engine.machine.attributes(var) {
+        Some(attrs) => attribute_list(engine, attrs),
+        None => return Ok(Err(())),
+    };
+    match attrs.into_iter().find(|&(m, _)| engine.machine.compare(m, module) == Ordering::Equal) {
+        Some((_, value)) => {
+            let arg = engine.machine.argument_cell(3);
+            Ok(engine.machine.unify_cells(value, arg))
+        }
+        None => Ok(Err(())),
+    }
+}
+
+/// `del_attr(Var, Module)`: removes the attribute of `Var` for
+/// `Module`, if it has one.
+fn del_attr<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                -> Result<Fallible, Error> {
+    let (var, module) = try!(attribute_arguments(engine));
+    if let Cell::Ref(_) = engine.machine.value(var) {
+        update_attribute(engine, var, module, None);
+    }
+    Ok(Ok(()))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Type checks
+//
+// These look only at the tag of the (dereferenced) first argument,
+// and never bind anything. There are no floats, so `number/1` is the
+// same as `integer/1` and `float/1` always fails.
+
+/// The kinds of term a type check can tell apart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Variable,
+    Integer,
+    Atom,
+    String,
+    Compound,
+}
+
+fn kind<O: MachineObserver>(engine: &Engine<O>, cell: Cell) -> Kind {
+    match engine.machine.value(cell) {
+        Cell::Ref(_) => Kind::Variable,
+        Cell::Integer(_) => Kind::Integer,
+        Cell::String(_) => Kind::String,
+        Cell::Structure(slot) if engine.machine.structure(slot).0.arity() == 0 => Kind::Atom,
+        Cell::Structure(_) => Kind::Compound,
+        cell => panic!("unexpected {:?} in argument", cell),
+    }
+}
+
+fn argument_kind<O: MachineObserver>(engine: &Engine<O>) -> Kind {
+    kind(engine, engine.machine.argument_cell(1))
+}
+
+fn var<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    Ok(test(argument_kind(engine) == Kind::Variable))
+}
+
+fn nonvar<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                              -> Result<Fallible, Error> {
+    Ok(test(argument_kind(engine) != Kind::Variable))
+}
+
+fn atom<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    Ok(test(argument_kind(engine) == Kind::Atom))
+}
+
+fn integer<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    Ok(test(argument_kind(engine) == Kind::Integer))
+}
+
+fn float<O: MachineObserver>(_: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    Ok(Err(()))
+}
+
+fn atomic<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                              -> Result<Fallible, Error> {
+    let kind = argument_kind(engine);
+    Ok(test(kind == Kind::Atom || kind == Kind::Integer || kind == Kind::String))
+}
+
+fn is_string<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    Ok(test(argument_kind(engine) == Kind::String))
+}
+
+fn compound<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                -> Result<Fallible, Error> {
+    Ok(test(argument_kind(engine) == Kind::Compound))
+}
+
+fn is_callable<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                   -> Result<Fallible, Error> {
+    let kind = argument_kind(engine);
+    Ok(test(kind == Kind::Atom || kind == Kind::Compound))
+}
+
+/// Succeeds if argument 1 is a proper list: one ending in `[]`.
+fn is_list<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let dot = functors(".", 2);
+    let nil = functors("[]", 0);
+    let mut cell = engine.machine.argument_cell(1);
+    loop {
+        match engine.machine.value(cell) {
+            Cell::Structure(slot) => {
+                let (f, args) = engine.machine.structure(slot);
+                if f == dot {
+                    cell = args[1];
+                } else {
+                    return Ok(test(f == nil));
+                }
+            }
+            _ => return Ok(Err(())),
+        }
+    }
+}
+
+/// Succeeds if argument 1 contains no unbound variables.
+fn ground<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                              -> Result<Fallible, Error> {
+    let mut pending = vec![engine.machine.argument_cell(1)];
+    while let Some(cell) = pending.pop() {
+        match engine.machine.value(cell) {
+            Cell::Ref(_) => return Ok(Err(())),
+            Cell::Structure(slot) => pending.extend(engine.machine.structure(slot).1),
+            _ => { }
+        }
+    }
+    Ok(Ok(()))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Atoms and text
+//
+// Where these take an atom apart, a number or a string will do as
+// well, a number standing for the atom of its digits.
+
+fn is_var(term: &Term) -> bool {
+    match *term {
+        Term::Variable(_) => true,
+        _ => false,
+    }
+}
+
+/// Takes a term apart as text: `text` or `any_text`.
+type TextOf = fn(&Term) -> Result<String, Error>;
+
+/// The text of an atom, number or string.
+fn text(term: &Term) -> Result<String, Error> {
+    match *term {
+        Term::Integer(i) => Ok(i.to_string()),
+        Term::String(ref s) => Ok(s.clone()),
+        _ => atom_name(term).map_err(|error| match error {
+            Error::Type(_, culprit) => Error::Type("atomic", culprit),
+            error => error,
+        }),
+    }
+}
+
+/// The text of an atom, number or string, or of a list of character
+/// codes or one-character atoms, any of which the string builtins
+/// take as text. `[]` is the empty list, as `""` reads under
+/// `double_quotes=codes`, rather than the atom.
+fn any_text(term: &Term) -> Result<String, Error> {
+    match *term {
+        Term::Structure(ref s) if s.functor == functors("[]", 0) => Ok(String::new()),
+        Term::Structure(ref s) if s.functor == functors(".", 2) => {
+            let chars = match s.terms[0] {
+                Term::Integer(_) => false,
+                _ => true,
+            };
+            list_text(term, chars)
+        }
+        _ => text(term),
+    }
+}
+
+/// The text made of a list of character codes, or, if `chars`, of
+/// one-character atoms.
+fn list_text(term: &Term, chars: bool) -> Result<String, Error> {
+    let items = match term.to_list() {
+        Some(items) => items,
+        None => {
+            try!(list(term));
+            return Err(Error::Instantiation);
+        }
+    };
+    let mut text = String::new();
+    for item in &items {
+        let c = match *item {
+            Term::Variable(_) => return Err(Error::Instantiation),
+            Term::Integer(code) if !chars => {
+                match ::std::char::from_u32(code as u32) {
+                    Some(c) if code >= 0 => c,
+                    _ => return Err(Error::Representation("character_code")),
+                }
+            }
+            _ if chars => try!(character(item)),
+            _ => return Err(Error::Type("integer", item.clone())),
+        };
+        text.push(c);
+    }
+    Ok(text)
+}
+
+/// The character of a one-character atom.
+fn character(term: &Term) -> Result<char, Error> {
+    let name = try!(atom_name(term).map_err(|_| Error::Type("character", term.clone())));
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(Error::Type("character", term.clone())),
+    }
+}
+
+fn codes(text: &str) -> Term {
+    Term::list(text.chars().map(|c| Term::Integer(c as i64)).collect(), Term::nil())
+}
+
+fn chars(text: &str) -> Term {
+    Term::list(text.chars().map(|c| Term::atom(&c.to_string())).collect(), Term::nil())
+}
+
+/// The integer that `text` reads as, if any.
+fn parse_integer<O: MachineObserver>(engine: &Engine<O>, text: &str) -> Option<i64> {
+    match engine.read_term(text) {
+        Ok(Term::Integer(i)) => Some(i),
+        _ => None,
+    }
+}
+
+/// An integer argument that may be unbound.
+fn optional_integer(term: Term) -> Result<Option<i64>, Error> {
+    match term {
+        Term::Variable(_) => Ok(None),
+        Term::Integer(i) => Ok(Some(i)),
+        term => Err(Error::Type("integer", term)),
+    }
+}
+
+/// `atom_codes/2` or, if `chars`, `atom_chars/2`; `text` takes the
+/// atom (or string) apart, and `make` makes it from its text.
+fn text_list<O: MachineObserver>(engine: &mut Engine<O>, chars: bool, text: TextOf,
+                                 make: fn(&str) -> Term) -> Result<Fallible, Error> {
+    let atom = engine.machine.argument(1);
+    if !is_var(&atom) {
+        let text = try!(text(&atom));
+        let list = if chars { self::chars(&text) } else { codes(&text) };
+        return Ok(unify_argument(engine, 2, &list, &mut HashMap::new()));
+    }
+    let text = try!(list_text(&engine.machine.argument(2), chars));
+    Ok(unify_argument(engine, 1, &make(&text), &mut HashMap::new()))
+}
+
+fn atom_codes<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    text_list(engine, false, text, Term::atom)
+}
+
+fn atom_chars<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    text_list(engine, true, text, Term::atom)
+}
+
+fn char_code<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    let atom = engine.machine.argument(1);
+    if !is_var(&atom) {
+        let c = try!(character(&atom));
+        return Ok(unify_argument(engine, 2, &Term::Integer(c as i64), &mut HashMap::new()));
+    }
+    let text = match try!(optional_integer(engine.machine.argument(2))) {
+        Some(code) => try!(list_text(&codes_of(&[code]), false)),
+        None => return Err(Error::Instantiation),
+    };
+    Ok(unify_argument(engine, 1, &Term::atom(&text), &mut HashMap::new()))
+}
+
+fn codes_of(codes: &[i64]) -> Term {
+    Term::list(codes.iter().map(|&code| Term::Integer(code)).collect(), Term::nil())
+}
+
+fn atom_length<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                   -> Result<Fallible, Error> {
+    text_length(engine, text)
+}
+
+/// `atom_length/2`, taking the atom apart with `text`.
+fn text_length<O: MachineObserver>(engine: &mut Engine<O>, text: TextOf)
+                                   -> Result<Fallible, Error> {
+    let text = try!(text(&engine.machine.argument(1)));
+    if let Some(length) = try!(optional_integer(engine.machine.argument(2))) {
+        if length < 0 {
+            return Err(Error::Domain("not_less_than_zero", Term::Integer(length)));
+        }
+    }
+    let length = Term::Integer(text.chars().count() as i64);
+    Ok(unify_argument(engine, 2, &length, &mut HashMap::new()))
+}
+
+/// `atom_concat(A, B, AB)`: joins `A` and `B` if both are bound, and
+/// otherwise splits `AB` in each possible way.
+fn atom_concat<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                   -> Result<Fallible, Error> {
+    concat(engine, redo, text, Term::atom)
+}
+
+/// `atom_concat/3`, taking text apart with `text` and making the
+/// results with `make`.
+fn concat<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>, text: TextOf,
+                              make: fn(&str) -> Term) -> Result<Fallible, Error> {
+    each_solution(engine, redo, |engine| {
+        let (a, b) = (engine.machine.argument(1), engine.machine.argument(2));
+        if !is_var(&a) && !is_var(&b) {
+            let whole = try!(text(&a)) + &try!(text(&b));
+            return Ok(vec![vec![a, b, make(&whole)]]);
+        }
+        let whole = engine.machine.argument(3);
+        let chars: Vec<char> = try!(text(&whole)).chars().collect();
+        Ok((0..chars.len() + 1).map(|i| {
+            let before: String = chars[..i].iter().collect();
+            let after: String = chars[i..].iter().collect();
+            vec![make(&before), make(&after), whole.clone()]
+        }).collect())
+    })
+}
+
+/// `sub_atom(Atom, Before, Length, After, Sub)`: `Sub` is the part of
+/// `Atom` that starts after `Before` characters, is `Length` long,
+/// and leaves `After` characters. Each such part is found in turn.
+fn sub_atom<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                -> Result<Fallible, Error> {
+    sub_text(engine, redo, text, Term::atom)
+}
+
+/// `sub_atom/5`, taking text apart with `text` and making the parts
+/// with `make`.
+fn sub_text<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>, text: TextOf,
+                                make: fn(&str) -> Term) -> Result<Fallible, Error> {
+    each_solution(engine, redo, |engine| {
+        let atom = engine.machine.argument(1);
+        let chars: Vec<char> = try!(text(&atom)).chars().collect();
+        let before = try!(optional_integer(engine.machine.argument(2)));
+        let length = try!(optional_integer(engine.machine.argument(3)));
+        let after = try!(optional_integer(engine.machine.argument(4)));
+        let sub = match engine.machine.argument(5) {
+            Term::Variable(_) => None,
+            sub => Some(try!(text(&sub)).chars().collect::<Vec<char>>()),
+        };
+        let n = chars.len();
+        let mut solutions = vec![];
+        for b in 0..n + 1 {
+            for l in 0..n - b + 1 {
+                let a = n - b - l;
+                let fits = |bound: Option<i64>, value: usize| {
+                    bound.map_or(true, |bound| bound == value as i64)
+                };
+                if !fits(before, b) || !fits(length, l) || !fits(after, a) {
+                    continue;
+                }
+                let part = &chars[b..b + l];
+                if sub.as_ref().map_or(false, |sub| &sub[..] != part) {
+                    continue;
+                }
+                let part: String = part.iter().collect();
+                solutions.push(vec![atom.clone(),
+                                    Term::Integer(b as i64),
+                                    Term::Integer(l as i64),
+                                    Term::Integer(a as i64),
+                                    make(&part)]);
+            }
+        }
+        Ok(solutions)
+    })
+}
+
+/// `atom_number(Atom, Number)`, which fails if `Atom` is not the text
+/// of a number.
+fn atom_number<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                   -> Result<Fallible, Error> {
+    let atom = engine.machine.argument(1);
+    if is_var(&atom) {
+        return match engine.machine.argument(2) {
+            Term::Variable(_) => Err(Error::Instantiation),
+            Term::Integer(i) => {
+                Ok(unify_argument(engine, 1, &Term::atom(&i.to_string()), &mut HashMap::new()))
+            }
+            number => Err(Error::Type("number", number)),
+        };
+    }
+    match parse_integer(engine, &try!(atom_name(&atom))) {
+        Some(i) => Ok(unify_argument(engine, 2, &Term::Integer(i), &mut HashMap::new())),
+        None => Ok(Err(())),
+    }
+}
+
+/// `number_codes(Number, Codes)`. If `Codes` is a complete list, it is
+/// read as a number, which must be valid.
+fn number_codes<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                    -> Result<Fallible, Error> {
+    match list_text(&engine.machine.argument(2), false) {
+        Ok(text) => match parse_integer(engine, &text) {
+            Some(i) => Ok(unify_argument(engine, 1, &Term::Integer(i), &mut HashMap::new())),
+            None => Err(Error::Syntax("illegal_number".to_string())),
+        },
+        Err(error) => match engine.machine.argument(1) {
+            Term::Variable(_) => Err(error),
+            Term::Integer(i) => {
+                Ok(unify_argument(engine, 2, &codes(&i.to_string()), &mut HashMap::new()))
+            }
+            number => Err(Error::Type("integer", number)),
+        },
+    }
+}
+
+fn upcase_atom<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                   -> Result<Fallible, Error> {
+    let text = try!(text(&engine.machine.argument(1)));
+    Ok(unify_argument(engine, 2, &Term::atom(&text.to_uppercase()), &mut HashMap::new()))
+}
+
+/// `term_to_atom(Term, Atom)`: reads `Atom` as a term if it is bound,
+/// and otherwise writes `Term` as `writeq/1` would.
+fn term_to_atom<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                    -> Result<Fallible, Error> {
+    let atom = engine.machine.argument(2);
+    if is_var(&atom) {
+        let text = Writer::new(&engine.operators).to_string(&engine.machine.argument(1));
+        return Ok(unify_argument(engine, 2, &Term::atom(&text), &mut HashMap::new()));
+    }
+    let term = match engine.read_term(&try!(text(&atom))) {
+        Ok(term) => term,
+        Err(error) => return Err(Error::Syntax(error.message)),
+    };
+    Ok(unify_argument(engine, 1, &term, &mut HashMap::new()))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Strings
+//
+// These are the text builtins above, but making strings rather than
+// atoms. They take any text, including lists of codes or characters.
+
+fn string_chars<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                    -> Result<Fallible, Error> {
+    text_list(engine, true, any_text, Term::string)
+}
+
+fn string_codes<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                    -> Result<Fallible, Error> {
+    text_list(engine, false, any_text, Term::string)
+}
+
+fn string_to_atom<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                      -> Result<Fallible, Error> {
+    let string = engine.machine.argument(1);
+    if !is_var(&string) {
+        let atom = Term::atom(&try!(any_text(&string)));
+        return Ok(unify_argument(engine, 2, &atom, &mut HashMap::new()));
+    }
+    let string = Term::string(&try!(any_text(&engine.machine.argument(2))));
+    Ok(unify_argument(engine, 1, &string, &mut HashMap::new()))
+}
+
+fn string_concat<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                     -> Result<Fallible, Error> {
+    concat(engine, redo, any_text, Term::string)
+}
+
+fn sub_string<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    sub_text(engine, redo, any_text, Term::string)
+}
+
+fn string_length<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                     -> Result<Fallible, Error> {
+    text_length(engine, any_text)
+}
+
+/// `string_code(Index, String, Code)`: `Code` is the character at
+/// `Index`, counting from 1. Fails if there is none.
+fn string_code<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                   -> Result<Fallible, Error> {
+    let index = try!(integer_argument(engine, 1));
+    let text = try!(any_text(&engine.machine.argument(2)));
+    if index < 0 {
+        return Err(Error::Domain("not_less_than_zero", Term::Integer(index)));
+    }
+    match text.chars().nth((index as usize).wrapping_sub(1)) {
+        Some(c) if index > 0 => {
+            Ok(unify_argument(engine, 3, &Term::Integer(c as i64), &mut HashMap::new()))
+        }
+        _ => Ok(Err(())),
+    }
+}
+
+/// `split_string(String, Separators, Pad, Parts)`: splits `String` at
+/// each of the characters in `Separators`, and removes the characters
+/// in `Pad` from both ends of each part.
+fn split_string<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                    -> Result<Fallible, Error> {
+    let string = try!(any_text(&engine.machine.argument(1)));
+    let separators = try!(any_text(&engine.machine.argument(2)));
+    let pad = try!(any_text(&engine.machine.argument(3)));
+    let parts = string.split(|c| separators.contains(c))
+                      .map(|part| Term::string(part.trim_matches(|c| pad.contains(c))))
+                      .collect();
+    Ok(unify_argument(engine, 4, &Term::list(parts, Term::nil()), &mut HashMap::new()))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Flags
+
+/// The value of each flag.
+fn flags<O: MachineObserver>(engine: &Engine<O>) -> Vec<(&'static str, &'static str)> {
+    let unknown = match engine.flags.unknown {
+        Unknown::Error => "error",
+        Unknown::Fail => "fail",
+    };
+    let double_quotes = match engine.flags.double_quotes {
+        DoubleQuotes::Codes => "codes",
+        DoubleQuotes::Chars => "chars",
+        DoubleQuotes::Atom => "atom",
+        DoubleQuotes::String => "string",
+    };
+    vec![("double_quotes", double_quotes), ("unknown", unknown)]
+}
+
+fn set_prolog_flag<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                       -> Result<Fallible, Error> {
+    let (flag, value) = (engine.machine.argument(1), engine.machine.argument(2));
+    let name = try!(atom_name(&flag));
+    if is_var(&value) {
+        return Err(Error::Instantiation);
+    }
+    match (&name[..], &try!(atom_name(&value))[..]) {
+        ("unknown", "error") => engine.flags.unknown = Unknown::Error,
+        ("unknown", "fail") => engine.flags.unknown = Unknown::Fail,
+        ("double_quotes", "codes") => engine.flags.double_quotes = DoubleQuotes::Codes,
+        ("double_quotes", "chars") => engine.flags.double_quotes = DoubleQuotes::Chars,
+        ("double_quotes", "atom") => engine.flags.double_quotes = DoubleQuotes::Atom,
+        ("double_quotes", "string") => engine.flags.double_quotes = DoubleQuotes::String,
+        _ if flags(engine).iter().any(|&(f, _)| f == name) => {
+            let culprit = Structure { functor: functors("+", 2), terms: vec![flag, value] };
+            return Err(Error::Domain("flag_value", Term::Structure(culprit)));
+        }
+        _ => return Err(Error::Domain("prolog_flag", flag)),
+    }
+    Ok(Ok(()))
+}
+
+fn current_prolog_flag<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                           -> Result<Fallible, Error> {
+    each_solution(engine, redo, |engine| {
+        Ok(flags(engine).into_iter()
+                        .map(|(flag, value)| vec![Term::atom(flag), Term::atom(value)])
+                        .collect())
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Loading source files
+
+fn consult<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                               -> Result<Fallible, Error> {
+    let (context, file) = try!(module_argument(engine, 1, &mut HashMap::new()));
+    try!(consult::consult(engine, &file, context));
+    Ok(Ok(()))
+}
+
+fn ensure_loaded<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                     -> Result<Fallible, Error> {
+    let (context, file) = try!(module_argument(engine, 1, &mut HashMap::new()));
+    try!(consult::use_module(engine, &file, context, None));
+    Ok(Ok(()))
+}
+
+/// `[File|Files]`: consults each file in turn.
+fn consult_list<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                    -> Result<Fallible, Error> {
+    let files = Term::list(vec![engine.machine.argument(1)], engine.machine.argument(2));
+    try!(list(&files));
+    match files.to_list() {
+        Some(files) => {
+            for file in &files {
+                try!(consult::consult(engine, file, module::user()));
+            }
+            Ok(Ok(()))
+        }
+        None => Err(Error::Instantiation),
+    }
+}
+
+/// `dcg_translate_rule(Rule, Clause)`: `Clause` is the clause that the
+/// grammar rule `Rule` is loaded as.
+fn dcg_translate_rule<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                          -> Result<Fallible, Error> {
+    let mut vars = HashMap::new();
+    let clause = match engine.machine.read_argument(1, &mut vars) {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Structure(ref s) if s.functor == functors("-->", 2) => {
+            try!(dcg::translate(&s.terms[0], &s.terms[1]))
+        }
+        rule => return Err(Error::Type("dcg_rule", rule)),
+    };
+    Ok(unify_argument(engine, 2, &clause, &mut vars))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Modules
+
+/// `use_module(File)` or `use_module(File, Imports)`: loads `File` if
+/// it has not been loaded, and imports the predicates it exports (or
+/// only those listed in `Imports`).
+fn use_module<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    let (context, file) = try!(module_argument(engine, 1, &mut HashMap::new()));
+    let imports = if called_arity(engine) > 1 {
+        let imports = engine.machine.argument(2);
+        let imports = match imports.to_list() {
+            Some(imports) => imports,
+            None => {
+                try!(list(&imports));
+                return Err(Error::Instantiation);
+            }
+        };
+        Some(try!(imports.into_iter().map(indicator).collect()))
+    } else {
+        None
+    };
+    try!(consult::use_module(engine, &file, context, imports));
+    Ok(Ok(()))
+}
+
+/// Declares how each predicate in a conjunction of heads such as
+/// `maplist(2, ?)` treats its arguments.
+fn meta_predicate<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                      -> Result<Fallible, Error> {
+    let (module, specs) = try!(module_argument(engine, 1, &mut HashMap::new()));
+    for spec in specs.conjuncts() {
+        let head = try!(callable(spec));
+        try!(modifiable(engine, head.functor));
+        let meta = try!(module::meta_args(&head.terms));
+        engine.database.declare_meta(module::qualify(module, head.functor), meta);
+    }
+    Ok(Ok(()))
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Operators
+
+/// `op(Priority, Type, Names)`: defines each of `Names` (an atom or a
+/// list of atoms) as an operator of `Type`, or removes it if
+/// `Priority` is 0.
+fn op<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    let priority = match engine.machine.argument(1) {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Integer(p) if p >= 0 && p <= 1200 => p as usize,
+        p @ Term::Integer(_) => return Err(Error::Domain("operator_priority", p)),
+        p => return Err(Error::Type("integer", p)),
+    };
+    let spec = engine.machine.argument(2);
+    let op_type = match OpType::from_name(&try!(atom_name(&spec))) {
+        Some(op_type) => op_type,
+        None => return Err(Error::Domain("operator_specifier", spec)),
+    };
+    let names = engine.machine.argument(3);
+    let names = match (names.to_list(), &names) {
+        (Some(names), _) => names,
+        (None, &Term::Structure(ref s)) if s.terms.is_empty() => vec![names.clone()],
+        (None, _) => {
+            try!(list(&names));
+            return Err(Error::Instantiation);
+        }
+    };
+    let names: Vec<String> = try!(names.iter().map(atom_name).collect());
+    for name in names {
+        match engine.operators.add(priority, op_type, &name) {
+            Ok(()) => { }
+            Err(OpError::Priority) => unreachable!(),
+            Err(OpError::Modify) => {
+                return Err(Error::Permission("modify", "operator", Term::atom(&name)));
+            }
+            Err(OpError::Create) => {
+                return Err(Error::Permission("create", "operator", Term::atom(&name)));
+            }
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// `current_op(Priority, Type, Name)`: enumerates the operators.
+fn current_op<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    each_solution(engine, redo, |engine| {
+        Ok(engine.operators.iter().map(|(name, op)| {
+            vec![Term::Integer(op.priority as i64),
+                 Term::atom(&op.op_type.to_string()),
+                 Term::atom(name)]
+        }).collect())
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Streams
+
+/// The arity of the builtin being called. Most stream builtins are
+/// registered twice: with an explicit stream as their first argument,
+/// and without, for the current input or output.
+fn called_arity<O: MachineObserver>(engine: &Engine<O>) -> usize {
+    engine.calling.as_ref().expect("arity outside of a builtin").0.arity()
+}
+
+/// The stream that `term` refers to, by stream term or alias.
+fn stream<O: MachineObserver>(engine: &Engine<O>, term: &Term) -> Result<usize, Error> {
+    let id = match *term {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Structure(ref s) if s.functor == functors("$stream", 1) => {
+            match s.terms[0] {
+                Term::Integer(id) if id >= 0 => Some(id as usize),
+                _ => None,
+            }
+        }
+        Term::Structure(ref s) if s.terms.is_empty() => {
+            s.functor.with_text(|name| engine.streams.lookup(name))
+        }
+        _ => return Err(Error::Domain("stream_or_alias", term.clone())),
+    };
+    match id {
+        Some(id) if engine.streams.get(id).is_some() => Ok(id),
+        _ => Err(Error::Existence("stream", term.clone())),
+    }
+}
+
+/// The input stream in argument `i`.
+fn input_argument<O: MachineObserver>(engine: &Engine<O>, i: usize) -> Result<usize, Error> {
+    let term = engine.machine.argument(i);
+    let id = try!(stream(engine, &term));
+    if !engine.streams.get(id).unwrap().is_input() {
+        return Err(Error::Permission("input", "stream", term));
+    }
+    Ok(id)
+}
+
+/// The output stream in argument `i`.
+fn output_argument<O: MachineObserver>(engine: &Engine<O>, i: usize) -> Result<usize, Error> {
+    let term = engine.machine.argument(i);
+    let id = try!(stream(engine, &term));
+    if engine.streams.get(id).unwrap().is_input() {
+        return Err(Error::Permission("output", "stream", term));
+    }
+    Ok(id)
+}
+
+/// The error for a failed read or write on stream `id`.
+fn stream_error(action: &'static str, id: usize) -> Error {
+    Error::Permission(action, "stream", stream_term(id))
+}
+
+/// Sends `text` to output stream `id`.
+fn output<O: MachineObserver>(engine: &mut Engine<O>, id: usize, text: &str)
+                              -> Result<(), Error> {
+    if id == engine.streams.output() {
+        if let Some(ref mut captured) = engine.captured {
+            captured.push_str(text);
+            return Ok(());
+        }
+    }
+    let writer = engine.streams.get_mut(id).and_then(|stream| stream.writer()).unwrap();
+    writer.write_all(text.as_bytes()).map_err(|_| stream_error("output", id))
+}
+
+/// `open(File, Mode, Stream, Options)`, where `Mode` is `read`, `write`
+/// or `append`, and the only option that matters is `alias(A)`.
+fn open<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    let source = engine.machine.argument(1);
+    let path = match source {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Structure(ref s) if s.terms.is_empty() => s.functor.with_text(|t| t.to_string()),
+        _ => return Err(Error::Domain("source_sink", source)),
+    };
+    let mode_term = engine.machine.argument(2);
+    let mode = match &try!(atom_name(&mode_term))[..] {
+        "read" => Mode::Read,
+        "write" => Mode::Write,
+        "append" => Mode::Append,
+        _ => return Err(Error::Domain("io_mode", mode_term)),
+    };
+    let mut alias = None;
+    let options = if called_arity(engine) > 3 {
+        try!(options_argument(engine, 4))
+    } else {
+        vec![]
+    };
+    for option in options {
+        match option {
+            Term::Variable(_) => return Err(Error::Instantiation),
+            Term::Structure(ref s) if s.functor == functor!(alias/1) => {
+                let name = try!(atom_name(&s.terms[0]));
+                if engine.streams.lookup(&name).is_some() {
+                    return Err(Error::Permission("open", "source_sink", option.clone()));
+                }
+                alias = Some(name);
+            }
+            Term::Structure(ref s) if s.functor == functor!(type/1) ||
+                                      s.functor == functor!(eof_action/1) ||
+                                      s.functor == functor!(reposition/1) => { }
+            _ => return Err(Error::Domain("stream_option", option.clone())),
+        }
+    }
+
+    let opened = match mode {
+        Mode::Read => File::open(&path).map(|file| Stream::input(Box::new(file))),
+        Mode::Write => File::create(&path).map(|file| Stream::output(Box::new(file), mode)),
+        Mode::Append => {
+            OpenOptions::new().append(true)
+                              .create(true)
+                              .open(&path)
+                              .map(|file| Stream::output(Box::new(file), mode))
+        }
+    };
+    let mut stream = match opened {
+        Ok(stream) => stream.with_file_name(&path),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Existence("source_sink", source));
+        }
+        Err(_) => return Err(Error::Permission("open", "source_sink", source)),
+    };
+    if let Some(alias) = alias {
+        stream = stream.with_alias(&alias);
+    }
+    let id = engine.streams.add(stream);
+    Ok(unify_argument(engine, 3, &stream_term(id), &mut HashMap::new()))
+}
+
+/// The items of the options list in argument `i`.
+fn options_argument<O: MachineObserver>(engine: &Engine<O>, i: usize)
+                                        -> Result<Vec<Term>, Error> {
+    let options = engine.machine.argument(i);
+    match options.to_list() {
+        Some(items) => Ok(items),
+        None => {
+            try!(list(&options));
+            Err(Error::Instantiation)
+        }
+    }
+}
+
+/// `close(Stream)` or `close(Stream, Options)`; the only option is
+/// `force(Bool)`, which ignores any error flushing the stream.
+fn close<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                             -> Result<Fallible, Error> {
+    let term = engine.machine.argument(1);
+    let id = try!(stream(engine, &term));
+    let mut force = false;
+    if called_arity(engine) > 1 {
+        for option in try!(options_argument(engine, 2)) {
+            match option {
+                Term::Variable(_) => return Err(Error::Instantiation),
+                Term::Structure(ref s) if s.functor == functor!(force/1) => {
+                    force = try!(atom_name(&s.terms[0])) == "true";
+                }
+                _ => return Err(Error::Domain("close_option", option.clone())),
+            }
+        }
+    }
+    match engine.streams.close(id) {
+        Err(_) if !force => Err(stream_error("output", id)),
+        _ => Ok(Ok(())),
+    }
+}
+
+fn current_input<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                     -> Result<Fallible, Error> {
+    let input = stream_term(engine.streams.input());
+    Ok(unify_arguments(engine, &[input]))
+}
+
+fn current_output<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                      -> Result<Fallible, Error> {
+    let output = stream_term(engine.streams.output());
+    Ok(unify_arguments(engine, &[output]))
+}
+
+fn set_input<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    let id = try!(input_argument(engine, 1));
+    engine.streams.set_input(id);
+    Ok(Ok(()))
+}
+
+fn set_output<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    let id = try!(output_argument(engine, 1));
+    engine.streams.set_output(id);
+    Ok(Ok(()))
+}
+
+/// The stream in the first of `arity` arguments, or the current input
+/// if there is one argument fewer.
+fn input_of<O: MachineObserver>(engine: &Engine<O>, arity: usize) -> Result<usize, Error> {
+    if called_arity(engine) == arity {
+        input_argument(engine, 1)
+    } else {
+        Ok(engine.streams.input())
+    }
+}
+
+/// The stream in the first of `arity` arguments, or the current output
+/// if there is one argument fewer.
+fn output_of<O: MachineObserver>(engine: &Engine<O>, arity: usize) -> Result<usize, Error> {
+    if called_arity(engine) == arity {
+        output_argument(engine, 1)
+    } else {
+        Ok(engine.streams.output())
+    }
+}
+
+/// `get_char(Stream, Char)`, or `get_char(Char)` on the current input.
+/// At the end of the stream, `Char` is `end_of_file`.
+fn get_char<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                -> Result<Fallible, Error> {
+    read_char(engine, false)
+}
+
+/// Like `get_char/2`, but leaves the character to be read again.
+fn peek_char<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    read_char(engine, true)
+}
+
+fn read_char<O: MachineObserver>(engine: &mut Engine<O>, peek: bool) -> Result<Fallible, Error> {
+    let id = try!(input_of(engine, 2));
+    let arity = called_arity(engine);
+    let target = engine.machine.argument(arity);
+    match target {
+        Term::Variable(_) => { }
+        Term::Structure(ref s) if s.terms.is_empty() &&
+                                  s.functor.with_text(|t| t == "end_of_file" ||
+                                                          t.chars().count() == 1) => { }
+        _ => return Err(Error::Type("in_character", target)),
+    }
+    let input = engine.streams.get_mut(id).and_then(|stream| stream.reader()).unwrap();
+    let c = if peek { input.peek_char() } else { input.read_char() };
+    let c = match c {
+        Ok(Some(c)) => Term::atom(&c.to_string()),
+        Ok(None) => Term::atom("end_of_file"),
+        Err(_) => return Err(stream_error("input", id)),
+    };
+    Ok(unify_argument(engine, arity, &c, &mut HashMap::new()))
+}
+
+/// `put_char(Stream, Char)`, or `put_char(Char)` on the current output.
+fn put_char<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                -> Result<Fallible, Error> {
+    let id = try!(output_of(engine, 2));
+    let c = engine.machine.argument(called_arity(engine));
+    let text = try!(atom_name(&c));
+    if text.chars().count() != 1 {
+        return Err(Error::Type("character", c));
+    }
+    try!(output(engine, id, &text));
+    Ok(Ok(()))
+}
+
+/// `nl(Stream)`, or `nl` on the current output.
+fn nl<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    let id = try!(output_of(engine, 1));
+    try!(output(engine, id, "\n"));
+    Ok(Ok(()))
+}
+
+/// `flush_output(Stream)`, or `flush_output` on the current output.
+fn flush_output<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                    -> Result<Fallible, Error> {
+    let id = try!(output_of(engine, 1));
+    match engine.streams.get_mut(id).unwrap().flush() {
+        Ok(()) => Ok(Ok(())),
+        Err(_) => Err(stream_error("output", id)),
+    }
+}
+
+/// `read_term(Stream, Term, Options)`, or `read_term(Term, Options)`
+/// on the current input. The options are `variables(Vars)` and
+/// `variable_names(['Name'=Var, ...])`. At the end of the stream,
+/// `Term` is `end_of_file`.
+fn read_term<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                 -> Result<Fallible, Error> {
+    let id = try!(input_of(engine, 3));
+    let arity = called_arity(engine);
+    let mut option_vars = HashMap::new();
+    let list = engine.machine.read_argument(arity, &mut option_vars);
+    let options = match list.to_list() {
+        Some(options) => options,
+        None => {
+            try!(self::list(&list));
+            return Err(Error::Instantiation);
+        }
+    };
+    for option in &options {
+        match *option {
+            Term::Variable(_) => return Err(Error::Instantiation),
+            Term::Structure(ref s) if s.functor == functor!(variables/1) ||
+                                      s.functor == functor!(variable_names/1) => { }
+            _ => return Err(Error::Domain("read_option", option.clone())),
+        }
+    }
+    read_from(engine, id, arity - 1, &options, option_vars)
+}
+
+/// `read(Stream, Term)`, or `read(Term)` on the current input.
+fn read<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    let id = try!(input_of(engine, 2));
+    let arity = called_arity(engine);
+    read_from(engine, id, arity, &[], HashMap::new())
+}
+
+/// Reads a term from stream `id` and unifies it with argument `i`, and
+/// the options, whose variables are in `option_vars`, with what they
+/// describe.
+fn read_from<O: MachineObserver>(engine: &mut Engine<O>, id: usize, i: usize, options: &[Term],
+                                 mut option_vars: HashMap<InternedString, Cell>)
+                                 -> Result<Fallible, Error> {
+    let input = engine.streams.get_mut(id).and_then(|stream| stream.reader()).unwrap();
+    let text = match input.read_clause() {
+        Ok(Some(text)) => text,
+        Ok(None) => return Ok(unify_argument(engine, i, &Term::atom("end_of_file"),
+                                             &mut HashMap::new())),
+        Err(_) => return Err(stream_error("input", id)),
+    };
+    let (term, names) = {
+        let mut reader = engine.reader(&text);
+        match reader.read_term() {
+            Ok(Some(term)) => (term, reader.variable_names().to_vec()),
+            Ok(None) => unreachable!(),
+            Err(error) => return Err(Error::Syntax(error.message)),
+        }
+    };
+    let mut vars = HashMap::new();
+    if unify_argument(engine, i, &term, &mut vars).is_err() {
+        return Ok(Err(()));
+    }
+    for option in options {
+        if let Term::Structure(ref s) = *option {
+            let value = if s.functor == functor!(variables/1) {
+                let mut variables = vec![];
+                term.variables(&mut variables);
+                Term::list(variables.into_iter().map(Term::Variable).collect(), Term::nil())
+            } else {
+                let bindings = names.iter().map(|&(ref name, v)| {
+                    Term::Structure(Structure { functor: functors("=", 2),
+                                                terms: vec![Term::atom(name), Term::Variable(v)] })
+                });
+                Term::list(bindings.collect(), Term::nil())
+            };
+            let value = engine.machine.put_term(&value, &mut vars);
+            let target = engine.machine.put_term(&s.terms[0], &mut option_vars);
+            if engine.machine.unify(value, target).is_err() {
+                return Ok(Err(()));
+            }
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Enumerates `stream_property(Stream, Property)`, where the
+/// properties are `input`, `output`, `mode(M)`, `alias(A)` and
+/// `file_name(F)`.
+fn stream_property<O: MachineObserver>(engine: &mut Engine<O>, redo: Option<Redo>)
+                                       -> Result<Fallible, Error> {
+    each_solution(engine, redo, |engine| {
+        match engine.machine.argument(1) {
+            Term::Variable(_) => { }
+            Term::Structure(ref s) if s.functor == functors("$stream", 1) => { }
+            term => return Err(Error::Domain("stream", term)),
+        }
+        let mut solutions = vec![];
+        for (id, stream) in engine.streams.iter() {
+            let mode = match stream.mode() {
+                Mode::Read => "read",
+                Mode::Write => "write",
+                Mode::Append => "append",
+            };
+            let direction = if stream.is_input() { "input" } else { "output" };
+            let mut properties = vec![Term::atom(direction), unary("mode", Term::atom(mode))];
+            if let Some(alias) = stream.alias() {
+                properties.push(unary("alias", Term::atom(alias)));
+            }
+            if let Some(name) = stream.file_name() {
+                properties.push(unary("file_name", Term::atom(name)));
+            }
+            for property in properties {
+                solutions.push(vec![stream_term(id), property]);
+            }
+        }
+        Ok(solutions)
+    })
+}
+
+fn unary(name: &str, arg: Term) -> Term {
+    Term::Structure(Structure { functor: functors(name, 1), terms: vec![arg] })
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Writing terms
+//
+// Each of these takes an optional stream as its first argument, and
+// writes to the current output if it is left out.
+
+/// The options of `write_term/2`.
+#[derive(Default)]
+struct WriteOptions {
+    quoted: bool,
+    ignore_ops: bool,
+    numbervars: bool,
+    portray: bool,
+    max_depth: usize,
+    variable_names: HashMap<InternedString, String>,
+}
+
+/// Writes the last argument with `options`, to the stream in the first
+/// argument if there are `arity` of them.
+fn write_argument<O: MachineObserver>(engine: &mut Engine<O>, arity: usize,
+                                      options: WriteOptions) -> Result<Fallible, Error> {
+    let id = try!(output_of(engine, arity));
+    let mut vars = HashMap::new();
+    let term = engine.machine.read_argument(called_arity(engine), &mut vars);
+    write_with(engine, id, &term, &vars, options)
+}
+
+/// Writes `term`, whose variables are those in `vars`, to stream `id`.
+fn write_with<O: MachineObserver>(engine: &mut Engine<O>, id: usize, term: &Term,
+                                  vars: &HashMap<InternedString, Cell>, options: WriteOptions)
+                                  -> Result<Fallible, Error> {
+    let ops = engine.operators.clone();
+    let portray = options.portray && engine.database.predicate(functor!(portray/1)).is_some();
+    let text = {
+        let mut writer = Writer::new(&ops).quoted(options.quoted)
+                                          .ignore_ops(options.ignore_ops)
+                                          .numbervars(options.numbervars)
+                                          .max_depth(options.max_depth)
+                                          .variable_names(options.variable_names);
+        if portray {
+            let mut portray = |term: &Term| call_portray(engine, term, vars);
+            writer.portray(&mut portray).to_string(term)
+        } else {
+            writer.to_string(term)
+        }
+    };
+    try!(output(engine, id, &text));
+    Ok(Ok(()))
+}
+
+/// Runs `portray(Term)`, returning the text it wrote if it succeeded.
+/// An error in `portray/1` counts as failure.
+fn call_portray<O: MachineObserver>(engine: &mut Engine<O>, term: &Term,
+                                    vars: &HashMap<InternedString, Cell>) -> Option<String> {
+    let goal = Term::Structure(Structure { functor: functor!(portray/1),
+                                           terms: vec![term.clone()] });
+    let outer = mem::replace(&mut engine.captured, Some(String::new()));
+    let mut succeeded = false;
+    let result = engine.solve(&[goal], vars, |_| {
+        succeeded = true;
+        Ok(false)
+    });
+    let text = mem::replace(&mut engine.captured, outer).unwrap();
+    if result.is_ok() && succeeded { Some(text) } else { None }
+}
+
+fn write<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    write_argument(engine, 2, WriteOptions { numbervars: true, ..WriteOptions::default() })
+}
+
+/// Like `writeq/1`, but letting `portray/1` write terms first.
+fn print<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    let options = WriteOptions { quoted: true, numbervars: true, portray: true,
+                                 ..WriteOptions::default() };
+    write_argument(engine, 2, options)
+}
+
+fn writeq<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>) -> Result<Fallible, Error> {
+    write_argument(engine, 2, WriteOptions { quoted: true, numbervars: true,
+                                             ..WriteOptions::default() })
+}
+
+fn write_canonical<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                       -> Result<Fallible, Error> {
+    write_argument(engine, 2, WriteOptions { quoted: true, ignore_ops: true,
+                                             ..WriteOptions::default() })
+}
+
+/// `write_term(Term, Options)`, where the options are `quoted(Bool)`,
+/// `ignore_ops(Bool)`, `numbervars(Bool)`, `portray(Bool)`,
+/// `max_depth(N)` and `variable_names(['Name'=Var, ...])`.
+fn write_term<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    let id = try!(output_of(engine, 3));
+    let arity = called_arity(engine);
+    let mut vars = HashMap::new();
+    let term = engine.machine.read_argument(arity - 1, &mut vars);
+    let list = engine.machine.read_argument(arity, &mut vars);
+    let items = match list.to_list() {
+        Some(items) => items,
+        None => {
+            try!(self::list(&list));
+            return Err(Error::Instantiation);
+        }
+    };
+    let mut options = WriteOptions::default();
+    for option in &items {
+        try!(write_option(option, &mut options));
+    }
+    write_with(engine, id, &term, &vars, options)
+}
+
+fn write_option(option: &Term, options: &mut WriteOptions) -> Result<(), Error> {
+    let domain = || Error::Domain("write_option", option.clone());
+    let flag = |value: &Term| match atom_name(value) {
+        Ok(ref name) if name == "true" => Ok(true),
+        Ok(ref name) if name == "false" => Ok(false),
+        Err(Error::Instantiation) => Err(Error::Instantiation),
+        _ => Err(domain()),
+    };
+    let (name, value) = match *option {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Structure(ref s) if s.terms.len() == 1 => {
+            (s.functor.with_text(|text| text.to_string()), &s.terms[0])
+        }
+        _ => return Err(domain()),
+    };
+    match &name[..] {
+        "quoted" => options.quoted = try!(flag(value)),
+        "ignore_ops" => options.ignore_ops = try!(flag(value)),
+        "numbervars" => options.numbervars = try!(flag(value)),
+        "portray" => options.portray = try!(flag(value)),
+        "max_depth" => {
+            options.max_depth = match *value {
+                Term::Integer(n) if n >= 0 => n as usize,
+                Term::Variable(_) => return Err(Error::Instantiation),
+                _ => return Err(domain()),
+            }
+        }
+        "variable_names" => {
+            let bindings = match value.to_list() {
+                Some(bindings) => bindings,
+                None => return Err(domain()),
+            };
+            for binding in bindings {
+                match binding {
+                    Term::Structure(ref s) if s.functor == functors("=", 2) => {
+                        let name = try!(atom_name(&s.terms[0]));
+                        if let Term::Variable(v) = s.terms[1] {
+                            options.variable_names.insert(v, name);
+                        }
+                    }
+                    Term::Variable(_) => return Err(Error::Instantiation),
+                    _ => return Err(domain()),
+                }
+            }
+        }
+        _ => return Err(domain()),
+    }
+    Ok(())
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Statistics
+//
+// The counts come from the engine's observer, so they are only kept
+// when the engine runs with a `Profiler`; otherwise they are all 0.
+
+/// `statistics`: writes the profile report to the current output.
+/// `statistics(Key, Value)`: `Value` is the count named by `Key`, one
+/// of `inferences`, `instructions`, `choice_points`, `backtracks` and
+/// `heap_cells`.
+fn statistics<O: MachineObserver>(engine: &mut Engine<O>, _: Option<Redo>)
+                                  -> Result<Fallible, Error> {
+    if called_arity(engine) == 0 {
+        let mut report = vec![];
+        match engine.profiler() {
+            Some(profile) => profile.report(&mut report).expect("writing to a Vec"),
+            None => report.extend_from_slice(b"no profile: the engine has no Profiler\n"),
+        }
+        let id = engine.streams.output();
+        try!(output(engine, id, &String::from_utf8_lossy(&report)));
+        return Ok(Ok(()));
+    }
+    let key = engine.machine.argument(1);
+    let count: fn(&Profiler) -> usize = match &try!(atom_name(&key))[..] {
+        "inferences" => Profiler::inferences,
+        "instructions" => Profiler::instructions,
+        "choice_points" => Profiler::choice_points,
+        "backtracks" => Profiler::backtracks,
+        "heap_cells" => Profiler::heap_cells,
+        _ => return Err(Error::Domain("statistics_key", key)),
+    };
+    let n = engine.profiler().map(count).unwrap_or(0);
+    Ok(unify_argument(engine, 2, &Term::Integer(n as i64), &mut HashMap::new()))
+}
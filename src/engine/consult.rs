@@ -0,0 +1,442 @@
+//! Loading source files with `consult/1`.
+//!
+//! A file is read one clause at a time, so that an `op/3` directive
+//! changes how the clauses after it are read. Directives run as soon
+//! as they are read, except for `initialization/1`, whose goals wait
+//! until the whole file is loaded. Problems with single clauses are
+//! reported as warnings on `user_error`, and loading carries on.
+//!
+//! Each term read is first offered to `term_expansion/2`, which may
+//! replace it with another term or a list of them. Grammar rules,
+//! written `Head --> Body`, are then translated into clauses (see
+//! `dcg`), and each goal in a clause body or directive is offered to
+//! `goal_expansion/2` until it no longer changes.
+//!
+//! A file that starts with `:- module(Name, Exports)` is loaded into
+//! the module `Name`, whose exports are then imported into the module
+//! that loaded it.
+
+use ast::{Clause, Structure, Term};
+use ast::write::Writer;
+use functor::{self, Functor};
+use intern::{self, InternedString};
+use machine::mem::Cell;
+use machine::observer::MachineObserver;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use super::{builtins, callable_body, dcg, module, Engine, Error};
+use super::stream::{self, Stream};
+
+/// The state of loading a file, along with the files it includes.
+struct Load {
+    /// The module that clauses and directives are in.
+    module: InternedString,
+    /// The file being read, and the line its next clause starts on.
+    file: PathBuf,
+    line: usize,
+    /// The predicates that have had clauses in this load.
+    defined: HashSet<Functor>,
+    /// The predicate of the last clause read.
+    last: Option<Functor>,
+    /// The goals of `initialization/1` directives, with where they were.
+    initialization: Vec<(Term, PathBuf, usize)>,
+}
+
+/// Loads the source file named by the atom `file`, on behalf of the
+/// module `context`. The clauses of each predicate in it replace any
+/// that predicate had before.
+pub fn consult<O: MachineObserver>(engine: &mut Engine<O>, file: &Term,
+                                   context: InternedString) -> Result<(), Error> {
+    let path = try!(resolve(&current_dir(engine), file));
+    let module = try!(load(engine, path, file));
+    import(engine, module, context, None)
+}
+
+/// Loads `file` unless it has been loaded already, and imports the
+/// predicates `imports` (or, if `None`, all of them) from the module
+/// it defines into `context`.
+pub fn use_module<O: MachineObserver>(engine: &mut Engine<O>, file: &Term,
+                                      context: InternedString, imports: Option<Vec<Functor>>)
+                                      -> Result<(), Error> {
+    let path = try!(resolve(&current_dir(engine), file));
+    let module = match engine.loaded.get(&path) {
+        Some(&module) => module,
+        None => try!(load(engine, path, file)),
+    };
+    import(engine, module, context, imports)
+}
+
+fn import<O: MachineObserver>(engine: &mut Engine<O>, module: InternedString,
+                              context: InternedString, imports: Option<Vec<Functor>>)
+                              -> Result<(), Error> {
+    if module == module::user() || module == context {
+        return Ok(());
+    }
+    engine.database.import(context, module, imports)
+}
+
+/// Loads the file at `path`, returning the module it defines.
+fn load<O: MachineObserver>(engine: &mut Engine<O>, path: PathBuf, file: &Term)
+                            -> Result<InternedString, Error> {
+    let mut load = Load { module: module::user(),
+                          file: path.clone(),
+                          line: 1,
+                          defined: HashSet::new(),
+                          last: None,
+                          initialization: vec![] };
+    engine.loading.push(path.clone());
+    let result = load_file(engine, &mut load, file);
+    engine.loading.pop();
+    try!(result);
+    engine.loaded.insert(path, load.module);
+    for (goal, file, line) in mem::replace(&mut load.initialization, vec![]) {
+        load.file = file;
+        load.line = line;
+        if let Err(error) = run(engine, &load, &goal) {
+            warn_error(engine, &load, error);
+        }
+    }
+    Ok(load.module)
+}
+
+/// The directory that names of files are relative to: that of the file
+/// being loaded, if any.
+fn current_dir<O: MachineObserver>(engine: &Engine<O>) -> PathBuf {
+    match engine.loading.last().and_then(|path| path.parent()) {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::new(),
+    }
+}
+
+/// The path of the file named by the atom `file`, relative to `dir`:
+/// the name as given, or with `.pl` added.
+fn resolve(dir: &Path, file: &Term) -> Result<PathBuf, Error> {
+    let name = match *file {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Structure(ref s) if s.terms.is_empty() => s.functor.with_text(|t| t.to_string()),
+        _ => return Err(Error::Domain("source_sink", file.clone())),
+    };
+    for candidate in &[name.clone(), format!("{}.pl", name)] {
+        let path = dir.join(candidate);
+        if path.is_file() {
+            return Ok(path.canonicalize().unwrap_or(path));
+        }
+    }
+    Err(Error::Existence("source_sink", file.clone()))
+}
+
+/// Reads the clauses of `load.file`, which `file` names in errors.
+fn load_file<O: MachineObserver>(engine: &mut Engine<O>, load: &mut Load, file: &Term)
+                                 -> Result<(), Error> {
+    let mut text = String::new();
+    let read = File::open(&load.file).and_then(|mut f| f.read_to_string(&mut text));
+    if read.is_err() {
+        return Err(Error::Permission("open", "source_sink", file.clone()));
+    }
+    let dir = load.file.parent().map(|dir| dir.to_path_buf()).unwrap_or(PathBuf::new());
+    let mut input = Stream::input(Box::new(Cursor::new(text.into_bytes())));
+    let input = input.reader().unwrap();
+    while let Ok(Some(text)) = input.read_clause() {
+        let start = load.line + lines(&text[..start_of_clause(&text)]);
+        load.line += lines(&text);
+        let (term, singletons) = {
+            let mut reader = engine.reader(&text);
+            match reader.read_term() {
+                Ok(Some(term)) => {
+                    let singletons = singletons(&term, reader.variable_names());
+                    (term, singletons)
+                }
+                Ok(None) => continue,
+                Err(error) => {
+                    let line = load.line - lines(&text) + lines(&text[..error.offset]);
+                    warn(engine, &load.file, line, &format!("Syntax error: {}", error.message));
+                    continue;
+                }
+            }
+        };
+
+        let next_line = load.line;
+        load.line = start;
+        if let Err(error) = expand_term(engine, load, &term, &singletons, &dir) {
+            warn_error(engine, load, error);
+        }
+        load.line = next_line;
+    }
+    Ok(())
+}
+
+/// Adds the terms that `term_expansion/2` makes of `term`, or `term`
+/// itself if it makes nothing.
+fn expand_term<O: MachineObserver>(engine: &mut Engine<O>, load: &mut Load, term: &Term,
+                                   singletons: &[String], dir: &Path) -> Result<(), Error> {
+    let terms = match try!(expand(engine, load, "term_expansion", term)) {
+        Some(expanded) => expanded.to_list().unwrap_or_else(|| vec![expanded]),
+        None => {
+            if !singletons.is_empty() && !is_directive(term) {
+                let message = format!("Singleton variables: [{}]", singletons.join(","));
+                warn(engine, &load.file, load.line, &message);
+            }
+            vec![term.clone()]
+        }
+    };
+    for term in &terms {
+        try!(add_term(engine, load, term, dir));
+    }
+    Ok(())
+}
+
+fn is_directive(term: &Term) -> bool {
+    match *term {
+        Term::Structure(ref s) => {
+            s.terms.len() == 1 && s.functor.with_text(|text| text == ":-" || text == "?-")
+        }
+        _ => false,
+    }
+}
+
+/// Runs `term` if it is a directive, and otherwise adds it to the
+/// database as a clause.
+fn add_term<O: MachineObserver>(engine: &mut Engine<O>, load: &mut Load, term: &Term,
+                                dir: &Path) -> Result<(), Error> {
+    if let Term::Structure(ref s) = *term {
+        if is_directive(term) {
+            return directive(engine, load, &s.terms[0], dir);
+        }
+    }
+    let rule;
+    let term = match *term {
+        Term::Structure(ref s) if dcg::is_rule(term) => {
+            rule = try!(dcg::translate(&s.terms[0], &s.terms[1]));
+            &rule
+        }
+        _ => term,
+    };
+
+    let mut clause = match Clause::from_term(term) {
+        Some(ref clause) if clause.body.iter().all(callable_body) => clause.clone(),
+        _ => return Err(Error::Type("callable", term.clone())),
+    };
+    if engine.builtins.contains_key(&clause.head.functor) {
+        let culprit = Term::indicator(clause.head.functor);
+        return Err(Error::Permission("modify", "static_procedure", culprit));
+    }
+    clause.head.functor = module::qualify(load.module, clause.head.functor);
+    let mut body = vec![];
+    for goal in &clause.body {
+        let goal = try!(expand_goal(engine, load, goal));
+        body.extend(goal.conjuncts());
+    }
+    clause.body = body.iter().map(|goal| module::qualify_goal(engine, load.module, goal)).collect();
+    let f = clause.head.functor;
+    if load.defined.insert(f) {
+        engine.database.wipe(f);
+    } else if load.last != Some(f) && !engine.database.predicate(f).unwrap().discontiguous {
+        let name = Writer::new(&engine.operators).to_string(&module::indicator(f));
+        let message = format!("Clauses of {} are not together in the source file", name);
+        warn(engine, &load.file, load.line, &message);
+    }
+    load.last = Some(f);
+    engine.database.add_clause(&clause);
+    Ok(())
+}
+
+fn directive<O: MachineObserver>(engine: &mut Engine<O>, load: &mut Load, goal: &Term,
+                                 dir: &Path) -> Result<(), Error> {
+    if let Term::Structure(ref s) = *goal {
+        if s.functor == functor!(include/1) {
+            let path = try!(resolve(dir, &s.terms[0]));
+            let (file, line) = (load.file.clone(), load.line);
+            load.file = path;
+            load.line = 1;
+            let result = load_file(engine, load, &s.terms[0]);
+            load.file = file;
+            load.line = line;
+            return result;
+        }
+        if s.functor == functor!(initialization/1) {
+            let goal = try!(expand_goal(engine, load, &s.terms[0]));
+            let goal = module::qualify_goal(engine, load.module, &goal);
+            load.initialization.push((goal, load.file.clone(), load.line));
+            return Ok(());
+        }
+        if s.functor == functor!(module/2) {
+            return start_module(engine, load, &s.terms[0], &s.terms[1]);
+        }
+    }
+    let goal = try!(expand_goal(engine, load, goal));
+    let goal = module::qualify_goal(engine, load.module, &goal);
+    run(engine, load, &goal)
+}
+
+/// `goal` as `goal_expansion/2` rewrites it: over and over until it
+/// makes no change, and then within each part of the control
+/// constructs that result.
+fn expand_goal<O: MachineObserver>(engine: &mut Engine<O>, load: &Load, goal: &Term)
+                                   -> Result<Term, Error> {
+    let mut goal = goal.clone();
+    if let Term::Variable(_) = goal {
+        return Ok(goal);
+    }
+    while let Some(expanded) = try!(expand(engine, load, "goal_expansion", &goal)) {
+        if expanded == goal {
+            break;
+        }
+        goal = expanded;
+    }
+    match goal {
+        Term::Structure(ref s) if is_control(s) => {
+            let terms = try!(s.terms.iter().map(|t| expand_goal(engine, load, t)).collect());
+            Ok(Term::Structure(Structure { functor: s.functor, terms: terms }))
+        }
+        goal => Ok(goal),
+    }
+}
+
+/// Whether the arguments of `goal` are goals that run as part of it.
+fn is_control(goal: &Structure) -> bool {
+    goal.functor.with_text(|text| match (text, goal.terms.len()) {
+        (",", 2) | (";", 2) | ("->", 2) | ("*->", 2) | ("\\+", 1) | ("not", 1) |
+        ("once", 1) | ("ignore", 1) | ("forall", 2) => true,
+        _ => false,
+    })
+}
+
+/// Calls `name(term, Expansion)` in the module being loaded, if it can
+/// see a predicate `name/2`, and returns the first `Expansion` found.
+/// The variables of `term` keep their names in it.
+fn expand<O: MachineObserver>(engine: &mut Engine<O>, load: &Load, name: &str, term: &Term)
+                              -> Result<Option<Term>, Error> {
+    let f = module::qualify(load.module, functor::functors().functor(name, 2, true));
+    if engine.database.resolve(f).is_none() {
+        return Ok(None);
+    }
+    let mut vars = HashMap::new();
+    let input = engine.machine.put_term(term, &mut vars);
+    let names: Vec<_> = vars.iter().map(|(&name, &cell)| (name, cell)).collect();
+    let (term_var, expansion_var) = (intern::intern("$Term"), intern::intern("$Expansion"));
+    vars.insert(term_var, Cell::Ref(input));
+    let output = engine.machine.put_term(&Term::Variable(expansion_var), &mut vars);
+    let goal = Term::Structure(Structure {
+        functor: f,
+        terms: vec![Term::Variable(term_var), Term::Variable(expansion_var)],
+    });
+
+    let mut expansion = None;
+    try!(engine.solve(&[goal], &vars, |engine| {
+        let mut renaming = HashMap::new();
+        for &(name, cell) in &names {
+            if let Term::Variable(v) = engine.machine.cell_term(cell) {
+                renaming.insert(v, name);
+            }
+        }
+        let term = engine.machine.term(output);
+        expansion = Some(builtins::rename(&term, &mut |v| *renaming.get(&v).unwrap_or(&v)));
+        Ok(false)
+    }));
+    Ok(expansion)
+}
+
+/// Starts loading the rest of the file into the module `name`, which
+/// exports the predicates in the list `exports`.
+fn start_module<O: MachineObserver>(engine: &mut Engine<O>, load: &mut Load, name: &Term,
+                                    exports: &Term) -> Result<(), Error> {
+    let name = match *name {
+        Term::Variable(_) => return Err(Error::Instantiation),
+        Term::Structure(ref s) if s.terms.is_empty() => {
+            s.functor.with_text(|text| intern::intern(text))
+        }
+        _ => return Err(Error::Type("atom", name.clone())),
+    };
+    let exports = match exports.to_list() {
+        Some(exports) => exports,
+        None => return Err(Error::Type("list", exports.clone())),
+    };
+    let exports = try!(exports.iter().map(|export| match export.to_indicator() {
+        Some(f) => Ok(f),
+        None => Err(Error::Type("predicate_indicator", export.clone())),
+    }).collect());
+    engine.database.declare_module(name, exports);
+    load.module = name;
+    Ok(())
+}
+
+/// Runs the directive `goal` once, warning if it fails.
+fn run<O: MachineObserver>(engine: &mut Engine<O>, load: &Load, goal: &Term)
+                           -> Result<(), Error> {
+    let mut succeeded = false;
+    try!(engine.solve(&goal.conjuncts(), &HashMap::new(), |_| {
+        succeeded = true;
+        Ok(false)
+    }));
+    if !succeeded {
+        let text = Writer::new(&engine.operators).to_string(goal);
+        warn(engine, &load.file, load.line, &format!("Goal (directive) failed: {}", text));
+    }
+    Ok(())
+}
+
+/// Warns of `error`, which arose while loading the clause at
+/// `load.line`.
+fn warn_error<O: MachineObserver>(engine: &mut Engine<O>, load: &Load, error: Error) {
+    let f = functor::functors().functor("consult", 1, true);
+    let text = Writer::new(&engine.operators).to_string(&error.ball(f));
+    warn(engine, &load.file, load.line, &text);
+}
+
+/// Writes a warning about line `line` of `file` to `user_error`. If
+/// that fails, there is nowhere better to report it.
+fn warn<O: MachineObserver>(engine: &mut Engine<O>, file: &Path, line: usize, message: &str) {
+    let text = format!("Warning: {}:{}: {}\n", file.display(), line, message);
+    if let Some(stream) = engine.streams.get_mut(stream::USER_ERROR) {
+        if let Some(writer) = stream.writer() {
+            let _ = writer.write_all(text.as_bytes());
+        }
+        let _ = stream.flush();
+    }
+}
+
+fn lines(text: &str) -> usize {
+    text.matches('\n').count()
+}
+
+/// The offset of the first character of `text` that is neither layout
+/// nor part of a `%` comment.
+fn start_of_clause(text: &str) -> usize {
+    let mut comment = false;
+    for (offset, c) in text.char_indices() {
+        match c {
+            '\n' => comment = false,
+            '%' => comment = true,
+            c if !comment && !c.is_whitespace() => return offset,
+            _ => { }
+        }
+    }
+    text.len()
+}
+
+/// The names of the variables that occur only once in `term`, other
+/// than those starting with `_`.
+fn singletons(term: &Term, names: &[(String, InternedString)]) -> Vec<String> {
+    fn count(term: &Term, counts: &mut HashMap<InternedString, usize>) {
+        match *term {
+            Term::Variable(v) => *counts.entry(v).or_insert(0) += 1,
+            Term::Structure(ref s) => {
+                for term in &s.terms {
+                    count(term, counts);
+                }
+            }
+            Term::Integer(_) | Term::String(_) => { }
+        }
+    }
+
+    let mut counts = HashMap::new();
+    count(term, &mut counts);
+    names.iter()
+         .filter(|&&(ref name, v)| !name.starts_with('_') && counts[&v] == 1)
+         .map(|&(ref name, _)| name.clone())
+         .collect()
+}
@@ -0,0 +1,162 @@
+//! Definite clause grammars. The rule `Head --> Body` is translated
+//! into an ordinary clause, in which each nonterminal has two more
+//! arguments: the list to be parsed, and what is left of it after.
+//! Thus `greeting --> [hello], name.` becomes
+//!
+//! ```text
+//! greeting(S0, S) :- S0 = [hello|S1], name(S1, S).
+//! ```
+
+use ast::{Structure, Term};
+use functor::Functor;
+use intern::{self, InternedString};
+use std::collections::HashSet;
+
+use super::Error;
+
+/// Whether `term` is a grammar rule.
+pub fn is_rule(term: &Term) -> bool {
+    match *term {
+        Term::Structure(ref s) => s.terms.len() == 2 && s.functor.with_text(|t| t == "-->"),
+        _ => false,
+    }
+}
+
+/// The clause that the grammar rule `head --> body` stands for. The
+/// head may be `Head, Pushback`, where `Pushback` is a list that is
+/// put back in front of what is left once `Body` has been parsed.
+pub fn translate(head: &Term, body: &Term) -> Result<Term, Error> {
+    let mut translator = Translator::new(&[head, body]);
+    let (s0, s) = (translator.fresh(), translator.fresh());
+    let (head, pushback) = match *head {
+        Term::Structure(ref h) if h.terms.len() == 2 && h.functor.with_text(|t| t == ",") => {
+            (&h.terms[0], Some(&h.terms[1]))
+        }
+        _ => (head, None),
+    };
+    let body = match pushback {
+        Some(pushback) => {
+            let rest = translator.fresh();
+            let body = try!(translator.body(body, s0.clone(), rest.clone()));
+            conjunction(body, try!(terminals(pushback, s.clone(), rest)))
+        }
+        None => try!(translator.body(body, s0.clone(), s.clone())),
+    };
+    let head = try!(nonterminal(head, vec![s0, s]));
+    Ok(compound(":-", vec![head, body]))
+}
+
+/// The goal that `phrase(Body, List, Rest)` runs.
+pub fn phrase(body: &Term, list: Term, rest: Term) -> Result<Term, Error> {
+    let mut translator = Translator::new(&[body, &list, &rest]);
+    translator.body(body, list, rest)
+}
+
+/// Makes up variables that do not occur in the rule being translated.
+struct Translator {
+    used: HashSet<InternedString>,
+    next: usize,
+}
+
+impl Translator {
+    fn new(terms: &[&Term]) -> Translator {
+        let mut used = vec![];
+        for term in terms {
+            term.variables(&mut used);
+        }
+        Translator { used: used.into_iter().collect(), next: 0 }
+    }
+
+    fn fresh(&mut self) -> Term {
+        loop {
+            let name = intern::intern(&format!("S{}", self.next));
+            self.next += 1;
+            if self.used.insert(name) {
+                return Term::Variable(name);
+            }
+        }
+    }
+
+    /// The goal that parses `body` from the list `s0`, leaving `s`.
+    fn body(&mut self, body: &Term, s0: Term, s: Term) -> Result<Term, Error> {
+        let b = match *body {
+            Term::Variable(_) => return Ok(compound("phrase", vec![body.clone(), s0, s])),
+            Term::String(ref text) => {
+                let codes = text.chars().map(|c| Term::Integer(c as i64)).collect();
+                return terminals(&Term::list(codes, Term::nil()), s0, s);
+            }
+            Term::Integer(_) => return Err(Error::Type("callable", body.clone())),
+            Term::Structure(ref b) => b,
+        };
+        let name = b.functor.with_text(|text| text.to_string());
+        Ok(match (&name[..], b.terms.len()) {
+            (",", 2) => {
+                let middle = self.fresh();
+                let first = try!(self.body(&b.terms[0], s0, middle.clone()));
+                conjunction(first, try!(self.body(&b.terms[1], middle, s)))
+            }
+            (";", 2) => {
+                let either = try!(self.body(&b.terms[0], s0.clone(), s.clone()));
+                compound(";", vec![either, try!(self.body(&b.terms[1], s0, s))])
+            }
+            ("->", 2) => {
+                let middle = self.fresh();
+                let condition = try!(self.body(&b.terms[0], s0, middle.clone()));
+                compound("->", vec![condition, try!(self.body(&b.terms[1], middle, s))])
+            }
+            ("\\+", 1) => {
+                let rest = self.fresh();
+                let goal = try!(self.body(&b.terms[0], s0.clone(), rest));
+                conjunction(compound("\\+", vec![goal]), unify(s0, s))
+            }
+            (":", 2) => compound(":", vec![b.terms[0].clone(),
+                                           try!(self.body(&b.terms[1], s0, s))]),
+            ("!", 0) => conjunction(Term::atom("!"), unify(s0, s)),
+            ("[]", 0) => unify(s0, s),
+            ("{}", 1) => conjunction(b.terms[0].clone(), unify(s0, s)),
+            (".", 2) => try!(terminals(body, s0, s)),
+            ("call", _) => {
+                let mut terms = b.terms.clone();
+                terms.push(s0);
+                terms.push(s);
+                compound("call", terms)
+            }
+            _ => try!(nonterminal(body, vec![s0, s])),
+        })
+    }
+}
+
+/// The goal that parses the items of `list` from `s0`, leaving `s`.
+fn terminals(list: &Term, s0: Term, s: Term) -> Result<Term, Error> {
+    match list.to_list() {
+        Some(items) => Ok(unify(s0, Term::list(items, s))),
+        None => Err(Error::Type("list", list.clone())),
+    }
+}
+
+/// The nonterminal `term` with `extra` added to its arguments.
+fn nonterminal(term: &Term, extra: Vec<Term>) -> Result<Term, Error> {
+    match *term {
+        Term::Structure(ref s) => {
+            let mut terms = s.terms.clone();
+            terms.extend(extra);
+            let name = s.functor.with_text(|text| text.to_string());
+            Ok(compound(&name, terms))
+        }
+        Term::Variable(_) => Err(Error::Instantiation),
+        Term::Integer(_) | Term::String(_) => Err(Error::Type("callable", term.clone())),
+    }
+}
+
+fn unify(a: Term, b: Term) -> Term {
+    compound("=", vec![a, b])
+}
+
+fn conjunction(a: Term, b: Term) -> Term {
+    compound(",", vec![a, b])
+}
+
+fn compound(name: &str, terms: Vec<Term>) -> Term {
+    let functor = Functor::transient(name, terms.len());
+    Term::Structure(Structure { functor: functor, terms: terms })
+}
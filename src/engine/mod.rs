@@ -0,0 +1,1092 @@
+//! A Prolog engine: a machine together with the program it runs.
+//!
+//! Each `Engine` owns its own `Database` of predicates and its own
+//! `Flags`, so any number of engines can coexist in one process (or
+//! run on different threads) without seeing each other's clauses.
+//! The only thing they share is the global functor table, in which
+//! each engine claims the atoms it creates at run time; see
+//! `Engine::collect_atoms`.
+
+use ast::{Clause, Structure, Term};
+use ast::ops::Operators;
+use ast::read::{DoubleQuotes, Reader, SyntaxError};
+use ast::write::Writer;
+use compile;
+use functor::{Functor, Marks, MarkFunctors, Owner};
+use intern::{self, InternedString};
+use machine::{Code, CodePtr, Fallible, Instruction, Machine, Resume};
+use machine::listing::Listing;
+use machine::mem::{Cell, Permanent};
+use machine::observer::MachineObserver;
+use machine::profile::Profiler;
+use machine::stack::Redo;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use self::module::MetaArg;
+use self::stream::{Mode, Stream, Streams};
+
+mod builtins;
+mod consult;
+mod dcg;
+mod module;
+pub mod stream;
+
+#[cfg(test)]
+mod test;
+
+pub struct Engine<O: MachineObserver = ()> {
+    machine: Machine<O>,
+    database: Database,
+    flags: Flags,
+    operators: Operators,
+    streams: Streams,
+    /// Text written to the current output while running `portray/1`,
+    /// which is kept here rather than sent to the stream.
+    captured: Option<String>,
+    /// The files loaded by `consult/1`, for `ensure_loaded/1`, with
+    /// the module each defines (`user` if none).
+    loaded: HashMap<PathBuf, InternedString>,
+    /// The files being loaded, innermost last.
+    loading: Vec<PathBuf>,
+    builtins: HashMap<Functor, Builtin<O>>,
+    /// The builtin currently running, and where to continue once it
+    /// succeeds.
+    calling: Option<(Functor, CodePtr)>,
+    /// Claims the transient functors this engine creates or is given.
+    owner: Owner,
+    /// The observer as a `Profiler`, if it is one; this is what
+    /// `statistics/0,2` report.
+    profiler: fn(&O) -> Option<&Profiler>,
+}
+
+/// A predicate implemented in Rust. Its arguments are in the machine's
+/// argument registers; `redo` is `None` on the first call, and
+/// otherwise the state it passed to `Engine::retry`.
+pub type Builtin<O> = fn(&mut Engine<O>, redo: Option<Redo>) -> Result<Fallible, Error>;
+
+/// The clauses of every predicate known to an engine, by module.
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    /// The module of code that is in no other.
+    user: Module,
+    modules: HashMap<InternedString, Module>,
+}
+
+/// The predicates of one module, and those it shares with others.
+#[derive(Clone, Debug, Default)]
+pub struct Module {
+    predicates: HashMap<Functor, Predicate>,
+    /// The predicates that `use_module/1` imports from this one.
+    exports: Vec<Functor>,
+    /// The predicates this one uses from others, with the module that
+    /// defines each.
+    imports: HashMap<Functor, InternedString>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Predicate {
+    /// Calls take a snapshot of this list, so changing it never
+    /// affects a call that is already running.
+    clauses: Arc<Vec<Arc<Code>>>,
+    /// Whether clauses may be added and removed while running.
+    dynamic: bool,
+    /// Whether its clauses may be spread through a source file.
+    discontiguous: bool,
+    /// How it treats its arguments, if it is a meta-predicate.
+    meta: Option<Vec<MetaArg>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Flags {
+    /// What to do when calling a predicate with no clauses.
+    pub unknown: Unknown,
+    /// How double-quoted text is read.
+    pub double_quotes: DoubleQuotes,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unknown {
+    Error,
+    Fail,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    UnknownPredicate(Functor),
+    /// An argument was unbound where a value was required.
+    Instantiation,
+    /// An argument (the term) was bound where a variable was required.
+    Uninstantiation(Term),
+    /// An argument (the term) was not of the expected type.
+    Type(&'static str, Term),
+    /// An argument (the term) was of the right type, but not one of
+    /// the values allowed.
+    Domain(&'static str, Term),
+    /// An operation (e.g., `modify`) is not allowed on this kind of
+    /// object (e.g., `static_procedure`).
+    Permission(&'static str, &'static str, Term),
+    /// There is no object (the term) of this kind, e.g. `stream`.
+    Existence(&'static str, Term),
+    /// Text read by `read_term/2` was not a valid term.
+    Syntax(String),
+    /// A value (e.g., an arity) cannot be represented.
+    Representation(&'static str),
+    /// A ball thrown by `throw/1` that nothing caught.
+    Exception(Term),
+}
+
+/// A running query; each item is the next solution.
+pub struct Query<'engine, O: MachineObserver + 'engine> {
+    engine: &'engine mut Engine<O>,
+    variables: Vec<(InternedString, Permanent)>,
+    state: State,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    Fresh,
+    Running,
+    Done,
+}
+
+/// The bindings of a query's named variables in one solution.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Answer {
+    bindings: Vec<(InternedString, Term)>,
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Engine
+
+impl Engine {
+    pub fn new() -> Engine {
+        Engine::with_observer(())
+    }
+}
+
+impl<O: MachineObserver> Engine<O> {
+    pub fn with_observer(observer: O) -> Engine<O>
+        where O: Any
+    {
+        Engine { machine: Machine::with_observer(0, observer),
+                 database: Database::new(),
+                 flags: Flags::default(),
+                 operators: Operators::new(),
+                 streams: Streams::new(),
+                 captured: None,
+                 loaded: HashMap::new(),
+                 loading: vec![],
+                 builtins: builtins::standard(),
+                 calling: None,
+                 owner: Owner::new(),
+                 profiler: profiler::<O> }
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    pub fn database_mut(&mut self) -> &mut Database {
+        &mut self.database
+    }
+
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    pub fn flags_mut(&mut self) -> &mut Flags {
+        &mut self.flags
+    }
+
+    pub fn operators(&self) -> &Operators {
+        &self.operators
+    }
+
+    pub fn operators_mut(&mut self) -> &mut Operators {
+        &mut self.operators
+    }
+
+    /// Reads `text` as a term, using this engine's operators and flags.
+    pub fn read_term(&self, text: &str) -> Result<Term, SyntaxError> {
+        let _entered = self.owner.enter();
+        self.reader(text).read_single()
+    }
+
+    fn reader<'text>(&'text self, text: &'text str) -> Reader<'text> {
+        Reader::new(text, &self.operators).double_quotes(self.flags.double_quotes)
+    }
+
+    /// Writes `term` using this engine's operators, quoting atoms where
+    /// needed for it to be read back in.
+    pub fn write_term(&self, term: &Term) -> String {
+        Writer::new(&self.operators).to_string(term)
+    }
+
+    /// Reads `user_input` from `input`, rather than standard input.
+    pub fn set_input(&mut self, input: Box<dyn Read + Send>) {
+        self.streams.replace(stream::USER_INPUT, Stream::input(input));
+    }
+
+    /// Writes `user_output` to `output`, rather than standard output.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.streams.replace(stream::USER_OUTPUT, Stream::output(output, Mode::Append));
+    }
+
+    /// Writes `user_error`, where warnings go, to `error`.
+    pub fn set_error(&mut self, error: Box<dyn Write + Send>) {
+        self.streams.replace(stream::USER_ERROR, Stream::output(error, Mode::Append));
+    }
+
+    /// Makes `stream` available to programs, returning the term that
+    /// refers to it.
+    pub fn add_stream(&mut self, stream: Stream) -> Term {
+        stream::stream_term(self.streams.add(stream))
+    }
+
+    pub fn observer(&self) -> &O {
+        self.machine.observer()
+    }
+
+    pub fn into_observer(self) -> O {
+        self.machine.into_observer()
+    }
+
+    /// The profile the observer has gathered, if it is a `Profiler`.
+    pub fn profiler(&self) -> Option<&Profiler> {
+        (self.profiler)(self.machine.observer())
+    }
+
+    /// Frees the atoms (and other functors) this engine created at run
+    /// time that it no longer refers to, returning how many went. Any
+    /// term it handed out earlier, such as an `Answer`, must not be
+    /// used afterwards unless it is also passed back in.
+    pub fn collect_atoms(&mut self) -> usize {
+        let mut marks = Marks::new();
+        self.mark_functors(&mut marks);
+        self.owner.collect(&marks)
+    }
+
+    pub fn add_clause(&mut self, clause: &Clause) {
+        self.owner.adopt(clause);
+        self.database.add_clause(clause);
+    }
+
+    /// Loads the source file at `path`, as `consult/1` does, returning
+    /// whether that goal succeeded.
+    pub fn consult(&mut self, path: &str) -> Result<bool, Error> {
+        let _entered = self.owner.enter();
+        let goal = Term::Structure(Structure { functor: functor!(consult/1),
+                                               terms: vec![Term::atom(path)] });
+        match self.query(&[goal]).next() {
+            Some(Ok(_)) => Ok(true),
+            Some(Err(error)) => Err(error),
+            None => Ok(false),
+        }
+    }
+
+    /// Starts solving the conjunction `goals`, abandoning any query
+    /// that was running before.
+    pub fn query<'engine>(&'engine mut self, goals: &[Term]) -> Query<'engine, O> {
+        self.owner.adopt(goals);
+        let compiled = compile::query(goals);
+        self.machine.start(Arc::new(compiled.code));
+        Query { engine: self, variables: compiled.variables, state: State::Fresh }
+    }
+
+    /// Runs until the query succeeds (true) or fails (false).
+    fn run(&mut self) -> Result<bool, Error> {
+        loop {
+            let instr = self.machine.fetch();
+            let result = match instr {
+                Instruction::Call(f) | Instruction::Execute(f) => {
+                    let last = instr == Instruction::Execute(f);
+                    let called = if self.machine.has_wakeups() {
+                        Ok(self.wake(Some(f), last))
+                    } else {
+                        self.call(f, last)
+                    };
+                    match called {
+                        Ok(result) => result,
+                        Err(error) => {
+                            try!(self.throw(error, f));
+                            Ok(())
+                        }
+                    }
+                }
+                Instruction::Cut | Instruction::CutTo(_) | Instruction::SoftCut(_) |
+                Instruction::Succeed if self.machine.has_wakeups() => {
+                    // run the hooks before committing to the bindings,
+                    // then come back to this instruction
+                    self.machine.unfetch();
+                    self.wake(None, false)
+                }
+                Instruction::Succeed => {
+                    let _ = self.machine.step(instr);
+                    return Ok(true);
+                }
+                _ => self.machine.step(instr),
+            };
+            if result.is_err() && !try!(self.backtrack()) {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Resumes from the newest choice point that still has an
+    /// alternative, returning false if there is none (or none before
+    /// the barrier of the current subcomputation).
+    fn backtrack(&mut self) -> Result<bool, Error> {
+        loop {
+            match self.machine.backtrack() {
+                Resume::Nothing | Resume::Barrier => return Ok(false),
+                Resume::Clause => return Ok(true),
+                Resume::Builtin(f, redo, next) => {
+                    let instr = Instruction::Call(f);
+                    match self.call_builtin(f, instr, next, Some(redo)) {
+                        Ok(Ok(())) => return Ok(true),
+                        Ok(Err(())) => { }
+                        Err(error) => {
+                            try!(self.throw(error, f));
+                            return Ok(true);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn call(&mut self, f: Functor, last: bool) -> Result<Fallible, Error> {
+        if f.arity() > 0 && f.with_text(|text| text == "call") || f == module::colon() {
+            return self.meta_call(f, last);
+        }
+        if f == functor!(catch/3) {
+            return self.catch(last);
+        }
+        if f == functor!(phrase/2) || f == functor!(phrase/3) {
+            return self.phrase(f, last);
+        }
+        if self.builtins.contains_key(&f) {
+            let next = self.machine.enter_builtin(f, last);
+            let instr = if last { Instruction::Execute(f) } else { Instruction::Call(f) };
+            return self.call_builtin(f, instr, next, None);
+        }
+
+        let clauses = match self.database.resolve(f) {
+            Some(predicate) => predicate.clauses.clone(),
+            None => match self.flags.unknown {
+                Unknown::Error => return Err(Error::UnknownPredicate(f)),
+                Unknown::Fail => Arc::new(vec![]),
+            },
+        };
+        Ok(self.machine.call(f, clauses, last))
+    }
+
+    /// Calls `call/N` (the goal in A1, with A2..An added to its
+    /// arguments) or `M:G`.
+    fn meta_call(&mut self, f: Functor, last: bool) -> Result<Fallible, Error> {
+        let mut vars = HashMap::new();
+        let goal = if f == module::colon() {
+            let terms = vec![self.machine.read_argument(1, &mut vars),
+                             self.machine.read_argument(2, &mut vars)];
+            try!(self.goal(Term::Structure(Structure { functor: f, terms: terms }), vec![]))
+        } else {
+            try!(self.read_goal(f.arity(), &mut vars))
+        };
+        Ok(self.call_goals(vec![goal], &vars, last))
+    }
+
+    /// The goal in A1, with A2..An (up to `arity`) added to its
+    /// arguments. The cells of its variables are recorded in `vars`.
+    fn read_goal(&self, arity: usize, vars: &mut HashMap<InternedString, Cell>)
+                 -> Result<Term, Error> {
+        let goal = self.machine.read_argument(1, vars);
+        let extra = (2..arity+1).map(|i| self.machine.read_argument(i, vars)).collect();
+        self.goal(goal, extra)
+    }
+
+    /// `goal` with `extra` added to its arguments. If it is qualified
+    /// as `M:G`, the goal runs in module `M`.
+    fn goal(&self, goal: Term, extra: Vec<Term>) -> Result<Term, Error> {
+        let (module, goal) = try!(module::strip(goal));
+        let mut goal = match goal {
+            Term::Structure(s) => s,
+            Term::Variable(_) => return Err(Error::Instantiation),
+            term @ Term::Integer(_) | term @ Term::String(_) => {
+                return Err(Error::Type("callable", term));
+            }
+        };
+        goal.terms.extend(extra);
+        let arity = goal.terms.len();
+        goal.functor = goal.functor.with_text(|text| {
+            Functor::transient(text, arity)
+        });
+        let goal = Term::Structure(goal);
+        if !callable_body(&goal) {
+            return Err(Error::Type("callable", goal));
+        }
+        Ok(match module {
+            Some(module) => module::qualify_goal(self, module, &goal),
+            None => goal,
+        })
+    }
+
+    /// Calls `goals`, whose variables have the cells in `vars`. They
+    /// are compiled as the body of a clause of their own, so that a cut
+    /// within them cuts no further than this call.
+    fn call_goals(&mut self, goals: Vec<Term>, vars: &HashMap<InternedString, Cell>, last: bool)
+                  -> Fallible {
+        let mut names = vec![];
+        for goal in &goals {
+            goal.variables(&mut names);
+        }
+        let head = Structure { functor: Functor::new(intern::intern("$call"), names.len()),
+                               terms: names.iter().map(|&v| Term::Variable(v)).collect() };
+        let clause = Clause { head: head, body: goals };
+        let code = Arc::new(compile::clause(&clause));
+        for (i, v) in names.iter().enumerate() {
+            self.machine.set_argument(i + 1, vars[v]);
+        }
+        self.machine.call(clause.head.functor, Arc::new(vec![code]), last)
+    }
+
+    /// Runs `M:attr_unify_hook(Value, Other)` for each attribute
+    /// `M`-`Value` of the attributed variables bound since the last
+    /// call, where `Other` is what the variable was bound to, and then
+    /// calls `f`, if any, with the arguments it was about to get.
+    /// Attributes whose module defines no hook are left alone.
+    fn wake(&mut self, f: Option<Functor>, last: bool) -> Fallible {
+        let mut vars = HashMap::new();
+        let mut goals = vec![];
+        for (attrs, other) in self.machine.take_wakeups() {
+            for (module, value) in builtins::attribute_list(self, attrs) {
+                let hook = match self.machine.cell_term(module) {
+                    Term::Structure(ref m) if m.terms.is_empty() => {
+                        let m = m.functor.with_text(|text| intern::intern(text));
+                        module::qualify(m, functor!(attr_unify_hook/2))
+                    }
+                    _ => continue,
+                };
+                if self.database.resolve(hook).is_some() {
+                    let terms = vec![self.machine.read_cell(value, &mut vars),
+                                     self.machine.read_cell(other, &mut vars)];
+                    goals.push(Term::Structure(Structure { functor: hook, terms: terms }));
+                }
+            }
+        }
+        if let Some(f) = f {
+            let terms = (1..f.arity()+1).map(|i| self.machine.read_argument(i, &mut vars));
+            goals.push(Term::Structure(Structure { functor: f, terms: terms.collect() }));
+        }
+        if goals.is_empty() {
+            goals.push(Term::atom("true"));
+        }
+        self.call_goals(goals, &vars, last)
+    }
+
+    /// Calls `catch(Goal, Catcher, Recovery)`: runs `Goal` behind a
+    /// catch frame, which `throw` can return to until `Goal` succeeds.
+    fn catch(&mut self, last: bool) -> Result<Fallible, Error> {
+        let flag = self.machine.push_catch(last);
+        let mut vars = HashMap::new();
+        let goal = try!(self.read_goal(1, &mut vars));
+        let exit = intern::intern("$flag");
+        vars.insert(exit, Cell::Ref(flag));
+        let exit = Term::Structure(Structure {
+            functor: Functor::new(intern::intern("$exit_catch"), 1),
+            terms: vec![Term::Variable(exit)],
+        });
+        // the continuation was saved along with the catch frame
+        Ok(self.call_goals(vec![goal, exit], &vars, true))
+    }
+
+    /// Calls `phrase(Body, List)` or `phrase(Body, List, Rest)`, which
+    /// parse `List` with the grammar rule body `Body`, leaving `Rest`
+    /// (or nothing).
+    fn phrase(&mut self, f: Functor, last: bool) -> Result<Fallible, Error> {
+        let mut vars = HashMap::new();
+        let body = self.machine.read_argument(1, &mut vars);
+        let list = self.machine.read_argument(2, &mut vars);
+        let rest = if f.arity() == 3 {
+            self.machine.read_argument(3, &mut vars)
+        } else {
+            Term::nil()
+        };
+        if let Term::Variable(_) = body {
+            return Err(Error::Instantiation);
+        }
+        try!(builtins::list(&list));
+        try!(builtins::list(&rest));
+        let goal = try!(dcg::phrase(&body, list, rest));
+        Ok(self.call_goals(vec![goal], &vars, last))
+    }
+
+    /// Raises `error`, which arose in a call of `f`: unwinds to the
+    /// newest running `catch/3` whose catcher unifies with its ball,
+    /// and starts running the recovery goal. If there is none in the
+    /// current (sub)computation, returns `error`.
+    fn throw(&mut self, error: Error, f: Functor) -> Result<(), Error> {
+        let ball = error.ball(f);
+        while self.machine.unwind_catch() {
+            let thrown = Cell::Ref(self.machine.put_term(&ball, &mut HashMap::new()));
+            let catcher = self.machine.argument_cell(2);
+            if self.machine.unifiable_cells(thrown, catcher) {
+                let _ = self.machine.unify_cells(thrown, catcher);
+                let recovery = self.machine.argument_cell(3);
+                self.machine.set_argument(1, recovery);
+                let call = functor!(call/1);
+                return match self.meta_call(call, true) {
+                    Ok(_) => Ok(()),
+                    Err(error) => self.throw(error, call),
+                };
+            }
+        }
+        Err(error)
+    }
+
+    fn call_builtin(&mut self, f: Functor, instr: Instruction, next: CodePtr,
+                    redo: Option<Redo>) -> Result<Fallible, Error> {
+        let builtin = self.builtins[&f];
+        self.calling = Some((f, next.clone()));
+        let result = try!(builtin(self, redo));
+        Ok(self.machine.exit_builtin(instr, next, result))
+    }
+
+    /// Runs the conjunction `goals` on behalf of the running builtin,
+    /// calling `each` after every solution until it returns false or
+    /// there are no more. `vars` holds the cells of variables bound
+    /// outside of `goals`. Once done, every binding made by `goals` is
+    /// undone.
+    fn solve<F>(&mut self, goals: &[Term], vars: &HashMap<InternedString, Cell>, mut each: F)
+                -> Result<(), Error>
+        where F: FnMut(&mut Engine<O>) -> Result<bool, Error>
+    {
+        let mut args = vec![];
+        for goal in goals {
+            goal.variables(&mut args);
+        }
+        args.retain(|v| vars.contains_key(v));
+
+        let compiled = compile::goal(goals, &args);
+        let (f, next) = self.calling.clone().expect("solve outside of a builtin");
+        let barrier = self.machine.push_goal(Arc::new(compiled.code), f.arity());
+        for (i, v) in args.iter().enumerate() {
+            self.machine.set_argument(i + 1, vars[v]);
+        }
+
+        let result = self.solutions(barrier, &mut each);
+        if result.is_err() {
+            // undo everything, as if the goals had failed
+            self.machine.cut_goal(barrier);
+            try!(self.backtrack());
+        }
+        self.calling = Some((f, next));
+        result
+    }
+
+    fn solutions<F>(&mut self, barrier: usize, each: &mut F) -> Result<(), Error>
+        where F: FnMut(&mut Engine<O>) -> Result<bool, Error>
+    {
+        let mut found = try!(self.run());
+        while found {
+            if !try!(each(self)) {
+                self.machine.cut_goal(barrier);
+                try!(self.backtrack());
+                break;
+            }
+            found = try!(self.backtrack()) && try!(self.run());
+        }
+        Ok(())
+    }
+
+    /// Arranges for the running builtin to be called again with `redo`
+    /// on backtracking. This must happen before it binds anything.
+    fn retry(&mut self, redo: Redo) {
+        let (f, next) = self.calling.clone().expect("retry outside of a builtin");
+        self.machine.push_retry(f, redo, next);
+    }
+}
+
+impl Error {
+    /// The ball thrown for this error, which arose in a call of `f`:
+    /// an ISO error term such as `error(type_error(T, C), f/N)`.
+    pub fn ball(&self, f: Functor) -> Term {
+        let formal = match *self {
+            Error::UnknownPredicate(g) => {
+                Term::Structure(Structure { functor: functor!(existence_error/2),
+                                            terms: vec![Term::atom("procedure"),
+                                                        module::indicator(g)] })
+            }
+            Error::Instantiation => Term::atom("instantiation_error"),
+            Error::Uninstantiation(ref culprit) => {
+                Term::Structure(Structure { functor: functor!(uninstantiation_error/1),
+                                            terms: vec![culprit.clone()] })
+            }
+            Error::Type(kind, ref culprit) => {
+                Term::Structure(Structure { functor: functor!(type_error/2),
+                                            terms: vec![Term::atom(kind), culprit.clone()] })
+            }
+            Error::Domain(domain, ref culprit) => {
+                Term::Structure(Structure { functor: functor!(domain_error/2),
+                                            terms: vec![Term::atom(domain), culprit.clone()] })
+            }
+            Error::Permission(action, kind, ref culprit) => {
+                Term::Structure(Structure { functor: functor!(permission_error/3),
+                                            terms: vec![Term::atom(action),
+                                                        Term::atom(kind),
+                                                        culprit.clone()] })
+            }
+            Error::Existence(kind, ref culprit) => {
+                Term::Structure(Structure { functor: functor!(existence_error/2),
+                                            terms: vec![Term::atom(kind), culprit.clone()] })
+            }
+            Error::Syntax(ref message) => {
+                Term::Structure(Structure { functor: functor!(syntax_error/1),
+                                            terms: vec![Term::atom(message)] })
+            }
+            Error::Representation(what) => {
+                Term::Structure(Structure { functor: functor!(representation_error/1),
+                                            terms: vec![Term::atom(what)] })
+            }
+            Error::Exception(ref ball) => return ball.clone(),
+        };
+        Term::Structure(Structure { functor: functor!(error/2),
+                                    terms: vec![formal, Term::indicator(f)] })
+    }
+}
+
+/// Whether `goal` is callable, as are the goals within any control
+/// constructs it is made of. Variables are called when reached.
+fn callable_body(goal: &Term) -> bool {
+    match *goal {
+        Term::Variable(_) => true,
+        Term::Integer(_) | Term::String(_) => false,
+        Term::Structure(ref s) => {
+            let control = s.terms.len() == 2 &&
+                          s.functor.with_text(|text| match text {
+                              "," | ";" | "->" | "*->" => true,
+                              _ => false,
+                          });
+            !control || s.terms.iter().all(callable_body)
+        }
+    }
+}
+
+impl<O: MachineObserver> MarkFunctors for Engine<O> {
+    fn mark_functors(&self, marks: &mut Marks) {
+        self.machine.mark_functors(marks);
+        self.database.mark_functors(marks);
+        self.flags.mark_functors(marks);
+        self.operators.mark_functors(marks);
+        self.streams.mark_functors(marks);
+        for &f in self.builtins.keys() {
+            marks.mark(f);
+        }
+        if let Some((f, ref ptr)) = self.calling {
+            marks.mark(f);
+            ptr.mark_functors(marks);
+        }
+    }
+}
+
+// Flags are plain enums, and operators and streams keep their names
+// as strings, so none of them hold on to a functor.
+
+impl MarkFunctors for Flags {
+    fn mark_functors(&self, _marks: &mut Marks) { }
+}
+
+impl MarkFunctors for Operators {
+    fn mark_functors(&self, _marks: &mut Marks) { }
+}
+
+impl MarkFunctors for Streams {
+    fn mark_functors(&self, _marks: &mut Marks) { }
+}
+
+impl Default for Flags {
+    fn default() -> Flags {
+        Flags { unknown: Unknown::Error, double_quotes: DoubleQuotes::Codes }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Database
+
+impl Database {
+    pub fn new() -> Database {
+        Database::default()
+    }
+
+    /// Compiles `clause` and adds it after any existing clauses for
+    /// its predicate, which is static unless declared dynamic.
+    pub fn add_clause(&mut self, clause: &Clause) {
+        let code = compile::clause(clause);
+        let predicate = self.entry(clause.head.functor);
+        Arc::make_mut(&mut predicate.clauses).push(Arc::new(code));
+    }
+
+    /// Adds `clause` to a dynamic predicate, before any existing
+    /// clauses if `first`, or otherwise after them.
+    pub fn assert(&mut self, clause: &Clause, first: bool) -> Result<(), Error> {
+        let f = clause.head.functor;
+        try!(self.declare_dynamic(f));
+        let code = Arc::new(compile::clause(clause));
+        let clauses = Arc::make_mut(&mut self.entry(f).clauses);
+        if first {
+            clauses.insert(0, code);
+        } else {
+            clauses.push(code);
+        }
+        Ok(())
+    }
+
+    /// Removes `code` from the dynamic predicate `f`, returning false
+    /// if it was already gone.
+    pub fn retract(&mut self, f: Functor, code: &Arc<Code>) -> bool {
+        let predicate = match self.predicate_mut(f) {
+            Some(predicate) => predicate,
+            None => return false,
+        };
+        match predicate.clauses.iter().position(|c| Arc::ptr_eq(c, code)) {
+            Some(index) => {
+                Arc::make_mut(&mut predicate.clauses).remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Declares `f` dynamic, creating it with no clauses if need be.
+    pub fn declare_dynamic(&mut self, f: Functor) -> Result<(), Error> {
+        if self.predicate(f).is_none() {
+            self.entry(f).dynamic = true;
+        }
+        if self.entry(f).dynamic {
+            Ok(())
+        } else {
+            Err(Error::Permission("modify", "static_procedure", module::indicator(f)))
+        }
+    }
+
+    /// Declares that the clauses of `f` need not be together in a
+    /// source file, creating it with no clauses if need be.
+    pub fn declare_discontiguous(&mut self, f: Functor) {
+        self.entry(f).discontiguous = true;
+    }
+
+    /// Declares how the predicate `f` treats its arguments, creating
+    /// it with no clauses if need be.
+    pub fn declare_meta(&mut self, f: Functor, meta: Vec<MetaArg>) {
+        self.entry(f).meta = Some(meta);
+    }
+
+    /// Removes every clause of `f`, which keeps its declarations.
+    pub fn wipe(&mut self, f: Functor) {
+        if let Some(predicate) = self.predicate_mut(f) {
+            predicate.clauses = Arc::new(vec![]);
+        }
+    }
+
+    /// Removes the dynamic predicate `f` altogether.
+    pub fn abolish(&mut self, f: Functor) -> Result<(), Error> {
+        match self.predicate(f) {
+            Some(predicate) if !predicate.dynamic => {
+                return Err(Error::Permission("modify", "static_procedure", module::indicator(f)));
+            }
+            _ => { }
+        }
+        let (name, f) = self.locate(f);
+        if let Some(module) = self.module_mut(name) {
+            module.predicates.remove(&f);
+        }
+        Ok(())
+    }
+
+    /// The predicate with the functor `f`, which is qualified if it is
+    /// not in `user`.
+    pub fn predicate(&self, f: Functor) -> Option<&Predicate> {
+        let (name, f) = self.locate(f);
+        self.module(name).and_then(|module| module.predicates.get(&f))
+    }
+
+    fn predicate_mut(&mut self, f: Functor) -> Option<&mut Predicate> {
+        let (name, f) = self.locate(f);
+        self.module_mut(name).and_then(|module| module.predicates.get_mut(&f))
+    }
+
+    /// The predicate `f`, created with no clauses if need be.
+    fn entry(&mut self, f: Functor) -> &mut Predicate {
+        let (name, f) = self.locate(f);
+        let module = match name {
+            Some(name) => self.modules.entry(name).or_insert_with(Module::default),
+            None => &mut self.user,
+        };
+        module.predicates.entry(f).or_insert_with(Predicate::default)
+    }
+
+    /// The module of `f` (`None` for `user`), and its functor there.
+    fn locate(&self, f: Functor) -> (Option<InternedString>, Functor) {
+        match module::split(f) {
+            Some((name, f)) => (Some(name), f),
+            None => (None, f),
+        }
+    }
+
+    /// The predicate that a call of `f` reaches: the one its module
+    /// defines, or else imports, or else the one in `user`.
+    pub fn resolve(&self, f: Functor) -> Option<&Predicate> {
+        let (name, f) = self.locate(f);
+        self.module(name)
+            .and_then(|module| self.visible(module, f))
+            .or_else(|| self.visible(&self.user, f))
+    }
+
+    fn visible<'d>(&'d self, module: &'d Module, f: Functor) -> Option<&'d Predicate> {
+        module.predicates.get(&f).or_else(|| {
+            module.imports
+                  .get(&f)
+                  .and_then(|name| self.modules.get(name))
+                  .and_then(|from| from.predicates.get(&f))
+        })
+    }
+
+    fn module(&self, name: Option<InternedString>) -> Option<&Module> {
+        match name {
+            Some(name) if name != module::user() => self.modules.get(&name),
+            _ => Some(&self.user),
+        }
+    }
+
+    fn module_mut(&mut self, name: Option<InternedString>) -> Option<&mut Module> {
+        match name {
+            Some(name) if name != module::user() => self.modules.get_mut(&name),
+            _ => Some(&mut self.user),
+        }
+    }
+
+    /// Starts the module `name`, which exports `exports`, or starts it
+    /// afresh if it exists.
+    pub fn declare_module(&mut self, name: InternedString, exports: Vec<Functor>) {
+        self.modules.entry(name).or_insert_with(Module::default).exports = exports;
+    }
+
+    /// Makes the predicates `only` (or, if `None`, every one exported
+    /// by the module `from`) visible in the module `into`.
+    pub fn import(&mut self, into: InternedString, from: InternedString,
+                  only: Option<Vec<Functor>>) -> Result<(), Error> {
+        let exports = match self.modules.get(&from) {
+            Some(module) => module.exports.clone(),
+            None => return Err(Error::Existence("module", Term::atom(&from.to_string()))),
+        };
+        let functors = only.unwrap_or_else(|| exports.clone());
+        for &f in &functors {
+            if !exports.contains(&f) {
+                let culprit = module::indicator(module::qualify(from, f));
+                return Err(Error::Permission("import", "private_procedure", culprit));
+            }
+        }
+        let into = if into == module::user() {
+            &mut self.user
+        } else {
+            self.modules.entry(into).or_insert_with(Module::default)
+        };
+        for f in functors {
+            into.imports.insert(f, from);
+        }
+        Ok(())
+    }
+
+    /// The functors of every predicate in `user`, ordered by name and
+    /// arity.
+    pub fn functors(&self) -> Vec<Functor> {
+        let mut functors: Vec<_> = self.user.predicates.keys().cloned().collect();
+        functors.sort_by_key(by_indicator);
+        functors
+    }
+
+    /// The clauses of `f` as Prolog source, in the format of
+    /// `listing/1`, with variables renamed `A`, `B`, ...
+    pub fn listing(&self, f: Functor) -> String {
+        let predicate = match self.predicate(f) {
+            Some(predicate) => predicate,
+            None => return String::new(),
+        };
+        let mut out = String::new();
+        if predicate.dynamic {
+            let name = f.with_text(|text| Term::atom(text));
+            out.push_str(&format!(":- dynamic {}/{}.\n\n", name, f.arity()));
+        }
+        for code in predicate.clauses.iter() {
+            if let Some(ref clause) = code.source {
+                out.push_str(&format!("{}\n", rename_variables(clause)));
+            }
+        }
+        out.push('\n');
+        out
+    }
+
+    /// The compiled code of each clause of `f`, as `wam_listing/1`
+    /// prints it: disassembled and labeled with `f`, one clause after
+    /// another.
+    pub fn wam_listing(&self, f: Functor) -> String {
+        let mut out = String::new();
+        if let Some(predicate) = self.predicate(f) {
+            for code in predicate.clauses.iter() {
+                out.push_str(&format!("{}\n", Listing::labeled(f, &code.instructions)));
+            }
+        }
+        out
+    }
+}
+
+fn profiler<O: Any>(observer: &O) -> Option<&Profiler> {
+    (observer as &dyn Any).downcast_ref()
+}
+
+fn by_indicator(f: &Functor) -> (String, usize) {
+    (f.with_text(|text| text.to_string()), f.arity())
+}
+
+fn rename_variables(clause: &Clause) -> Clause {
+    fn rename(term: &Term, names: &mut HashMap<InternedString, InternedString>) -> Term {
+        match *term {
+            Term::Variable(v) => {
+                let next = names.len();
+                Term::Variable(*names.entry(v).or_insert_with(|| {
+                    let letter = (b'A' + (next % 26) as u8) as char;
+                    match next / 26 {
+                        0 => intern::intern(&letter.to_string()),
+                        n => intern::intern(&format!("{}{}", letter, n)),
+                    }
+                }))
+            }
+            Term::Structure(ref s) => {
+                Term::Structure(Structure {
+                    functor: s.functor,
+                    terms: s.terms.iter().map(|t| rename(t, names)).collect(),
+                })
+            }
+            Term::Integer(_) | Term::String(_) => term.clone(),
+        }
+    }
+
+    let mut names = HashMap::new();
+    let head = match rename(&Term::Structure(clause.head.clone()), &mut names) {
+        Term::Structure(s) => s,
+        _ => unreachable!(),
+    };
+    let body = clause.body.iter().map(|goal| rename(goal, &mut names)).collect();
+    Clause { head: head, body: body }
+}
+
+impl MarkFunctors for Database {
+    fn mark_functors(&self, marks: &mut Marks) {
+        self.user.mark_functors(marks);
+        for module in self.modules.values() {
+            module.mark_functors(marks);
+        }
+    }
+}
+
+impl MarkFunctors for Module {
+    fn mark_functors(&self, marks: &mut Marks) {
+        for (&f, predicate) in &self.predicates {
+            marks.mark(f);
+            predicate.clauses.mark_functors(marks);
+        }
+        for &f in self.exports.iter().chain(self.imports.keys()) {
+            marks.mark(f);
+        }
+    }
+}
+
+impl Predicate {
+    pub fn clauses(&self) -> &Arc<Vec<Arc<Code>>> {
+        &self.clauses
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.dynamic
+    }
+
+    pub fn is_discontiguous(&self) -> bool {
+        self.discontiguous
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Queries
+
+impl<'engine, O: MachineObserver> Query<'engine, O> {
+    fn answer(&self) -> Answer {
+        let bindings =
+            self.variables.iter()
+                          .filter(|&&(v, _)| !v.to_string().starts_with('_'))
+                          .map(|&(v, y)| (v, self.engine.machine.permanent_term(y)))
+                          .collect();
+        Answer { bindings: bindings }
+    }
+}
+
+impl<'engine, O: MachineObserver> Iterator for Query<'engine, O> {
+    type Item = Result<Answer, Error>;
+
+    fn next(&mut self) -> Option<Result<Answer, Error>> {
+        let _entered = self.engine.owner.enter();
+        let result = match self.state {
+            State::Fresh => {
+                self.state = State::Running;
+                self.engine.run()
+            }
+            State::Running => {
+                match self.engine.backtrack() {
+                    Ok(true) => self.engine.run(),
+                    other => other,
+                }
+            }
+            State::Done => return None,
+        };
+        match result {
+            Ok(true) => Some(Ok(self.answer())),
+            Ok(false) => {
+                self.state = State::Done;
+                None
+            }
+            Err(err) => {
+                self.state = State::Done;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl Answer {
+    pub fn bindings(&self) -> &[(InternedString, Term)] {
+        &self.bindings
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Term> {
+        self.bindings.iter()
+                     .find(|&&(v, _)| v.to_string() == name)
+                     .map(|&(_, ref term)| term)
+    }
+}
+
+impl Debug for Answer {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        if self.bindings.is_empty() {
+            return write!(fmt, "true");
+        }
+        let mut sep = "";
+        for &(v, ref term) in &self.bindings {
+            try!(write!(fmt, "{}{} = {:?}", sep, v, term));
+            sep = ", ";
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+//! Modules, which give each library a namespace of its own.
+//!
+//! The predicate `p/N` of module `m` is known outside `m` by the
+//! qualified functor `'m:p'/N`, and code loaded into `m` calls the
+//! predicates it mentions by their qualified functors. Which
+//! predicate such a call reaches is decided when it is made: the one
+//! `m` defines, or else the one it imports, or else the one in `user`.
+//! Predicates of `user` keep their own functors.
+//!
+//! A goal passed to a meta-predicate is qualified with the module it
+//! came from, as `m:G`, so that it runs there wherever it is called.
+
+use ast::{Structure, Term};
+use functor::{self, Functor};
+use intern::{self, InternedString};
+use machine::observer::MachineObserver;
+
+use super::{Engine, Error};
+
+/// How a predicate treats one of its arguments, as declared by
+/// `meta_predicate/1`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MetaArg {
+    /// A goal, or another term that depends on the calling module:
+    /// `0`..`9` or `:`.
+    Qualified,
+    /// A goal that may have `V^` prefixes, as in `bagof/3`: `^`.
+    Existential,
+    /// Any other argument: `+`, `-` or `?`.
+    Normal,
+}
+
+/// The module-sensitive arguments of the builtins, in the notation of
+/// `meta_predicate/1`.
+const BUILTINS: &'static [(&'static str, &'static str)] = &[
+    ("findall", "?0-"), ("findall", "?0-?"), ("bagof", "?^-"), ("setof", "?^-"),
+    ("aggregate_all", "?0-"), ("predsort", "3+-"), ("catch", "0?0"),
+    ("assert", ":"), ("asserta", ":"), ("assertz", ":"), ("retract", ":"),
+    ("retractall", ":"), ("abolish", ":"), ("dynamic", ":"), ("discontiguous", ":"),
+    ("meta_predicate", ":"), ("consult", ":"), ("ensure_loaded", ":"),
+    ("use_module", ":"), ("use_module", ":?"), ("phrase", "2?"), ("phrase", "2??"),
+];
+
+pub fn user() -> InternedString {
+    intern::intern("user")
+}
+
+pub fn colon() -> Functor {
+    functor::functors().functor(":", 2, true)
+}
+
+/// The functor by which `f`, a predicate of `module`, is known outside
+/// it.
+pub fn qualify(module: InternedString, f: Functor) -> Functor {
+    if module == user() {
+        return f;
+    }
+    let name = f.with_text(|text| format!("{}:{}", module, text));
+    functor::functors().functor(&name, f.arity(), true)
+}
+
+/// The module of the qualified functor `f`, and its functor there;
+/// `None` if `f` is a predicate of `user`.
+pub fn split(f: Functor) -> Option<(InternedString, Functor)> {
+    f.with_text(|text| match text.find(':') {
+        Some(i) if i > 0 && i + 1 < text.len() => {
+            Some((intern::intern(&text[..i]),
+                  functor::functors().functor(&text[i + 1..], f.arity(), true)))
+        }
+        _ => None,
+    })
+}
+
+/// The predicate indicator for `f`, as `m:name/N` if it is qualified.
+pub fn indicator(f: Functor) -> Term {
+    match split(f) {
+        Some((module, f)) => qualified(module, Term::indicator(f)),
+        None => Term::indicator(f),
+    }
+}
+
+/// The term `module:term`.
+pub fn qualified(module: InternedString, term: Term) -> Term {
+    Term::Structure(Structure { functor: colon(),
+                                terms: vec![Term::atom(&module.to_string()), term] })
+}
+
+/// Removes the `M:` prefixes from `term`, returning the innermost
+/// module, if any, along with what is left.
+pub fn strip(mut term: Term) -> Result<(Option<InternedString>, Term), Error> {
+    let mut module = None;
+    loop {
+        term = match term {
+            Term::Structure(ref s) if s.functor == colon() => {
+                match s.terms[0] {
+                    Term::Variable(_) => return Err(Error::Instantiation),
+                    Term::Structure(ref m) if m.terms.is_empty() => {
+                        module = Some(m.functor.with_text(|text| intern::intern(text)));
+                    }
+                    ref m => return Err(Error::Type("atom", m.clone())),
+                }
+                s.terms[1].clone()
+            }
+            term => return Ok((module, term)),
+        };
+    }
+}
+
+/// The arguments of a `meta_predicate/1` declaration.
+pub fn meta_args(args: &[Term]) -> Result<Vec<MetaArg>, Error> {
+    args.iter().map(|arg| {
+        let spec = match *arg {
+            Term::Integer(n) if n >= 0 && n <= 9 => return Ok(MetaArg::Qualified),
+            Term::Structure(ref s) if s.terms.is_empty() => s.functor.with_text(meta_arg),
+            _ => None,
+        };
+        spec.ok_or_else(|| Error::Domain("meta_argument_specifier", arg.clone()))
+    }).collect()
+}
+
+fn meta_arg(text: &str) -> Option<MetaArg> {
+    match text {
+        ":" => Some(MetaArg::Qualified),
+        "^" => Some(MetaArg::Existential),
+        "+" | "-" | "?" => Some(MetaArg::Normal),
+        _ => None,
+    }
+}
+
+/// `goal` as it runs in `module`: calls of predicates are qualified,
+/// as are the arguments of meta-predicates that depend on the module.
+/// Goals in `user` pass to the builtins as they are.
+pub fn qualify_goal<O: MachineObserver>(engine: &Engine<O>, module: InternedString, goal: &Term)
+                                        -> Term {
+    let in_user = module == user();
+    let s = match *goal {
+        Term::Variable(_) if !in_user => return qualified(module, goal.clone()),
+        Term::Structure(ref s) => s,
+        _ => return goal.clone(),
+    };
+    let name = s.functor.with_text(|text| text.to_string());
+    let arity = s.terms.len();
+    let (functor, meta) = match (&name[..], arity) {
+        (",", 2) | (";", 2) | ("->", 2) | ("*->", 2) | ("\\+", 1) | ("not", 1) |
+        ("once", 1) | ("ignore", 1) | ("forall", 2) => {
+            let terms = s.terms.iter().map(|t| qualify_goal(engine, module, t)).collect();
+            return Term::Structure(Structure { functor: s.functor, terms: terms });
+        }
+        ("!", 0) | ("true", 0) | ("fail", 0) | ("false", 0) | (":", 2) => return goal.clone(),
+        ("call", _) | ("catch", 3) if in_user => return goal.clone(),
+        ("call", _) if arity > 0 => {
+            let mut meta = vec![MetaArg::Normal; arity];
+            meta[0] = MetaArg::Qualified;
+            (s.functor, meta)
+        }
+        _ if engine.builtins.contains_key(&s.functor) || name == "catch" && arity == 3 ||
+             name == "phrase" && (arity == 2 || arity == 3) => {
+            let spec = BUILTINS.iter().find(|&&(n, spec)| n == name && spec.len() == arity);
+            match spec {
+                Some(&(_, spec)) if !in_user => {
+                    let meta = spec.chars().map(|c| match c {
+                        '0'..='9' => MetaArg::Qualified,
+                        c => meta_arg(&c.to_string()).unwrap(),
+                    });
+                    (s.functor, meta.collect())
+                }
+                _ => return goal.clone(),
+            }
+        }
+        _ => {
+            let f = qualify(module, s.functor);
+            match engine.database.resolve(f).and_then(|p| p.meta.clone()) {
+                Some(meta) => (f, meta),
+                None => (f, vec![MetaArg::Normal; arity]),
+            }
+        }
+    };
+    let terms = s.terms.iter().zip(meta).map(|(term, meta)| match meta {
+        MetaArg::Qualified => qualify_argument(module, term),
+        MetaArg::Existential => existential(module, term),
+        MetaArg::Normal => term.clone(),
+    });
+    Term::Structure(Structure { functor: functor, terms: terms.collect() })
+}
+
+fn qualify_argument(module: InternedString, term: &Term) -> Term {
+    match *term {
+        Term::Structure(ref s) if s.functor == colon() => term.clone(),
+        _ => qualified(module, term.clone()),
+    }
+}
+
+/// Qualifies the goal of `V^Goal`.
+fn existential(module: InternedString, term: &Term) -> Term {
+    match *term {
+        Term::Structure(ref s) if s.terms.len() == 2 && s.functor.with_text(|t| t == "^") => {
+            Term::Structure(Structure { functor: s.functor,
+                                        terms: vec![s.terms[0].clone(),
+                                                    existential(module, &s.terms[1])] })
+        }
+        _ => qualify_argument(module, term),
+    }
+}
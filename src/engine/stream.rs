@@ -0,0 +1,350 @@
+//! Streams: the sources and sinks of text that programs read from and
+//! write to. Each is backed by a Rust `Read` or `Write`, so embedders
+//! can plug in in-memory buffers as easily as files.
+//!
+//! Programs refer to a stream by the term `'$stream'(N)`, or by an
+//! alias such as `user_input`.
+
+use ast::{Structure, Term};
+use ast::read::SYMBOLS;
+use functor;
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::str;
+
+pub const USER_INPUT: usize = 0;
+pub const USER_OUTPUT: usize = 1;
+pub const USER_ERROR: usize = 2;
+
+/// The streams of an engine, with its current input and output.
+pub struct Streams {
+    /// Indexed by stream number; a closed stream leaves `None` behind,
+    /// so that numbers are never reused.
+    streams: Vec<Option<Stream>>,
+    aliases: HashMap<String, usize>,
+    input: usize,
+    output: usize,
+}
+
+pub struct Stream {
+    kind: Kind,
+    mode: Mode,
+    alias: Option<String>,
+    file_name: Option<String>,
+}
+
+enum Kind {
+    Input(Input),
+    Output(Box<dyn Write + Send>),
+}
+
+/// The reading end of an input stream.
+pub struct Input {
+    reader: BufReader<Box<dyn Read + Send>>,
+    /// The next character, if it has been peeked at; `Some(None)` at
+    /// the end of the stream.
+    peeked: Option<Option<char>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Read,
+    Write,
+    Append,
+}
+
+impl Streams {
+    /// The three standard streams, on the process's own.
+    pub fn new() -> Streams {
+        let mut streams = Streams { streams: vec![],
+                                    aliases: HashMap::new(),
+                                    input: USER_INPUT,
+                                    output: USER_OUTPUT };
+        streams.add(Stream::input(Box::new(io::stdin())).with_alias("user_input"));
+        streams.add(Stream::output(Box::new(io::stdout()), Mode::Append).with_alias("user_output"));
+        streams.add(Stream::output(Box::new(io::stderr()), Mode::Append).with_alias("user_error"));
+        streams
+    }
+
+    /// Adds `stream`, returning its number. If its alias is already in
+    /// use, the stream that had it loses it.
+    pub fn add(&mut self, stream: Stream) -> usize {
+        let id = self.streams.len();
+        if let Some(ref alias) = stream.alias {
+            if let Some(old) = self.aliases.insert(alias.clone(), id) {
+                if let Some(old) = self.get_mut(old) {
+                    old.alias = None;
+                }
+            }
+        }
+        self.streams.push(Some(stream));
+        id
+    }
+
+    /// Puts `stream` in place of stream `id`, which keeps its alias.
+    pub fn replace(&mut self, id: usize, mut stream: Stream) {
+        stream.alias = self.streams[id].as_ref().and_then(|old| old.alias.clone());
+        self.streams[id] = Some(stream);
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Stream> {
+        self.streams.get(id).and_then(|stream| stream.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Stream> {
+        self.streams.get_mut(id).and_then(|stream| stream.as_mut())
+    }
+
+    /// The stream with the alias `name`.
+    pub fn lookup(&self, name: &str) -> Option<usize> {
+        self.aliases.get(name).cloned()
+    }
+
+    /// Closes stream `id`, flushing it first. The standard streams are
+    /// only flushed; if the current input or output is closed, the
+    /// standard one takes its place.
+    pub fn close(&mut self, id: usize) -> io::Result<()> {
+        let result = match self.get_mut(id) {
+            Some(stream) => stream.flush(),
+            None => return Ok(()),
+        };
+        if id > USER_ERROR {
+            if let Some(Some(stream)) = self.streams.get_mut(id).map(|s| s.take()) {
+                if let Some(alias) = stream.alias {
+                    self.aliases.remove(&alias);
+                }
+            }
+            if self.input == id {
+                self.input = USER_INPUT;
+            }
+            if self.output == id {
+                self.output = USER_OUTPUT;
+            }
+        }
+        result
+    }
+
+    pub fn input(&self) -> usize {
+        self.input
+    }
+
+    pub fn output(&self) -> usize {
+        self.output
+    }
+
+    pub fn set_input(&mut self, id: usize) {
+        self.input = id;
+    }
+
+    pub fn set_output(&mut self, id: usize) {
+        self.output = id;
+    }
+
+    /// Every open stream, with its number.
+    pub fn iter<'s>(&'s self) -> impl Iterator<Item=(usize, &'s Stream)> + 's {
+        self.streams.iter().enumerate().filter_map(|(id, stream)| {
+            stream.as_ref().map(|stream| (id, stream))
+        })
+    }
+}
+
+impl Default for Streams {
+    fn default() -> Streams {
+        Streams::new()
+    }
+}
+
+/// The term `'$stream'(id)` by which programs refer to a stream.
+pub fn stream_term(id: usize) -> Term {
+    Term::Structure(Structure { functor: functor::functors().functor("$stream", 1, true),
+                                terms: vec![Term::Integer(id as i64)] })
+}
+
+impl Stream {
+    pub fn input(reader: Box<dyn Read + Send>) -> Stream {
+        let input = Input { reader: BufReader::new(reader), peeked: None };
+        Stream { kind: Kind::Input(input), mode: Mode::Read, alias: None, file_name: None }
+    }
+
+    /// An output stream; `mode` is `Write` or `Append`.
+    pub fn output(writer: Box<dyn Write + Send>, mode: Mode) -> Stream {
+        Stream { kind: Kind::Output(writer), mode: mode, alias: None, file_name: None }
+    }
+
+    pub fn with_alias(mut self, alias: &str) -> Stream {
+        self.alias = Some(alias.to_string());
+        self
+    }
+
+    pub fn with_file_name(mut self, name: &str) -> Stream {
+        self.file_name = Some(name.to_string());
+        self
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_ref().map(|alias| &alias[..])
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_ref().map(|name| &name[..])
+    }
+
+    pub fn is_input(&self) -> bool {
+        self.mode == Mode::Read
+    }
+
+    pub fn reader(&mut self) -> Option<&mut Input> {
+        match self.kind {
+            Kind::Input(ref mut input) => Some(input),
+            Kind::Output(_) => None,
+        }
+    }
+
+    pub fn writer(&mut self) -> Option<&mut (dyn Write + Send)> {
+        match self.kind {
+            Kind::Input(_) => None,
+            Kind::Output(ref mut writer) => Some(&mut **writer),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.kind {
+            Kind::Input(_) => Ok(()),
+            Kind::Output(ref mut writer) => writer.flush(),
+        }
+    }
+}
+
+impl Input {
+    /// The next character, or `None` at the end of the stream.
+    pub fn read_char(&mut self) -> io::Result<Option<char>> {
+        match self.peeked.take() {
+            Some(c) => Ok(c),
+            None => self.decode(),
+        }
+    }
+
+    /// The next character, without reading it.
+    pub fn peek_char(&mut self) -> io::Result<Option<char>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(try!(self.decode()));
+        }
+        Ok(self.peeked.unwrap())
+    }
+
+    fn decode(&mut self) -> io::Result<Option<char>> {
+        let mut buf = [0; 4];
+        if try!(self.reader.read(&mut buf[..1])) == 0 {
+            return Ok(None);
+        }
+        let len = match buf[0] {
+            b if b < 0x80 => 1,
+            b if b >= 0xf0 => 4,
+            b if b >= 0xe0 => 3,
+            _ => 2,
+        };
+        try!(self.reader.read_exact(&mut buf[1..len]));
+        match str::from_utf8(&buf[..len]) {
+            Ok(text) => Ok(text.chars().next()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid UTF-8")),
+        }
+    }
+
+    fn next_is(&mut self, c: char) -> io::Result<bool> {
+        Ok(try!(self.peek_char()) == Some(c))
+    }
+
+    /// The text of the next clause, up to and including the `.` that
+    /// ends it and the layout character after that, or `None` if only
+    /// layout is left. This follows enough of the syntax (quotes and
+    /// comments) to find the right `.`, and leaves the rest to the
+    /// reader.
+    pub fn read_clause(&mut self) -> io::Result<Option<String>> {
+        let mut text = String::new();
+        let mut blank = true;
+        let mut prev = ' ';
+        while let Some(c) = try!(self.read_char()) {
+            text.push(c);
+            if c == '%' {
+                while let Some(c) = try!(self.read_char()) {
+                    text.push(c);
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                prev = ' ';
+                continue;
+            }
+            if c == '/' && try!(self.next_is('*')) {
+                try!(self.read_char());
+                text.push('*');
+                let mut star = false;
+                while let Some(c) = try!(self.read_char()) {
+                    text.push(c);
+                    if star && c == '/' {
+                        break;
+                    }
+                    star = c == '*';
+                }
+                prev = ' ';
+                continue;
+            }
+            if !c.is_whitespace() {
+                blank = false;
+            }
+            if c == '\'' && prev == '0' {
+                // a character code such as `0'a` or `0'\n`
+                match try!(self.read_char()) {
+                    Some(c @ '\\') | Some(c @ '\'') => {
+                        text.push(c);
+                        if let Some(c) = try!(self.read_char()) {
+                            text.push(c);
+                        }
+                    }
+                    Some(c) => text.push(c),
+                    None => { }
+                }
+            } else if c == '\'' || c == '"' || c == '`' {
+                while let Some(d) = try!(self.read_char()) {
+                    text.push(d);
+                    if d == '\\' {
+                        if let Some(d) = try!(self.read_char()) {
+                            text.push(d);
+                        }
+                    } else if d == c {
+                        break;
+                    }
+                }
+            } else if SYMBOLS.contains(c) {
+                let mut run = 1;
+                while let Some(d) = try!(self.peek_char()) {
+                    if !SYMBOLS.contains(d) {
+                        break;
+                    }
+                    text.push(d);
+                    try!(self.read_char());
+                    run += 1;
+                }
+                if c == '.' && run == 1 {
+                    match try!(self.peek_char()) {
+                        None | Some('%') => return Ok(Some(text)),
+                        Some(d) if d.is_whitespace() => {
+                            try!(self.read_char());
+                            text.push(d);
+                            return Ok(Some(text));
+                        }
+                        Some(_) => { }
+                    }
+                }
+            }
+            // a `0` only starts a number if it does not continue a name
+            // or another number
+            prev = if c.is_alphanumeric() && prev.is_alphanumeric() { 'a' } else { c };
+        }
+        Ok(if blank { None } else { Some(text) })
+    }
+}
@@ -0,0 +1,1102 @@
+use ast::{Clause, Structure};
+use functor::{self, Owner};
+use machine::profile::Profiler;
+use std::env;
+use std::fs;
+use std::io::{self, Cursor, Write};
+use std::panic;
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+
+use super::stream::{Mode, Stream};
+
+use super::*;
+
+fn append() -> Vec<Clause> {
+    vec![clause!(app(nil, ?L, ?L)),
+         clause!(app(cons(?H, ?T), ?L, cons(?H, ?R)) :- app(?T, ?L, ?R))]
+}
+
+fn member() -> Vec<Clause> {
+    vec![clause!(member(?X, cons(?X, ?T))),
+         clause!(member(?X, cons(?H, ?T)) :- member(?X, ?T)),
+         clause!(first(?X, ?L) :- member(?X, ?L), !)]
+}
+
+fn engine<O: MachineObserver>(mut engine: Engine<O>, clauses: &[Clause]) -> Engine<O> {
+    for clause in clauses {
+        engine.add_clause(clause);
+    }
+    engine
+}
+
+fn solve<O: MachineObserver>(engine: &mut Engine<O>, goals: &[Term]) -> Vec<String> {
+    engine.query(goals)
+          .map(|answer| format!("{:?}", answer.unwrap()))
+          .collect()
+}
+
+#[test]
+fn append_forwards_and_backwards() {
+    let mut engine = engine(Engine::new(), &append());
+    assert_eq!(solve(&mut engine, &goals!(app(cons(a, nil), cons(b, nil), ?X))),
+               vec!["X = cons(a,cons(b,nil))"]);
+    assert_eq!(solve(&mut engine, &goals!(app(?X, ?Y, cons(a, cons(b, nil))))),
+               vec!["X = nil, Y = cons(a,cons(b,nil))",
+                    "X = cons(a,nil), Y = cons(b,nil)",
+                    "X = cons(a,cons(b,nil)), Y = nil"]);
+    assert!(solve(&mut engine, &goals!(app(?X, cons(c, nil), cons(a, nil)))).is_empty());
+}
+
+#[test]
+fn conjunction_and_cut() {
+    let mut engine = engine(Engine::new(), &member());
+    assert_eq!(solve(&mut engine, &goals!(member(?X, cons(a, cons(b, nil))),
+                                          member(?X, cons(b, cons(a, nil))))),
+               vec!["X = a", "X = b"]);
+    assert_eq!(solve(&mut engine, &goals!(member(?X, cons(a, cons(b, nil))), !)),
+               vec!["X = a"]);
+    assert_eq!(solve(&mut engine, &goals!(first(?X, cons(b, cons(a, nil))))),
+               vec!["X = b"]);
+}
+
+#[test]
+fn unknown_predicates() {
+    let mut engine = Engine::new();
+    let mut query = engine.query(&goals!(p(?X)));
+    assert_eq!(query.next(), Some(Err(Error::UnknownPredicate(functor!(p/1)))));
+    assert_eq!(query.next(), None);
+    drop(query);
+
+    engine.flags_mut().unknown = Unknown::Fail;
+    assert!(solve(&mut engine, &goals!(p(?X))).is_empty());
+}
+
+#[test]
+fn isolated_engines() {
+    let mut first = engine(Engine::new(), &[clause!(tenant(first))]);
+    let mut second = engine(Engine::new(), &[clause!(tenant(second))]);
+    assert_eq!(solve(&mut first, &goals!(tenant(?X))), vec!["X = first"]);
+    assert_eq!(solve(&mut second, &goals!(tenant(?X))), vec!["X = second"]);
+
+    // engines can also run on other threads
+    let answers = thread::spawn(move || {
+        second.add_clause(&clause!(tenant(third)));
+        solve(&mut second, &goals!(tenant(?X)))
+    }).join().unwrap();
+    assert_eq!(answers, vec!["X = second", "X = third"]);
+    assert_eq!(solve(&mut first, &goals!(tenant(?X))), vec!["X = first"]);
+}
+
+#[test]
+fn collect_atoms() {
+    // atoms read by someone else are claimed by the engine they are
+    // handed to, so they outlive their reader
+    let reader = Owner::new();
+    let (kept, dropped) = {
+        let _entered = reader.enter();
+        (Functor::transient("collect_atoms_kept", 0),
+         Functor::transient("collect_atoms_dropped", 0))
+    };
+    let atom = |f| Term::Structure(Structure { functor: f, terms: vec![] });
+    let mut engine = Engine::new();
+    engine.add_clause(&Clause { head: Structure { functor: functor!(kept/1),
+                                                  terms: vec![atom(kept)] },
+                                body: vec![] });
+    let goal = Term::Structure(Structure { functor: functor!(kept/1), terms: vec![atom(dropped)] });
+    assert!(solve(&mut engine, &[goal]).is_empty());
+    assert_eq!(solve(&mut engine, &goals!(kept(?X))), vec!["X = collect_atoms_kept"]);
+    drop(reader);
+
+    // only what the engine still refers to survives
+    assert_eq!(engine.collect_atoms(), 1);
+    assert_eq!(engine.collect_atoms(), 0);
+    assert_eq!(solve(&mut engine, &goals!(kept(?X))), vec!["X = collect_atoms_kept"]);
+    assert!(panic::catch_unwind(|| dropped.arity()).is_err());
+}
+
+#[test]
+fn profile_predicates() {
+    let mut engine = engine(Engine::with_observer(Profiler::new()), &append());
+    assert_eq!(solve(&mut engine, &goals!(app(?X, ?Y, cons(a, cons(b, nil))))).len(), 3);
+
+    let profiler = engine.observer();
+    assert_eq!(profiler.calls(None, functor!(app/3)), 1);
+    assert_eq!(profiler.calls(Some(functor!(app/3)), functor!(app/3)), 2);
+    assert_eq!(profiler.inferences(), 3);
+    assert_eq!(profiler.predicates()[&Some(functor!(app/3))].calls, 3);
+    assert_eq!(profiler.choice_points(), 3);
+    assert_eq!(profiler.backtracks(), 3);
+    let mut report = vec![];
+    profiler.report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.contains("         2  app/3 -> app/3\n"), "{}", report);
+
+    let output = Output::default();
+    engine.set_output(Box::new(output.clone()));
+    let goal = engine.read_term("statistics(inferences, N), statistics").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["N = 4"]);
+    let report = output.take();
+    assert!(report.contains("         2  app/3 -> app/3\n"), "{}", report);
+    assert!(report.contains("         1  (query) -> statistics/2\n"), "{}", report);
+
+    let mut engine = Engine::new();
+    let goal = engine.read_term("statistics(inferences, N)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["N = 0"]);
+    let goal = engine.read_term("catch(statistics(nothing, _), error(E, _), true)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["E = domain_error(statistics_key,nothing)"]);
+}
+
+#[test]
+fn assert_and_retract() {
+    let mut engine = Engine::new();
+    assert_eq!(solve(&mut engine, &goals!(assertz(q(2)), assertz(q(3)), asserta(q(1)))),
+               vec!["true"]);
+    assert_eq!(solve(&mut engine, &goals!(q(?X))), vec!["X = 1", "X = 2", "X = 3"]);
+
+    assert_eq!(solve(&mut engine, &goals!(retract(q(2)))), vec!["true"]);
+    assert_eq!(solve(&mut engine, &goals!(q(?X))), vec!["X = 1", "X = 3"]);
+
+    // retract/1 is nondeterministic
+    assert_eq!(solve(&mut engine, &goals!(retract(q(?X)))), vec!["X = 1", "X = 3"]);
+    assert!(solve(&mut engine, &goals!(q(?X))).is_empty());
+}
+
+#[test]
+fn assert_rules() {
+    let mut engine = engine(Engine::new(), &append());
+    let rule = Clause { head: structure!(twice(?X, ?Y)),
+                        body: goals!(app(?X, ?X, ?Y)) }.to_term();
+    let mut query = engine.query(&[Term::Structure(Structure { functor: functor!(assert/1),
+                                                               terms: vec![rule] })]);
+    assert!(query.next().unwrap().is_ok());
+    drop(query);
+    assert_eq!(solve(&mut engine, &goals!(twice(cons(a, nil), ?Y))),
+               vec!["Y = cons(a,cons(a,nil))"]);
+}
+
+#[test]
+fn logical_update_view() {
+    let mut engine = Engine::new();
+    assert_eq!(solve(&mut engine, &goals!(assertz(p(a)), assertz(p(b)))).len(), 1);
+
+    // each call sees the clauses as they were when it started, so
+    // this terminates
+    assert_eq!(solve(&mut engine, &goals!(p(?X), assertz(p(?X)))), vec!["X = a", "X = b"]);
+    assert_eq!(solve(&mut engine, &goals!(p(?X))).len(), 4);
+
+    // likewise, clauses retracted while a call is running are still
+    // seen by it
+    assert_eq!(solve(&mut engine, &goals!(p(?X), retractall(p(?Y)))).len(), 4);
+    assert!(solve(&mut engine, &goals!(p(?X))).is_empty());
+}
+
+#[test]
+fn dynamic_declarations() {
+    let mut engine = engine(Engine::new(), &[clause!(fixed(a))]);
+    let dynamic = |f| Term::Structure(Structure { functor: functor!(dynamic/1),
+                                                  terms: vec![Term::indicator(f)] });
+    let abolish = |f| Term::Structure(Structure { functor: functor!(abolish/1),
+                                                  terms: vec![Term::indicator(f)] });
+
+    // a dynamic predicate with no clauses fails instead of raising an error
+    assert_eq!(solve(&mut engine, &[dynamic(functor!(d/1))]), vec!["true"]);
+    assert!(solve(&mut engine, &goals!(d(?X))).is_empty());
+
+    // static predicates cannot be changed
+    let error = Error::Permission("modify", "static_procedure", Term::indicator(functor!(fixed/1)));
+    assert_eq!(engine.query(&goals!(assertz(fixed(b)))).next(), Some(Err(error.clone())));
+    assert_eq!(engine.query(&goals!(retract(fixed(a)))).next(), Some(Err(error.clone())));
+    assert_eq!(engine.query(&[dynamic(functor!(fixed/1))]).next(), Some(Err(error.clone())));
+    assert_eq!(engine.query(&[abolish(functor!(fixed/1))]).next(), Some(Err(error)));
+    assert_eq!(engine.query(&goals!(assertz(?X))).next(), Some(Err(Error::Instantiation)));
+
+    // abolishing a predicate removes it altogether
+    assert_eq!(solve(&mut engine, &goals!(assertz(d(1)))), vec!["true"]);
+    assert_eq!(solve(&mut engine, &[abolish(functor!(d/1))]), vec!["true"]);
+    assert_eq!(engine.query(&goals!(d(?X))).next(),
+               Some(Err(Error::UnknownPredicate(functor!(d/1)))));
+}
+
+#[test]
+fn examine_clauses() {
+    let mut engine = engine(Engine::new(), &append());
+    assert_eq!(solve(&mut engine, &goals!(clause(app(?X, ?Y, ?Z), ?B))).len(), 2);
+    assert_eq!(solve(&mut engine, &goals!(clause(app(nil, a, ?Z), ?B))),
+               vec!["Z = a, B = true"]);
+    assert_eq!(solve(&mut engine, &goals!(clause(app(cons(x, nil), ?L, ?R), ?B))).len(), 1);
+    assert_eq!(engine.query(&goals!(clause(?H, ?B))).next(), Some(Err(Error::Instantiation)));
+    assert_eq!(engine.query(&goals!(clause(assertz(?X), ?B))).next(),
+               Some(Err(Error::Permission("access", "private_procedure",
+                                          Term::indicator(functor!(assertz/1))))));
+}
+
+#[test]
+fn examine_predicates() {
+    let mut engine = engine(Engine::new(), &append());
+    assert_eq!(solve(&mut engine, &goals!(assertz(counter(0)))).len(), 1);
+    assert_eq!(solve(&mut engine, &goals!(current_predicate(?P))),
+               vec!["P = /(app,3)", "P = /(counter,1)"]);
+    let spec = Term::Structure(Structure { functor: functor!(current_predicate/1),
+                                           terms: vec![Term::indicator(functor!(counter/1))] });
+    assert_eq!(solve(&mut engine, &[spec]), vec!["true"]);
+
+    assert_eq!(solve(&mut engine, &goals!(predicate_property(app(?_X, ?_Y, ?_Z), ?P))),
+               vec!["P = static", "P = defined", "P = number_of_clauses(2)"]);
+    assert_eq!(solve(&mut engine, &goals!(predicate_property(counter(?_X), dynamic))),
+               vec!["true"]);
+    assert_eq!(solve(&mut engine, &goals!(predicate_property(assertz(?_X), built_in))),
+               vec!["true"]);
+    assert!(solve(&mut engine, &goals!(predicate_property(missing, ?P))).is_empty());
+
+    assert_eq!(engine.database().listing(functor!(app/3)),
+               "app(nil,A,A).\n\
+                app(cons(A,B),C,cons(A,D)) :-\n    app(B,C,D).\n\n");
+    assert_eq!(engine.database().listing(functor!(counter/1)),
+               ":- dynamic counter/1.\n\ncounter(0).\n\n");
+
+    let output = Output::default();
+    engine.set_output(Box::new(output.clone()));
+    assert_eq!(written(&mut engine, &output, "wam_listing(app/3)"),
+               "app/3 : get_structure nil/0, A1\n\
+                \x20       get_variable X4, A2\n\
+                \x20       get_value X4, A3\n\
+                \x20       proceed\n\
+                \n\
+                app/3 : get_structure cons/2, A1\n\
+                \x20       unify_variable X4\n\
+                \x20       unify_variable X5\n\
+                \x20       get_variable X6, A2\n\
+                \x20       get_structure cons/2, A3\n\
+                \x20       unify_value X4\n\
+                \x20       unify_variable X7\n\
+                \x20       put_value X5, A1\n\
+                \x20       put_value X6, A2\n\
+                \x20       put_value X7, A3\n\
+                \x20       execute app/3\n\n");
+}
+
+fn ages() -> Vec<Clause> {
+    vec![clause!(age(peter, 7)),
+         clause!(age(ann, 11)),
+         clause!(age(pat, 8)),
+         clause!(age(tom, 5)),
+         clause!(age(mike, 11))]
+}
+
+/// The goal `name(args...)`, for names the macros cannot parse.
+fn goal(name: &str, args: Vec<Term>) -> Term {
+    Term::Structure(Structure { functor: functor::functors().functor(name, args.len(), true),
+                                terms: args })
+}
+
+fn list(items: Vec<Term>) -> Term {
+    Term::list(items, Term::nil())
+}
+
+#[test]
+fn findall() {
+    let mut engine = engine(Engine::new(), &ages());
+    assert_eq!(solve(&mut engine, &goals!(findall(?_N, age(?_N, ?_A), ?L))),
+               vec!["L = [peter,ann,pat,tom,mike]"]);
+    assert_eq!(solve(&mut engine, &goals!(findall(?_N, age(?_N, 11), ?L, rest))),
+               vec!["L = [ann,mike|rest]"]);
+    assert_eq!(solve(&mut engine, &goals!(findall(?_N, age(?_N, 99), ?L))), vec!["L = []"]);
+
+    // nested, and with bindings from outside
+    assert_eq!(solve(&mut engine, &goals!(age(peter, ?A), findall(?_N, age(?_N, ?A), ?L))),
+               vec!["A = 7, L = [peter]"]);
+    assert_eq!(solve(&mut engine, &goals!(findall(?_L, findall(?_N, age(?_N, 11), ?_L), ?R))),
+               vec!["R = [[ann,mike]]"]);
+
+    assert_eq!(engine.query(&goals!(findall(?X, ?G, ?L))).next(),
+               Some(Err(Error::Instantiation)));
+}
+
+#[test]
+fn bagof_and_setof() {
+    let mut engine = engine(Engine::new(), &ages());
+    assert_eq!(solve(&mut engine, &goals!(bagof(?_N, age(?_N, ?A), ?L))),
+               vec!["A = 7, L = [peter]",
+                    "A = 11, L = [ann,mike]",
+                    "A = 8, L = [pat]",
+                    "A = 5, L = [tom]"]);
+    assert_eq!(solve(&mut engine, &goals!(setof(?_N, age(?_N, ?A), ?L))),
+               vec!["A = 5, L = [tom]",
+                    "A = 7, L = [peter]",
+                    "A = 8, L = [pat]",
+                    "A = 11, L = [ann,mike]"]);
+    assert!(solve(&mut engine, &goals!(bagof(?_N, age(?_N, 99), ?L))).is_empty());
+
+    let exists = goal("^", vec![term!(?_N), term!(age(?_N, ?_A))]);
+    assert_eq!(solve(&mut engine, &[goal("setof", vec![term!(?_A), exists, term!(?L)])]),
+               vec!["L = [5,7,8,11]"]);
+
+    // variables in the results are ordered as sort/2 orders them: the
+    // copy from an earlier solution is older
+    for i in 1..13 {
+        engine.add_clause(&Clause { head: Structure { functor: functor!(nth/1),
+                                                      terms: vec![Term::Integer(i)] },
+                                    body: vec![] });
+    }
+    let numbers = |answers: Vec<String>| -> Vec<i64> {
+        answers[0].split(',').filter_map(|s| s.split(')').next().unwrap().parse().ok()).collect()
+    };
+    assert_eq!(numbers(solve(&mut engine, &goals!(setof(s(?_V, ?_N), nth(?_N), ?L)))),
+               (1..13).collect::<Vec<_>>());
+    assert_eq!(numbers(solve(&mut engine, &goals!(aggregate_all(set(s(?_V, ?_N)), nth(?_N),
+                                                                ?L)))),
+               (1..13).collect::<Vec<_>>());
+}
+
+#[test]
+fn aggregate_all() {
+    let mut engine = engine(Engine::new(), &ages());
+    assert_eq!(solve(&mut engine, &goals!(aggregate_all(count, age(?_N, ?_A), ?C),
+                                          aggregate_all(sum(?_B), age(?_M, ?_B), ?S),
+                                          aggregate_all(max(?_D), age(?_O, ?_D), ?X))),
+               vec!["C = 5, S = 42, X = 11"]);
+    assert_eq!(solve(&mut engine, &goals!(aggregate_all(set(?_A), age(?_N, ?_A), ?S))),
+               vec!["S = [5,7,8,11]"]);
+    assert!(solve(&mut engine, &goals!(aggregate_all(min(?_A), age(?_N, 99), ?M))).is_empty());
+    assert_eq!(solve(&mut engine, &goals!(aggregate_all(count, age(?_N, 99), ?C))),
+               vec!["C = 0"]);
+}
+
+#[test]
+fn standard_order() {
+    let mut engine = Engine::new();
+    let items = || list(vec![term!(b), term!(2), term!(f(x)), term!(?_X), term!(a),
+                             term!(g(a, b)), term!(1), term!(f(a)), term!(a)]);
+    let sorted = || Term::list(vec![term!(?_V)], term!(?L));
+    assert_eq!(solve(&mut engine, &[goal("msort", vec![items(), sorted()]),
+                                    goal("==", vec![term!(?_V), term!(?_X)])]),
+               vec!["L = [1,2,a,a,b,f(a),f(x),g(a,b)]"]);
+    assert_eq!(solve(&mut engine, &[goal("sort", vec![items(), sorted()])]),
+               vec!["L = [1,2,a,b,f(a),f(x),g(a,b)]"]);
+    assert_eq!(solve(&mut engine, &goals!(compare(?O, f(b), g(a)), compare(?P, f(z), g(a, a)))),
+               vec!["O = <, P = <"]);
+    assert!(solve(&mut engine, &[goal("==", vec![term!(?X), term!(?Y)])]).is_empty());
+    assert_eq!(solve(&mut engine, &[goal("@<", vec![term!(?X), term!(1)]),
+                                    goal("@>=", vec![term!(f(a)), term!(z)])]).len(), 1);
+}
+
+#[test]
+fn sorting() {
+    let mut engine = engine(Engine::new(), &ages());
+    let pairs = || list(vec![goal("-", vec![term!(b), term!(1)]),
+                             goal("-", vec![term!(a), term!(2)]),
+                             goal("-", vec![term!(b), term!(0)])]);
+    assert_eq!(solve(&mut engine, &[goal("keysort", vec![pairs(), term!(?L)])]),
+               vec!["L = [-(a,2),-(b,1),-(b,0)]"]);
+    assert_eq!(solve(&mut engine,
+                     &[goal("sort", vec![term!(2), Term::atom("@>="), pairs(), term!(?L)])]),
+               vec!["L = [-(a,2),-(b,1),-(b,0)]"]);
+    assert_eq!(solve(&mut engine,
+                     &[goal("sort", vec![term!(1), Term::atom("@<"), pairs(), term!(?L)])]),
+               vec!["L = [-(a,2),-(b,1)]"]);
+
+    // predsort/3 drops items that compare equal
+    engine.add_clause(&clause!(by_age(?O, ?A, ?B) :-
+                               age(?A, ?X), age(?B, ?Y), compare(?O, ?X, ?Y)));
+    let people = list(vec![term!(ann), term!(peter), term!(mike), term!(tom)]);
+    assert_eq!(solve(&mut engine, &[goal("predsort", vec![term!(by_age), people, term!(?L)])]),
+               vec!["L = [tom,peter,ann]"]);
+
+    assert_eq!(engine.query(&goals!(msort(?X, ?L))).next(), Some(Err(Error::Instantiation)));
+}
+
+#[test]
+fn taking_terms_apart() {
+    let mut engine = Engine::new();
+    assert_eq!(solve(&mut engine, &goals!(functor(f(a, b), ?N, ?A), functor(3, ?M, ?B))),
+               vec!["N = f, A = 2, M = 3, B = 0"]);
+    assert_eq!(solve(&mut engine, &goals!(functor(?T, g, 1), arg(1, ?T, a), functor(?U, c, 0))),
+               vec!["T = g(a), U = c"]);
+    assert_eq!(solve(&mut engine, &goals!(arg(?N, f(a, b, c), ?X))),
+               vec!["N = 1, X = a", "N = 2, X = b", "N = 3, X = c"]);
+    assert!(solve(&mut engine, &goals!(arg(4, f(a, b, c), ?X))).is_empty());
+    assert_eq!(engine.query(&goals!(functor(?T, ?N, 2))).next(), Some(Err(Error::Instantiation)));
+    assert_eq!(engine.query(&[goal("functor", vec![term!(?T), term!(f), Term::Integer(1 << 40)])])
+                     .next(),
+               Some(Err(Error::Representation("max_arity"))));
+    let n = Term::Integer(1024);
+    assert_eq!(solve(&mut engine, &[goal("functor", vec![term!(?_T), term!(f), n.clone()]),
+                                    goal("arg", vec![n.clone(), term!(?_T), term!(z)]),
+                                    goal("arg", vec![n, term!(?_T), term!(?Z)])]),
+               vec!["Z = z"]);
+    assert_eq!(engine.query(&goals!(arg(1, a, ?X))).next(),
+               Some(Err(Error::Type("compound", term!(a)))));
+
+    assert_eq!(solve(&mut engine, &[goal("=..", vec![term!(f(a, g(b))), term!(?L)]),
+                                    goal("=..", vec![term!(?T), list(vec![term!(g), term!(1)])])]),
+               vec!["L = [f,a,g(b)], T = g(1)"]);
+    assert_eq!(engine.query(&[goal("=..", vec![term!(?T), list(vec![])])]).next(),
+               Some(Err(Error::Domain("non_empty_list", Term::nil()))));
+}
+
+#[test]
+fn copying_terms() {
+    let mut engine = Engine::new();
+    assert_eq!(solve(&mut engine, &[term!(copy_term(f(?_X, ?_Y, ?_X), f(a, b, ?Z))),
+                                    goal("\\==", vec![term!(?_X), term!(a)])]),
+               vec!["Z = a"]);
+
+    let vars = list(vec![term!(?_X), term!(?_Y), term!(?_Z)]);
+    assert_eq!(solve(&mut engine, &[term!(term_variables(f(?_X, g(?_Y, ?_X), ?_Z), ?_L)),
+                                    goal("==", vec![term!(?_L), vars])]),
+               vec!["true"]);
+}
+
+#[test]
+fn changing_arguments() {
+    let mut engine = engine(Engine::new(), &ages());
+    assert_eq!(solve(&mut engine, &goals!(copy_term(f(a, b), ?T), setarg(1, ?T, c))),
+               vec!["T = f(c,b)"]);
+
+    // backtracking undoes setarg/3, but not nb_setarg/3
+    assert_eq!(solve(&mut engine, &goals!(copy_term(f(none), ?_T), age(?N, 11),
+                                          arg(1, ?_T, ?X), setarg(1, ?_T, ?N))),
+               vec!["N = ann, X = none", "N = mike, X = none"]);
+    assert_eq!(solve(&mut engine, &goals!(copy_term(f(none), ?_T), age(?N, 11),
+                                          arg(1, ?_T, ?X), nb_setarg(1, ?_T, ?N))),
+               vec!["N = ann, X = none", "N = mike, X = ann"]);
+
+    // a failure-driven loop of nb_setarg/3 keeps only the last value
+    for clause in &[clause!(digit(0)), clause!(digit(1)), clause!(digit(2)), clause!(digit(3)),
+                    clause!(digit(4)), clause!(digit(5)), clause!(digit(6)), clause!(digit(7)),
+                    clause!(digit(8)), clause!(digit(9)),
+                    clause!(count(?S) :- digit(?_A), digit(?_B), digit(?_C), digit(?_D),
+                            digit(?_E), nb_setarg(1, ?S, f(?_V)), digit(none)),
+                    clause!(count(?_S))] {
+        engine.add_clause(clause);
+    }
+    let answer = engine.query(&goals!(copy_term(s(none), ?_T), count(?_T))).next();
+    assert!(answer.unwrap().is_ok());
+    assert!(engine.machine.heap_size() < 100, "{} heap cells", engine.machine.heap_size());
+
+    // bindings made inside a kept value are still undone
+    engine.add_clause(&clause!(first(?T) :- arg(1, ?T, none), nb_setarg(1, ?T, f(?_V))));
+    engine.add_clause(&clause!(first(?T) :- arg(1, ?T, f(?_V))));
+    assert_eq!(solve(&mut engine, &goals!(copy_term(s(none), ?_T), age(?N, 11), first(?_T),
+                                          arg(1, ?_T, f(?N)))),
+               vec!["N = ann", "N = ann", "N = mike"]);
+}
+
+#[test]
+fn type_checks() {
+    let mut engine = Engine::new();
+    let holds = |engine: &mut Engine, goal: Term| !solve(engine, &[goal]).is_empty();
+
+    assert!(holds(&mut engine, term!(var(?X))));
+    assert!(!holds(&mut engine, term!(var(a))));
+    assert!(holds(&mut engine, term!(nonvar(f(?X)))));
+    assert!(holds(&mut engine, term!(atom(a))));
+    assert!(holds(&mut engine, goal("atom", vec![Term::nil()])));
+    assert!(!holds(&mut engine, term!(atom(f(a)))));
+    assert!(!holds(&mut engine, term!(atom(1))));
+    assert!(holds(&mut engine, term!(integer(1))));
+    assert!(holds(&mut engine, term!(number(1))));
+    assert!(!holds(&mut engine, term!(float(1))));
+    assert!(holds(&mut engine, term!(atomic(1))));
+    assert!(!holds(&mut engine, term!(atomic(?X))));
+    assert!(holds(&mut engine, term!(compound(f(a)))));
+    assert!(!holds(&mut engine, term!(compound(a))));
+    assert!(holds(&mut engine, term!(callable(a))));
+    assert!(!holds(&mut engine, term!(callable(1))));
+    assert!(holds(&mut engine, goal("is_list", vec![list(vec![Term::atom("a")])])));
+    let partial = Term::list(vec![Term::atom("a")], term!(?T));
+    assert!(!holds(&mut engine, goal("is_list", vec![partial])));
+    assert!(holds(&mut engine, term!(ground(f(a, g(1))))));
+    assert!(!holds(&mut engine, term!(ground(f(a, g(?X))))));
+
+    // checking a variable does not bind it
+    assert_eq!(solve(&mut engine, &goals!(var(?X), copy_term(a, ?X))), vec!["X = a"]);
+}
+
+fn rule(head: Term, body: Term) -> Clause {
+    Clause::from_term(&goal(":-", vec![head, body])).unwrap()
+}
+
+fn or(either: Term, or: Term) -> Term {
+    goal(";", vec![either, or])
+}
+
+fn if_then(cond: Term, then: Term) -> Term {
+    goal("->", vec![cond, then])
+}
+
+#[test]
+fn control_constructs() {
+    let mut engine = engine(Engine::new(), &ages());
+    engine.add_clause(&rule(term!(picked(?N)), or(term!(age(?N, 5)), term!(age(?N, 11)))));
+    assert_eq!(solve(&mut engine, &goals!(picked(?N))),
+               vec!["N = tom", "N = ann", "N = mike"]);
+
+    // the condition of an if-then-else is committed to
+    let cond = if_then(term!(age(?N, 11)), term!(copy_term(yes, ?E)));
+    engine.add_clause(&rule(term!(eleven(?N, ?E)), or(cond, term!(copy_term(no, ?E)))));
+    assert_eq!(solve(&mut engine, &goals!(eleven(ann, ?E))), vec!["E = yes"]);
+    assert_eq!(solve(&mut engine, &goals!(eleven(tom, ?E))), vec!["E = no"]);
+    assert_eq!(solve(&mut engine, &goals!(eleven(?N, ?E))), vec!["N = ann, E = yes"]);
+
+    // but the soft-cut version keeps every solution of it
+    let cond = goal("*->", vec![term!(age(?N, 11)), term!(copy_term(yes, ?E))]);
+    assert_eq!(solve(&mut engine, &[or(cond, term!(copy_term(no, ?E)))]),
+               vec!["N = ann, E = yes", "N = mike, E = yes"]);
+    let cond = goal("*->", vec![term!(age(?_N, 99)), term!(copy_term(yes, ?E))]);
+    assert_eq!(solve(&mut engine, &[or(cond, term!(copy_term(no, ?E)))]), vec!["E = no"]);
+
+    // negation, and friends built on it
+    assert_eq!(solve(&mut engine, &[goal("\\+", vec![term!(age(bob, ?_A))])]), vec!["true"]);
+    assert_eq!(solve(&mut engine, &goals!(not(age(ann, ?_A)))), Vec::<String>::new());
+    assert_eq!(solve(&mut engine, &goals!(once(age(?N, 11)))), vec!["N = ann"]);
+    assert_eq!(solve(&mut engine, &goals!(ignore(age(bob, ?_A)))), vec!["true"]);
+    assert_eq!(solve(&mut engine, &goals!(forall(age(?_N, 11), age(?_M, 11)))), vec!["true"]);
+    assert_eq!(solve(&mut engine, &goals!(forall(age(?_N, ?_A), age(?_N, 11)))),
+               Vec::<String>::new());
+    assert_eq!(solve(&mut engine, &goals!(true, fail)), Vec::<String>::new());
+    assert_eq!(solve(&mut engine, &goals!(false)), Vec::<String>::new());
+
+    // a cut in a branch cuts the whole clause
+    let cut = or(goal(",", vec![term!(age(?N, 11)), Term::atom("!")]), term!(age(?N, 5)));
+    engine.add_clause(&rule(term!(first_eleven(?N)), cut));
+    assert_eq!(solve(&mut engine, &goals!(first_eleven(?N))), vec!["N = ann"]);
+
+    // but one in a condition is local to it
+    let cond = goal(",", vec![Term::atom("!"), Term::atom("fail")]);
+    let cond = if_then(cond, term!(copy_term(yes, ?E)));
+    assert_eq!(solve(&mut engine, &[or(cond, term!(copy_term(no, ?E)))]), vec!["E = no"]);
+}
+
+#[test]
+fn meta_call() {
+    let mut engine = engine(Engine::new(), &ages());
+    assert_eq!(solve(&mut engine, &goals!(copy_term(age(?_N, 11), ?G), call(?G))),
+               vec!["G = age(ann,11)", "G = age(mike,11)"]);
+    assert_eq!(solve(&mut engine, &goals!(call(age, ?N, 5))), vec!["N = tom"]);
+    assert_eq!(solve(&mut engine, &goals!(call(age(?N), 5))), vec!["N = tom"]);
+
+    // goals built at runtime may use control constructs too
+    let either = or(term!(age(?N, 5)), term!(age(?N, 8)));
+    assert_eq!(solve(&mut engine, &[goal("call", vec![either])]), vec!["N = tom", "N = pat"]);
+
+    // a cut within the goal cuts no further than the call
+    let cut = goal(",", vec![term!(age(?N, 11)), Term::atom("!")]);
+    assert_eq!(solve(&mut engine, &[goal("call", vec![cut])]), vec!["N = ann"]);
+    assert_eq!(solve(&mut engine, &[term!(age(?N, 11)), goal("call", vec![Term::atom("!")])]),
+               vec!["N = ann", "N = mike"]);
+
+    assert_eq!(engine.query(&goals!(call(?G))).next(), Some(Err(Error::Instantiation)));
+    assert_eq!(engine.query(&goals!(call(1))).next(),
+               Some(Err(Error::Type("callable", Term::Integer(1)))));
+    let bad = goal(",", vec![Term::atom("fail"), Term::Integer(1)]);
+    assert_eq!(engine.query(&[goal("call", vec![bad.clone()])]).next(),
+               Some(Err(Error::Type("callable", bad))));
+}
+
+fn catch(goal: Term, catcher: Term, recovery: Term) -> Term {
+    self::goal("catch", vec![goal, catcher, recovery])
+}
+
+#[test]
+fn exceptions() {
+    let mut engine = engine(Engine::new(), &ages());
+    assert_eq!(solve(&mut engine, &[catch(term!(throw(oops)), term!(?E), Term::atom("true"))]),
+               vec!["E = oops"]);
+    assert_eq!(solve(&mut engine, &[catch(term!(throw(oops)), term!(oops),
+                                          term!(copy_term(caught, ?R)))]),
+               vec!["R = caught"]);
+
+    // errors from builtins are thrown as ISO error terms
+    assert_eq!(solve(&mut engine, &[catch(term!(functor(?_T, ?_N, ?_A)), term!(?E),
+                                          Term::atom("true"))]),
+               vec!["E = error(instantiation_error,/(functor,3))"]);
+    assert_eq!(solve(&mut engine, &[catch(term!(nosuch), term!(?E), Term::atom("true"))]),
+               vec!["E = error(existence_error(procedure,/(nosuch,0)),/(nosuch,0))"]);
+
+    // bindings made by the goal are undone
+    let bind = goal(",", vec![term!(copy_term(a, ?_X)), term!(throw(oops))]);
+    assert_eq!(solve(&mut engine, &[catch(bind, term!(?_E), Term::atom("true")), term!(var(?_X))]),
+               vec!["true"]);
+
+    // across subcomputations, in either direction
+    assert_eq!(solve(&mut engine, &[goal("findall", vec![term!(?_X),
+                                                         catch(term!(throw(oops)), term!(?_X),
+                                                               Term::atom("true")),
+                                                         term!(?L)])]),
+               vec!["L = [oops]"]);
+    let inner = goal("findall", vec![term!(?_X), term!(throw(inner)), term!(?_L)]);
+    assert_eq!(solve(&mut engine, &[catch(inner, term!(?E), Term::atom("true"))]),
+               vec!["E = inner"]);
+
+    // uncaught balls reach the caller
+    assert_eq!(engine.query(&[catch(term!(throw(oops)), term!(other), Term::atom("true"))]).next(),
+               Some(Err(Error::Exception(Term::atom("oops")))));
+    assert_eq!(engine.query(&[catch(term!(throw(a)), term!(a), term!(throw(b)))]).next(),
+               Some(Err(Error::Exception(Term::atom("b")))));
+    assert_eq!(engine.query(&goals!(throw(?X))).next(), Some(Err(Error::Instantiation)));
+
+    // once its goal has succeeded, a catch no longer applies
+    assert_eq!(engine.query(&[catch(term!(age(?_N, 11)), term!(?_E), Term::atom("true")),
+                              term!(throw(late))]).next(),
+               Some(Err(Error::Exception(Term::atom("late")))));
+}
+
+#[test]
+fn operators() {
+    let mut engine = Engine::new();
+    let query = engine.read_term("op(700, xfx, ===), op(200, xfy, [++, --])").unwrap();
+    assert_eq!(solve(&mut engine, &[query]), vec!["true"]);
+    let term = engine.read_term("a === b ++ c ++ d").unwrap();
+    assert_eq!(&format!("{:?}", term), "===(a,++(b,++(c,d)))");
+    assert_eq!(engine.write_term(&term), "a===b++c++d");
+    assert_eq!(engine.write_term(&engine.read_term("(a ++ b) ++ c").unwrap()), "(a++b)++c");
+
+    let query = engine.read_term("current_op(P, T, ===)").unwrap();
+    assert_eq!(solve(&mut engine, &[query]), vec!["P = 700, T = xfx"]);
+    let query = engine.read_term("current_op(P, T, -)").unwrap();
+    assert_eq!(solve(&mut engine, &[query]), vec!["P = 200, T = fy", "P = 500, T = yfx"]);
+
+    // removing an operator
+    let query = engine.read_term("op(0, xfx, ===)").unwrap();
+    assert_eq!(solve(&mut engine, &[query]), vec!["true"]);
+    assert!(engine.read_term("a === b").is_err());
+
+    let errors = ["op(1201, xfx, foo)", "op(700, yfy, foo)", "op(1000, xfy, ',')",
+                  "op(200, xf, +)", "op(_, xfx, foo)"];
+    let errors: Vec<_> = errors.iter().map(|text| {
+        let query = engine.read_term(text).unwrap();
+        engine.query(&[query]).next().unwrap().unwrap_err()
+    }).collect();
+    assert_eq!(errors, vec![Error::Domain("operator_priority", Term::Integer(1201)),
+                            Error::Domain("operator_specifier", Term::atom("yfy")),
+                            Error::Permission("modify", "operator", Term::atom(",")),
+                            Error::Permission("create", "operator", Term::atom("+")),
+                            Error::Instantiation]);
+}
+
+/// Output that a test can read back after giving it to an engine.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> String {
+        let bytes = self.0.lock().unwrap().drain(..).collect();
+        String::from_utf8(bytes).unwrap()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What the goal `text` writes, checking that it succeeds once.
+fn written(engine: &mut Engine, output: &Output, text: &str) -> String {
+    let goal = engine.read_term(text).unwrap();
+    assert_eq!(engine.query(&[goal]).map(|answer| answer.unwrap()).count(), 1);
+    output.take()
+}
+
+#[test]
+fn writing_terms() {
+    let mut engine = Engine::new();
+    let output = Output::default();
+    engine.set_output(Box::new(output.clone()));
+
+    assert_eq!(written(&mut engine, &output, "write('hello world'), nl"), "hello world\n");
+    assert_eq!(written(&mut engine, &output, "writeq(f('A', b, 'it''s', \"x\"))"),
+               "f('A',b,'it\\'s',[120])");
+    assert_eq!(written(&mut engine, &output, "writeq(1+2*3-(4-5))"), "1+2*3-(4-5)");
+    assert_eq!(written(&mut engine, &output, "writeq(-(1) + -(-(1)) + -1)"), "- 1+ - - 1+ -1");
+    assert_eq!(written(&mut engine, &output, "write_canonical([a|b] + 'X')"), "+([a|b],'X')");
+    assert_eq!(written(&mut engine, &output, "writeq('$VAR'(1) - '$VAR'(27))"), "B-B1");
+    assert_eq!(written(&mut engine, &output, "write_canonical('$VAR'(1))"), "'$VAR'(1)");
+
+    // options
+    assert_eq!(written(&mut engine, &output, "write_term([1,2,3,4,5], [max_depth(3)])"),
+               "[1,2,3|...]");
+    assert_eq!(written(&mut engine, &output, "write_term(f(g(h(i))), [max_depth(2)])"),
+               "f(g(...))");
+    assert!(written(&mut engine, &output, "write_term(f(X, _), [variable_names(['X'=X])])")
+                .starts_with("f(X,_G"));
+    let goal = engine.read_term("write_term(a, [quoted(maybe)])").unwrap();
+    assert_eq!(engine.query(&[goal]).next(),
+               Some(Err(Error::Domain("write_option", engine.read_term("quoted(maybe)")
+                                                           .unwrap()))));
+
+    // print/1 lets portray/1 write terms first
+    let portray = engine.read_term("portray(secret) :- write('***')").unwrap();
+    engine.add_clause(&Clause::from_term(&portray).unwrap());
+    assert_eq!(written(&mut engine, &output, "print(f(secret, 'A'))"), "f(***,'A')");
+    assert_eq!(written(&mut engine, &output, "writeq(f(secret))"), "f(secret)");
+}
+
+#[test]
+fn streams() {
+    let mut engine = Engine::new();
+    let output = Output::default();
+    engine.set_output(Box::new(output.clone()));
+    engine.set_input(Box::new(Cursor::new("foo(X, Y, X). 'a b'.\n% the end\n".as_bytes())));
+
+    // reading terms from the current input
+    let text = "read_term(T, [variable_names(Vs)]), write_term(T, [variable_names(Vs)]), \
+                read(U), writeq(U), read(V), write(V)";
+    assert_eq!(written(&mut engine, &output, text), "foo(X,Y,X)'a b'end_of_file");
+
+    // characters
+    let chars = Stream::input(Box::new(Cursor::new("h\u{e9}".as_bytes()))).with_alias("chars");
+    engine.add_stream(chars);
+    let goal = engine.read_term("get_char(chars, A), peek_char(chars, B), get_char(chars, C), \
+                                 get_char(chars, D)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["A = h, B = \u{e9}, C = \u{e9}, D = end_of_file"]);
+
+    // writing to another stream, directly or as the current output
+    let log = Output::default();
+    engine.add_stream(Stream::output(Box::new(log.clone()), Mode::Write).with_alias("log"));
+    let text = "put_char(log, x), nl(log), write(log, f('A')), \
+                current_output(S), set_output(log), write(y), set_output(S), write(z)";
+    assert_eq!(written(&mut engine, &output, text), "z");
+    assert_eq!(log.take(), "x\nf(A)y");
+    let goal = engine.read_term("stream_property(S, alias(log))").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["S = $stream(4)"]);
+
+    let errors = ["get_char(log, _)", "put_char(nosuch, a)", "put_char(log, ab)",
+                  "close(log), nl(log)"];
+    let errors: Vec<_> = errors.iter().map(|text| {
+        let goal = engine.read_term(text).unwrap();
+        engine.query(&[goal]).next().unwrap().unwrap_err()
+    }).collect();
+    assert_eq!(errors, vec![Error::Permission("input", "stream", Term::atom("log")),
+                            Error::Existence("stream", Term::atom("nosuch")),
+                            Error::Type("character", Term::atom("ab")),
+                            Error::Existence("stream", Term::atom("log"))]);
+
+    engine.add_stream(Stream::input(Box::new(Cursor::new("foo(.".as_bytes()))).with_alias("bad"));
+    let goal = engine.read_term("read(bad, _)").unwrap();
+    match engine.query(&[goal]).next() {
+        Some(Err(Error::Syntax(_))) => { }
+        answer => panic!("expected a syntax error, got {:?}", answer),
+    }
+}
+
+#[test]
+fn files() {
+    let mut engine = Engine::new();
+    let path = env::temp_dir().join(format!("rusty-wam-{}.pl", process::id()));
+    let path = path.to_str().unwrap();
+    let text = format!("open('{0}', write, _S), writeq(_S, hello('W')), write(_S, '.'), \
+                        close(_S), open('{0}', read, _R), read(_R, T), close(_R)", path);
+    let goal = engine.read_term(&text).unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["T = hello(W)"]);
+    fs::remove_file(path).unwrap();
+
+    let goal = engine.read_term(&format!("open('{}', read, _)", path)).unwrap();
+    assert_eq!(engine.query(&[goal]).next(),
+               Some(Err(Error::Existence("source_sink", Term::atom(path)))));
+}
+
+#[test]
+fn consulting() {
+    let mut engine = Engine::new();
+    let errors = Output::default();
+    engine.set_error(Box::new(errors.clone()));
+    let dir = env::temp_dir().join(format!("rusty-wam-consult-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let main = dir.join("main.pl");
+    fs::write(&main, "\
+        % a family\n\
+        :- op(700, xfx, likes).\n\
+        :- dynamic(seen/1).\n\
+        parent(tom, bob).\n\
+        parent(bob, ann).\n\
+        tom likes bob.\n\
+        grandparent(X, Z) :- parent(X, Y), parent(Y, Z).\n\
+        :- include(extra).\n\
+        lonely(X) :- true.\n\
+        parent(ann, joe).\n\
+        :- initialization(assertz(seen(main))).\n").unwrap();
+    fs::write(dir.join("extra.pl"), "sibling(bob, liz).\n").unwrap();
+    let path = dir.join("main").to_str().unwrap().to_string();
+
+    assert!(engine.consult(&path).unwrap());
+    let goal = engine.read_term("grandparent(tom, W), X likes Y, sibling(bob, S), seen(M)")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]),
+               vec!["W = ann, X = tom, Y = bob, S = liz, M = main"]);
+    let main = main.canonicalize().unwrap();
+    assert_eq!(errors.take(),
+               format!("Warning: {0}:9: Singleton variables: [X]\n\
+                        Warning: {0}:10: Clauses of parent/2 are not together in the source \
+                        file\n",
+                       main.display()));
+
+    // loading again replaces the clauses rather than adding to them
+    let goal = engine.read_term(&format!("ensure_loaded('{0}'), consult('{0}'), \
+                                          findall(_C, parent(_C, _), Cs)", path)).unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["Cs = [tom,bob,ann]"]);
+
+    let goal = engine.read_term("consult(missing)").unwrap();
+    assert_eq!(engine.query(&[goal]).next(),
+               Some(Err(Error::Existence("source_sink", Term::atom("missing")))));
+    assert_eq!(engine.consult("missing"),
+               Err(Error::Existence("source_sink", Term::atom("missing"))));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn modules() {
+    let mut engine = Engine::new();
+    let dir = env::temp_dir().join(format!("rusty-wam-modules-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("lists.pl"), "\
+        :- module(lists, [member/2, maplist/2, bump/0]).\n\
+        :- meta_predicate maplist(1, ?).\n\
+        member(X, [X|_]).\n\
+        member(X, [_|T]) :- member(X, T).\n\
+        maplist(_, []).\n\
+        maplist(G, [X|Xs]) :- call(G, X), maplist(G, Xs).\n\
+        :- dynamic(counter/1).\n\
+        counter(0).\n\
+        bump :- retract(counter(N)), assertz(counter(s(N))).\n\
+        helper(lists).\n").unwrap();
+    fs::write(dir.join("checks.pl"), "\
+        :- module(checks, [check/1]).\n\
+        :- use_module(lists).\n\
+        positive(s(_)).\n\
+        check(L) :- maplist(positive, L).\n").unwrap();
+    fs::write(dir.join("main.pl"), "\
+        :- use_module(checks).\n\
+        :- use_module(lists, [member/2]).\n\
+        helper(user).\n").unwrap();
+    assert!(engine.consult(dir.join("main").to_str().unwrap()).unwrap());
+
+    let goal = engine.read_term("helper(U), lists:helper(L), findall(_X, member(_X, [a,b]), Xs)")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["U = user, L = lists, Xs = [a,b]"]);
+
+    // `positive/1` is only visible in `checks`, which `maplist/2` knows
+    let goal = engine.read_term("check([s(0), s(s(0))]), \\+ check([0])").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["true"]);
+
+    let goal = engine.read_term("lists:bump, lists:bump, lists:counter(N)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["N = s(s(0))"]);
+
+    // only `member/2` was imported from `lists`
+    let goal = engine.read_term("bump").unwrap();
+    assert_eq!(engine.query(&[goal]).next(),
+               Some(Err(Error::UnknownPredicate(functor!(bump/0)))));
+    let goal = engine.read_term("catch(lists:nothing, error(E, _), true)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]),
+               vec!["E = existence_error(procedure,:(lists,/(nothing,0)))"]);
+    let text = format!("catch(use_module('{}', [helper/1]), error(E, _), true)",
+                       dir.join("lists").display());
+    let goal = engine.read_term(&text).unwrap();
+    assert_eq!(solve(&mut engine, &[goal]),
+               vec!["E = permission_error(import,private_procedure,:(lists,/(helper,1)))"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn atoms() {
+    let mut engine = Engine::new();
+    let goal = engine.read_term("atom_codes(abc, C), atom_chars(A, [x, y]), atom_length(hello, L)")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["C = [97,98,99], A = xy, L = 5"]);
+
+    let goal = engine.read_term("atom_concat(X, Y, ab)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]),
+               vec!["X = , Y = ab", "X = a, Y = b", "X = ab, Y = "]);
+
+    let goal = engine.read_term("sub_atom(banana, B, 2, _X, an)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["B = 1", "B = 3"]);
+
+    let goal = engine.read_term("atom_number('42', N), upcase_atom(abc, U), \\+ atom_number(x, _X)")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["N = 42, U = ABC"]);
+
+    let goal = engine.read_term("term_to_atom(f(x, 'A'), T), term_to_atom(S, 'g(1)')").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["T = f(x,'A'), S = g(1)"]);
+
+    let goal = engine.read_term("catch(atom_length(_X, _L), error(E, _), true)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["E = instantiation_error"]);
+
+    let goal = engine.read_term("set_prolog_flag(double_quotes, chars)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["true"]);
+    let goal = engine.read_term("atom_chars(A, \"hi\"), current_prolog_flag(double_quotes, F)")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["A = hi, F = chars"]);
+    let goal = engine.read_term("catch(set_prolog_flag(double_quotes, x), error(E, _), true)")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]),
+               vec!["E = domain_error(flag_value,+(double_quotes,x))"]);
+}
+
+#[test]
+fn strings() {
+    let mut engine = Engine::new();
+    let goal = engine.read_term("set_prolog_flag(double_quotes, string)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["true"]);
+    let clause = engine.read_term("greeting(\"hello\")").unwrap();
+    engine.database.add_clause(&Clause::from_term(&clause).unwrap());
+
+    let goal = engine.read_term("greeting(S), string(S), greeting(\"hello\"), \\+ greeting(hello)")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["S = \"hello\""]);
+
+    let goal = engine.read_term("string_concat(\"ab\", cd, S), string_length(S, L), \
+                                 string_code(2, S, C), string_chars(S2, [x, y])")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["S = \"abcd\", L = 4, C = 98, S2 = \"xy\""]);
+
+    let goal = engine.read_term("sub_string(\"banana\", 1, 3, _X, Sub)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["Sub = \"ana\""]);
+
+    let goal = engine.read_term("split_string(\"a, b,,c \", \",\", \" \", Parts)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["Parts = [\"a\",\"b\",\"\",\"c\"]"]);
+
+    // strings come after atoms and before compound terms
+    let goal = engine.read_term("msort([f(x), \"b\", b, 1, \"a\"], L)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["L = [1,b,\"a\",\"b\",f(x)]"]);
+
+    // under double_quotes=codes, "..." is a list of codes, which the
+    // string builtins take as text too
+    let goal = engine.read_term("set_prolog_flag(double_quotes, codes)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["true"]);
+    let goal = engine.read_term("string_concat(\"ab\", [c, d], S), string_length(\"abc\", L), \
+                                 string_code(2, \"abc\", C), sub_string(\"banana\", 1, 3, _, Sub), \
+                                 split_string(\"a,b\", \",\", \"\", Parts), \
+                                 string_to_atom(\"xy\", A), string_codes(S2, \"xy\"), \
+                                 string_concat(\"\", \"ab\", S3)")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]),
+               vec!["S = \"abcd\", L = 3, C = 98, Sub = \"ana\", Parts = [\"a\",\"b\"], \
+                     A = xy, S2 = \"xy\", S3 = \"ab\""]);
+    let goal = engine.read_term("string_concat([a|_], b, S)").unwrap();
+    assert_eq!(engine.query(&[goal]).next(), Some(Err(Error::Instantiation)));
+    let goal = engine.read_term("atom_length(\"abc\", L)").unwrap();
+    assert!(engine.query(&[goal]).next().unwrap().is_err());
+}
+
+#[test]
+fn grammar_rules() {
+    let mut engine = Engine::new();
+    let dir = env::temp_dir().join(format!("rusty-wam-dcg-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("greeting.pl"), "\
+        greeting(N) --> [hello], name(N), ( [!] | [] ).\n\
+        name(world) --> \"world\", !.\n\
+        name(N) --> [N], { atom(N) }.\n\
+        digits([D|T]) --> digit(D), digits(T).\n\
+        digits([D]) --> digit(D).\n\
+        digit(D) --> [D], { D @>= 48, D @=< 57 }.\n\
+        peek(X), [X] --> [X].\n\
+        twice(G) --> call(G), call(G).\n").unwrap();
+    assert!(engine.consult(dir.join("greeting").to_str().unwrap()).unwrap());
+
+    let goal = engine.read_term("atom_codes(world, _W), phrase(greeting(N), [hello|_W])")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["N = world"]);
+    let goal = engine.read_term("phrase(greeting(N), [hello, bob])").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["N = bob"]);
+    let goal = engine.read_term("phrase(digits(Ds), \"42x\", R)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]),
+               vec!["Ds = [52,50], R = [120]", "Ds = [52], R = [50,120]"]);
+    let goal = engine.read_term("phrase(peek(X), [a, b], R), phrase(twice(peek(Y)), [c], [c])")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["X = a, R = [a,b], Y = c"]);
+
+    let goal = engine.read_term("dcg_translate_rule((a --> b, [c]), \
+                                 (a(_S0, _S) :- b(_S0, _S1), _S1 = [c|_S]))")
+                     .unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["true"]);
+    let goal = engine.read_term("catch(phrase(_G, []), error(E, _), true)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["E = instantiation_error"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn expansion() {
+    let mut engine = Engine::new();
+    let dir = env::temp_dir().join(format!("rusty-wam-expansion-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("expand.pl"), "\
+        :- dynamic(seen/1).\n\
+        term_expansion(pair(X, Y), [left(X), right(Y)]).\n\
+        term_expansion(ignored(_), []).\n\
+        goal_expansion(note(X), assertz(seen(X))).\n\
+        goal_expansion(twice(G), (G, G)).\n\
+        pair(a, b).\n\
+        ignored(c).\n\
+        run(X) :- twice(note(X)).\n\
+        :- note(loaded).\n").unwrap();
+    assert!(engine.consult(dir.join("expand").to_str().unwrap()).unwrap());
+
+    let goal = engine.read_term("left(L), right(R), run(x), findall(_S, seen(_S), Ss)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["L = a, R = b, Ss = [loaded,x,x]"]);
+    let goal = engine.read_term("catch(ignored(_X), error(E, _), true)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]),
+               vec!["E = existence_error(procedure,/(ignored,1))"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn attributed_variables() {
+    let mut engine = Engine::new();
+    let dir = env::temp_dir().join(format!("rusty-wam-attributes-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("positive.pl"), "\
+        :- module(positive, [positive/1]).\n\
+        positive(X) :- put_attr(X, positive, true).\n\
+        attr_unify_hook(_, Y) :- ( var(Y) -> true ; integer(Y), Y @> 0 ).\n").unwrap();
+    fs::write(dir.join("main.pl"), "\
+        :- use_module(positive).\n\
+        p(5).\n\
+        p(-1).\n\
+        p(7).\n\
+        sign(X, S) :- ( X = 0 -> S = zero ; S = other ).\n\
+        q(-1) :- ( fail ; true ).\n\
+        q(2) :- ( fail ; true ).\n\
+        w(X) :- X = -1, ( fail ; true ).\n").unwrap();
+    assert!(engine.consult(dir.join("main").to_str().unwrap()).unwrap());
+
+    let goal = engine.read_term("positive(X), p(X)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["X = 5", "X = 7"]);
+    let goal = engine.read_term("positive(_X), findall(_X, p(_X), L)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["L = [5,7]"]);
+    let goal = engine.read_term("positive(_X), sign(_X, S)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["S = other"]);
+    let goal = engine.read_term("positive(X), positive(Y), X = Y, \\+ X = 0, X = 3").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["X = 3, Y = 3"]);
+
+    // backtracking into a choice point made after a binding still
+    // wakes the variable bound before it
+    let goal = engine.read_term("positive(X), q(X)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["X = 2"]);
+    let goal = engine.read_term("positive(X), w(X)").unwrap();
+    assert!(solve(&mut engine, &[goal]).is_empty());
+
+    // copies of attributed variables have copies of their attributes
+    let goal = engine.read_term("put_attr(_X, m, f(_Y)), put_attr(_Y, n, 1), \
+                                 copy_term(g(_X, _Z), g(_C, _)), get_attr(_C, m, f(_D)), \
+                                 get_attr(_D, n, V), _C \\== _X, _D \\== _Y").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["V = 1"]);
+    let goal = engine.read_term("positive(_X), copy_term(_X, _C), _C = -1").unwrap();
+    assert!(solve(&mut engine, &[goal]).is_empty());
+
+    let goal = engine.read_term("put_attr(_X, m, 1), ( put_attr(_X, m, 2), fail \
+                                 ; get_attr(_X, m, A) ), put_attr(_X, n, 3), \
+                                 del_attr(_X, m), findall(_V, get_attr(_X, m, _V), B), \
+                                 get_attr(_X, n, C), \\+ get_attr(a, m, _)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["A = 1, B = [], C = 3"]);
+    let goal = engine.read_term("catch(put_attr(a, m, 1), error(E, _), true)").unwrap();
+    assert_eq!(solve(&mut engine, &[goal]), vec!["E = uninstantiation_error(a)"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,24 +1,70 @@
 //! Functor interning table.
+//!
+//! There is a single table per process, shared by every thread, so a
+//! `Functor` (and any code or heap that mentions one) can be freely
+//! sent between threads. Entries are stored in segments that never
+//! move once allocated, so reads take no lock, while new entries are
+//! added under a mutex.
+//!
+//! Functors created with `Functor::new` live forever. Those created
+//! with `Functor::transient` (e.g., atoms built from user input) are
+//! claimed by the `Owner` entered on the current thread, typically an
+//! engine, and can be reclaimed once no owner claims them any more;
+//! see `Owner::collect`. Outside any owner they are permanent.
+//! Reclaimed indices are recycled with a new generation, so a stale
+//! `Functor` is detected rather than silently naming another entry.
 
-use intern::InternedString;
-use std::cell::RefCell;
+use intern::{self, InternedString};
+use std::collections::HashSet;
 use std::collections::HashMap;
+use std::cell::Cell;
 use std::fmt::{Debug, Error, Formatter};
+use std::ptr;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 
 pub struct Functors {
-    data: Vec<FunctorData>,
+    /// Segment `i` holds `FIRST_SEGMENT << i` entries.
+    segments: Vec<OnceLock<Box<[AtomicPtr<Entry>]>>>,
+    len: AtomicUsize,
+    /// Number of threads currently looking at an entry; reclaimed
+    /// entries are only freed once this drops to zero.
+    readers: AtomicUsize,
+    writer: Mutex<Writer>,
+}
+
+struct Writer {
     map: HashMap<FunctorData, Functor>,
+    /// Reclaimed indices, with the generation to use next.
+    free: Vec<(u32, u32)>,
+    /// Entries unlinked from the table that some reader may still see.
+    retired: Vec<Box<Entry>>,
+    /// The owners holding on to each transient functor. A functor
+    /// whose last claim is released can be reclaimed.
+    claims: HashMap<Functor, Vec<usize>>,
+}
+
+struct Entry {
+    data: FunctorData,
+    generation: u32,
+    permanent: AtomicBool,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
-pub struct Functor(usize);
+pub struct Functor {
+    index: u32,
+    generation: u32,
+}
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct FunctorData {
-    pub text: InternedString,
+    pub text: Box<str>,
     pub arity: usize,
 }
 
+const FIRST_SEGMENT: usize = 64;
+const NUM_SEGMENTS: usize = 26;
+
 ///////////////////////////////////////////////////////////////////////////
 // Convenience macro
 
@@ -31,48 +77,291 @@ macro_rules! functor {
 }
 
 ///////////////////////////////////////////////////////////////////////////
-// TLS
+// The global table
 
-thread_local! {
-    static FUNCTOR_TLS: RefCell<Functors> =
-        RefCell::new(Functors::new())
-}
+static FUNCTORS: LazyLock<Functors> = LazyLock::new(Functors::new);
 
-pub fn read<F,R>(f: F) -> R
-    where F: FnOnce(&Functors) -> R
-{
-    FUNCTOR_TLS.with(|functors| f(&*functors.borrow()))
+pub fn functors() -> &'static Functors {
+    &FUNCTORS
 }
 
-fn write<F,R>(f: F) -> R
-    where F: FnOnce(&mut Functors) -> R
-{
-    FUNCTOR_TLS.with(|functors| f(&mut *functors.borrow_mut()))
+thread_local! {
+    static CURRENT: Cell<Option<usize>> = Cell::new(None);
 }
 
+static NEXT_OWNER: AtomicUsize = AtomicUsize::new(0);
+
 ///////////////////////////////////////////////////////////////////////////
 // Functors table
 
 impl Functors {
     fn new() -> Functors {
-        Functors { map: HashMap::new(), data: vec![] }
+        Functors {
+            segments: (0..NUM_SEGMENTS).map(|_| OnceLock::new()).collect(),
+            len: AtomicUsize::new(0),
+            readers: AtomicUsize::new(0),
+            writer: Mutex::new(Writer { map: HashMap::new(),
+                                        free: vec![],
+                                        retired: vec![],
+                                        claims: HashMap::new() }),
+        }
     }
 
-    pub fn functor(&mut self, text: InternedString, arity: usize) -> Functor {
-        let data = FunctorData { text: text, arity: arity };
-        match self.map.get(&data) {
-            Some(&functor) => { return functor; }
+    /// Looks up or adds `text/arity`. A transient functor is claimed
+    /// by the current owner, or made permanent if there is none.
+    pub fn functor(&self, text: &str, arity: usize, permanent: bool) -> Functor {
+        let data = FunctorData { text: text.into(), arity: arity };
+        let owner = if permanent { None } else { CURRENT.with(|current| current.get()) };
+        let permanent = permanent || owner.is_none();
+        let mut writer = self.writer.lock().unwrap();
+        match writer.map.get(&data).cloned() {
+            Some(functor) => {
+                // we hold the writer lock, so the entry cannot be
+                // reclaimed out from under us
+                let entry = self.slot(functor.index as usize).load(Ordering::SeqCst);
+                if permanent {
+                    unsafe { (*entry).permanent.store(true, Ordering::SeqCst); }
+                    writer.claims.remove(&functor);
+                } else if let Some(owner) = owner {
+                    if !unsafe { (*entry).permanent.load(Ordering::SeqCst) } {
+                        writer.claim(functor, owner);
+                    }
+                }
+                return functor;
+            }
             None => { }
         }
 
-        let functor = Functor(self.data.len());
-        self.map.insert(data, functor);
-        self.data.push(data);
+        let (index, generation) = match writer.free.pop() {
+            Some(free) => free,
+            None => {
+                // only one writer at a time holds the lock, so nobody
+                // else can be appending concurrently
+                let index = self.len.load(Ordering::SeqCst);
+                let (segment, _) = locate(index);
+                self.segments[segment].get_or_init(|| {
+                    let size = FIRST_SEGMENT << segment;
+                    (0..size).map(|_| AtomicPtr::new(ptr::null_mut()))
+                             .collect::<Vec<_>>()
+                             .into_boxed_slice()
+                });
+                self.len.store(index + 1, Ordering::SeqCst);
+                (index as u32, 0)
+            }
+        };
+
+        let entry = Box::new(Entry { data: data.clone(),
+                                     generation: generation,
+                                     permanent: AtomicBool::new(permanent) });
+        self.slot(index as usize).store(Box::into_raw(entry), Ordering::SeqCst);
+
+        let functor = Functor { index: index, generation: generation };
+        writer.map.insert(data, functor);
+        if let Some(owner) = owner {
+            writer.claim(functor, owner);
+        }
         functor
     }
 
-    pub fn data(&self, f: Functor) -> &FunctorData {
-        &self.data[f.0]
+    /// Invokes `op` with the data for `f`; panics if `f` has been
+    /// reclaimed.
+    pub fn with_data<F,R>(&self, f: Functor, op: F) -> R
+        where F: FnOnce(&FunctorData) -> R
+    {
+        self.readers.fetch_add(1, Ordering::SeqCst);
+        let _guard = ReadGuard { readers: &self.readers };
+        let entry = self.slot(f.index as usize).load(Ordering::SeqCst);
+
+        // Safe because entries are only freed while no reader is
+        // active (see `collect`).
+        match unsafe { entry.as_ref() } {
+            Some(entry) if entry.generation == f.generation => op(&entry.data),
+            _ => panic!("use of reclaimed functor {}#{}", f.index, f.generation),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// Releases `owner`'s claims on the functors `keep` rejects, then
+    /// reclaims every transient functor left without any claim.
+    fn release<F>(&self, owner: usize, keep: F) -> usize
+        where F: Fn(Functor) -> bool
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let mut unclaimed = vec![];
+        for (&functor, owners) in &mut writer.claims {
+            if !keep(functor) {
+                owners.retain(|&o| o != owner);
+            }
+            if owners.is_empty() {
+                unclaimed.push(functor);
+            }
+        }
+
+        let mut freed = 0;
+        for functor in unclaimed {
+            writer.claims.remove(&functor);
+            let slot = self.slot(functor.index as usize);
+            let entry = slot.load(Ordering::SeqCst);
+            slot.store(ptr::null_mut(), Ordering::SeqCst);
+            let entry = unsafe { Box::from_raw(entry) };
+            writer.map.remove(&entry.data);
+            writer.free.push((functor.index, functor.generation.wrapping_add(1)));
+            writer.retired.push(entry);
+            freed += 1;
+        }
+
+        // Anyone who starts reading after this point cannot reach a
+        // retired entry, so if nobody is reading right now they can
+        // go; otherwise, try again next time.
+        if self.readers.load(Ordering::SeqCst) == 0 {
+            writer.retired.clear();
+        }
+        freed
+    }
+
+    fn slot(&self, index: usize) -> &AtomicPtr<Entry> {
+        let (segment, offset) = locate(index);
+        &self.segments[segment].get().expect("functor index out of range")[offset]
+    }
+}
+
+struct ReadGuard<'a> {
+    readers: &'a AtomicUsize,
+}
+
+impl<'a> Drop for ReadGuard<'a> {
+    fn drop(&mut self) {
+        self.readers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Writer {
+    fn claim(&mut self, functor: Functor, owner: usize) {
+        let owners = self.claims.entry(functor).or_insert(vec![]);
+        if !owners.contains(&owner) {
+            owners.push(owner);
+        }
+    }
+}
+
+/// Maps a functor index to its segment and the offset within it.
+fn locate(index: usize) -> (usize, usize) {
+    let biased = index / FIRST_SEGMENT + 1;
+    let segment = (usize::BITS - 1 - biased.leading_zeros()) as usize;
+    let offset = index - FIRST_SEGMENT * ((1 << segment) - 1);
+    (segment, offset)
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Owners
+
+/// Something, typically an engine, that holds on to the transient
+/// functors created while it is entered. Each owner collects its own
+/// claims; a functor is reclaimed once no owner claims it.
+#[derive(Debug)]
+pub struct Owner {
+    id: usize,
+}
+
+/// Restores the previously entered owner when dropped.
+pub struct Entered {
+    previous: Option<usize>,
+}
+
+impl Owner {
+    pub fn new() -> Owner {
+        Owner { id: NEXT_OWNER.fetch_add(1, Ordering::SeqCst) }
+    }
+
+    /// Makes this the owner of transient functors created on this
+    /// thread until the result is dropped.
+    pub fn enter(&self) -> Entered {
+        let previous = CURRENT.with(|current| current.replace(Some(self.id)));
+        Entered { previous: previous }
+    }
+
+    /// Claims every transient functor in `value`, e.g. a term that
+    /// was read by another owner.
+    pub fn adopt<T: ?Sized + MarkFunctors>(&self, value: &T) {
+        let mut marks = Marks::new();
+        value.mark_functors(&mut marks);
+        let mut writer = functors().writer.lock().unwrap();
+        for &functor in &marks.live {
+            if writer.claims.contains_key(&functor) {
+                writer.claim(functor, self.id);
+            }
+        }
+    }
+
+    /// Releases this owner's claims on every functor not in `marks`
+    /// and reclaims those that nobody else claims, returning how many
+    /// were freed. The caller is responsible for marking every functor
+    /// it still holds; see `MarkFunctors`.
+    pub fn collect(&self, marks: &Marks) -> usize {
+        functors().release(self.id, |functor| marks.contains(functor))
+    }
+}
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        functors().release(self.id, |_| false);
+    }
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.previous));
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Marking
+
+/// The set of functors still in use, gathered before a `collect`.
+#[derive(Debug, Default)]
+pub struct Marks {
+    live: HashSet<Functor>,
+}
+
+impl Marks {
+    pub fn new() -> Marks {
+        Marks::default()
+    }
+
+    pub fn mark(&mut self, f: Functor) {
+        self.live.insert(f);
+    }
+
+    pub fn contains(&self, f: Functor) -> bool {
+        self.live.contains(&f)
+    }
+}
+
+/// Implemented by anything that can hold on to functors.
+pub trait MarkFunctors {
+    fn mark_functors(&self, marks: &mut Marks);
+}
+
+impl<T: MarkFunctors> MarkFunctors for [T] {
+    fn mark_functors(&self, marks: &mut Marks) {
+        for t in self {
+            t.mark_functors(marks);
+        }
+    }
+}
+
+impl<T: MarkFunctors> MarkFunctors for Arc<T> {
+    fn mark_functors(&self, marks: &mut Marks) {
+        (**self).mark_functors(marks)
+    }
+}
+
+impl<T: MarkFunctors> MarkFunctors for Vec<T> {
+    fn mark_functors(&self, marks: &mut Marks) {
+        (**self).mark_functors(marks)
     }
 }
 
@@ -81,20 +370,42 @@ impl Functors {
 
 impl Functor {
     pub fn new(text: InternedString, arity: usize) -> Functor {
-        write(|f| f.functor(text, arity))
+        functors().functor(&text.to_string(), arity, true)
+    }
+
+    /// Like `new`, but the functor is claimed by the current `Owner`
+    /// and may be reclaimed once no owner still claims it. Calling
+    /// `new` with the same name and arity later makes it permanent.
+    pub fn transient(text: &str, arity: usize) -> Functor {
+        functors().functor(text, arity, false)
     }
 
+    /// The functor's name, interned for the current thread. Note that
+    /// interned strings are never freed; prefer `with_text` for
+    /// transient functors.
     pub fn text(self) -> InternedString {
-        read(|f| f.data(self).text)
+        self.with_text(|text| intern::intern(text))
+    }
+
+    pub fn with_text<F,R>(self, op: F) -> R
+        where F: FnOnce(&str) -> R
+    {
+        functors().with_data(self, |data| op(&data.text))
     }
 
     pub fn arity(self) -> usize {
-        read(|f| f.data(self).arity)
+        functors().with_data(self, |data| data.arity)
+    }
+}
+
+impl MarkFunctors for Functor {
+    fn mark_functors(&self, marks: &mut Marks) {
+        marks.mark(*self);
     }
 }
 
 impl Debug for Functor {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
-        write!(fmt, "{}/{}", self.text(), self.arity())
+        functors().with_data(*self, |data| write!(fmt, "{}/{}", data.text, data.arity))
     }
 }
@@ -12,10 +12,10 @@ mod test;
 
 pub fn query<M:MachineOps>(machine: &mut M, structure: &Structure) {
     let mut interpreter = QueryInterpreter { machine: machine,
-                                             registers: 1,
+                                             registers: 2,
                                              map: HashMap::new(),
                                              generated: HashSet::new() };
-    interpreter.structure(structure, Register(0));
+    interpreter.structure(structure, Register(1));
 }
 
 pub struct QueryInterpreter<'term, M:MachineOps+'term> {
@@ -45,7 +45,7 @@ impl<'term, M:MachineOps> QueryInterpreter<'term, M> {
                     self.structure(substructure, reg);
                 }
 
-                Term::Variable(_) => { }
+                Term::Variable(_) | Term::Integer(_) | Term::String(_) => { }
             }
         }
 
@@ -55,7 +55,7 @@ impl<'term, M:MachineOps> QueryInterpreter<'term, M> {
         self.machine.put_structure(structure.functor, into);
         for (term, &reg) in structure.terms.iter().zip(&term_registers) {
             match *term {
-                Term::Structure(_) => {
+                Term::Structure(_) | Term::Integer(_) | Term::String(_) => {
                     self.machine.set_value(reg);
                 }
 
@@ -76,6 +76,10 @@ impl<'term, M:MachineOps> QueryInterpreter<'term, M> {
                 let register = bump_register(&mut self.registers);
                 register
             }
+            Term::Integer(_) | Term::String(_) => {
+                // M0 has no constants other than atoms; see `compile`
+                panic!("cannot interpret constant {:?}", term)
+            }
             Term::Variable(v) => {
                 match self.map.entry(v) {
                     // already have a register for this term; no work to do
@@ -97,10 +101,10 @@ impl<'term, M:MachineOps> QueryInterpreter<'term, M> {
 
 pub fn program<M:MachineOps>(machine: &mut M, structure: &Structure) -> Fallible {
     let mut interpreter = ProgramInterpreter { machine: machine,
-                                               registers: 1,
+                                               registers: 2,
                                                map: HashMap::new(),
                                                generated: HashSet::new() };
-    interpreter.structure(structure, Register(0))
+    interpreter.structure(structure, Register(1))
 }
 
 pub struct ProgramInterpreter<'term, M:MachineOps+'term> {
@@ -127,7 +131,7 @@ impl<'term, M:MachineOps> ProgramInterpreter<'term, M> {
         try!(self.machine.get_structure(structure.functor, into));
         for (term, &reg) in structure.terms.iter().zip(&term_registers) {
             match *term {
-                Term::Structure(_) => {
+                Term::Structure(_) | Term::Integer(_) | Term::String(_) => {
                     self.machine.unify_variable(reg);
                 }
 
@@ -150,7 +154,7 @@ impl<'term, M:MachineOps> ProgramInterpreter<'term, M> {
                     try!(self.structure(substructure, reg));
                 }
 
-                Term::Variable(_) => { }
+                Term::Variable(_) | Term::Integer(_) | Term::String(_) => { }
             }
         }
 
@@ -163,6 +167,10 @@ impl<'term, M:MachineOps> ProgramInterpreter<'term, M> {
                 let register = bump_register(&mut self.registers);
                 register
             }
+            Term::Integer(_) | Term::String(_) => {
+                // M0 has no constants other than atoms; see `compile`
+                panic!("cannot interpret constant {:?}", term)
+            }
             Term::Variable(v) => {
                 match self.map.entry(v) {
                     // already have a register for this term; no work to do
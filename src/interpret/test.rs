@@ -57,15 +57,15 @@ fn test1() {
     test_query(
         &structure!(p(?Z,h(?Z,?W),f(?W))),
         vec![
-    "put_structure h/2,R2",
-    "set_variable R1",
-    "set_variable R4",
-    "put_structure f/1,R3",
-    "set_value R4",
-    "put_structure p/3,R0",
-    "set_value R1",
+    "put_structure h/2,R3",
+    "set_variable R2",
+    "set_variable R5",
+    "put_structure f/1,R4",
+    "set_value R5",
+    "put_structure p/3,R1",
     "set_value R2",
-    "set_value R3"
+    "set_value R3",
+    "set_value R4"
             ]);
 }
 
@@ -84,17 +84,17 @@ fn program1() {
     test_program(
         &structure!(p(f(?X), h(?Y, f(a)), ?Y)),
         vec![
-    "get_structure p/3,R0",
-    "unify_variable R1",
+    "get_structure p/3,R1",
     "unify_variable R2",
     "unify_variable R3",
-    "get_structure f/1,R1",
     "unify_variable R4",
-    "get_structure h/2,R2",
-    "unify_value R3",
+    "get_structure f/1,R2",
     "unify_variable R5",
-    "get_structure f/1,R5",
+    "get_structure h/2,R3",
+    "unify_value R4",
     "unify_variable R6",
-    "get_structure a/0,R6"
+    "get_structure f/1,R6",
+    "unify_variable R7",
+    "get_structure a/0,R7"
             ]);
 }
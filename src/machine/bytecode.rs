@@ -0,0 +1,477 @@
+//! A stable binary format for compiled code, so that a program can be
+//! compiled once and loaded later without reparsing or recompiling.
+//!
+//! Functors are process-local indices, so the file carries its own
+//! functor table (name and arity) and instructions refer to functors
+//! by their position in that table; loading re-interns each entry.
+//! All integers are little-endian `u32`s, except for the `i64`
+//! operands of the constant instructions, and strings (including the
+//! operands of the string instructions) are UTF-8 prefixed by their
+//! length in bytes:
+//!
+//! ```text
+//! file      := "WAM\0" version functors predicates
+//! functors  := count (name arity)*
+//! predicates:= count (functor count instruction*)*
+//! instruction := opcode:u8 operand*
+//! var       := tag:u8 index        (tag 0 = Xn, 1 = Yn)
+//! ```
+
+use functor::{self, Functor, Marks, MarkFunctors};
+use intern;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::Instruction;
+use super::mem::{Permanent, Register, Var};
+
+const MAGIC: &'static [u8; 4] = b"WAM\0";
+
+/// Bumped whenever the encoding changes; files written by any other
+/// version are rejected when loaded.
+pub const VERSION: u32 = 5;
+
+/// A compiled program: the code for each clause, keyed by the functor
+/// of its predicate, in program order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Program {
+    pub predicates: Vec<(Functor, Vec<Instruction>)>,
+}
+
+impl MarkFunctors for Program {
+    fn mark_functors(&self, marks: &mut Marks) {
+        for &(functor, ref code) in &self.predicates {
+            marks.mark(functor);
+            code.mark_functors(marks);
+        }
+    }
+}
+
+const PUT_STRUCTURE: u8 = 0;
+const SET_VARIABLE: u8 = 1;
+const SET_VALUE: u8 = 2;
+const GET_STRUCTURE: u8 = 3;
+const UNIFY_VARIABLE: u8 = 4;
+const UNIFY_VALUE: u8 = 5;
+const PUT_VARIABLE: u8 = 6;
+const PUT_VALUE: u8 = 7;
+const GET_VARIABLE: u8 = 8;
+const GET_VALUE: u8 = 9;
+const CALL: u8 = 10;
+const EXECUTE: u8 = 11;
+const PROCEED: u8 = 12;
+const ALLOCATE: u8 = 13;
+const DEALLOCATE: u8 = 14;
+const NECK_CUT: u8 = 15;
+const CUT: u8 = 16;
+const SUCCEED: u8 = 17;
+const PUT_CONSTANT: u8 = 18;
+const SET_CONSTANT: u8 = 19;
+const GET_CONSTANT: u8 = 20;
+const UNIFY_CONSTANT: u8 = 21;
+const TRY_ME_ELSE: u8 = 22;
+const JUMP: u8 = 23;
+const GET_LEVEL: u8 = 24;
+const CUT_TO: u8 = 25;
+const SOFT_CUT: u8 = 26;
+const FAIL: u8 = 27;
+const PUT_STRING: u8 = 28;
+const GET_STRING: u8 = 29;
+
+const TEMPORARY: u8 = 0;
+const PERMANENT: u8 = 1;
+
+pub fn save_program<W: Write>(program: &Program, out: W) -> io::Result<()> {
+    let mut encoder = Encoder { out: out, functors: HashMap::new(), table: vec![] };
+
+    // the functor table comes first, so collect every functor up front
+    for &(functor, ref code) in &program.predicates {
+        encoder.intern(functor);
+        for instr in code {
+            match *instr {
+                Instruction::PutStructure(f, _) |
+                Instruction::GetStructure(f, _) |
+                Instruction::Call(f) |
+                Instruction::Execute(f) => { encoder.intern(f); }
+                _ => { }
+            }
+        }
+    }
+
+    try!(encoder.out.write_all(MAGIC));
+    try!(encoder.u32(VERSION));
+    try!(encoder.u32(encoder.table.len() as u32));
+    for f in encoder.table.clone() {
+        try!(f.with_text(|text| encoder.string(text)));
+        try!(encoder.u32(f.arity() as u32));
+    }
+
+    try!(encoder.u32(program.predicates.len() as u32));
+    for &(functor, ref code) in &program.predicates {
+        try!(encoder.functor(functor));
+        try!(encoder.u32(code.len() as u32));
+        for &instr in code {
+            try!(encoder.instruction(instr));
+        }
+    }
+    encoder.out.flush()
+}
+
+pub fn load_program<R: Read>(input: R) -> io::Result<Program> {
+    let mut decoder = Decoder { input: input, functors: vec![] };
+
+    let mut magic = [0; 4];
+    try!(decoder.input.read_exact(&mut magic));
+    if &magic != MAGIC {
+        return Err(invalid("not a compiled WAM program"));
+    }
+    let version = try!(decoder.u32());
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported bytecode version {} (expected {})",
+                                    version, VERSION)));
+    }
+
+    let num_functors = try!(decoder.u32());
+    for _ in 0..num_functors {
+        let text = try!(decoder.string());
+        let arity = try!(decoder.u32());
+        decoder.functors.push(functor::functors().functor(&text, arity as usize, true));
+    }
+
+    let num_predicates = try!(decoder.u32());
+    let mut program = Program::default();
+    for _ in 0..num_predicates {
+        let functor = try!(decoder.functor());
+        // the lengths come from the file, so nothing is allocated for
+        // them up front; a corrupt length runs into the end of input
+        let len = try!(decoder.u32());
+        let mut code = vec![];
+        for _ in 0..len {
+            code.push(try!(decoder.instruction()));
+        }
+        program.predicates.push((functor, code));
+    }
+    Ok(program)
+}
+
+pub fn save_program_file<P: AsRef<Path>>(program: &Program, path: P) -> io::Result<()> {
+    let file = try!(File::create(path));
+    save_program(program, BufWriter::new(file))
+}
+
+pub fn load_program_file<P: AsRef<Path>>(path: P) -> io::Result<Program> {
+    let file = try!(File::open(path));
+    load_program(BufReader::new(file))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Encoding
+
+struct Encoder<W: Write> {
+    out: W,
+    functors: HashMap<Functor, u32>,
+    table: Vec<Functor>,
+}
+
+impl<W: Write> Encoder<W> {
+    fn intern(&mut self, f: Functor) {
+        let table = &mut self.table;
+        self.functors.entry(f).or_insert_with(|| {
+            table.push(f);
+            (table.len() - 1) as u32
+        });
+    }
+
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.out.write_all(&[value])
+    }
+
+    fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.out.write_all(&[value as u8,
+                             (value >> 8) as u8,
+                             (value >> 16) as u8,
+                             (value >> 24) as u8])
+    }
+
+    fn i64(&mut self, value: i64) -> io::Result<()> {
+        try!(self.u32(value as u32));
+        self.u32((value >> 32) as u32)
+    }
+
+    fn string(&mut self, text: &str) -> io::Result<()> {
+        try!(self.u32(text.len() as u32));
+        self.out.write_all(text.as_bytes())
+    }
+
+    fn functor(&mut self, f: Functor) -> io::Result<()> {
+        let index = self.functors[&f];
+        self.u32(index)
+    }
+
+    fn register(&mut self, r: Register) -> io::Result<()> {
+        self.u32(r.0 as u32)
+    }
+
+    fn var(&mut self, v: Var) -> io::Result<()> {
+        match v {
+            Var::Temporary(r) => {
+                try!(self.u8(TEMPORARY));
+                self.register(r)
+            }
+            Var::Permanent(y) => {
+                try!(self.u8(PERMANENT));
+                self.u32(y.0 as u32)
+            }
+        }
+    }
+
+    fn instruction(&mut self, instr: Instruction) -> io::Result<()> {
+        match instr {
+            Instruction::PutStructure(f, r) => {
+                try!(self.u8(PUT_STRUCTURE));
+                try!(self.functor(f));
+                self.register(r)
+            }
+            Instruction::SetVariable(v) => {
+                try!(self.u8(SET_VARIABLE));
+                self.var(v)
+            }
+            Instruction::SetValue(v) => {
+                try!(self.u8(SET_VALUE));
+                self.var(v)
+            }
+            Instruction::GetStructure(f, r) => {
+                try!(self.u8(GET_STRUCTURE));
+                try!(self.functor(f));
+                self.register(r)
+            }
+            Instruction::UnifyVariable(v) => {
+                try!(self.u8(UNIFY_VARIABLE));
+                self.var(v)
+            }
+            Instruction::UnifyValue(v) => {
+                try!(self.u8(UNIFY_VALUE));
+                self.var(v)
+            }
+            Instruction::PutConstant(c, r) => {
+                try!(self.u8(PUT_CONSTANT));
+                try!(self.i64(c));
+                self.register(r)
+            }
+            Instruction::SetConstant(c) => {
+                try!(self.u8(SET_CONSTANT));
+                self.i64(c)
+            }
+            Instruction::GetConstant(c, r) => {
+                try!(self.u8(GET_CONSTANT));
+                try!(self.i64(c));
+                self.register(r)
+            }
+            Instruction::UnifyConstant(c) => {
+                try!(self.u8(UNIFY_CONSTANT));
+                self.i64(c)
+            }
+            Instruction::PutString(s, r) => {
+                try!(self.u8(PUT_STRING));
+                try!(self.string(&s.to_string()));
+                self.register(r)
+            }
+            Instruction::GetString(s, r) => {
+                try!(self.u8(GET_STRING));
+                try!(self.string(&s.to_string()));
+                self.register(r)
+            }
+            Instruction::PutVariable(v, r) => {
+                try!(self.u8(PUT_VARIABLE));
+                try!(self.var(v));
+                self.register(r)
+            }
+            Instruction::PutValue(v, r) => {
+                try!(self.u8(PUT_VALUE));
+                try!(self.var(v));
+                self.register(r)
+            }
+            Instruction::GetVariable(v, r) => {
+                try!(self.u8(GET_VARIABLE));
+                try!(self.var(v));
+                self.register(r)
+            }
+            Instruction::GetValue(v, r) => {
+                try!(self.u8(GET_VALUE));
+                try!(self.var(v));
+                self.register(r)
+            }
+            Instruction::Call(f) => {
+                try!(self.u8(CALL));
+                self.functor(f)
+            }
+            Instruction::Execute(f) => {
+                try!(self.u8(EXECUTE));
+                self.functor(f)
+            }
+            Instruction::Proceed => self.u8(PROCEED),
+            Instruction::Allocate(n) => {
+                try!(self.u8(ALLOCATE));
+                self.u32(n as u32)
+            }
+            Instruction::Deallocate => self.u8(DEALLOCATE),
+            Instruction::NeckCut => self.u8(NECK_CUT),
+            Instruction::Cut => self.u8(CUT),
+            Instruction::TryMeElse(offset) => {
+                try!(self.u8(TRY_ME_ELSE));
+                self.u32(offset as u32)
+            }
+            Instruction::Jump(offset) => {
+                try!(self.u8(JUMP));
+                self.u32(offset as u32)
+            }
+            Instruction::GetLevel(v) => {
+                try!(self.u8(GET_LEVEL));
+                self.var(v)
+            }
+            Instruction::CutTo(v) => {
+                try!(self.u8(CUT_TO));
+                self.var(v)
+            }
+            Instruction::SoftCut(v) => {
+                try!(self.u8(SOFT_CUT));
+                self.var(v)
+            }
+            Instruction::Fail => self.u8(FAIL),
+            Instruction::Succeed => self.u8(SUCCEED),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Decoding
+
+struct Decoder<R: Read> {
+    input: R,
+    functors: Vec<Functor>,
+}
+
+impl<R: Read> Decoder<R> {
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0; 1];
+        try!(self.input.read_exact(&mut buf));
+        Ok(buf[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        try!(self.input.read_exact(&mut buf));
+        Ok((buf[0] as u32) |
+           (buf[1] as u32) << 8 |
+           (buf[2] as u32) << 16 |
+           (buf[3] as u32) << 24)
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        let low = try!(self.u32()) as u64;
+        let high = try!(self.u32()) as u64;
+        Ok((high << 32 | low) as i64)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = try!(self.u32());
+        let mut buf = vec![];
+        try!((&mut self.input).take(len as u64).read_to_end(&mut buf));
+        if buf.len() < len as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "string is cut short"));
+        }
+        String::from_utf8(buf).map_err(|_| invalid("string is not valid UTF-8"))
+    }
+
+    fn functor(&mut self) -> io::Result<Functor> {
+        let index = try!(self.u32()) as usize;
+        match self.functors.get(index) {
+            Some(&f) => Ok(f),
+            None => Err(invalid(&format!("functor index {} out of range", index))),
+        }
+    }
+
+    fn register(&mut self) -> io::Result<Register> {
+        Ok(Register(try!(self.u32()) as usize))
+    }
+
+    fn var(&mut self) -> io::Result<Var> {
+        match try!(self.u8()) {
+            TEMPORARY => Ok(Var::Temporary(try!(self.register()))),
+            PERMANENT => Ok(Var::Permanent(Permanent(try!(self.u32()) as usize))),
+            tag => Err(invalid(&format!("unknown variable tag {}", tag))),
+        }
+    }
+
+    fn instruction(&mut self) -> io::Result<Instruction> {
+        match try!(self.u8()) {
+            PUT_STRUCTURE => {
+                let f = try!(self.functor());
+                Ok(Instruction::PutStructure(f, try!(self.register())))
+            }
+            SET_VARIABLE => Ok(Instruction::SetVariable(try!(self.var()))),
+            SET_VALUE => Ok(Instruction::SetValue(try!(self.var()))),
+            GET_STRUCTURE => {
+                let f = try!(self.functor());
+                Ok(Instruction::GetStructure(f, try!(self.register())))
+            }
+            UNIFY_VARIABLE => Ok(Instruction::UnifyVariable(try!(self.var()))),
+            UNIFY_VALUE => Ok(Instruction::UnifyValue(try!(self.var()))),
+            PUT_CONSTANT => {
+                let c = try!(self.i64());
+                Ok(Instruction::PutConstant(c, try!(self.register())))
+            }
+            SET_CONSTANT => Ok(Instruction::SetConstant(try!(self.i64()))),
+            GET_CONSTANT => {
+                let c = try!(self.i64());
+                Ok(Instruction::GetConstant(c, try!(self.register())))
+            }
+            UNIFY_CONSTANT => Ok(Instruction::UnifyConstant(try!(self.i64()))),
+            PUT_STRING => {
+                let s = intern::intern(&try!(self.string()));
+                Ok(Instruction::PutString(s, try!(self.register())))
+            }
+            GET_STRING => {
+                let s = intern::intern(&try!(self.string()));
+                Ok(Instruction::GetString(s, try!(self.register())))
+            }
+            PUT_VARIABLE => {
+                let v = try!(self.var());
+                Ok(Instruction::PutVariable(v, try!(self.register())))
+            }
+            PUT_VALUE => {
+                let v = try!(self.var());
+                Ok(Instruction::PutValue(v, try!(self.register())))
+            }
+            GET_VARIABLE => {
+                let v = try!(self.var());
+                Ok(Instruction::GetVariable(v, try!(self.register())))
+            }
+            GET_VALUE => {
+                let v = try!(self.var());
+                Ok(Instruction::GetValue(v, try!(self.register())))
+            }
+            CALL => Ok(Instruction::Call(try!(self.functor()))),
+            EXECUTE => Ok(Instruction::Execute(try!(self.functor()))),
+            PROCEED => Ok(Instruction::Proceed),
+            ALLOCATE => Ok(Instruction::Allocate(try!(self.u32()) as usize)),
+            DEALLOCATE => Ok(Instruction::Deallocate),
+            NECK_CUT => Ok(Instruction::NeckCut),
+            CUT => Ok(Instruction::Cut),
+            TRY_ME_ELSE => Ok(Instruction::TryMeElse(try!(self.u32()) as usize)),
+            JUMP => Ok(Instruction::Jump(try!(self.u32()) as usize)),
+            GET_LEVEL => Ok(Instruction::GetLevel(try!(self.var()))),
+            CUT_TO => Ok(Instruction::CutTo(try!(self.var()))),
+            SOFT_CUT => Ok(Instruction::SoftCut(try!(self.var()))),
+            FAIL => Ok(Instruction::Fail),
+            SUCCEED => Ok(Instruction::Succeed),
+            opcode => Err(invalid(&format!("unknown opcode {}", opcode))),
+        }
+    }
+}
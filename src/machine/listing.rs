@@ -0,0 +1,218 @@
+//! Disassembler for compiled instruction sequences. The output
+//! follows the layout of the tutorial's figures:
+//!
+//! ```text
+//! p/3 : get_structure f/1, A1
+//!       unify_variable X4
+//!       ...
+//! ```
+//!
+//! Registers are shown as `Xn`, except where they hold the arguments
+//! of the clause head or of the call being prepared, where they are
+//! shown as `An`; permanent variables are shown as `Yn`. The targets
+//! of branches are labeled `L1`, `L2` and so on, in order.
+
+use ast::write::quote_string;
+use functor::Functor;
+use std::fmt::{Display, Error, Formatter};
+
+use std::cmp;
+
+use super::Instruction;
+use super::mem::{Register, Var};
+
+pub struct Listing<'code> {
+    label: Option<Functor>,
+    code: &'code [Instruction],
+}
+
+impl<'code> Listing<'code> {
+    pub fn new(code: &'code [Instruction]) -> Listing<'code> {
+        Listing { label: None, code: code }
+    }
+
+    /// Prefix the first instruction with `label`, as the tutorial
+    /// does for the code of a predicate.
+    pub fn labeled(label: Functor, code: &'code [Instruction]) -> Listing<'code> {
+        Listing { label: Some(label), code: code }
+    }
+
+    /// The number of argument registers in use at instruction `index`.
+    fn arguments(&self, index: usize) -> usize {
+        match self.code[index] {
+            Instruction::GetStructure(..) |
+            Instruction::GetConstant(..) |
+            Instruction::GetString(..) |
+            Instruction::GetVariable(..) |
+            Instruction::GetValue(..) => {
+                // code from the tutorial's first chapter (M0) has no
+                // argument registers; everything is an X register
+                let clause = self.code.iter().any(|instr| match *instr {
+                    Instruction::Call(_) | Instruction::Execute(_) |
+                    Instruction::Proceed | Instruction::Succeed => true,
+                    _ => false,
+                });
+                match self.label {
+                    Some(f) if clause => f.arity(),
+                    _ => 0,
+                }
+            }
+            Instruction::PutStructure(..) |
+            Instruction::PutConstant(..) |
+            Instruction::PutString(..) |
+            Instruction::PutVariable(..) |
+            Instruction::PutValue(..) => {
+                self.code[index..].iter()
+                                  .filter_map(|instr| match *instr {
+                                      Instruction::Call(f) |
+                                      Instruction::Execute(f) => Some(f.arity()),
+                                      _ => None,
+                                  })
+                                  .next()
+                                  .unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    /// The offsets that branches jump to, in order; `L1` labels the
+    /// first.
+    fn targets(&self) -> Vec<usize> {
+        let mut targets: Vec<usize> = self.code.iter()
+                                                .filter_map(|instr| match *instr {
+                                                    Instruction::TryMeElse(offset) |
+                                                    Instruction::Jump(offset) => Some(offset),
+                                                    _ => None,
+                                                })
+                                                .collect();
+        targets.sort();
+        targets.dedup();
+        targets
+    }
+}
+
+impl<'code> Display for Listing<'code> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let label = match self.label {
+            Some(f) => format!("{:?} : ", f),
+            None => String::new(),
+        };
+        let targets = self.targets();
+        let target = |offset: usize| targets.binary_search(&offset).ok().map(|i| i + 1);
+        let width = match targets.len() {
+            0 => label.len(),
+            n => cmp::max(label.len(), format!("L{}: ", n).len()),
+        };
+        for (i, &instr) in self.code.iter().enumerate() {
+            let prefix = match target(i) {
+                Some(n) if i > 0 || label.is_empty() => format!("L{}: ", n),
+                _ if i == 0 => label.clone(),
+                _ => String::new(),
+            };
+            let operand = match instr {
+                Instruction::TryMeElse(offset) | Instruction::Jump(offset) => target(offset),
+                _ => None,
+            };
+            let instr = Disassemble { instr: instr, arguments: self.arguments(i), target: operand };
+            try!(writeln!(fmt, "{:width$}{}", prefix, instr, width = width));
+        }
+        // a branch may jump to the end of the code
+        if let Some(n) = target(self.code.len()) {
+            try!(writeln!(fmt, "L{}:", n));
+        }
+        Ok(())
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}", Disassemble { instr: *self, arguments: 0, target: None })
+    }
+}
+
+/// An instruction, where registers up to `arguments` are shown as
+/// argument registers, and a branch to label `L<target>` is shown as
+/// such rather than as an offset.
+struct Disassemble {
+    instr: Instruction,
+    arguments: usize,
+    target: Option<usize>,
+}
+
+impl Disassemble {
+    fn register(&self, r: Register) -> String {
+        if r.0 >= 1 && r.0 <= self.arguments {
+            format!("A{}", r.0)
+        } else {
+            format!("{}", r)
+        }
+    }
+
+    fn var(&self, v: Var) -> String {
+        match v {
+            Var::Temporary(r) => self.register(r),
+            Var::Permanent(y) => format!("{}", y),
+        }
+    }
+}
+
+impl Display for Disassemble {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        let name = self.instr.name();
+        match self.instr {
+            Instruction::PutStructure(f, r) |
+            Instruction::GetStructure(f, r) => {
+                write!(fmt, "{} {:?}, {}", name, f, self.register(r))
+            }
+            Instruction::PutConstant(c, r) |
+            Instruction::GetConstant(c, r) => {
+                write!(fmt, "{} {}, {}", name, c, self.register(r))
+            }
+            Instruction::SetConstant(c) |
+            Instruction::UnifyConstant(c) => {
+                write!(fmt, "{} {}", name, c)
+            }
+            Instruction::PutString(s, r) |
+            Instruction::GetString(s, r) => {
+                write!(fmt, "{} {}, {}", name, quote_string(&s.to_string()), self.register(r))
+            }
+            Instruction::SetVariable(v) |
+            Instruction::SetValue(v) |
+            Instruction::UnifyVariable(v) |
+            Instruction::UnifyValue(v) => {
+                write!(fmt, "{} {}", name, self.var(v))
+            }
+            Instruction::PutVariable(v, r) |
+            Instruction::PutValue(v, r) |
+            Instruction::GetVariable(v, r) |
+            Instruction::GetValue(v, r) => {
+                write!(fmt, "{} {}, {}", name, self.var(v), self.register(r))
+            }
+            Instruction::Call(f) |
+            Instruction::Execute(f) => {
+                write!(fmt, "{} {:?}", name, f)
+            }
+            Instruction::TryMeElse(n) |
+            Instruction::Jump(n) => match self.target {
+                Some(label) => write!(fmt, "{} L{}", name, label),
+                None => write!(fmt, "{} {}", name, n),
+            },
+            Instruction::Allocate(n) => {
+                write!(fmt, "{} {}", name, n)
+            }
+            Instruction::GetLevel(v) |
+            Instruction::CutTo(v) |
+            Instruction::SoftCut(v) => {
+                write!(fmt, "{} {}", name, self.var(v))
+            }
+            Instruction::Proceed |
+            Instruction::Deallocate |
+            Instruction::NeckCut |
+            Instruction::Cut |
+            Instruction::Fail |
+            Instruction::Succeed => {
+                write!(fmt, "{}", name)
+            }
+        }
+    }
+}
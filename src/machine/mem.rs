@@ -1,39 +1,125 @@
-use functor::Functor;
-use std::fmt::{Debug, Error, Formatter};
+use ast::{Structure, Term};
+use functor::{Functor, Marks, MarkFunctors};
+use intern::{self, InternedString};
+use std::cmp::{self, Ordering};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Error, Formatter};
 use std::iter::repeat;
+use std::mem;
 use std::ops;
 
 use super::Fallible;
+use super::observer::MachineObserver;
+use super::stack::Frame;
 
 pub struct Memory {
     heap: Vec<Cell>,
     registers: Vec<Cell>,
+    stack: Vec<Frame>,
+    /// Cells changed since the oldest choice point, with the values
+    /// to restore on backtracking.
+    trail: Vec<(Slot, Cell)>,
+    /// Heap size when the newest choice point was created (HB);
+    /// bindings of variables below this must be trailed.
+    boundary: usize,
+    /// Values stored by `nb_setarg/3`, which backtracking keeps.
+    kept: Vec<Kept>,
+    /// Attributed variables bound since the last call, each with the
+    /// value it was bound to; see `Cell::Attributed`.
+    wakeups: Vec<(Slot, Cell)>,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Slot(usize);
 
+/// A value stored in the argument cell `target` by `keep`, whose copy
+/// fills the `len` heap cells from `start`.
+#[derive(Copy, Clone, Debug)]
+struct Kept {
+    target: Slot,
+    start: Slot,
+    len: usize,
+}
+
+impl Kept {
+    fn holds(&self, slot: Slot) -> bool {
+        self.start.0 <= slot.0 && slot.0 < self.start.0 + self.len
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Register(pub usize);
 
+/// A permanent variable Yn (counting from 1) of the current
+/// environment.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Permanent(pub usize);
+
+/// The variable operand of the `put_variable`, `put_value`,
+/// `get_variable` and `get_value` instructions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Var {
+    Temporary(Register),
+    Permanent(Permanent),
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Address {
     Heap(usize),
     Register(usize),
+    /// Permanent variable `index` (counting from 0) of the
+    /// environment at stack frame `frame`.
+    Stack(usize, usize),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Cell {
     Structure(Slot),
     Ref(Slot),
+    /// An unbound variable, like a `Ref` to itself, that carries
+    /// attributes: the cell after it holds them. Binding it records a
+    /// wakeup, so that the engine can run the hooks of its attributes.
+    Attributed(Slot),
     Functor(Functor),
+    Integer(i64),
+    /// A string, whose text starts at the slot (see `pack`).
+    String(Slot),
+    /// The length in bytes of a string's UTF-8 text, which fills the
+    /// `Bytes` cells that follow, eight bytes to a cell.
+    Text(usize),
+    Bytes([u8; 8]),
     Uninitialized,
 }
 
+/// The cells that hold `text` on the heap: a `Text` cell and then its
+/// bytes, padded with zeroes so that equal strings have equal cells.
+pub fn pack(text: &str) -> Vec<Cell> {
+    let mut cells = vec![Cell::Text(text.len())];
+    for chunk in text.as_bytes().chunks(8) {
+        let mut bytes = [0; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        cells.push(Cell::Bytes(bytes));
+    }
+    cells
+}
+
 impl Memory {
     pub fn new(num_regs: usize) -> Memory {
         let registers = repeat(Cell::Uninitialized).take(num_regs).collect();
-        Memory { heap: vec![], registers: registers }
+        Memory { heap: vec![], registers: registers, stack: vec![], trail: vec![], boundary: 0,
+                 kept: vec![], wakeups: vec![] }
+    }
+
+    pub fn reset(&mut self) {
+        self.heap.clear();
+        self.stack.clear();
+        self.trail.clear();
+        self.boundary = 0;
+        self.kept.clear();
+        self.wakeups.clear();
+        for cell in &mut self.registers {
+            *cell = Cell::Uninitialized;
+        }
     }
 
     pub fn heap(&self) -> &[Cell] {
@@ -48,6 +134,138 @@ impl Memory {
         self.heap.push(cell);
     }
 
+    /// Discards everything pushed onto the heap since `slot`. A kept
+    /// value (see `keep`) above `slot` whose argument cell survives is
+    /// moved down to just above `slot` instead.
+    pub fn truncate_heap(&mut self, slot: Slot) {
+        // the old start, length and new start of each value moved
+        let mut moved: Vec<(usize, usize, usize)> = vec![];
+        let mut kept = vec![];
+        let mut next = slot.0;
+        for k in mem::replace(&mut self.kept, vec![]) {
+            // a value kept in an argument of another kept value moves
+            // with it, and is dropped along with it
+            let target = match relocated(&moved, slot, k.target) {
+                Some(target) => target,
+                None => continue,
+            };
+            if k.start.0 < slot.0 {
+                kept.push(k);
+                continue;
+            }
+            moved.push((k.start.0, k.len, next));
+            kept.push(Kept { target: target, start: Slot(next), len: k.len });
+            next += k.len;
+        }
+
+        let cells: Vec<Cell> = moved.iter()
+                                    .flat_map(|&(start, len, _)| &self.heap[start..start + len])
+                                    .map(|&cell| relocated_cell(&moved, slot, cell))
+                                    .collect();
+        self.heap.truncate(slot.0);
+        self.heap.extend(cells);
+        for k in &kept {
+            self.heap[k.target.0] = relocated_cell(&moved, slot, self.heap[k.target.0]);
+        }
+        for entry in &mut self.trail {
+            entry.1 = relocated_cell(&moved, slot, entry.1);
+        }
+        self.kept = kept;
+    }
+
+    /// Stores `cell` in the argument cell `target` for good, as
+    /// `nb_setarg/3` does. The copy of the value that `cell` refers to
+    /// fills the heap from `start` up; backtracking moves it rather than
+    /// discarding it, and restores `target` to `cell` rather than to
+    /// what it held before.
+    pub fn keep(&mut self, target: Slot, start: Slot, cell: Cell) {
+        self.kept.retain(|k| k.target != target);
+        self.kept.push(Kept { target: target, start: start, len: self.heap.len() - start.0 });
+        self.heap[target.0] = cell;
+        for entry in &mut self.trail {
+            if entry.0 == target {
+                entry.1 = cell;
+            }
+        }
+    }
+
+    pub fn set_boundary(&mut self, slot: Option<Slot>) {
+        self.boundary = slot.map(|s| s.0).unwrap_or(0);
+    }
+
+    /// Whether a change to the cell at `slot` must be trailed: it is
+    /// older than the newest choice point, or part of a kept value,
+    /// which backtracking moves rather than discards.
+    fn trailed(&self, slot: Slot) -> bool {
+        slot.0 < self.boundary || self.kept.iter().any(|k| k.holds(slot))
+    }
+
+    pub fn trail_len(&self) -> usize {
+        self.trail.len()
+    }
+
+    /// Undoes every change trailed since the trail had length `len`.
+    pub fn unwind_trail(&mut self, len: usize) {
+        for (slot, cell) in self.trail.drain(len..).rev() {
+            self.heap[slot.0] = cell;
+        }
+    }
+
+    /// Overwrites the cell at `slot`, trailing its old value so that
+    /// backtracking restores it.
+    pub fn assign(&mut self, slot: Slot, cell: Cell) {
+        if self.trailed(slot) {
+            self.trail.push((slot, self.heap[slot.0]));
+        }
+        self.heap[slot.0] = cell;
+    }
+
+    /// The attributes of the unbound variable at `var`, if it has any.
+    pub fn attributes(&self, var: Slot) -> Option<Cell> {
+        match self.heap[var.0] {
+            Cell::Attributed(_) => Some(self.heap[var.0 + 1]),
+            _ => None,
+        }
+    }
+
+    /// The attributed variables bound since this was last called, each
+    /// with its attributes and the value it was bound to.
+    pub fn take_wakeups(&mut self) -> Vec<(Cell, Cell)> {
+        let wakeups = self.wakeups.drain(..).collect::<Vec<_>>();
+        wakeups.into_iter().map(|(var, cell)| (self.heap[var.0 + 1], cell)).collect()
+    }
+
+    pub fn has_wakeups(&self) -> bool {
+        !self.wakeups.is_empty()
+    }
+
+    /// The wakeups not yet taken, for a choice point to restore.
+    pub fn pending_wakeups(&self) -> Vec<(Slot, Cell)> {
+        self.wakeups.clone()
+    }
+
+    /// Backtracking undoes the bindings made since `wakeups` were
+    /// saved, and any hooks run for them, so exactly those saved are
+    /// pending again.
+    pub fn restore_wakeups(&mut self, wakeups: Vec<(Slot, Cell)>) {
+        self.wakeups = wakeups;
+    }
+
+    pub fn frame(&self, index: usize) -> &Frame {
+        &self.stack[index]
+    }
+
+    pub fn frame_mut(&mut self, index: usize) -> &mut Frame {
+        &mut self.stack[index]
+    }
+
+    /// Pushes `frame` at `index`, discarding any dead frames at or
+    /// above it.
+    pub fn push_frame(&mut self, index: usize, frame: Frame) {
+        self.stack.truncate(index);
+        self.stack.push(frame);
+    }
+
     pub fn load<P:Pointer>(&self, p: P) -> Cell {
         p.load(self)
     }
@@ -63,16 +281,60 @@ impl Memory {
         p.store(self, cell)
     }
 
-    pub fn bind(&mut self, addr1: Address, addr2: Address) {
-        println!("bind({:?}={:?}, {:?}={:?})",
-                 addr1, self.load(addr1),
-                 addr2, self.load(addr2));
+    /// The cells packed by `pack` for the string at `slot`.
+    fn packed(&self, slot: Slot) -> &[Cell] {
+        match self.heap[slot.0] {
+            Cell::Text(len) => &self.heap[slot.0..slot.0 + 1 + (len + 7) / 8],
+            cell => panic!("packed found odd format for cell: {:?}", cell),
+        }
+    }
+
+    /// The text of the string at `slot`, as found in a `Cell::String`.
+    pub fn string(&self, slot: Slot) -> String {
+        let cells = self.packed(slot);
+        let mut bytes = vec![];
+        for cell in &cells[1..] {
+            if let Cell::Bytes(ref chunk) = *cell {
+                bytes.extend_from_slice(chunk);
+            }
+        }
+        match cells[0] {
+            Cell::Text(len) => bytes.truncate(len),
+            _ => unreachable!(),
+        }
+        String::from_utf8(bytes).expect("string is not valid UTF-8")
+    }
+
+    pub fn bind<O:MachineObserver>(&mut self, addr1: Address, addr2: Address, observer: &mut O) {
         match (self.load(addr1), self.load(addr2)) {
-            (Cell::Ref(_), cell2) => {
-                self.store(addr1, cell2);
+            // when binding two variables, always bind the newer one,
+            // so that no older cell points into the part of the heap
+            // that backtracking discards
+            (Cell::Ref(slot1), cell2 @ Cell::Ref(slot2)) if slot1.0 > slot2.0 => {
+                self.bind_slot(slot1, cell2, observer);
+            }
+            (cell1 @ Cell::Ref(_), Cell::Ref(slot2)) => {
+                self.bind_slot(slot2, cell1, observer);
+            }
+            (Cell::Ref(slot1), cell2) => {
+                self.bind_slot(slot1, cell2, observer);
+            }
+            (cell1, Cell::Ref(slot2)) => {
+                self.bind_slot(slot2, cell1, observer);
+            }
+            // only a plain variable is bound to an attributed one, so
+            // that its attributes stay
+            (Cell::Attributed(slot1), cell2 @ Cell::Attributed(slot2)) if slot1.0 > slot2.0 => {
+                self.bind_slot(slot1, cell2, observer);
+            }
+            (cell1 @ Cell::Attributed(_), Cell::Attributed(slot2)) => {
+                self.bind_slot(slot2, cell1, observer);
+            }
+            (Cell::Attributed(slot1), cell2) => {
+                self.bind_slot(slot1, cell2, observer);
             }
-            (cell1, Cell::Ref(_)) => {
-                self.store(addr2, cell1);
+            (cell1, Cell::Attributed(slot2)) => {
+                self.bind_slot(slot2, cell1, observer);
             }
             (cell1, cell2) => {
                 panic!("bind invoked with two non-ref addresses: {:?}=>{:?}, {:?}=>{:?}",
@@ -81,7 +343,29 @@ impl Memory {
         }
     }
 
-    pub fn unify(&mut self, addr1: Address, addr2: Address) -> Fallible {
+    /// Binds the unbound variable at `var` (once dereferenced) to the
+    /// atomic value `cell`.
+    pub fn bind_to<O:MachineObserver>(&mut self, var: Address, cell: Cell, observer: &mut O) {
+        match self.load(var) {
+            Cell::Ref(slot) | Cell::Attributed(slot) => self.bind_slot(slot, cell, observer),
+            other => panic!("bind_to invoked with non-ref address: {:?}=>{:?}", var, other),
+        }
+    }
+
+    fn bind_slot<O:MachineObserver>(&mut self, var: Slot, cell: Cell, observer: &mut O) {
+        let old = self.load(var);
+        self.store(var, cell);
+        if self.trailed(var) {
+            self.trail.push((var, old));
+        }
+        if let Cell::Attributed(_) = old {
+            self.wakeups.push((var, cell));
+        }
+        observer.bind(var.to_address(), cell);
+    }
+
+    pub fn unify<O:MachineObserver>(&mut self, addr1: Address, addr2: Address, observer: &mut O)
+                                    -> Fallible {
         let mut stack = vec![];
         stack.push((addr1, addr2));
         while let Some((d1, d2)) = stack.pop() {
@@ -93,8 +377,10 @@ impl Memory {
 
             match (self.load(d1), self.load(d2)) {
                 (Cell::Ref(_), _) |
-                (_, Cell::Ref(_)) => {
-                    self.bind(d1, d2);
+                (_, Cell::Ref(_)) |
+                (Cell::Attributed(_), _) |
+                (_, Cell::Attributed(_)) => {
+                    self.bind(d1, d2, observer);
                 }
 
                 (Cell::Structure(v1), Cell::Structure(v2)) => {
@@ -106,10 +392,27 @@ impl Memory {
                                         (v2 + i).to_address()));
                         }
                     } else {
+                        observer.unify_failure(self.load(d1), self.load(d2));
                         return Err(());
                     }
                 }
 
+                (Cell::Integer(i1), Cell::Integer(i2)) if i1 == i2 => { }
+
+                (Cell::String(s1), Cell::String(s2)) if self.packed(s1) == self.packed(s2) => { }
+
+                (cell1 @ Cell::Structure(_), cell2 @ Cell::Integer(_)) |
+                (cell1 @ Cell::Integer(_), cell2 @ Cell::Structure(_)) |
+                (cell1 @ Cell::Integer(_), cell2 @ Cell::Integer(_)) |
+                (cell1 @ Cell::String(_), cell2 @ Cell::Structure(_)) |
+                (cell1 @ Cell::String(_), cell2 @ Cell::Integer(_)) |
+                (cell1 @ Cell::String(_), cell2 @ Cell::String(_)) |
+                (cell1 @ Cell::Structure(_), cell2 @ Cell::String(_)) |
+                (cell1 @ Cell::Integer(_), cell2 @ Cell::String(_)) => {
+                    observer.unify_failure(cell1, cell2);
+                    return Err(());
+                }
+
                 (cell1, cell2) => {
                     panic!("Unexpected cell kind encountered in unify: {:?}=>{:?}, {:?}=>{:?}",
                            d1, cell1, d2, cell2)
@@ -119,9 +422,24 @@ impl Memory {
         Ok(())
     }
 
-    pub fn deref<P:Pointer+FromSlot>(&mut self, ptr: P) -> P {
+    /// Tests whether `addr1` and `addr2` unify, leaving memory as it
+    /// was either way.
+    pub fn unifiable(&mut self, addr1: Address, addr2: Address) -> bool {
+        // trail every binding, however old the variable, so that all
+        // of them can be undone
+        let (boundary, trail) = (self.boundary, self.trail.len());
+        let wakeups = self.wakeups.clone();
+        self.boundary = self.heap.len();
+        let result = self.unify(addr1, addr2, &mut ());
+        self.unwind_trail(trail);
+        self.boundary = boundary;
+        self.wakeups = wakeups;
+        result.is_ok()
+    }
+
+    pub fn deref<P:Pointer+FromSlot>(&self, ptr: P) -> P {
         match self.load(ptr) {
-            Cell::Ref(referent) => {
+            Cell::Ref(referent) | Cell::Attributed(referent) => {
                 let referent = P::from_slot(referent);
                 if ptr == referent {
                     ptr
@@ -129,7 +447,8 @@ impl Memory {
                     self.deref(referent)
                 }
             }
-            Cell::Structure(_) | Cell::Functor(_) => {
+            Cell::Structure(_) | Cell::Functor(_) | Cell::Integer(_) | Cell::String(_) |
+            Cell::Text(_) | Cell::Bytes(_) => {
                 ptr
             }
             Cell::Uninitialized => {
@@ -139,6 +458,29 @@ impl Memory {
     }
 }
 
+impl MarkFunctors for Cell {
+    fn mark_functors(&self, marks: &mut Marks) {
+        match *self {
+            Cell::Functor(f) => marks.mark(f),
+            Cell::Structure(_) | Cell::Ref(_) | Cell::Attributed(_) | Cell::Integer(_) |
+            Cell::String(_) | Cell::Text(_) | Cell::Bytes(_) | Cell::Uninitialized => { }
+        }
+    }
+}
+
+impl MarkFunctors for Memory {
+    fn mark_functors(&self, marks: &mut Marks) {
+        self.heap.mark_functors(marks);
+        self.registers.mark_functors(marks);
+        for frame in &self.stack {
+            match *frame {
+                Frame::Environment(ref env) => env.permanents.mark_functors(marks),
+                Frame::ChoicePoint(ref choice) => choice.args.mark_functors(marks),
+            }
+        }
+    }
+}
+
 impl Slot {
     pub fn bump(&mut self) {
         self.0 += 1;
@@ -175,21 +517,29 @@ impl Pointer for Address {
     fn to_slot(self) -> Option<Slot> {
         match self {
             Address::Heap(i) => Some(Slot(i)),
-            Address::Register(_) => None,
+            Address::Register(_) | Address::Stack(..) => None,
         }
     }
 
     fn load(self, mem: &Memory) -> Cell {
         match self {
             Address::Heap(i) => mem.heap[i],
-            Address::Register(i) => mem.registers[i],
+            Address::Register(i) => Register(i).load(mem),
+            Address::Stack(frame, i) => match mem.stack[frame] {
+                Frame::Environment(ref env) => env.permanents[i],
+                Frame::ChoicePoint(_) => panic!("{:?} is not an environment", self),
+            },
         }
     }
 
     fn store(self, mem: &mut Memory, cell: Cell) {
         match self {
             Address::Heap(i) => mem.heap[i] = cell,
-            Address::Register(i) => mem.registers[i] = cell,
+            Address::Register(i) => Register(i).store(mem, cell),
+            Address::Stack(frame, i) => match mem.stack[frame] {
+                Frame::Environment(ref mut env) => env.permanents[i] = cell,
+                Frame::ChoicePoint(_) => panic!("{:?} is not an environment", self),
+            },
         }
     }
 }
@@ -234,10 +584,16 @@ impl Pointer for Register {
     }
 
     fn load(self, mem: &Memory) -> Cell {
-        mem.registers[self.0]
+        mem.registers.get(self.0).cloned().unwrap_or(Cell::Uninitialized)
     }
 
     fn store(self, mem: &mut Memory, cell: Cell) {
+        // registers are allocated on demand, since compiled code may
+        // use any number of them
+        if self.0 >= mem.registers.len() {
+            let len = cmp::max(self.0 + 1, mem.registers.len() * 2);
+            mem.registers.resize(len, Cell::Uninitialized);
+        }
         mem.registers[self.0] = cell;
     }
 }
@@ -248,6 +604,33 @@ impl Debug for Register {
     }
 }
 
+impl Display for Register {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "X{}", self.0)
+    }
+}
+
+impl Debug for Permanent {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "Y{}", self.0)
+    }
+}
+
+impl Display for Permanent {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "Y{}", self.0)
+    }
+}
+
+impl Display for Var {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match *self {
+            Var::Temporary(r) => write!(fmt, "{}", r),
+            Var::Permanent(y) => write!(fmt, "{}", y),
+        }
+    }
+}
+
 impl Debug for Slot {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         write!(fmt, "H{}", self.0)
@@ -271,7 +654,7 @@ impl<'mem> MGU<'mem> {
         match self.mem.load(ptr) {
             Cell::Structure(mut slot) => {
                 let functor = self.mem.load_functor(slot);
-                try!(write!(fmt, "{}", functor.text()));
+                try!(functor.with_text(|text| write!(fmt, "{}", text)));
                 if functor.arity() > 0 {
                     try!(write!(fmt, "("));
                     slot.bump();
@@ -285,14 +668,22 @@ impl<'mem> MGU<'mem> {
                 }
                 Ok(())
             }
-            Cell::Ref(referent) => {
+            Cell::Ref(referent) | Cell::Attributed(referent) => {
                 if referent.to_address() == ptr.to_address() {
                     write!(fmt, "?")
                 } else {
                     self.write(fmt, referent)
                 }
             }
+            Cell::Integer(i) => {
+                write!(fmt, "{}", i)
+            }
+            Cell::String(slot) => {
+                write!(fmt, "{:?}", self.mem.string(slot))
+            }
             cell @ Cell::Functor(_) |
+            cell @ Cell::Text(_) |
+            cell @ Cell::Bytes(_) |
             cell @ Cell::Uninitialized => {
                 panic!("MGU found odd format for cell: {:?}", cell)
             }
@@ -300,12 +691,159 @@ impl<'mem> MGU<'mem> {
     }
 }
 
+/// Where the cell at `slot` is once `truncate_heap(top)` has made the
+/// moves in `moved`, or `None` if it is discarded.
+fn relocated(moved: &[(usize, usize, usize)], top: Slot, slot: Slot) -> Option<Slot> {
+    if slot.0 < top.0 {
+        return Some(slot);
+    }
+    moved.iter()
+         .find(|&&(start, len, _)| start <= slot.0 && slot.0 < start + len)
+         .map(|&(start, _, to)| Slot(slot.0 - start + to))
+}
+
+/// `cell`, pointing where its referent is once `truncate_heap(top)`
+/// has made the moves in `moved`.
+fn relocated_cell(moved: &[(usize, usize, usize)], top: Slot, cell: Cell) -> Cell {
+    let slot = |s: Slot| relocated(moved, top, s).unwrap_or(s);
+    match cell {
+        Cell::Structure(s) => Cell::Structure(slot(s)),
+        Cell::Ref(s) => Cell::Ref(slot(s)),
+        Cell::Attributed(s) => Cell::Attributed(slot(s)),
+        Cell::String(s) => Cell::String(slot(s)),
+        cell => cell,
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Reading terms back out of memory
+
+impl Memory {
+    /// Reconstructs the term at `addr`. Unbound variables are named
+    /// after the heap slot they occupy, e.g. `_G12`.
+    pub fn term<P:Pointer+FromSlot>(&self, ptr: P) -> Term {
+        self.read_term(ptr, &mut HashMap::new())
+    }
+
+    /// Like `term`, but also records the cell of each unbound variable
+    /// under the name it is given in the term.
+    pub fn read_term<P:Pointer+FromSlot>(&self, ptr: P, vars: &mut HashMap<InternedString, Cell>)
+                                        -> Term {
+        let ptr = self.deref(ptr);
+        self.read_cell(self.load(ptr), vars)
+    }
+
+    /// Reads the term that `cell` refers to, as `read_term` does.
+    pub fn read_cell(&self, cell: Cell, vars: &mut HashMap<InternedString, Cell>) -> Term {
+        match self.resolve(cell) {
+            Cell::Structure(slot) => {
+                let functor = self.load_functor(slot);
+                let terms = (1..functor.arity()+1).map(|i| self.read_term(slot + i, vars))
+                                                  .collect();
+                Term::Structure(Structure { functor: functor, terms: terms })
+            }
+            cell @ Cell::Ref(slot) => {
+                let name = intern::intern(&format!("_G{}", slot.0));
+                vars.insert(name, cell);
+                Term::Variable(name)
+            }
+            Cell::Integer(i) => {
+                Term::Integer(i)
+            }
+            Cell::String(slot) => {
+                Term::String(self.string(slot))
+            }
+            cell @ Cell::Attributed(_) |
+            cell @ Cell::Functor(_) |
+            cell @ Cell::Text(_) |
+            cell @ Cell::Bytes(_) |
+            cell @ Cell::Uninitialized => {
+                panic!("term found odd format for cell: {:?}", cell)
+            }
+        }
+    }
+
+    /// `cell`, after following any chain of references; an unbound
+    /// variable, attributed or not, is a `Cell::Ref` to itself.
+    pub fn resolve(&self, cell: Cell) -> Cell {
+        match cell {
+            Cell::Ref(slot) | Cell::Attributed(slot) => match self.load(self.deref(slot)) {
+                Cell::Attributed(slot) => Cell::Ref(slot),
+                cell => cell,
+            },
+            cell => cell,
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Standard order
+
+impl Memory {
+    /// Compares the terms `a` and `b` refer to in the standard order:
+    /// variables (oldest first), then numbers, then atoms
+    /// (alphabetically), then strings, then compound terms (by arity,
+    /// then name, then arguments from left to right).
+    pub fn compare(&self, a: Cell, b: Cell) -> Ordering {
+        fn rank(mem: &Memory, cell: Cell) -> u8 {
+            match cell {
+                Cell::Ref(_) => 0,
+                Cell::Integer(_) => 1,
+                Cell::Structure(slot) if mem.load_functor(slot).arity() == 0 => 2,
+                Cell::String(_) => 3,
+                Cell::Structure(_) => 4,
+                Cell::Attributed(_) | Cell::Functor(_) | Cell::Text(_) | Cell::Bytes(_) |
+                Cell::Uninitialized => {
+                    panic!("compare found odd format for cell: {:?}", cell)
+                }
+            }
+        }
+
+        let (mut a, mut b) = (a, b);
+        loop {
+            let (x, y) = (self.resolve(a), self.resolve(b));
+            match (x, y) {
+                (Cell::Ref(x), Cell::Ref(y)) => return x.0.cmp(&y.0),
+                (Cell::Integer(i), Cell::Integer(j)) => return i.cmp(&j),
+                (Cell::String(x), Cell::String(y)) => return self.string(x).cmp(&self.string(y)),
+                (Cell::Structure(x), Cell::Structure(y)) => {
+                    let (f, g) = (self.load_functor(x), self.load_functor(y));
+                    let order = f.arity().cmp(&g.arity()).then_with(|| {
+                        f.with_text(|f| g.with_text(|g| f.cmp(g)))
+                    });
+                    if order != Ordering::Equal || f.arity() == 0 {
+                        return order;
+                    }
+                    for i in 1..f.arity() {
+                        let order = self.compare(self.load(x + i), self.load(y + i));
+                        if order != Ordering::Equal {
+                            return order;
+                        }
+                    }
+                    // compare the last arguments without recursing, so
+                    // that long lists do not exhaust the stack
+                    a = self.load(x + f.arity());
+                    b = self.load(y + f.arity());
+                }
+                _ => return rank(self, x).cmp(&rank(self, y)),
+            }
+        }
+    }
+}
+
 impl<'mem> Debug for MGU<'mem> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         self.write(fmt, self.addr)
     }
 }
 
+/// Writes the solution in Prolog syntax, using the ISO operators.
+impl<'mem> Display for MGU<'mem> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        write!(fmt, "{}", self.mem.term(self.addr))
+    }
+}
+
 impl Debug for Memory {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         try!(writeln!(fmt, "Memory {{"));
@@ -1,18 +1,77 @@
 //! Definition of the WAM.
 
-use functor::Functor;
+use ast::{Clause, Term};
+use functor::{Functor, Marks, MarkFunctors};
+use intern::InternedString;
+use std::cmp;
+use std::collections::HashMap;
+use std::iter::repeat;
+use std::sync::Arc;
 
-use self::mem::{Cell, Memory, Pointer, Slot, Register};
+use self::mem::{Address, Cell, FromSlot, Memory, Permanent, Pointer, Slot, Register, Var};
+use self::mem::pack;
+use self::observer::MachineObserver;
+use self::stack::{Alternative, ChoicePoint, Environment, Frame, Redo};
 
+pub mod bytecode;
+pub mod listing;
 pub mod mem;
+pub mod observer;
+pub mod profile;
+pub mod stack;
 
 #[cfg(test)]
 mod test;
 
 #[derive(Debug)]
-pub struct Machine {
+pub struct Machine<O: MachineObserver = ()> {
     mem: Memory,
     mode: Mode,
+    observer: O,
+
+    /// The next instruction to execute (P).
+    p: Option<CodePtr>,
+    /// The continuation (CP).
+    cp: Option<CodePtr>,
+    /// The current environment (E), choice point (B) and cut
+    /// barrier (B0), as stack frame indices.
+    e: Option<usize>,
+    b: Option<usize>,
+    b0: Option<usize>,
+}
+
+/// A compiled clause or query. Code is immutable once compiled and
+/// shared by reference, so a running call keeps its code alive even
+/// if the clause is later removed from the database.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Code {
+    /// The predicate this code belongs to, or `None` for a query.
+    pub predicate: Option<Functor>,
+    pub instructions: Vec<Instruction>,
+    /// The clause this code was compiled from, if known.
+    pub source: Option<Clause>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CodePtr {
+    code: Arc<Code>,
+    offset: usize,
+}
+
+/// What `Machine::backtrack` found to resume.
+#[derive(Debug)]
+pub enum Resume {
+    /// There were no choice points left.
+    Nothing,
+    /// The next clause of a predicate (or the other branch of a
+    /// disjunction) is ready to run.
+    Clause,
+    /// The builtin `f` should be called again with the given state,
+    /// continuing at the given code if it succeeds.
+    Builtin(Functor, Redo, CodePtr),
+    /// A subcomputation has no more solutions, and the machine is
+    /// back in the state it was in before `push_goal`.
+    Barrier,
 }
 
 #[derive(Debug)]
@@ -23,6 +82,186 @@ enum Mode {
 
 pub type Fallible = Result<(),()>;
 
+/// A single WAM instruction, as reported to a `MachineObserver`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    PutStructure(Functor, Register),
+    SetVariable(Var),
+    SetValue(Var),
+    GetStructure(Functor, Register),
+    UnifyVariable(Var),
+    UnifyValue(Var),
+
+    PutConstant(i64, Register),
+    SetConstant(i64),
+    GetConstant(i64, Register),
+    UnifyConstant(i64),
+
+    /// Like `put_constant` and `get_constant`, for strings. A string
+    /// inside a structure is built or matched in a temporary register,
+    /// as a nested structure is, since its text takes several cells.
+    PutString(InternedString, Register),
+    GetString(InternedString, Register),
+
+    PutVariable(Var, Register),
+    PutValue(Var, Register),
+    GetVariable(Var, Register),
+    GetValue(Var, Register),
+    Call(Functor),
+    Execute(Functor),
+    Proceed,
+
+    Allocate(usize),
+    Deallocate,
+
+    /// Cut back to B0; only valid before the first call of a body.
+    NeckCut,
+    /// Cut back to the barrier saved in the current environment.
+    Cut,
+
+    /// Pushes a choice point that resumes at the given offset of the
+    /// current code: the other branch of a disjunction.
+    TryMeElse(usize),
+    /// Continues at the given offset of the current code.
+    Jump(usize),
+    /// Saves the current choice point (B) in a variable, so that a
+    /// later `cut_to` or `soft_cut` can refer to it.
+    GetLevel(Var),
+    /// Cuts back to the choice point saved by `get_level`.
+    CutTo(Var),
+    /// Removes the choice point saved by `get_level`, but none of the
+    /// newer ones (for `*->`).
+    SoftCut(Var),
+    Fail,
+
+    /// Ends a query: report a solution to whoever is running it.
+    Succeed,
+}
+
+impl Instruction {
+    /// The opcode name, as used in the tutorial.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Instruction::PutStructure(..) => "put_structure",
+            Instruction::SetVariable(..) => "set_variable",
+            Instruction::SetValue(..) => "set_value",
+            Instruction::GetStructure(..) => "get_structure",
+            Instruction::UnifyVariable(..) => "unify_variable",
+            Instruction::UnifyValue(..) => "unify_value",
+            Instruction::PutConstant(..) => "put_constant",
+            Instruction::SetConstant(..) => "set_constant",
+            Instruction::GetConstant(..) => "get_constant",
+            Instruction::UnifyConstant(..) => "unify_constant",
+            Instruction::PutString(..) => "put_string",
+            Instruction::GetString(..) => "get_string",
+            Instruction::PutVariable(..) => "put_variable",
+            Instruction::PutValue(..) => "put_value",
+            Instruction::GetVariable(..) => "get_variable",
+            Instruction::GetValue(..) => "get_value",
+            Instruction::Call(..) => "call",
+            Instruction::Execute(..) => "execute",
+            Instruction::Proceed => "proceed",
+            Instruction::Allocate(..) => "allocate",
+            Instruction::Deallocate => "deallocate",
+            Instruction::NeckCut => "neck_cut",
+            Instruction::Cut => "cut",
+            Instruction::TryMeElse(..) => "try_me_else",
+            Instruction::Jump(..) => "jump",
+            Instruction::GetLevel(..) => "get_level",
+            Instruction::CutTo(..) => "cut_to",
+            Instruction::SoftCut(..) => "soft_cut",
+            Instruction::Fail => "fail",
+            Instruction::Succeed => "succeed",
+        }
+    }
+}
+
+impl MarkFunctors for Instruction {
+    fn mark_functors(&self, marks: &mut Marks) {
+        match *self {
+            Instruction::PutStructure(f, _) |
+            Instruction::GetStructure(f, _) => marks.mark(f),
+            Instruction::Call(f) |
+            Instruction::Execute(f) => marks.mark(f),
+            Instruction::PutConstant(..) |
+            Instruction::SetConstant(_) |
+            Instruction::GetConstant(..) |
+            Instruction::UnifyConstant(_) |
+            Instruction::PutString(..) |
+            Instruction::GetString(..) |
+            Instruction::SetVariable(_) |
+            Instruction::SetValue(_) |
+            Instruction::UnifyVariable(_) |
+            Instruction::UnifyValue(_) |
+            Instruction::PutVariable(..) |
+            Instruction::PutValue(..) |
+            Instruction::GetVariable(..) |
+            Instruction::GetValue(..) |
+            Instruction::Proceed |
+            Instruction::Allocate(_) |
+            Instruction::Deallocate |
+            Instruction::NeckCut |
+            Instruction::Cut |
+            Instruction::TryMeElse(_) |
+            Instruction::Jump(_) |
+            Instruction::GetLevel(_) |
+            Instruction::CutTo(_) |
+            Instruction::SoftCut(_) |
+            Instruction::Fail |
+            Instruction::Succeed => { }
+        }
+    }
+}
+
+impl MarkFunctors for Code {
+    fn mark_functors(&self, marks: &mut Marks) {
+        if let Some(f) = self.predicate {
+            marks.mark(f);
+        }
+        self.instructions.mark_functors(marks);
+        if let Some(ref source) = self.source {
+            source.mark_functors(marks);
+        }
+    }
+}
+
+impl MarkFunctors for CodePtr {
+    fn mark_functors(&self, marks: &mut Marks) {
+        self.code.mark_functors(marks);
+    }
+}
+
+/// Compiling into a vector records the instructions rather than
+/// executing them; the result can later be run with
+/// `Machine::execute` or printed with `listing::Listing`.
+impl MachineOps for Vec<Instruction> {
+    fn put_structure(&mut self, f: Functor, r: Register) {
+        self.push(Instruction::PutStructure(f, r));
+    }
+
+    fn set_variable(&mut self, r: Register) {
+        self.push(Instruction::SetVariable(Var::Temporary(r)));
+    }
+
+    fn set_value(&mut self, r: Register) {
+        self.push(Instruction::SetValue(Var::Temporary(r)));
+    }
+
+    fn get_structure(&mut self, f: Functor, r: Register) -> Fallible {
+        self.push(Instruction::GetStructure(f, r));
+        Ok(())
+    }
+
+    fn unify_variable(&mut self, r: Register) {
+        self.push(Instruction::UnifyVariable(Var::Temporary(r)));
+    }
+
+    fn unify_value(&mut self, r: Register) -> Fallible {
+        self.push(Instruction::UnifyValue(Var::Temporary(r)));
+        Ok(())
+    }
+}
+
 pub trait MachineOps {
     fn put_structure(&mut self, f: Functor, r: Register);
     fn set_variable(&mut self, r: Register);
@@ -34,154 +273,929 @@ pub trait MachineOps {
 
 impl Machine {
     pub fn new(num_registers: usize) -> Machine {
-        Machine { mem: Memory::new(num_registers), mode: Mode::Write }
+        Machine::with_observer(num_registers, ())
+    }
+}
+
+impl<O: MachineObserver> Machine<O> {
+    pub fn with_observer(num_registers: usize, observer: O) -> Machine<O> {
+        Machine { mem: Memory::new(num_registers),
+                  mode: Mode::Write,
+                  observer: observer,
+                  p: None,
+                  cp: None,
+                  e: None,
+                  b: None,
+                  b0: None }
     }
 
     pub fn mgu<'m,P:mem::Pointer>(&'m self, addr: P) -> mem::MGU<'m> {
         mem::MGU::new(&self.mem, addr.to_address())
     }
 
-    pub fn dump<'m>(&'m mut self) -> DumpMachine<'m> {
-        DumpMachine { machine: self }
+    /// Executes a straight-line instruction sequence, stopping at the
+    /// first instruction that fails.
+    pub fn execute(&mut self, code: &[Instruction]) -> Fallible {
+        for &instr in code {
+            try!(self.step(instr));
+        }
+        Ok(())
     }
-}
 
-impl MachineOps for Machine {
-    /// from tutorial figure 2.2
-    fn put_structure(&mut self, f: Functor, r: Register) {
-        let ptr = self.mem.next_slot();
-        let cell = Cell::Structure(ptr + 1);
-        self.mem.push(cell);
-        self.mem.push(Cell::Functor(f));
-        self.mem.store(r, cell);
+    pub fn observer(&self) -> &O {
+        &self.observer
     }
 
-    /// from tutorial figure 2.2
-    fn set_variable(&mut self, r: Register) {
-        let ptr = self.mem.next_slot();
-        let cell = Cell::Ref(ptr);
-        self.mem.push(cell);
-        self.mem.store(r, cell);
+    pub fn observer_mut(&mut self) -> &mut O {
+        &mut self.observer
     }
 
-    /// from tutorial figure 2.2
-    fn set_value(&mut self, r: Register) {
-        let cell = self.mem.load(r);
+    pub fn into_observer(self) -> O {
+        self.observer
+    }
+
+    fn push(&mut self, cell: Cell) {
+        let slot = self.mem.next_slot();
         self.mem.push(cell);
+        self.observer.heap_push(slot, cell);
     }
 
-    fn get_structure(&mut self, f: Functor, r: Register) -> Fallible {
+    fn executed(&mut self, instr: Instruction, result: Fallible) -> Fallible {
+        self.observer.instruction(instr, result, &self.mem);
+        result
+    }
+
+    fn get_structure_inner(&mut self, f: Functor, r: Register) -> Fallible {
         let addr = self.mem.deref(r.to_address());
         match self.mem.load(addr) {
-            Cell::Ref(_) => {
+            Cell::Ref(_) | Cell::Attributed(_) => {
                 let slot = self.mem.next_slot();
-                self.mem.push(Cell::Structure(slot + 1));
-                self.mem.push(Cell::Functor(f));
-                self.mem.bind(addr, slot.to_address());
+                self.push(Cell::Structure(slot + 1));
+                self.push(Cell::Functor(f));
+                self.mem.bind(addr, slot.to_address(), &mut self.observer);
                 self.mode = Mode::Write;
                 Ok(())
             }
             Cell::Structure(slot) => {
-                if self.mem.load(slot) == Cell::Functor(f) {
+                let cell = self.mem.load(slot);
+                if cell == Cell::Functor(f) {
                     let next = slot + 1;
                     self.mode = Mode::Read(next);
                     Ok(())
                 } else {
                     // if the pointer doesn't reference a functor, heap is inconsistent
-                    debug_assert!(match self.mem.load(slot) {
+                    debug_assert!(match cell {
                         Cell::Functor(_) => true,
                         _ => false,
                     });
+                    self.observer.unify_failure(cell, Cell::Functor(f));
                     Err(())
                 }
             }
-            Cell::Functor(_) => {
+            cell @ Cell::Functor(_) |
+            cell @ Cell::Integer(_) |
+            cell @ Cell::String(_) => {
+                self.observer.unify_failure(cell, Cell::Functor(f));
                 Err(())
             }
-            Cell::Uninitialized => {
+            Cell::Text(_) | Cell::Bytes(_) | Cell::Uninitialized => {
                 panic!("Load from uninitialized cell at {:?}", addr)
             }
         }
     }
+}
+
+impl<O: MachineObserver> MarkFunctors for Machine<O> {
+    fn mark_functors(&self, marks: &mut Marks) {
+        self.mem.mark_functors(marks);
+        // code reachable only from a continuation or choice point
+        // (e.g., a clause retracted while running) is still live
+        for ptr in self.p.iter().chain(&self.cp) {
+            ptr.code.mark_functors(marks);
+        }
+        for index in 0..self.top() {
+            match *self.mem.frame(index) {
+                Frame::Environment(ref env) => {
+                    if let Some(ref cp) = env.cp {
+                        cp.code.mark_functors(marks);
+                    }
+                }
+                Frame::ChoicePoint(ref choice) => {
+                    if let Some(ref cp) = choice.cp {
+                        cp.code.mark_functors(marks);
+                    }
+                    match choice.alternative {
+                        Alternative::Clauses(ref clauses, next) => {
+                            clauses[next..].mark_functors(marks);
+                        }
+                        Alternative::Builtin(f, ref redo, ref next) => {
+                            marks.mark(f);
+                            redo.mark_functors(marks);
+                            next.code.mark_functors(marks);
+                        }
+                        Alternative::Branch(ref p) => p.code.mark_functors(marks),
+                        Alternative::Barrier(ref p) => {
+                            if let Some(ref p) = *p {
+                                p.code.mark_functors(marks);
+                            }
+                        }
+                        Alternative::Catch(_) | Alternative::Removed => { }
+                    }
+                }
+            }
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+// Control: the instructions of tutorial chapters 3 and 4
+
+impl<O: MachineObserver> Machine<O> {
+    /// Clears all state and prepares to run `code` from the start.
+    pub fn start(&mut self, code: Arc<Code>) {
+        self.mem.reset();
+        self.mode = Mode::Write;
+        self.cp = None;
+        self.e = None;
+        self.b = None;
+        self.b0 = None;
+        self.jump(code);
+    }
+
+    /// Fetches the next instruction, advancing P past it.
+    pub fn fetch(&mut self) -> Instruction {
+        let p = self.p.as_mut().expect("no code to run");
+        let instr = p.code.instructions[p.offset];
+        p.offset += 1;
+        instr
+    }
+
+    /// Steps back to the instruction just fetched, so that it is the
+    /// next to run again.
+    pub fn unfetch(&mut self) {
+        self.p.as_mut().expect("no code to run").offset -= 1;
+    }
+
+    /// The predicate whose code is currently running, if any.
+    pub fn current_predicate(&self) -> Option<Functor> {
+        self.p.as_ref().and_then(|p| p.code.predicate)
+    }
 
-    fn unify_variable(&mut self, reg: Register) {
-        match self.mode {
-            Mode::Read(ref mut next) => {
-                let cell = self.mem.load(*next);
-                self.mem.store(reg, cell);
-                next.bump();
+    /// Executes any instruction except `call` and `execute`, which
+    /// need a database and are left to the engine.
+    pub fn step(&mut self, instr: Instruction) -> Fallible {
+        match instr {
+            Instruction::PutStructure(f, r) => Ok(self.put_structure(f, r)),
+            Instruction::GetStructure(f, r) => self.get_structure(f, r),
+            _ => {
+                let result = self.control(instr);
+                self.executed(instr, result)
             }
+        }
+    }
 
-            Mode::Write => {
+    fn control(&mut self, instr: Instruction) -> Fallible {
+        match instr {
+            Instruction::SetVariable(v) => {
+                // from tutorial figure 2.2
                 let ptr = self.mem.next_slot();
                 let cell = Cell::Ref(ptr);
-                self.mem.push(cell);
-                self.mem.store(reg, cell);
+                self.push(cell);
+                let addr = self.var(v);
+                self.mem.store(addr, cell);
+                Ok(())
+            }
+            Instruction::SetValue(v) => {
+                // from tutorial figure 2.2
+                let cell = self.mem.load(self.var(v));
+                self.push(cell);
+                Ok(())
             }
+            Instruction::UnifyVariable(v) => {
+                let addr = self.var(v);
+                match self.mode {
+                    Mode::Read(ref mut next) => {
+                        let cell = self.mem.load(*next);
+                        self.mem.store(addr, cell);
+                        next.bump();
+                    }
+
+                    Mode::Write => {
+                        let ptr = self.mem.next_slot();
+                        let cell = Cell::Ref(ptr);
+                        self.push(cell);
+                        self.mem.store(addr, cell);
+                    }
+                }
+                Ok(())
+            }
+            Instruction::UnifyValue(v) => {
+                let addr = self.var(v);
+                match self.mode {
+                    Mode::Read(next) => {
+                        try!(self.mem.unify(addr, next.to_address(), &mut self.observer));
+                        self.mode = Mode::Read(next + 1);
+                        Ok(())
+                    }
+                    Mode::Write => {
+                        let cell = self.mem.load(addr);
+                        self.push(cell);
+                        Ok(())
+                    }
+                }
+            }
+            Instruction::PutConstant(c, a) => {
+                self.mem.store(a, Cell::Integer(c));
+                Ok(())
+            }
+            Instruction::SetConstant(c) => {
+                self.push(Cell::Integer(c));
+                Ok(())
+            }
+            Instruction::GetConstant(c, a) => {
+                self.get_constant(c, a.to_address())
+            }
+            Instruction::UnifyConstant(c) => {
+                match self.mode {
+                    Mode::Read(next) => {
+                        self.mode = Mode::Read(next + 1);
+                        self.get_constant(c, next.to_address())
+                    }
+                    Mode::Write => {
+                        self.push(Cell::Integer(c));
+                        Ok(())
+                    }
+                }
+            }
+            Instruction::PutString(s, a) => {
+                let cell = self.new_string(&s.to_string());
+                self.mem.store(a, cell);
+                Ok(())
+            }
+            Instruction::GetString(s, a) => {
+                self.get_string(&s.to_string(), a.to_address())
+            }
+            Instruction::PutVariable(v, a) => {
+                let slot = self.mem.next_slot();
+                let cell = Cell::Ref(slot);
+                self.push(cell);
+                let addr = self.var(v);
+                self.mem.store(addr, cell);
+                self.mem.store(a, cell);
+                Ok(())
+            }
+            Instruction::PutValue(v, a) => {
+                let cell = self.mem.load(self.var(v));
+                self.mem.store(a, cell);
+                Ok(())
+            }
+            Instruction::GetVariable(v, a) => {
+                let cell = self.mem.load(a);
+                let addr = self.var(v);
+                self.mem.store(addr, cell);
+                Ok(())
+            }
+            Instruction::GetValue(v, a) => {
+                let addr = self.var(v);
+                self.mem.unify(addr, a.to_address(), &mut self.observer)
+            }
+            Instruction::Proceed => {
+                let cp = self.cp.clone().expect("proceed without a continuation");
+                self.p = Some(cp);
+                self.entered();
+                Ok(())
+            }
+            Instruction::Allocate(n) => {
+                let env = Environment {
+                    prev: self.e,
+                    cp: self.cp.clone(),
+                    cut: self.b0,
+                    permanents: repeat(Cell::Uninitialized).take(n).collect(),
+                };
+                let top = self.top();
+                self.mem.push_frame(top, Frame::Environment(env));
+                self.e = Some(top);
+                Ok(())
+            }
+            Instruction::Deallocate => {
+                let (prev, cp) = match *self.mem.frame(self.env()) {
+                    Frame::Environment(ref env) => (env.prev, env.cp.clone()),
+                    Frame::ChoicePoint(_) => unreachable!(),
+                };
+                self.e = prev;
+                self.cp = cp;
+                Ok(())
+            }
+            Instruction::NeckCut => {
+                let b0 = self.b0;
+                self.cut(b0);
+                Ok(())
+            }
+            Instruction::Cut => {
+                let cut = match *self.mem.frame(self.env()) {
+                    Frame::Environment(ref env) => env.cut,
+                    Frame::ChoicePoint(_) => unreachable!(),
+                };
+                self.cut(cut);
+                Ok(())
+            }
+            Instruction::TryMeElse(offset) => {
+                let p = self.p.clone().expect("try_me_else outside of any code");
+                let branch = CodePtr { code: p.code, offset: offset };
+                // every variable lives in the environment, so there are
+                // no argument registers to save
+                self.push_choice_point(0, Alternative::Branch(branch));
+                Ok(())
+            }
+            Instruction::Jump(offset) => {
+                self.p.as_mut().expect("jump outside of any code").offset = offset;
+                Ok(())
+            }
+            Instruction::GetLevel(v) => {
+                let level = self.b.map(|b| b as i64).unwrap_or(-1);
+                let addr = self.var(v);
+                self.mem.store(addr, Cell::Integer(level));
+                Ok(())
+            }
+            Instruction::CutTo(v) => {
+                let level = self.level(v);
+                self.cut(level);
+                Ok(())
+            }
+            Instruction::SoftCut(v) => {
+                let index = self.level(v).expect("soft_cut without a choice point");
+                if self.b == Some(index) {
+                    let prev = match *self.mem.frame(index) {
+                        Frame::ChoicePoint(ref choice) => choice.prev,
+                        Frame::Environment(_) => unreachable!(),
+                    };
+                    self.pop_choice_point(prev);
+                } else if let Frame::ChoicePoint(ref mut choice) = *self.mem.frame_mut(index) {
+                    // newer choice points still need it to be restored
+                    // from, so leave it in place with nothing to try
+                    choice.alternative = Alternative::Removed;
+                }
+                Ok(())
+            }
+            Instruction::Fail => Err(()),
+            Instruction::Succeed => {
+                // whoever is running the query reports the solution
+                Ok(())
+            }
+            Instruction::Call(_) |
+            Instruction::Execute(_) => {
+                panic!("{:?} must be executed by an engine", instr)
+            }
+            _ => unreachable!(),
         }
     }
 
-    fn unify_value(&mut self, reg: Register) -> Fallible {
-        match self.mode {
-            Mode::Read(ref mut next) => {
-                try!(self.mem.unify(reg.to_address(), next.to_address()));
-                next.bump();
+    fn get_constant(&mut self, c: i64, addr: Address) -> Fallible {
+        let addr = self.mem.deref(addr);
+        match self.mem.load(addr) {
+            Cell::Ref(_) | Cell::Attributed(_) => {
+                self.mem.bind_to(addr, Cell::Integer(c), &mut self.observer);
                 Ok(())
             }
-            Mode::Write => {
-                let cell = self.mem.load(reg);
-                self.mem.push(cell);
+            Cell::Integer(i) if i == c => Ok(()),
+            cell => {
+                self.observer.unify_failure(cell, Cell::Integer(c));
+                Err(())
+            }
+        }
+    }
+
+    fn get_string(&mut self, text: &str, addr: Address) -> Fallible {
+        let addr = self.mem.deref(addr);
+        match self.mem.load(addr) {
+            Cell::Ref(_) | Cell::Attributed(_) => {
+                let cell = self.new_string(text);
+                self.mem.bind_to(addr, cell, &mut self.observer);
                 Ok(())
             }
+            Cell::String(slot) if self.mem.string(slot) == text => Ok(()),
+            cell => {
+                let string = self.new_string(text);
+                self.observer.unify_failure(cell, string);
+                Err(())
+            }
         }
     }
-}
 
-pub struct DumpMachine<'m> {
-    machine: &'m mut Machine
+    /// Performs a `call` (or, if `last`, an `execute`) of `callee`,
+    /// whose clauses are `clauses`. If there is more than one, a
+    /// choice point is pushed to try the others on backtracking.
+    pub fn call(&mut self, callee: Functor, clauses: Arc<Vec<Arc<Code>>>, last: bool) -> Fallible {
+        let caller = self.current_predicate();
+        self.observer.call(caller, callee);
+        if !last {
+            self.cp = self.p.clone();
+        }
+        self.b0 = self.b;
+
+        let result = if clauses.is_empty() {
+            Err(())
+        } else {
+            if clauses.len() > 1 {
+                let arity = callee.arity();
+                self.push_choice_point(arity, Alternative::Clauses(clauses.clone(), 1));
+            }
+            self.jump(clauses[0].clone());
+            Ok(())
+        };
+
+        let instr = if last { Instruction::Execute(callee) } else { Instruction::Call(callee) };
+        self.executed(instr, result)
+    }
+
+    /// Restores the state saved in the newest choice point and
+    /// resumes from its next alternative.
+    pub fn backtrack(&mut self) -> Resume {
+        let index = match self.b {
+            Some(index) => index,
+            None => return Resume::Nothing,
+        };
+        self.observer.backtrack();
+
+        let (resume, exhausted) = match *self.mem.frame_mut(index) {
+            Frame::ChoicePoint(ref mut choice) => {
+                match choice.alternative {
+                    Alternative::Clauses(ref clauses, ref mut next) => {
+                        let code = clauses[*next].clone();
+                        *next += 1;
+                        (Ok(CodePtr { code: code, offset: 0 }), *next == clauses.len())
+                    }
+                    Alternative::Branch(ref p) => (Ok(p.clone()), true),
+                    // a builtin pushes a new choice point if it has
+                    // yet more solutions
+                    Alternative::Builtin(f, ref redo, ref next) => {
+                        (Err(Some(Resume::Builtin(f, redo.clone(), next.clone()))), true)
+                    }
+                    Alternative::Barrier(ref p) => {
+                        self.p = p.clone();
+                        (Err(Some(Resume::Barrier)), true)
+                    }
+                    Alternative::Catch(_) | Alternative::Removed => (Err(None), true),
+                }
+            }
+            Frame::Environment(_) => unreachable!(),
+        };
+
+        let prev = self.restore(index);
+        if exhausted {
+            // that was the last alternative (`trust_me`)
+            self.pop_choice_point(prev);
+        }
+        match resume {
+            Ok(p) => {
+                self.p = Some(p);
+                self.entered();
+                Resume::Clause
+            }
+            Err(Some(resume)) => resume,
+            // nothing to try here, e.g. after a soft cut
+            Err(None) => self.backtrack(),
+        }
+    }
+
+    /// Notes a call of the builtin `callee`, which is implemented
+    /// outside the machine, returning where to continue if it succeeds.
+    pub fn enter_builtin(&mut self, callee: Functor, last: bool) -> CodePtr {
+        let caller = self.current_predicate();
+        self.observer.call(caller, callee);
+        let next = if last { &self.cp } else { &self.p };
+        next.clone().expect("builtin called without a continuation")
+    }
+
+    /// Finishes `instr`, a call of a builtin, continuing at `next` if
+    /// it succeeded.
+    pub fn exit_builtin(&mut self, instr: Instruction, next: CodePtr, result: Fallible)
+                        -> Fallible {
+        if result.is_ok() {
+            self.p = Some(next);
+            self.entered();
+        }
+        self.executed(instr, result)
+    }
+
+    /// Pushes a choice point that, on backtracking, calls the builtin
+    /// `f` again with `redo`. Must be called before the builtin binds
+    /// anything, while its arguments are still in place.
+    pub fn push_retry(&mut self, f: Functor, redo: Redo, next: CodePtr) {
+        self.push_choice_point(f.arity(), Alternative::Builtin(f, redo, next));
+    }
+
+    /// Starts running `code` as a subcomputation, e.g. for `findall/3`,
+    /// behind a barrier choice point that saves A1..An. Cuts in `code`
+    /// go no further than the barrier, and once `code` has no more
+    /// solutions, backtracking into the barrier restores the state from
+    /// before this call and returns `Resume::Barrier`.
+    ///
+    /// Returns the barrier, which can be passed to `cut_goal`.
+    pub fn push_goal(&mut self, code: Arc<Code>, arity: usize) -> usize {
+        let p = self.p.clone();
+        self.push_choice_point(arity, Alternative::Barrier(p));
+        self.b0 = self.b;
+        self.jump(code);
+        self.b.unwrap()
+    }
+
+    /// Discards the choice points of the subcomputation started with
+    /// `barrier`, so that backtracking goes straight to the barrier.
+    pub fn cut_goal(&mut self, barrier: usize) {
+        self.cut(Some(barrier));
+    }
+
+    /// Starts a call (or, if `last`, a tail call) of `catch/3`, pushing
+    /// a choice point that saves its arguments and continuation for
+    /// `unwind_catch`. Returns the flag to pass to `exit_catch` once
+    /// the goal succeeds.
+    pub fn push_catch(&mut self, last: bool) -> Slot {
+        if !last {
+            self.cp = self.p.clone();
+        }
+        let flag = self.mem.next_slot();
+        self.push(Cell::Ref(flag));
+        self.push_choice_point(3, Alternative::Catch(flag));
+        flag
+    }
+
+    /// Notes that the goal of the `catch/3` with `flag` has succeeded,
+    /// so that it no longer catches anything, at least until
+    /// backtracking returns into the goal.
+    pub fn exit_catch(&mut self, flag: Slot) {
+        self.mem.assign(flag, Cell::Integer(0));
+    }
+
+    /// Unwinds to the newest `catch/3` whose goal is still running,
+    /// restoring the state saved by `push_catch`, with its arguments
+    /// in A1..A3, and removing its frame. Returns false if there is no
+    /// such `catch/3` before the barrier of the current subcomputation,
+    /// leaving B at the barrier (if any).
+    pub fn unwind_catch(&mut self) -> bool {
+        while let Some(index) = self.b {
+            let (active, prev) = match *self.mem.frame(index) {
+                Frame::ChoicePoint(ref choice) => {
+                    let active = match choice.alternative {
+                        Alternative::Catch(flag) => self.mem.load(flag) == Cell::Ref(flag),
+                        Alternative::Barrier(_) => return false,
+                        _ => false,
+                    };
+                    (active, choice.prev)
+                }
+                Frame::Environment(_) => unreachable!(),
+            };
+            if active {
+                self.restore(index);
+            }
+            self.pop_choice_point(prev);
+            if active {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Reads argument register `i` back out as a term.
+    pub fn argument(&self, i: usize) -> Term {
+        self.mem.term(Register(i).to_address())
+    }
+
+    /// Like `argument`, but also records the cell of each variable in
+    /// `vars`, so that `put_term` can refer to the same variables.
+    pub fn read_argument(&self, i: usize, vars: &mut HashMap<InternedString, Cell>) -> Term {
+        self.mem.read_term(Register(i).to_address(), vars)
+    }
+
+    pub fn set_argument(&mut self, i: usize, cell: Cell) {
+        self.mem.store(Register(i), cell);
+    }
+
+    /// Reads the term at `ptr` back out of memory.
+    pub fn term<P:Pointer+FromSlot>(&self, ptr: P) -> Term {
+        self.mem.term(ptr)
+    }
+
+    /// Reads the term that `cell` refers to back out of memory.
+    pub fn cell_term(&self, cell: Cell) -> Term {
+        self.mem.read_cell(cell, &mut HashMap::new())
+    }
+
+    /// `cell`, after following any chain of references; an unbound
+    /// variable is a `Cell::Ref` to itself.
+    pub fn value(&self, cell: Cell) -> Cell {
+        self.mem.resolve(cell)
+    }
+
+    /// The functor and argument cells of the structure at `slot`, as
+    /// found in a `Cell::Structure`.
+    pub fn structure(&self, slot: Slot) -> (Functor, Vec<Cell>) {
+        let f = self.mem.load_functor(slot);
+        (f, (1..f.arity()+1).map(|i| self.mem.load(slot + i)).collect())
+    }
+
+    /// Builds the structure `f(args...)` on the heap.
+    pub fn new_structure(&mut self, f: Functor, args: &[Cell]) -> Cell {
+        let slot = self.mem.next_slot();
+        self.push(Cell::Functor(f));
+        for &cell in args {
+            self.push(cell);
+        }
+        Cell::Structure(slot)
+    }
+
+    /// Packs the string `text` onto the heap.
+    pub fn new_string(&mut self, text: &str) -> Cell {
+        let slot = self.mem.next_slot();
+        for cell in pack(text) {
+            self.push(cell);
+        }
+        Cell::String(slot)
+    }
+
+    /// Pushes a fresh variable onto the heap.
+    pub fn new_variable(&mut self) -> Cell {
+        let cell = Cell::Ref(self.mem.next_slot());
+        self.push(cell);
+        cell
+    }
+
+    /// The attributes of the variable `var` refers to, if it is an
+    /// unbound variable that has any.
+    pub fn attributes(&self, var: Cell) -> Option<Cell> {
+        match self.mem.resolve(var) {
+            Cell::Ref(slot) => self.mem.attributes(slot),
+            _ => None,
+        }
+    }
+
+    /// Gives the unbound variable `var` refers to the attributes
+    /// `attrs`, until backtracking undoes it. A plain variable is bound
+    /// to a new attributed one.
+    pub fn set_attributes(&mut self, var: Cell, attrs: Cell) {
+        let var = match self.mem.resolve(var) {
+            Cell::Ref(slot) => slot,
+            cell => panic!("set_attributes found bound variable: {:?}", cell),
+        };
+        if self.mem.attributes(var).is_some() {
+            self.mem.assign(var + 1, attrs);
+        } else {
+            let slot = self.mem.next_slot();
+            self.push(Cell::Attributed(slot));
+            self.push(attrs);
+            self.mem.assign(var, Cell::Ref(slot));
+        }
+    }
+
+    /// Whether attributed variables have been bound since the last call
+    /// of `take_wakeups`.
+    pub fn has_wakeups(&self) -> bool {
+        self.mem.has_wakeups()
+    }
+
+    /// The attributed variables bound since this was last called, each
+    /// as its attributes and the value it was bound to.
+    pub fn take_wakeups(&mut self) -> Vec<(Cell, Cell)> {
+        self.mem.take_wakeups()
+    }
+
+    /// Reads the term that `cell` refers to, recording the cell of each
+    /// variable in `vars` as `read_argument` does.
+    pub fn read_cell(&self, cell: Cell, vars: &mut HashMap<InternedString, Cell>) -> Term {
+        self.mem.read_cell(cell, vars)
+    }
+
+    /// Replaces argument `i` of the structure at `slot` with `cell`,
+    /// until backtracking undoes it.
+    pub fn set_arg(&mut self, slot: Slot, i: usize, cell: Cell) {
+        self.mem.assign(slot + i, cell);
+    }
+
+    /// Replaces argument `i` of the structure at `slot` with a copy of
+    /// `value` for good: backtracking undoes neither the change nor the
+    /// copy.
+    pub fn nb_set_arg(&mut self, slot: Slot, i: usize, value: &Term) {
+        let start = self.mem.next_slot();
+        let copy = self.put_term(value, &mut HashMap::new());
+        self.mem.keep(slot + i, start, Cell::Ref(copy));
+    }
+
+    /// The number of cells on the heap.
+    pub fn heap_size(&self) -> usize {
+        self.mem.heap().len()
+    }
+
+    pub fn unify_cells(&mut self, a: Cell, b: Cell) -> Fallible {
+        let a = self.cell_address(a);
+        let b = self.cell_address(b);
+        self.mem.unify(a, b, &mut self.observer)
+    }
+
+    /// Tests whether the terms `a` and `b` refer to unify, without
+    /// binding anything.
+    pub fn unifiable_cells(&mut self, a: Cell, b: Cell) -> bool {
+        let a = self.cell_address(a);
+        let b = self.cell_address(b);
+        self.mem.unifiable(a, b)
+    }
+
+    /// Compares the terms `a` and `b` refer to in the standard order.
+    pub fn compare(&self, a: Cell, b: Cell) -> cmp::Ordering {
+        self.mem.compare(a, b)
+    }
+
+    /// An address holding `cell`, pushing it onto the heap if need be.
+    fn cell_address(&mut self, cell: Cell) -> Address {
+        match cell {
+            Cell::Ref(slot) | Cell::Attributed(slot) => slot.to_address(),
+            cell => {
+                let slot = self.mem.next_slot();
+                self.push(cell);
+                slot.to_address()
+            }
+        }
+    }
+
+    pub fn argument_cell(&self, i: usize) -> Cell {
+        self.mem.load(Register(i))
+    }
+
+    /// Builds `term` on the heap, returning the slot that holds it.
+    /// Variables are looked up in `vars`, and any not found there are
+    /// created fresh and added.
+    pub fn put_term(&mut self, term: &Term, vars: &mut HashMap<InternedString, Cell>) -> Slot {
+        let cell = self.term_cell(term, vars);
+        let slot = self.mem.next_slot();
+        self.push(cell);
+        slot
+    }
+
+    fn term_cell(&mut self, term: &Term, vars: &mut HashMap<InternedString, Cell>) -> Cell {
+        match *term {
+            Term::Variable(v) => {
+                if let Some(&cell) = vars.get(&v) {
+                    return cell;
+                }
+                let slot = self.mem.next_slot();
+                let cell = Cell::Ref(slot);
+                self.push(cell);
+                vars.insert(v, cell);
+                cell
+            }
+            Term::Structure(ref s) => {
+                let args: Vec<_> = s.terms.iter().map(|t| self.term_cell(t, vars)).collect();
+                let slot = self.mem.next_slot();
+                self.push(Cell::Functor(s.functor));
+                for cell in args {
+                    self.push(cell);
+                }
+                Cell::Structure(slot)
+            }
+            Term::Integer(i) => Cell::Integer(i),
+            Term::String(ref s) => self.new_string(s),
+        }
+    }
+
+    pub fn unify<P:Pointer, Q:Pointer>(&mut self, a: P, b: Q) -> Fallible {
+        self.mem.unify(a.to_address(), b.to_address(), &mut self.observer)
+    }
+
+    /// Tests whether `a` and `b` unify, without binding anything.
+    pub fn unifiable<P:Pointer, Q:Pointer>(&mut self, a: P, b: Q) -> bool {
+        self.mem.unifiable(a.to_address(), b.to_address())
+    }
+
+    /// Reads permanent variable `y` of the current environment back
+    /// out as a term.
+    pub fn permanent_term(&self, y: Permanent) -> Term {
+        self.mem.term(self.var(Var::Permanent(y)))
+    }
+
+    fn jump(&mut self, code: Arc<Code>) {
+        self.p = Some(CodePtr { code: code, offset: 0 });
+        self.entered();
+    }
+
+    fn entered(&mut self) {
+        let predicate = self.current_predicate();
+        self.observer.enter(predicate);
+    }
+
+    fn env(&self) -> usize {
+        self.e.expect("no current environment")
+    }
+
+    fn var(&self, v: Var) -> Address {
+        match v {
+            Var::Temporary(r) => r.to_address(),
+            Var::Permanent(y) => Address::Stack(self.env(), y.0 - 1),
+        }
+    }
+
+    /// The index at which the next frame is pushed: just above the
+    /// newer of E and B.
+    fn top(&self) -> usize {
+        let above = |frame: Option<usize>| frame.map(|i| i + 1).unwrap_or(0);
+        cmp::max(above(self.e), above(self.b))
+    }
+
+    fn push_choice_point(&mut self, arity: usize, alternative: Alternative) {
+        let heap = self.mem.next_slot();
+        let choice = ChoicePoint {
+            args: (1..arity+1).map(|i| self.mem.load(Register(i))).collect(),
+            env: self.e,
+            cp: self.cp.clone(),
+            prev: self.b,
+            trail: self.mem.trail_len(),
+            heap: heap,
+            wakeups: self.mem.pending_wakeups(),
+            cut: self.b0,
+            alternative: alternative,
+        };
+        let top = self.top();
+        self.mem.push_frame(top, Frame::ChoicePoint(choice));
+        self.b = Some(top);
+        self.mem.set_boundary(Some(heap));
+        self.observer.choice_point();
+    }
+
+    /// Restores the registers, trail and heap saved in the choice
+    /// point at `index`, returning the choice point before it.
+    fn restore(&mut self, index: usize) -> Option<usize> {
+        let (args, trail, heap, wakeups, prev) = match *self.mem.frame(index) {
+            Frame::ChoicePoint(ref choice) => {
+                self.e = choice.env;
+                self.cp = choice.cp.clone();
+                self.b0 = choice.cut;
+                (choice.args.clone(), choice.trail, choice.heap, choice.wakeups.clone(),
+                 choice.prev)
+            }
+            Frame::Environment(_) => unreachable!(),
+        };
+        for (i, cell) in args.into_iter().enumerate() {
+            self.mem.store(Register(i + 1), cell);
+        }
+        self.mem.unwind_trail(trail);
+        self.mem.truncate_heap(heap);
+        self.mem.restore_wakeups(wakeups);
+        prev
+    }
+
+    fn pop_choice_point(&mut self, prev: Option<usize>) {
+        self.b = prev;
+        let boundary = prev.map(|index| match *self.mem.frame(index) {
+            Frame::ChoicePoint(ref choice) => choice.heap,
+            Frame::Environment(_) => panic!("frame {} is not a choice point", index),
+        });
+        self.mem.set_boundary(boundary);
+    }
+
+    /// The choice point saved in `v` by `get_level`.
+    fn level(&self, v: Var) -> Option<usize> {
+        match self.mem.load(self.var(v)) {
+            Cell::Integer(level) if level >= 0 => Some(level as usize),
+            Cell::Integer(_) => None,
+            cell => panic!("{:?} does not hold a level", cell),
+        }
+    }
+
+    /// Discards every choice point newer than `barrier`.
+    fn cut(&mut self, barrier: Option<usize>) {
+        if self.b > barrier {
+            self.pop_choice_point(barrier);
+        }
+    }
 }
 
-impl<'m> MachineOps for DumpMachine<'m> {
+impl<O: MachineObserver> MachineOps for Machine<O> {
+    /// from tutorial figure 2.2
     fn put_structure(&mut self, f: Functor, r: Register) {
-        let result = self.machine.put_structure(f, r);
-        println!("put_structure({:?}, {:?}) = {:?}", f, r, result);
-        println!("{:#?}", self.machine);
-        result
+        let ptr = self.mem.next_slot();
+        let cell = Cell::Structure(ptr + 1);
+        self.push(cell);
+        self.push(Cell::Functor(f));
+        self.mem.store(r, cell);
+        let _ = self.executed(Instruction::PutStructure(f, r), Ok(()));
     }
 
     fn set_variable(&mut self, r: Register) {
-        let result = self.machine.set_variable(r);
-        println!("set_variable({:?}) = {:?}", r, result);
-        println!("{:#?}", self.machine);
-        result
+        let _ = self.step(Instruction::SetVariable(Var::Temporary(r)));
     }
 
     fn set_value(&mut self, r: Register) {
-        let result = self.machine.set_value(r);
-        println!("set_value({:?}) = {:?}", r, result);
-        println!("{:#?}", self.machine);
-        result
+        let _ = self.step(Instruction::SetValue(Var::Temporary(r)));
     }
 
     fn get_structure(&mut self, f: Functor, r: Register) -> Fallible {
-        let result = self.machine.get_structure(f, r);
-        println!("get_structure({:?}, {:?}) = {:?}", f, r, result);
-        println!("{:#?}", self.machine);
-        result
+        let result = self.get_structure_inner(f, r);
+        self.executed(Instruction::GetStructure(f, r), result)
     }
 
     fn unify_variable(&mut self, r: Register) {
-        let result = self.machine.unify_variable(r);
-        println!("unify_variable({:?}) = {:?}", r, result);
-        println!("{:#?}", self.machine);
-        result
+        let _ = self.step(Instruction::UnifyVariable(Var::Temporary(r)));
     }
 
     fn unify_value(&mut self, r: Register) -> Fallible {
-        let result = self.machine.unify_value(r);
-        println!("unify_variable({:?}) = {:?}", r, result);
-        println!("{:#?}", self.machine);
-        result
+        self.step(Instruction::UnifyValue(Var::Temporary(r)))
     }
 }
-
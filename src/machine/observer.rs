@@ -0,0 +1,80 @@
+//! Instrumentation hooks. A `MachineObserver` receives structured
+//! events as the machine runs; the unit observer `()` ignores them
+//! all, and since `Machine` is generic over its observer those calls
+//! compile away entirely when nobody is listening.
+
+use std::io::Write;
+
+use functor::Functor;
+
+use super::{Fallible, Instruction};
+use super::mem::{Address, Cell, Memory, Slot};
+
+pub trait MachineObserver {
+    /// Invoked after each instruction completes, with its result and
+    /// the state of memory afterwards.
+    fn instruction(&mut self, _instr: Instruction, _result: Fallible, _mem: &Memory) {
+    }
+
+    /// Invoked when the unbound variable at `var` is bound to `value`.
+    fn bind(&mut self, _var: Address, _value: Cell) {
+    }
+
+    /// Invoked when unification fails because `left` and `right`
+    /// cannot be made equal.
+    fn unify_failure(&mut self, _left: Cell, _right: Cell) {
+    }
+
+    /// Invoked when `cell` is pushed onto the heap at `slot`.
+    fn heap_push(&mut self, _slot: Slot, _cell: Cell) {
+    }
+
+    /// Invoked when code for `caller` (`None` for a query) calls
+    /// `callee`, i.e., on every logical inference.
+    fn call(&mut self, _caller: Option<Functor>, _callee: Functor) {
+    }
+
+    /// Invoked whenever control passes into the code of a predicate
+    /// (`None` for a query): on calls, returns and backtracking.
+    fn enter(&mut self, _predicate: Option<Functor>) {
+    }
+
+    /// Invoked when a choice point is created.
+    fn choice_point(&mut self) {
+    }
+
+    /// Invoked when the machine backtracks into a choice point.
+    fn backtrack(&mut self) {
+    }
+}
+
+impl MachineObserver for () {
+}
+
+/// Writes a trace of every instruction and binding, along with a dump
+/// of memory after each instruction, to `out`.
+pub struct Dump<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Dump<W> {
+    pub fn new(out: W) -> Dump<W> {
+        Dump { out: out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> MachineObserver for Dump<W> {
+    fn instruction(&mut self, instr: Instruction, result: Fallible, mem: &Memory) {
+        // tracing is best effort; an I/O error should not abort execution
+        let _ = writeln!(self.out, "{:?} = {:?}", instr, result);
+        let _ = writeln!(self.out, "{:#?}", mem);
+    }
+
+    fn bind(&mut self, var: Address, value: Cell) {
+        let _ = writeln!(self.out, "bind({:?}, {:?})", var, value);
+    }
+}
@@ -0,0 +1,229 @@
+//! Instruction- and predicate-level profiling, implemented as a
+//! `MachineObserver`.
+
+use functor::Functor;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use super::{Fallible, Instruction};
+use super::mem::{Address, Cell, Memory, Slot};
+use super::observer::MachineObserver;
+
+#[derive(Debug)]
+pub struct Profiler {
+    started: Option<Instant>,
+    elapsed: Duration,
+    opcodes: BTreeMap<&'static str, OpcodeCounts>,
+    heap_cells: usize,
+    bindings: usize,
+    failures: usize,
+
+    /// Per-predicate counts; `None` stands for the query.
+    predicates: HashMap<Option<Functor>, PredicateCounts>,
+    /// Number of calls along each edge of the call graph.
+    calls: HashMap<(Option<Functor>, Functor), usize>,
+    /// The predicate currently running, and when control entered it.
+    current: Option<(Option<Functor>, Instant)>,
+    choice_points: usize,
+    backtracks: usize,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PredicateCounts {
+    pub calls: usize,
+    pub instructions: usize,
+    /// Time spent running this predicate's own code.
+    pub time: Duration,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpcodeCounts {
+    pub executed: usize,
+    pub failed: usize,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            started: None,
+            elapsed: Duration::from_secs(0),
+            opcodes: BTreeMap::new(),
+            heap_cells: 0,
+            bindings: 0,
+            failures: 0,
+            predicates: HashMap::new(),
+            calls: HashMap::new(),
+            current: None,
+            choice_points: 0,
+            backtracks: 0,
+        }
+    }
+
+    /// Counts for each predicate that was called or ran any code.
+    pub fn predicates(&self) -> &HashMap<Option<Functor>, PredicateCounts> {
+        &self.predicates
+    }
+
+    /// How many times `caller` (`None` for the query) called `callee`.
+    pub fn calls(&self, caller: Option<Functor>, callee: Functor) -> usize {
+        self.calls.get(&(caller, callee)).cloned().unwrap_or(0)
+    }
+
+    /// Logical inferences, i.e., predicate calls.
+    pub fn inferences(&self) -> usize {
+        self.calls.values().sum()
+    }
+
+    pub fn choice_points(&self) -> usize {
+        self.choice_points
+    }
+
+    pub fn backtracks(&self) -> usize {
+        self.backtracks
+    }
+
+    /// Execution counts for each opcode, keyed by opcode name.
+    pub fn opcodes(&self) -> &BTreeMap<&'static str, OpcodeCounts> {
+        &self.opcodes
+    }
+
+    pub fn instructions(&self) -> usize {
+        self.opcodes.values().map(|c| c.executed).sum()
+    }
+
+    pub fn heap_cells(&self) -> usize {
+        self.heap_cells
+    }
+
+    pub fn bindings(&self) -> usize {
+        self.bindings
+    }
+
+    pub fn unify_failures(&self) -> usize {
+        self.failures
+    }
+
+    /// Wall-clock time from the first instruction to the most recent one.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn reset(&mut self) {
+        *self = Profiler::new();
+    }
+
+    /// Writes a flat profile, one line per opcode and then one per
+    /// predicate, sorted by the number of times each was executed,
+    /// followed by the call graph: one line per caller and callee.
+    pub fn report<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let total = self.instructions();
+        try!(writeln!(out, "{} instructions in {:?}", total, self.elapsed));
+        try!(writeln!(out, "{} heap cells, {} bindings, {} unification failures",
+                      self.heap_cells, self.bindings, self.failures));
+        try!(writeln!(out, "{} inferences, {} choice points, {} backtracks",
+                      self.inferences(), self.choice_points, self.backtracks));
+        try!(writeln!(out, ""));
+        try!(writeln!(out, "{:>10} {:>6} {:>8}  opcode", "executed", "%", "failed"));
+
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.1.executed.cmp(&a.1.executed));
+        for (name, counts) in opcodes {
+            let percent = 100.0 * counts.executed as f64 / total as f64;
+            try!(writeln!(out, "{:>10} {:>6.2} {:>8}  {}",
+                          counts.executed, percent, counts.failed, name));
+        }
+
+        if self.predicates.is_empty() {
+            return Ok(());
+        }
+        try!(writeln!(out, ""));
+        try!(writeln!(out, "{:>10} {:>12} {:>12}  predicate", "calls", "instructions", "time"));
+        let mut predicates: Vec<_> = self.predicates.iter().collect();
+        predicates.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions));
+        for (&predicate, counts) in predicates {
+            try!(writeln!(out, "{:>10} {:>12} {:>12}  {}",
+                          counts.calls, counts.instructions,
+                          format!("{:?}", counts.time), name(predicate)));
+        }
+
+        if self.calls.is_empty() {
+            return Ok(());
+        }
+        try!(writeln!(out, ""));
+        try!(writeln!(out, "{:>10}  caller -> callee", "calls"));
+        let mut calls: Vec<_> = self.calls.iter()
+                                          .map(|(&(caller, callee), &n)| {
+                                              (n, name(caller), name(Some(callee)))
+                                          })
+                                          .collect();
+        calls.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| (&a.1, &a.2).cmp(&(&b.1, &b.2))));
+        for (n, caller, callee) in calls {
+            try!(writeln!(out, "{:>10}  {} -> {}", n, caller, callee));
+        }
+        Ok(())
+    }
+
+    fn predicate(&mut self, predicate: Option<Functor>) -> &mut PredicateCounts {
+        self.predicates.entry(predicate).or_insert_with(PredicateCounts::default)
+    }
+}
+
+/// How the report names `predicate`.
+fn name(predicate: Option<Functor>) -> String {
+    match predicate {
+        Some(f) => format!("{:?}", f),
+        None => "(query)".to_string(),
+    }
+}
+
+impl MachineObserver for Profiler {
+    fn instruction(&mut self, instr: Instruction, result: Fallible, _mem: &Memory) {
+        let now = Instant::now();
+        let started = *self.started.get_or_insert(now);
+        self.elapsed = now.duration_since(started);
+
+        let counts = self.opcodes.entry(instr.name()).or_insert_with(OpcodeCounts::default);
+        counts.executed += 1;
+        if result.is_err() {
+            counts.failed += 1;
+        }
+
+        if let Some((predicate, _)) = self.current {
+            self.predicate(predicate).instructions += 1;
+        }
+    }
+
+    fn call(&mut self, caller: Option<Functor>, callee: Functor) {
+        *self.calls.entry((caller, callee)).or_insert(0) += 1;
+        self.predicate(Some(callee)).calls += 1;
+    }
+
+    fn enter(&mut self, predicate: Option<Functor>) {
+        let now = Instant::now();
+        if let Some((previous, since)) = self.current {
+            self.predicate(previous).time += now.duration_since(since);
+        }
+        self.current = Some((predicate, now));
+    }
+
+    fn choice_point(&mut self) {
+        self.choice_points += 1;
+    }
+
+    fn backtrack(&mut self) {
+        self.backtracks += 1;
+    }
+
+    fn bind(&mut self, _var: Address, _value: Cell) {
+        self.bindings += 1;
+    }
+
+    fn unify_failure(&mut self, _left: Cell, _right: Cell) {
+        self.failures += 1;
+    }
+
+    fn heap_push(&mut self, _slot: Slot, _cell: Cell) {
+        self.heap_cells += 1;
+    }
+}
@@ -0,0 +1,97 @@
+//! The WAM stack, which holds environments and choice points
+//! interleaved (tutorial sections 4.1 and 4.2). Frames are addressed
+//! by their index; a new frame always goes just above whichever of
+//! the current environment and choice point is newer, so that a
+//! choice point protects the environments it may return to.
+
+use ast::Term;
+use functor::{Functor, Marks, MarkFunctors};
+use std::sync::Arc;
+
+use super::{Code, CodePtr};
+use super::mem::{Cell, Slot};
+
+#[derive(Debug)]
+pub enum Frame {
+    Environment(Environment),
+    ChoicePoint(ChoicePoint),
+}
+
+#[derive(Debug)]
+pub struct Environment {
+    /// The caller's environment (CE).
+    pub prev: Option<usize>,
+    /// Where to continue once this clause's body is done (CP).
+    pub cp: Option<CodePtr>,
+    /// The choice point to cut back to (B0 at the time of the call).
+    pub cut: Option<usize>,
+    /// The permanent variables Y1..Yn.
+    pub permanents: Vec<Cell>,
+}
+
+#[derive(Debug)]
+pub struct ChoicePoint {
+    /// The argument registers A1..An at the time of the call.
+    pub args: Vec<Cell>,
+    pub env: Option<usize>,
+    pub cp: Option<CodePtr>,
+    /// The previous choice point.
+    pub prev: Option<usize>,
+    /// Length of the trail and heap when the choice point was made.
+    pub trail: usize,
+    pub heap: Slot,
+    /// The attributed variables bound, but not yet woken, when the
+    /// choice point was made; backtracking leaves them bound.
+    pub wakeups: Vec<(Slot, Cell)>,
+    /// B0 at the time of the call.
+    pub cut: Option<usize>,
+    pub alternative: Alternative,
+}
+
+/// What to try when backtracking into a choice point.
+#[derive(Clone, Debug)]
+pub enum Alternative {
+    /// The remaining clauses of a predicate, starting at the given
+    /// index. The clause list is a snapshot taken at the time of the
+    /// call.
+    Clauses(Arc<Vec<Arc<Code>>>, usize),
+    /// The other branch of a disjunction within a clause.
+    Branch(CodePtr),
+    /// Call a builtin again with the given state, continuing at the
+    /// given code if it succeeds.
+    Builtin(Functor, Redo, CodePtr),
+    /// The start of a subcomputation (see `Machine::push_goal`), with
+    /// the code to return to once it has no more solutions.
+    Barrier(Option<CodePtr>),
+    /// A running `catch/3`, found by `Machine::unwind_catch`; there is
+    /// nothing to try on backtracking. The goal is still running as
+    /// long as the variable at the slot is unbound.
+    Catch(Slot),
+    /// Nothing: a soft cut removed the alternative, but the frame is
+    /// still needed below newer choice points.
+    Removed,
+}
+
+/// The state a nondeterministic builtin needs to find its next
+/// solution.
+#[derive(Clone, Debug)]
+pub enum Redo {
+    /// Continue with the clause at the given index of a snapshot.
+    Clauses(Arc<Vec<Arc<Code>>>, usize),
+    /// Unify the arguments with the terms at the given index of a
+    /// precomputed list of solutions.
+    Solutions(Arc<Vec<Vec<Term>>>, usize),
+    /// Continue from the given position, e.g. the next argument of a
+    /// term.
+    Next(usize),
+}
+
+impl MarkFunctors for Redo {
+    fn mark_functors(&self, marks: &mut Marks) {
+        match *self {
+            Redo::Clauses(ref clauses, next) => clauses[next..].mark_functors(marks),
+            Redo::Solutions(ref solutions, next) => solutions[next..].mark_functors(marks),
+            Redo::Next(_) => { }
+        }
+    }
+}
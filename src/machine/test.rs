@@ -1,7 +1,14 @@
-use super::{Fallible, Machine, MachineOps};
-use super::mem::Register;
+use super::{Fallible, Instruction, Machine, MachineOps};
+use super::mem::{Address, Cell, Memory, Register, Slot};
+use super::bytecode::{self, Program};
+use super::listing::Listing;
+use super::observer::{Dump, MachineObserver};
+use super::profile::Profiler;
 
+use functor::{Functor, Marks, MarkFunctors, Owner};
 use interpret;
+use std::panic;
+use std::thread;
 
 fn heap(machine: &Machine) -> Vec<String> {
     machine.mem.heap().iter()
@@ -25,36 +32,36 @@ macro_rules! test_heap {
 }
 
 fn figure2_3<M:MachineOps>(machine: &mut M) {
-    machine.put_structure(functor!(h/2), Register(2));
-    machine.set_variable(Register(1));
-    machine.set_variable(Register(4));
-    machine.put_structure(functor!(f/1), Register(3));
-    machine.set_value(Register(4));
-    machine.put_structure(functor!(p/3), Register(0));
-    machine.set_value(Register(1));
+    machine.put_structure(functor!(h/2), Register(3));
+    machine.set_variable(Register(2));
+    machine.set_variable(Register(5));
+    machine.put_structure(functor!(f/1), Register(4));
+    machine.set_value(Register(5));
+    machine.put_structure(functor!(p/3), Register(1));
     machine.set_value(Register(2));
     machine.set_value(Register(3));
+    machine.set_value(Register(4));
 }
 
 fn figure2_4<M:MachineOps>(machine: &mut M) -> Fallible {
-    try!(machine.get_structure(functor!(p/3), Register(0)));
-    machine.unify_variable(Register(1));
+    try!(machine.get_structure(functor!(p/3), Register(1)));
     machine.unify_variable(Register(2));
     machine.unify_variable(Register(3));
-    try!(machine.get_structure(functor!(f/1), Register(1)));
     machine.unify_variable(Register(4));
-    try!(machine.get_structure(functor!(h/2), Register(2)));
-    try!(machine.unify_value(Register(3)));
+    try!(machine.get_structure(functor!(f/1), Register(2)));
     machine.unify_variable(Register(5));
-    try!(machine.get_structure(functor!(f/1), Register(5)));
+    try!(machine.get_structure(functor!(h/2), Register(3)));
+    try!(machine.unify_value(Register(4)));
     machine.unify_variable(Register(6));
-    try!(machine.get_structure(functor!(a/0), Register(6)));
+    try!(machine.get_structure(functor!(f/1), Register(6)));
+    machine.unify_variable(Register(7));
+    try!(machine.get_structure(functor!(a/0), Register(7)));
     Ok(())
 }
 
 #[test]
 fn exercise2_1() {
-    let mut machine = Machine::new(5);
+    let mut machine = Machine::new(6);
     figure2_3(&mut machine);
 
     test_heap!(
@@ -75,11 +82,11 @@ fn exercise2_1() {
 
 #[test]
 fn exercise2_3() {
-    let mut machine = Machine::new(7);
+    let mut machine = Machine::new(8);
     figure2_3(&mut machine);
     figure2_4(&mut machine).unwrap();
     assert_eq!(
-        &format!("{:?}", machine.mgu(Register(0))),
+        &format!("{:?}", machine.mgu(Register(1))),
         "p(f(f(a)),h(f(f(a)),f(a)),f(f(a)))");
 }
 
@@ -88,16 +95,12 @@ fn exercise2_3_1() {
     // same as above, but using `interpret` to drive the machine,
     // instead of a hardcoded sequence of instructions
 
-    let mut machine = Machine::new(7);
-
-    {
-        let mut machine = machine.dump();
-        interpret::query(&mut machine,   &structure!(p(?Z,    h(?Z, ?W),   f(?W))));
-        interpret::program(&mut machine, &structure!(p(f(?X), h(?Y, f(a)), ?Y))).unwrap();
-    }
+    let mut machine = Machine::with_observer(8, Dump::new(vec![]));
+    interpret::query(&mut machine,   &structure!(p(?Z,    h(?Z, ?W),   f(?W))));
+    interpret::program(&mut machine, &structure!(p(f(?X), h(?Y, f(a)), ?Y))).unwrap();
 
     assert_eq!(
-        &format!("{:?}", machine.mgu(Register(0))),
+        &format!("{:?}", machine.mgu(Register(1))),
         "p(f(f(a)),h(f(f(a)),f(a)),f(f(a)))");
 }
 
@@ -106,7 +109,7 @@ fn fail_to_unify1() {
     // same as above, but using `interpret` to drive the machine,
     // instead of a hardcoded sequence of instructions
 
-    let mut machine = Machine::new(7);
+    let mut machine = Machine::new(8);
     interpret::query(&mut machine,   &structure!(p(?Z,    ?Z)));
     assert!(interpret::program(&mut machine, &structure!(p(f(?X), g(?X)))).is_err());
 }
@@ -116,11 +119,224 @@ fn exercise2_3_5() {
     // same as above, but using `interpret` to drive the machine,
     // instead of a hardcoded sequence of instructions
 
-    let mut machine = Machine::new(7);
+    let mut machine = Machine::new(8);
     interpret::query(&mut machine,   &structure!(p(f(?X), h(?Y, f(a)), ?Y)));
     interpret::program(&mut machine, &structure!(p(?Z,    h(?Z, ?W),   f(?W)))).unwrap();
 
     assert_eq!(
-        &format!("{:?}", machine.mgu(Register(0))),
+        &format!("{:?}", machine.mgu(Register(1))),
+        "p(f(f(a)),h(f(f(a)),f(a)),f(f(a)))");
+}
+
+#[derive(Default)]
+struct Events {
+    instructions: Vec<String>,
+    binds: Vec<String>,
+    failures: Vec<String>,
+    pushes: usize,
+}
+
+impl MachineObserver for Events {
+    fn instruction(&mut self, instr: Instruction, result: Fallible, _mem: &Memory) {
+        self.instructions.push(format!("{:?} = {:?}", instr, result));
+    }
+
+    fn bind(&mut self, var: Address, value: Cell) {
+        self.binds.push(format!("{:?} := {:?}", var, value));
+    }
+
+    fn unify_failure(&mut self, left: Cell, right: Cell) {
+        self.failures.push(format!("{:?} != {:?}", left, right));
+    }
+
+    fn heap_push(&mut self, _slot: Slot, _cell: Cell) {
+        self.pushes += 1;
+    }
+}
+
+#[test]
+fn observe_events() {
+    let mut machine = Machine::with_observer(7, Events::default());
+    interpret::query(&mut machine,   &structure!(p(?Z,    ?Z)));
+    assert!(interpret::program(&mut machine, &structure!(p(f(?X), g(?X)))).is_err());
+
+    let events = machine.into_observer();
+    assert_eq!(events.instructions.last().unwrap(), "GetStructure(g/1, R3) = Err(())");
+    assert_eq!(events.binds, vec!["Heap(2) := Structure(H5)"]);
+    assert_eq!(events.failures, vec!["Functor(f/1) != Functor(g/1)"]);
+    assert_eq!(events.pushes, 7);
+}
+
+#[test]
+fn profile_opcodes() {
+    let mut machine = Machine::with_observer(7, Profiler::new());
+    interpret::query(&mut machine,   &structure!(p(?Z,    ?Z)));
+    assert!(interpret::program(&mut machine, &structure!(p(f(?X), g(?X)))).is_err());
+
+    let profiler = machine.observer();
+    assert_eq!(profiler.instructions(), 9);
+    assert_eq!(profiler.opcodes()["get_structure"].executed, 3);
+    assert_eq!(profiler.opcodes()["get_structure"].failed, 1);
+    assert_eq!(profiler.opcodes()["set_variable"].executed, 1);
+    assert_eq!(profiler.heap_cells(), 7);
+    assert_eq!(profiler.bindings(), 1);
+    assert_eq!(profiler.unify_failures(), 1);
+
+    let mut report = vec![];
+    profiler.report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.contains("3  33.33        1  get_structure"));
+}
+
+#[test]
+fn execute_compiled() {
+    let mut query = vec![];
+    interpret::query(&mut query, &structure!(p(?Z, h(?Z, ?W), f(?W))));
+    let mut program = vec![];
+    interpret::program(&mut program, &structure!(p(f(?X), h(?Y, f(a)), ?Y))).unwrap();
+
+    let mut machine = Machine::new(8);
+    machine.execute(&query).unwrap();
+    machine.execute(&program).unwrap();
+    assert_eq!(
+        &format!("{:?}", machine.mgu(Register(1))),
         "p(f(f(a)),h(f(f(a)),f(a)),f(f(a)))");
 }
+
+#[test]
+fn listing() {
+    let mut program = vec![];
+    interpret::program(&mut program, &structure!(p(f(?X), ?X))).unwrap();
+    assert_eq!(
+        &format!("{}", Listing::labeled(functor!(p/2), &program)),
+        "p/2 : get_structure p/2, X1\n\
+         \x20     unify_variable X2\n\
+         \x20     unify_variable X3\n\
+         \x20     get_structure f/1, X2\n\
+         \x20     unify_value X3\n");
+}
+
+#[test]
+fn bytecode_round_trip() {
+    let mut program = Program::default();
+    let mut code = vec![];
+    interpret::program(&mut code, &structure!(p(f(?X), h(?Y, f(a)), ?Y))).unwrap();
+    program.predicates.push((functor!(p/3), code));
+
+    let mut bytes = vec![];
+    bytecode::save_program(&program, &mut bytes).unwrap();
+    assert_eq!(&bytes[0..4], b"WAM\0");
+    let loaded = bytecode::load_program(&bytes[..]).unwrap();
+    assert_eq!(loaded, program);
+
+    let mut query = vec![];
+    interpret::query(&mut query, &structure!(p(?Z, h(?Z, ?W), f(?W))));
+    let mut machine = Machine::new(8);
+    machine.execute(&query).unwrap();
+    machine.execute(&loaded.predicates[0].1).unwrap();
+    assert_eq!(
+        &format!("{:?}", machine.mgu(Register(1))),
+        "p(f(f(a)),h(f(f(a)),f(a)),f(f(a)))");
+}
+
+#[test]
+fn bytecode_rejects_other_versions() {
+    let mut bytes = vec![];
+    bytecode::save_program(&Program::default(), &mut bytes).unwrap();
+    bytes[4] = bytes[4].wrapping_add(1);
+    assert!(bytecode::load_program(&bytes[..]).is_err());
+    assert!(bytecode::load_program(&b"ELF\0"[..]).is_err());
+}
+
+#[test]
+fn bytecode_rejects_corrupt_lengths() {
+    fn u32(bytes: &mut Vec<u8>, n: u32) {
+        bytes.extend_from_slice(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+    }
+    let mut header = b"WAM\0".to_vec();
+    u32(&mut header, bytecode::VERSION);
+
+    // a functor whose name is far longer than the file
+    let mut bytes = header.clone();
+    u32(&mut bytes, 1);
+    u32(&mut bytes, u32::max_value());
+    bytes.extend_from_slice(b"p");
+    assert!(bytecode::load_program(&bytes[..]).is_err());
+
+    // a predicate with far more instructions than the file
+    let mut bytes = header.clone();
+    u32(&mut bytes, 1);
+    u32(&mut bytes, 1);
+    bytes.extend_from_slice(b"p");
+    u32(&mut bytes, 0);
+    u32(&mut bytes, 1);
+    u32(&mut bytes, 0);
+    u32(&mut bytes, u32::max_value());
+    assert!(bytecode::load_program(&bytes[..]).is_err());
+}
+
+#[test]
+fn share_across_threads() {
+    fn assert_send_sync<T: Send + Sync>() { }
+    assert_send_sync::<Functor>();
+    assert_send_sync::<Program>();
+
+    // compile on one thread...
+    let code = thread::spawn(|| {
+        let mut code = vec![];
+        interpret::program(&mut code, &structure!(p(f(?X), h(?Y, f(a)), ?Y))).unwrap();
+        code
+    }).join().unwrap();
+
+    // ...and run it, along with a machine built elsewhere, on another
+    let mut machine = Machine::new(8);
+    let mut query = vec![];
+    interpret::query(&mut query, &structure!(p(?Z, h(?Z, ?W), f(?W))));
+    machine.execute(&query).unwrap();
+    let text = thread::spawn(move || {
+        machine.execute(&code).unwrap();
+        format!("{:?}", machine.mgu(Register(1)))
+    }).join().unwrap();
+    assert_eq!(&text, "p(f(f(a)),h(f(f(a)),f(a)),f(f(a)))");
+}
+
+#[test]
+fn collect_transient_functors() {
+    let owner = Owner::new();
+    let other = Owner::new();
+    let (kept, dropped, shared) = {
+        let _entered = owner.enter();
+        (Functor::transient("collect_transient_functors_kept", 0),
+         Functor::transient("collect_transient_functors_dropped", 1),
+         Functor::transient("collect_transient_functors_shared", 0))
+    };
+    other.adopt(&shared);
+    let permanent = functor!(collect_transient_functors_permanent/0);
+    let unowned = Functor::transient("collect_transient_functors_unowned", 0);
+
+    // only `kept` is reachable from the machine
+    let mut machine = Machine::new(1);
+    machine.put_structure(kept, Register(0));
+
+    let mut marks = Marks::new();
+    machine.mark_functors(&mut marks);
+    assert_eq!(owner.collect(&marks), 1);
+
+    assert_eq!(&format!("{:?}", kept), "collect_transient_functors_kept/0");
+    assert_eq!(&format!("{:?}", permanent), "collect_transient_functors_permanent/0");
+    assert!(panic::catch_unwind(|| dropped.arity()).is_err());
+
+    // `shared` is still claimed by the other owner until it goes, and
+    // functors made outside any owner are permanent
+    assert_eq!(shared.arity(), 0);
+    drop(other);
+    assert!(panic::catch_unwind(|| shared.arity()).is_err());
+    assert_eq!(unowned.arity(), 0);
+
+    // re-creating the dropped functor yields a fresh handle, which
+    // is never confused with the stale one
+    let _entered = owner.enter();
+    let recreated = Functor::transient("collect_transient_functors_dropped", 1);
+    assert!(recreated != dropped);
+    assert_eq!(recreated.arity(), 1);
+}
@@ -6,5 +6,7 @@ mod ast;
 #[macro_use]
 mod functor;
 
+mod compile;
+mod engine;
 mod interpret;
 mod machine;